@@ -32,4 +32,57 @@ fn main() {
         dir = src_dir.display()
     );
     std::fs::write(format!("{out_dir}/generated_protocol.rs"), glue).unwrap();
+
+    build_info();
+}
+
+/// Expose the commit, build time and target to `build_info.rs`. Image builds
+/// without a `.git` directory can pass `COCOON_GIT_COMMIT` in; `SOURCE_DATE_EPOCH`
+/// pins the timestamp for reproducible builds.
+fn build_info() {
+    println!("cargo:rerun-if-env-changed=COCOON_GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    let git = |args: &[&str]| {
+        std::process::Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+
+    // Rebuild when HEAD moves, so the commit is never stale
+    for path in [git(&["rev-parse", "--git-path", "HEAD"]), git(&["rev-parse", "--git-path", "refs/heads"])]
+        .into_iter()
+        .flatten()
+    {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+
+    let commit = std::env::var("COCOON_GIT_COMMIT")
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(|| {
+            let commit = git(&["rev-parse", "--short=12", "HEAD"])?;
+            // Uncommitted changes to tracked files
+            let dirty = git(&["status", "--porcelain", "--untracked-files=no"]).is_some();
+            Some(if dirty { format!("{}-dirty", commit) } else { commit })
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+
+    let timestamp = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0)
+        });
+
+    println!("cargo:rustc-env=COCOON_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=COCOON_BUILD_TIMESTAMP={}", timestamp);
+    println!("cargo:rustc-env=COCOON_BUILD_TARGET={}", std::env::var("TARGET").unwrap_or_default());
 }
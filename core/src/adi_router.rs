@@ -153,43 +153,16 @@ impl AdiRouter {
     pub async fn handle_subscription(&self, subscription: AdiSubscription) -> AdiSubscription {
         match subscription {
             AdiSubscription::Subscribe { request_id, plugin, event, filter } => {
-                let svc = match self.plugins.get(&plugin) {
-                    Some(s) => s,
-                    None => return AdiSubscription::Error {
-                        request_id,
-                        code: "plugin_not_found".to_string(),
-                        message: format!("Plugin '{}' not found", plugin),
-                    },
-                };
-
-                if !svc.capabilities().subscriptions {
-                    return AdiSubscription::Error {
-                        request_id,
-                        code: "not_supported".to_string(),
-                        message: format!("Plugin '{}' does not support subscriptions", plugin),
-                    };
-                }
-
-                match svc.subscribe(&event, filter).await {
-                    Ok(_receiver) => {
-                        let subscription_id = Uuid::new_v4();
-                        let mut subs = self.subscriptions.write().await;
-                        subs.insert(subscription_id, ActiveSubscription {
-                            plugin: plugin.clone(),
-                            event: event.clone(),
-                        });
-
+                match self.open_subscription(&plugin, &event, filter).await {
+                    Ok((subscription_id, _receiver)) => {
                         AdiSubscription::Subscribed { request_id, subscription_id, plugin, event }
                     }
-                    Err(e) => AdiSubscription::Error {
-                        request_id, code: e.code, message: e.message,
-                    },
+                    Err((code, message)) => AdiSubscription::Error { request_id, code, message },
                 }
             }
 
             AdiSubscription::Unsubscribe { subscription_id } => {
-                let mut subs = self.subscriptions.write().await;
-                subs.remove(&subscription_id);
+                self.close_subscription(subscription_id).await;
                 AdiSubscription::Unsubscribed { subscription_id }
             }
 
@@ -197,6 +170,40 @@ impl AdiRouter {
         }
     }
 
+    /// Subscribe to `event` on `plugin`, returning the subscription id and the
+    /// receiver its events arrive on. Errors are `(code, message)`.
+    pub async fn open_subscription(
+        &self,
+        plugin: &str,
+        event: &str,
+        filter: Option<JsonValue>,
+    ) -> Result<(Uuid, broadcast::Receiver<SubscriptionEvent>), (String, String)> {
+        let svc = self.plugins.get(plugin).ok_or_else(|| {
+            ("plugin_not_found".to_string(), format!("Plugin '{}' not found", plugin))
+        })?;
+
+        if !svc.capabilities().subscriptions {
+            return Err((
+                "not_supported".to_string(),
+                format!("Plugin '{}' does not support subscriptions", plugin),
+            ));
+        }
+
+        let receiver = svc.subscribe(event, filter).await.map_err(|e| (e.code, e.message))?;
+        let subscription_id = Uuid::new_v4();
+        let mut subs = self.subscriptions.write().await;
+        subs.insert(subscription_id, ActiveSubscription {
+            plugin: plugin.to_string(),
+            event: event.to_string(),
+        });
+        Ok((subscription_id, receiver))
+    }
+
+    /// Forget a subscription; `false` if it was not open
+    pub async fn close_subscription(&self, subscription_id: Uuid) -> bool {
+        self.subscriptions.write().await.remove(&subscription_id).is_some()
+    }
+
     /// Handle a binary-framed ADI request.
     ///
     /// Parses the frame header, routes to the plugin, and returns a complete
@@ -0,0 +1,132 @@
+//! Jittered exponential backoff for signaling connection attempts
+//!
+//! When a signaling server restarts, every cocoon in a fleet loses its connection
+//! at the same moment. Retrying on a plain exponential schedule would bring them
+//! all back in lockstep, so each delay is drawn from a jitter window. The draw
+//! combines a per-device offset derived from the device ID with a random
+//! component, spreading the fleet even when many cocoons share the same clock.
+
+use lib_env_parse::{env_opt, env_vars};
+use rand::Rng;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+env_vars! {
+    CocoonReconnectBaseMs => "COCOON_RECONNECT_BASE_MS",
+    CocoonReconnectMaxMs => "COCOON_RECONNECT_MAX_MS",
+    CocoonReconnectJitter => "COCOON_RECONNECT_JITTER",
+}
+
+const DEFAULT_BASE: Duration = Duration::from_secs(1);
+const DEFAULT_MAX: Duration = Duration::from_secs(60);
+/// Full jitter: delays are drawn from the whole `[0, ceiling]` window
+const DEFAULT_JITTER: f64 = 1.0;
+
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    /// Fraction of the ceiling that is randomized, in `[0, 1]`
+    jitter: f64,
+    /// Per-device offset in `[0, 1)`
+    device_offset: f64,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration, jitter: f64, device_id: Option<&str>) -> Self {
+        let device_offset = match device_id {
+            Some(id) => {
+                let mut hasher = DefaultHasher::new();
+                id.hash(&mut hasher);
+                (hasher.finish() % 10_000) as f64 / 10_000.0
+            }
+            None => 0.0,
+        };
+        Self {
+            base,
+            max: max.max(base),
+            jitter: jitter.clamp(0.0, 1.0),
+            device_offset,
+            attempt: 0,
+        }
+    }
+
+    /// Backoff configured from `COCOON_RECONNECT_*`, falling back to defaults
+    pub fn from_env(device_id: Option<&str>) -> Self {
+        let millis = |var: EnvVar, default: Duration| {
+            env_opt(var.as_str())
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default)
+        };
+        let jitter = env_opt(EnvVar::CocoonReconnectJitter.as_str())
+            .and_then(|v| v.parse::<f64>().ok())
+            .filter(|j| j.is_finite())
+            .unwrap_or(DEFAULT_JITTER);
+
+        Self::new(
+            millis(EnvVar::CocoonReconnectBaseMs, DEFAULT_BASE),
+            millis(EnvVar::CocoonReconnectMaxMs, DEFAULT_MAX),
+            jitter,
+            device_id,
+        )
+    }
+
+    /// Ceiling for the current attempt: `base * 2^attempt`, capped at `max`
+    fn ceiling(&self) -> Duration {
+        let factor = 1u32.checked_shl(self.attempt.min(31)).unwrap_or(u32::MAX);
+        self.base.saturating_mul(factor).min(self.max)
+    }
+
+    /// Delay before the next attempt, never more than `max`
+    pub fn next_delay(&mut self) -> Duration {
+        let random: f64 = rand::rng().random();
+        let delay = self.delay_for(random);
+        self.attempt = self.attempt.saturating_add(1);
+        delay
+    }
+
+    fn delay_for(&self, random: f64) -> Duration {
+        let ceiling = self.ceiling();
+        let window = ceiling.mul_f64(self.jitter);
+        let position = (self.device_offset + random).fract();
+        (ceiling - window) + window.mul_f64(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_caps_at_max() {
+        let max = Duration::from_secs(10);
+        let mut backoff = Backoff::new(Duration::from_secs(1), max, 1.0, Some("device-1"));
+        for _ in 0..64 {
+            assert!(backoff.next_delay() <= max);
+        }
+        assert_eq!(backoff.ceiling(), max);
+    }
+
+    #[test]
+    fn test_backoff_jitter_window() {
+        let backoff = Backoff::new(Duration::from_secs(4), Duration::from_secs(60), 0.5, None);
+        assert_eq!(backoff.delay_for(0.0), Duration::from_secs(2));
+        assert!(backoff.delay_for(0.999) < Duration::from_secs(4));
+
+        let no_jitter = Backoff::new(Duration::from_secs(4), Duration::from_secs(60), 0.0, None);
+        assert_eq!(no_jitter.delay_for(0.7), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_device_offset_spreads_devices() {
+        let a = Backoff::new(DEFAULT_BASE, DEFAULT_MAX, 1.0, Some("device-a"));
+        let b = Backoff::new(DEFAULT_BASE, DEFAULT_MAX, 1.0, Some("device-b"));
+        assert_ne!(a.delay_for(0.0), b.delay_for(0.0));
+        assert_eq!(
+            a.delay_for(0.0),
+            Backoff::new(DEFAULT_BASE, DEFAULT_MAX, 1.0, Some("device-a")).delay_for(0.0)
+        );
+    }
+}
@@ -0,0 +1,56 @@
+//! Which cocoon binary is running and how it was built
+//!
+//! The commit, build time and target come from `build.rs`. The executable path
+//! has symlinks resolved, so it names the file an update actually replaced.
+
+use serde::{Deserialize, Serialize};
+
+/// Cargo features that change what a build can do
+const FEATURES: &[(&str, bool)] = &[
+    ("services", cfg!(feature = "services")),
+    ("standalone", cfg!(feature = "standalone")),
+    ("tasks-core", cfg!(feature = "tasks-core")),
+    ("tools-core", cfg!(feature = "tools-core")),
+    ("kb-service", cfg!(feature = "kb-service")),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Short commit hash, `-dirty` with uncommitted changes, or `unknown`
+    pub git_commit: String,
+    /// Unix seconds
+    pub build_timestamp: u64,
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub executable: Option<String>,
+    pub features: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let executable = std::env::current_exe()
+            .and_then(|exe| exe.canonicalize())
+            .map(|exe| exe.display().to_string())
+            .ok();
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("COCOON_GIT_COMMIT").to_string(),
+            build_timestamp: env!("COCOON_BUILD_TIMESTAMP").parse().unwrap_or(0),
+            target: env!("COCOON_BUILD_TARGET").to_string(),
+            executable,
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+        }
+    }
+
+    /// Build time as RFC 3339
+    pub fn built_at(&self) -> String {
+        chrono::DateTime::from_timestamp(self.build_timestamp as i64, 0)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_else(|| self.build_timestamp.to_string())
+    }
+}
@@ -0,0 +1,89 @@
+//! Environment of commands run on behalf of clients
+//!
+//! `execute`, `pipeline`, Silk and PTY children inherit the cocoon's environment
+//! by default, and that environment holds the cocoon's own credentials. Those
+//! are stripped from every child unless the client passes them explicitly. With `clean_env` a
+//! child starts from a minimal baseline instead and sees nothing of the
+//! cocoon's environment beyond it. Every child gets `COCOON_ACTIVE=1`, so an
+//! `adi cocoon run` inside it knows it is nested.
+
+use std::collections::HashMap;
+
+/// Cocoon variables never inherited by client commands
+pub const SENSITIVE_VARS: &[&str] = &[
+    "COCOON_SECRET",
+    "COCOON_SETUP_TOKEN",
+    "CREDENTIALS_DIRECTORY",
+    "WEBRTC_TURN_USERNAME",
+    "WEBRTC_TURN_CREDENTIAL",
+    "WEBRTC_TURN_REST_URL",
+];
+
+/// Variables a clean environment keeps from the cocoon so commands still resolve
+const BASELINE_VARS: &[&str] = &["PATH", "HOME", "TERM"];
+
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Full environment for a child: clear the command's environment, then set exactly this.
+/// `explicit` is what the client asked for and always wins.
+pub fn child_env(clean_env: bool, explicit: &HashMap<String, String>) -> HashMap<String, String> {
+    build(std::env::vars(), clean_env, explicit)
+}
+
+fn build(
+    inherited: impl Iterator<Item = (String, String)>,
+    clean_env: bool,
+    explicit: &HashMap<String, String>,
+) -> HashMap<String, String> {
+    let mut env: HashMap<String, String> = inherited
+        .filter(|(key, _)| {
+            if clean_env {
+                BASELINE_VARS.contains(&key.as_str())
+            } else {
+                !SENSITIVE_VARS.contains(&key.as_str())
+            }
+        })
+        .collect();
+    if clean_env {
+        env.entry("PATH".to_string()).or_insert_with(|| DEFAULT_PATH.to_string());
+    }
+    env.insert(crate::core::ACTIVE_MARKER.to_string(), "1".to_string());
+    env.extend(explicit.iter().map(|(k, v)| (k.clone(), v.clone())));
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_env_strips_secrets_and_cleans() {
+        let inherited = || {
+            [
+                ("PATH", "/bin"),
+                ("HOME", "/root"),
+                ("COCOON_SECRET", "s3cret"),
+                ("WEBRTC_TURN_CREDENTIAL", "turn"),
+                ("EDITOR", "vim"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+        };
+        let explicit: HashMap<String, String> =
+            [("WEBRTC_TURN_CREDENTIAL".to_string(), "mine".to_string())].into_iter().collect();
+
+        let inherit = build(inherited(), false, &explicit);
+        assert!(!inherit.contains_key("COCOON_SECRET"));
+        assert_eq!(inherit["WEBRTC_TURN_CREDENTIAL"], "mine");
+        assert_eq!(inherit["EDITOR"], "vim");
+        assert_eq!(inherit[crate::core::ACTIVE_MARKER], "1");
+
+        let clean = build(inherited(), true, &HashMap::new());
+        let mut keys: Vec<&str> = clean.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, vec!["COCOON_ACTIVE", "HOME", "PATH"]);
+
+        let clean_no_path = build(std::iter::empty(), true, &HashMap::new());
+        assert_eq!(clean_no_path["PATH"], DEFAULT_PATH);
+    }
+}
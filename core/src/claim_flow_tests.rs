@@ -0,0 +1,240 @@
+//! Claim flow integration tests
+//!
+//! Drives `run_with` end to end against an in-process mock signaling server.
+//! The mock speaks just enough of the `SignalingMessage` protocol to register a
+//! cocoon: it derives the device ID from the secret, refuses a stored ID that
+//! does not match, and claims the cocoon for an owner when a valid setup token
+//! is presented. Connections are in-memory channel pairs handed to the cocoon
+//! through a `SignalingConnector`, so no socket is involved.
+
+use crate::core::{run_with, Identity, Launch, SignalingConnector, WsSink, WsStream};
+use crate::e2e_tests::TEST_SECRET;
+use crate::RegistrationError;
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use lib_signaling_protocol::SignalingMessage;
+use signaling_core::security::{derive_device_id, validate_secret};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+const SALT: &str = "mock-signaling-salt";
+const SETUP_TOKEN: &str = "setup_tok_valid";
+const OWNER_ID: &str = "user_owner";
+
+/// Upper bound on a whole run, including reconnect backoff
+const RUN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Registration {
+    device_id: String,
+    owner_id: Option<String>,
+}
+
+#[derive(Default)]
+struct MockState {
+    connections: usize,
+    /// Connections to hang up on before answering, as a restarting server would
+    drop_before_answer: usize,
+    registrations: Vec<Registration>,
+}
+
+/// Mock signaling server; every `connect` opens a new session with it
+#[derive(Clone, Default)]
+struct MockSignaling {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockSignaling {
+    fn dropping_first(connections: usize) -> Self {
+        let mock = Self::default();
+        mock.state.lock().unwrap().drop_before_answer = connections;
+        mock
+    }
+
+    fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    fn registrations(&self) -> Vec<Registration> {
+        self.state.lock().unwrap().registrations.clone()
+    }
+
+    fn register(
+        &self,
+        secret: &str,
+        device_id: Option<String>,
+        tags: Option<HashMap<String, String>>,
+    ) -> SignalingMessage {
+        if let Err(message) = validate_secret(secret) {
+            return SignalingMessage::SystemError { message };
+        }
+
+        let derived_id = derive_device_id(secret, SALT);
+        if device_id.is_some_and(|id| id != derived_id) {
+            return SignalingMessage::SystemError {
+                message: "Device ID mismatch".to_string(),
+            };
+        }
+
+        let mut tags = tags.unwrap_or_default();
+        let owner_id = match tags.remove("setup_token") {
+            Some(token) if token == SETUP_TOKEN => Some(OWNER_ID.to_string()),
+            Some(_) => {
+                return SignalingMessage::SystemError {
+                    message: "Invalid setup token".to_string(),
+                }
+            }
+            None => None,
+        };
+        if let Some(ref owner_id) = owner_id {
+            tags.insert("owner_id".to_string(), owner_id.clone());
+        }
+
+        self.state.lock().unwrap().registrations.push(Registration {
+            device_id: derived_id.clone(),
+            owner_id,
+        });
+
+        SignalingMessage::DeviceRegisterResponse {
+            device_id: derived_id,
+            tags: if tags.is_empty() { None } else { Some(tags) },
+        }
+    }
+
+    /// Answer registrations on one connection. Hangs up once a cocoon is
+    /// registered, which ends its run.
+    async fn serve(
+        self,
+        mut incoming: mpsc::UnboundedReceiver<Message>,
+        outgoing: mpsc::UnboundedSender<Message>,
+        drop_before_answer: bool,
+    ) {
+        while let Some(msg) = incoming.next().await {
+            let Message::Text(text) = msg else { continue };
+            let Ok(SignalingMessage::DeviceRegister {
+                secret,
+                device_id,
+                tags,
+                ..
+            }) = serde_json::from_str(&text)
+            else {
+                continue;
+            };
+
+            if drop_before_answer {
+                return;
+            }
+
+            let reply = self.register(&secret, device_id, tags);
+            let registered = matches!(reply, SignalingMessage::DeviceRegisterResponse { .. });
+            let json = serde_json::to_string(&reply).unwrap();
+            if outgoing.unbounded_send(Message::Text(json)).is_err() || registered {
+                return;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl SignalingConnector for MockSignaling {
+    async fn connect(&self) -> Result<(WsSink, WsStream), String> {
+        let (to_server, from_cocoon) = mpsc::unbounded::<Message>();
+        let (to_cocoon, from_server) = mpsc::unbounded::<Message>();
+
+        let drop_before_answer = {
+            let mut state = self.state.lock().unwrap();
+            state.connections += 1;
+            let drop = state.drop_before_answer > 0;
+            state.drop_before_answer = state.drop_before_answer.saturating_sub(1);
+            drop
+        };
+        tokio::spawn(self.clone().serve(from_cocoon, to_cocoon, drop_before_answer));
+
+        let sink = to_server.sink_map_err(|_| WsError::ConnectionClosed);
+        Ok((Box::pin(sink), Box::pin(from_server.map(Ok))))
+    }
+}
+
+async fn run_against(
+    mock: &MockSignaling,
+    device_id: Option<String>,
+    setup_token: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let launch = Launch {
+        job: None,
+        connector: Some(Arc::new(mock.clone())),
+        identity: Some(Identity {
+            secret: TEST_SECRET.to_string(),
+            device_id,
+            setup_token: setup_token.map(str::to_string),
+        }),
+    };
+    tokio::time::timeout(RUN_TIMEOUT, run_with(launch))
+        .await
+        .expect("run did not finish")
+        .map(|_| ())
+}
+
+fn assert_permanently_rejected(result: Result<(), Box<dyn std::error::Error>>, reason: &str) {
+    let err = result.expect_err("registration should be rejected");
+    let err = err
+        .downcast_ref::<RegistrationError>()
+        .expect("expected a RegistrationError");
+    assert!(err.is_permanent(), "{}", err);
+    assert!(err.to_string().contains(reason), "{}", err);
+}
+
+#[tokio::test]
+async fn test_setup_token_claims_cocoon() {
+    let mock = MockSignaling::default();
+    run_against(&mock, None, Some(SETUP_TOKEN)).await.unwrap();
+
+    assert_eq!(
+        mock.registrations(),
+        vec![Registration {
+            device_id: derive_device_id(TEST_SECRET, SALT),
+            owner_id: Some(OWNER_ID.to_string()),
+        }]
+    );
+}
+
+#[tokio::test]
+async fn test_invalid_setup_token_is_not_retried() {
+    let mock = MockSignaling::default();
+    let result = run_against(&mock, None, Some("setup_tok_wrong")).await;
+
+    assert_permanently_rejected(result, "Invalid setup token");
+    assert_eq!(mock.connections(), 1);
+    assert!(mock.registrations().is_empty());
+}
+
+#[tokio::test]
+async fn test_stored_device_id_is_verified() {
+    let mock = MockSignaling::default();
+    let device_id = derive_device_id(TEST_SECRET, SALT);
+    run_against(&mock, Some(device_id.clone()), None).await.unwrap();
+
+    let registrations = mock.registrations();
+    assert_eq!(registrations.len(), 1);
+    assert_eq!(registrations[0].device_id, device_id);
+    assert_eq!(registrations[0].owner_id, None);
+
+    let mock = MockSignaling::default();
+    let result = run_against(&mock, Some("device-from-another-secret".to_string()), None).await;
+    assert_permanently_rejected(result, "Device ID mismatch");
+    assert_eq!(mock.connections(), 1);
+}
+
+#[tokio::test]
+async fn test_reconnects_when_registration_is_dropped() {
+    let mock = MockSignaling::dropping_first(1);
+    run_against(&mock, None, Some(SETUP_TOKEN)).await.unwrap();
+
+    assert_eq!(mock.connections(), 2);
+    let registrations = mock.registrations();
+    assert_eq!(registrations.len(), 1);
+    assert_eq!(registrations[0].owner_id.as_deref(), Some(OWNER_ID));
+}
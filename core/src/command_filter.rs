@@ -0,0 +1,256 @@
+//! Which programs a cocoon will run
+//!
+//! `COCOON_CMD_ALLOWLIST` and `COCOON_CMD_DENYLIST` are comma-separated program
+//! names or glob patterns, e.g. `git,cargo,npm*`. The program is the first word
+//! of the command after any leading `NAME=value` assignments. When an allowlist
+//! is set it alone decides and only the programs on it run; otherwise programs
+//! on the denylist are refused.
+//!
+//! Only the first program is looked at, so with an allowlist a command may not
+//! chain or nest others: `;`, `&`, `|`, `$(`, backticks, process substitution
+//! and line breaks are refused anywhere in it, even inside quotes. Pipes go
+//! through `pipeline`, whose stages are checked one by one. Leading assignments
+//! are refused too, as `GIT_PAGER=id git -p log` or `LD_PRELOAD=...` make an
+//! allowed program run another. An allowlist pattern without a `/` only admits
+//! a bare name looked up in `PATH`, so `./git` is not `git`; a pattern with a
+//! `/` is matched against the word as written.
+//!
+//! Denylist patterns with a `/` are likewise matched as written, others against
+//! the file name, so `rm` also covers `/bin/rm`. The denylist keeps honest
+//! clients away from a few programs; it is not a sandbox against
+//! `ls; rm -rf ~` or `sh -c`.
+
+use glob::Pattern;
+use lib_env_parse::{env_opt, env_vars};
+use std::sync::OnceLock;
+
+env_vars! {
+    CocoonCmdAllowlist => "COCOON_CMD_ALLOWLIST",
+    CocoonCmdDenylist => "COCOON_CMD_DENYLIST",
+}
+
+/// Error code for a command the lists do not let run
+pub const COMMAND_FORBIDDEN: &str = "command_forbidden";
+
+/// Shell syntax that runs another command, refused when there is an allowlist
+const CHAINING: &[&str] = &[";", "&", "|", "$(", "`", "<(", ">(", "\n", "\r"];
+
+#[derive(Debug, Default)]
+pub struct CommandFilter {
+    allow: Option<Vec<Pattern>>,
+    deny: Vec<Pattern>,
+}
+
+fn parse_list(var: &str, value: &str) -> Result<Vec<Pattern>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(|p| Pattern::new(p).map_err(|e| format!("Invalid pattern '{}' in {}: {}", p, var, e)))
+        .collect()
+}
+
+impl CommandFilter {
+    pub fn new(allowlist: Option<&str>, denylist: Option<&str>) -> Result<Self, String> {
+        let allow = allowlist
+            .filter(|v| !v.trim().is_empty())
+            .map(|v| parse_list(EnvVar::CocoonCmdAllowlist.as_str(), v))
+            .transpose()?;
+        let deny = match denylist {
+            Some(v) => parse_list(EnvVar::CocoonCmdDenylist.as_str(), v)?,
+            None => Vec::new(),
+        };
+        Ok(Self { allow, deny })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_none() && self.deny.is_empty()
+    }
+
+    /// `Err` with the reason when `command` may not run
+    pub fn check(&self, command: &str) -> Result<(), String> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        if self.allow.is_some() {
+            if let Some(op) = CHAINING.iter().find(|op| command.contains(*op)) {
+                return Err(format!(
+                    "{:?} may run a program outside COCOON_CMD_ALLOWLIST; send one command, or use pipeline for pipes",
+                    op
+                ));
+            }
+            let first = command.split_whitespace().next().unwrap_or_default();
+            if let Some((name, _)) = first.split_once('=').filter(|_| is_assignment(first)) {
+                return Err(format!(
+                    "Setting {} in front of the command is not allowed with COCOON_CMD_ALLOWLIST",
+                    name
+                ));
+            }
+        }
+        let Some(program) = program(command) else {
+            return Ok(());
+        };
+
+        match &self.allow {
+            Some(allow) if !allow.iter().any(|p| allows(p, &program)) => {
+                Err(format!("'{}' is not in COCOON_CMD_ALLOWLIST", program))
+            }
+            Some(_) => Ok(()),
+            None if self.deny.iter().any(|p| matches(p, &program)) => {
+                Err(format!("'{}' is in COCOON_CMD_DENYLIST", program))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Allowlist match: a pattern without a `/` only admits a bare name, which the
+/// shell resolves through `PATH`
+fn allows(pattern: &Pattern, program: &str) -> bool {
+    if pattern.as_str().contains('/') {
+        return pattern.matches(program);
+    }
+    !program.contains('/') && pattern.matches(program)
+}
+
+/// Denylist match: a pattern without a `/` also covers the program at any path
+fn matches(pattern: &Pattern, program: &str) -> bool {
+    if pattern.as_str().contains('/') {
+        return pattern.matches(program);
+    }
+    let name = program.rsplit('/').next().unwrap_or(program);
+    pattern.matches(name)
+}
+
+/// First word of `command` that is not a `NAME=value` assignment, unquoted
+fn program(command: &str) -> Option<String> {
+    command
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c| c == '\'' || c == '"'))
+        .find(|word| !is_assignment(word))
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty()
+                && !name.starts_with(|c: char| c.is_ascii_digit())
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+static FILTER: OnceLock<CommandFilter> = OnceLock::new();
+
+/// Read both lists once at startup; an invalid pattern is an error
+pub fn init_from_env() -> Result<(), String> {
+    let allowlist = env_opt(EnvVar::CocoonCmdAllowlist.as_str());
+    let denylist = env_opt(EnvVar::CocoonCmdDenylist.as_str());
+    let filter = CommandFilter::new(allowlist.as_deref(), denylist.as_deref())?;
+    if filter.allow.is_some() && !filter.deny.is_empty() {
+        tracing::warn!("⚠️ COCOON_CMD_ALLOWLIST is set, ignoring COCOON_CMD_DENYLIST");
+    }
+    if !filter.is_empty() {
+        tracing::info!(
+            "🔒 Command filter: {} allowed, {} denied pattern(s)",
+            filter.allow.as_ref().map_or("any".to_string(), |a| a.len().to_string()),
+            filter.deny.len()
+        );
+    }
+    let _ = FILTER.set(filter);
+    Ok(())
+}
+
+/// Whether `COCOON_CMD_ALLOWLIST` is in force
+pub fn has_allowlist() -> bool {
+    FILTER.get().is_some_and(|filter| filter.allow.is_some())
+}
+
+/// Check `command` against the configured lists; always passes before `init_from_env`
+pub fn check(command: &str) -> Result<(), String> {
+    match FILTER.get() {
+        Some(filter) => filter.check(command),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_allowlist() {
+        let filter = CommandFilter::new(Some("git, cargo*, /opt/tools/*"), None).unwrap();
+        assert!(filter.check("git status").is_ok());
+        assert!(filter.check("cargo-nextest run").is_ok());
+        assert!(filter.check("/opt/tools/lint --fix").is_ok());
+        assert!(filter.check("rm -rf /tmp/x").is_err());
+        assert!(filter.check("/usr/local/bin/lint").is_err());
+        assert!(filter.check("GIT=1 bash").is_err());
+
+        // Assignments in front may make an allowed program run another
+        for assigned in [
+            "GIT_PAGER=id git -p log",
+            "PATH=/tmp/x git status",
+            "LD_PRELOAD=/tmp/evil.so git status",
+            "RUST_LOG=debug cargo build",
+        ] {
+            let err = filter.check(assigned).unwrap_err();
+            assert!(!err.contains('/'), "the value stays out of {:?}", err);
+        }
+
+        // A bare pattern means the program found in PATH, not one at a path
+        for elsewhere in ["./git status", "x/git log", "/usr/bin/git log", "'./git' log"] {
+            assert!(filter.check(elsewhere).is_err(), "{:?} should be refused", elsewhere);
+        }
+
+        // The first program being allowed does not let others ride along
+        for chained in [
+            "git status; curl x | sh",
+            "git log && rm -rf ~",
+            "git log | sh",
+            "git $(curl x)",
+            "git `id`",
+            "cargo build\nrm -rf ~",
+            "git diff <(curl x)",
+            "git log &",
+        ] {
+            assert!(filter.check(chained).is_err(), "{:?} should be refused", chained);
+        }
+        let denylist_only = CommandFilter::new(None, Some("rm")).unwrap();
+        assert!(denylist_only.check("git log | head").is_ok());
+    }
+
+    #[test]
+    fn test_command_denylist() {
+        let filter = CommandFilter::new(None, Some("rm,shutdown,mkfs.*")).unwrap();
+        assert!(filter.check("ls -la").is_ok());
+        assert!(filter.check("echo rm").is_ok());
+        assert!(filter.check("rm -rf /").is_err());
+        assert!(filter.check("'/bin/rm' file").is_err());
+        assert!(filter.check("mkfs.ext4 /dev/sda1").is_err());
+
+        let err = filter.check("FORCE=1 shutdown now").unwrap_err();
+        assert!(err.contains("'shutdown'"), "{}", err);
+    }
+
+    #[test]
+    fn test_command_allowlist_takes_precedence() {
+        let filter = CommandFilter::new(Some("git,rm"), Some("rm,ls")).unwrap();
+        assert!(filter.check("git push").is_ok());
+        assert!(filter.check("rm old.log").is_ok());
+        assert!(filter.check("ls").is_err());
+        assert!(filter.check("cat README").is_err());
+
+        // An empty allowlist means no allowlist
+        let filter = CommandFilter::new(Some(" "), Some("rm")).unwrap();
+        assert!(filter.check("ls").is_ok());
+        assert!(filter.check("rm x").is_err());
+
+        assert!(CommandFilter::new(Some("git,[oops"), None).is_err());
+        assert!(CommandFilter::default().check("anything").is_ok());
+    }
+}
@@ -0,0 +1,120 @@
+//! Commands too long to pass as a single argument
+//!
+//! Linux caps one exec argument at 128 KiB (`MAX_ARG_STRLEN`), so a giant
+//! here-doc or inline base64 blob handed to `sh -c` fails to spawn with
+//! `E2BIG`. Commands over [`MAX_INLINE_BYTES`] are written to a private script
+//! in the temp directory instead, and the shell sources it with `. <path>`.
+//! Sourcing runs it in the same shell, so `exit`, stdin and the exit code
+//! behave as they do with `-c`. The script is removed when the returned
+//! [`ShellCommand`] is dropped and never lands in `/cocoon/output`.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Longest command passed to the shell inline, well under `MAX_ARG_STRLEN`
+pub const MAX_INLINE_BYTES: usize = 64 * 1024;
+
+/// A command ready for `<shell> -c`, holding its script file while it runs
+pub struct ShellCommand {
+    text: String,
+    script: Option<ScriptFile>,
+}
+
+impl ShellCommand {
+    pub fn prepare(command: &str) -> Result<Self, String> {
+        if command.len() <= MAX_INLINE_BYTES {
+            return Ok(Self {
+                text: command.to_string(),
+                script: None,
+            });
+        }
+
+        let script = ScriptFile::create(command)?;
+        tracing::debug!(
+            "Command is {} bytes, running it from {}",
+            command.len(),
+            script.0.display()
+        );
+        Ok(Self {
+            text: format!(". '{}'", script.0.display()),
+            script: Some(script),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Whether the command went to a script file
+    pub fn is_script(&self) -> bool {
+        self.script.is_some()
+    }
+}
+
+struct ScriptFile(PathBuf);
+
+impl ScriptFile {
+    fn create(command: &str) -> Result<Self, String> {
+        let path = std::env::temp_dir().join(format!("cocoon-cmd-{}.sh", uuid::Uuid::new_v4()));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&path)
+            .map_err(|e| format!("Failed to create command script {}: {}", path.display(), e))?;
+        // Own the path first, so a failed write still removes the file
+        let script = Self(path);
+        file.write_all(command.as_bytes())
+            .and_then(|_| file.write_all(b"\n"))
+            .map_err(|e| format!("Failed to write command script {}: {}", script.0.display(), e))?;
+        Ok(script)
+    }
+}
+
+impl Drop for ScriptFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_command_runs_from_script() {
+        let short = ShellCommand::prepare("echo hi").unwrap();
+        assert!(!short.is_script());
+        assert_eq!(short.as_str(), "echo hi");
+
+        // Over MAX_ARG_STRLEN, so `sh -c` on it directly would fail with E2BIG
+        let blob = "x".repeat(200 * 1024);
+        let command = format!("read line; echo \"$line\"; echo {} | wc -c; exit 7", blob);
+        let prepared = ShellCommand::prepare(&command).unwrap();
+        assert!(prepared.is_script());
+        assert!(prepared.as_str().len() < 200);
+        let path = prepared.script.as_ref().unwrap().0.clone();
+        assert!(path.exists());
+
+        let mut child = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(prepared.as_str())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(b"from stdin\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(output.status.code(), Some(7));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let count = (blob.len() + 1).to_string();
+        assert_eq!(stdout.split_whitespace().collect::<Vec<_>>(), vec!["from", "stdin", count.as_str()]);
+
+        drop(prepared);
+        assert!(!path.exists());
+    }
+}
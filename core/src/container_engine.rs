@@ -0,0 +1,123 @@
+//! Which container engine runs Docker-runtime cocoons
+//!
+//! `COCOON_CONTAINER_ENGINE` picks `docker` or `podman`. When it is unset the
+//! first of the two found on `PATH` is used, and `docker` when neither is, so
+//! the usual "is Docker installed" errors still show up. Both engines accept
+//! the same CLI for everything cocoon runs, with one exception: Podman only
+//! understands `host-gateway` in `--add-host` from 5.3 on.
+
+use lib_console_output::out_warn;
+use lib_env_parse::{env_opt, env_vars};
+use std::fmt;
+use std::sync::OnceLock;
+
+env_vars! {
+    CocoonContainerEngine => "COCOON_CONTAINER_ENGINE",
+    Path => "PATH",
+}
+
+/// First Podman release that maps `host-gateway` in `--add-host`
+const PODMAN_HOST_GATEWAY_SINCE: semver::Version = semver::Version::new(5, 3, 0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContainerEngine {
+    Docker,
+    Podman,
+}
+
+impl fmt::Display for ContainerEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.binary())
+    }
+}
+
+impl ContainerEngine {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "docker" => Ok(Self::Docker),
+            "podman" => Ok(Self::Podman),
+            other => Err(format!(
+                "Invalid COCOON_CONTAINER_ENGINE '{}': expected docker or podman",
+                other
+            )),
+        }
+    }
+
+    pub fn binary(self) -> &'static str {
+        match self {
+            Self::Docker => "docker",
+            Self::Podman => "podman",
+        }
+    }
+
+    /// New command for this engine's CLI
+    pub fn command(self) -> std::process::Command {
+        std::process::Command::new(self.binary())
+    }
+
+    /// Whether `--add-host name:host-gateway` works with this engine
+    pub fn supports_host_gateway(self) -> bool {
+        match self {
+            Self::Docker => true,
+            Self::Podman => self
+                .command()
+                .args(["version", "--format", "{{.Client.Version}}"])
+                .output()
+                .ok()
+                .filter(|o| o.status.success())
+                .and_then(|o| semver::Version::parse(String::from_utf8_lossy(&o.stdout).trim()).ok())
+                .is_some_and(|v| v >= PODMAN_HOST_GATEWAY_SINCE),
+        }
+    }
+}
+
+/// Engine named by `configured`, else the first of docker and podman that `on_path` finds
+fn detect(configured: Option<&str>, on_path: impl Fn(&str) -> bool) -> Result<ContainerEngine, String> {
+    if let Some(value) = configured.map(str::trim).filter(|v| !v.is_empty()) {
+        return ContainerEngine::parse(value);
+    }
+    Ok([ContainerEngine::Docker, ContainerEngine::Podman]
+        .into_iter()
+        .find(|engine| on_path(engine.binary()))
+        .unwrap_or(ContainerEngine::Docker))
+}
+
+fn on_path(binary: &str) -> bool {
+    env_opt(EnvVar::Path.as_str())
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(binary).is_file()))
+        .unwrap_or(false)
+}
+
+/// Engine for this process, detected on first use. An invalid
+/// `COCOON_CONTAINER_ENGINE` is reported once and then ignored.
+pub fn current() -> ContainerEngine {
+    static ENGINE: OnceLock<ContainerEngine> = OnceLock::new();
+    *ENGINE.get_or_init(|| {
+        let configured = env_opt(EnvVar::CocoonContainerEngine.as_str());
+        detect(configured.as_deref(), on_path).unwrap_or_else(|e| {
+            out_warn!("{}; detecting the engine instead", e);
+            detect(None, on_path).unwrap_or(ContainerEngine::Docker)
+        })
+    })
+}
+
+/// New command for the current engine's CLI
+pub fn command() -> std::process::Command {
+    current().command()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_container_engine() {
+        let only = |name: &'static str| move |binary: &str| binary == name;
+
+        assert_eq!(detect(Some(" Podman "), only("docker")).unwrap(), ContainerEngine::Podman);
+        assert_eq!(detect(Some(""), only("podman")).unwrap(), ContainerEngine::Podman);
+        assert_eq!(detect(None, |_| true).unwrap(), ContainerEngine::Docker);
+        assert_eq!(detect(None, |_| false).unwrap(), ContainerEngine::Docker);
+        assert!(detect(Some("containerd"), |_| true).is_err());
+    }
+}
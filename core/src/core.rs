@@ -1,5 +1,9 @@
 use crate::adi_router::AdiRouter;
-use crate::silk::{AnsiToHtml, SilkSession};
+use crate::backoff::Backoff;
+use crate::command_filter::COMMAND_FORBIDDEN;
+use crate::silk::{
+    detect_missing_command, missing_command_hint, AnsiToHtml, SilkSession, DUPLICATE_COMMAND_ID,
+};
 use futures::{SinkExt, StreamExt};
 use crate::protocol::messages::CocoonMessage;
 use crate::protocol::types::{SilkHtmlSpan, SilkStream};
@@ -8,15 +12,20 @@ use portable_pty::{CommandBuilder, PtySize};
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::Read;
 use std::path::Path;
+use std::pin::Pin;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncWriteExt;
 use tokio::sync::{broadcast, Mutex};
 use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::Instrument;
 use uuid::Uuid;
+use webrtc::data_channel::RTCDataChannel;
 use lib_env_parse::{env_vars, env_opt, env_or};
 
 env_vars! {
@@ -26,12 +35,33 @@ env_vars! {
     CocoonSetupToken => "COCOON_SETUP_TOKEN",
     CocoonName => "COCOON_NAME",
     CocoonProtocols => "COCOON_PROTOCOLS",
+    Path => "PATH",
+    CocoonRequirePersistence => "COCOON_REQUIRE_PERSISTENCE",
+    CocoonMaxResultBytes => "COCOON_MAX_RESULT_BYTES",
+    CocoonJobScript => "COCOON_JOB_SCRIPT",
+    CocoonJobUpload => "COCOON_JOB_UPLOAD",
+    CredentialsDirectory => "CREDENTIALS_DIRECTORY",
+    CocoonStrictSecret => "COCOON_STRICT_SECRET",
+    CocoonOutputMaxFileBytes => "COCOON_OUTPUT_MAX_FILE_BYTES",
+    CocoonOutputMaxTotalBytes => "COCOON_OUTPUT_MAX_TOTAL_BYTES",
+    CocoonOutputMaxFiles => "COCOON_OUTPUT_MAX_FILES",
+    CocoonStrictNested => "COCOON_STRICT_NESTED",
+    CocoonShutdownGraceMs => "COCOON_SHUTDOWN_GRACE_MS",
+    CocoonMaxPtySessions => "COCOON_MAX_PTY_SESSIONS",
+    CocoonProxyStreamThreshold => "COCOON_PROXY_STREAM_THRESHOLD",
+    CocoonPingInterval => "COCOON_PING_INTERVAL",
+    CocoonServiceMode => "COCOON_SERVICE_MODE",
 }
 
+const DATA_DIR: &str = "/cocoon";
 const OUTPUT_DIR: &str = "/cocoon/output";
 const RESPONSE_PATH: &str = "/cocoon/output/response.json";
-const SECRET_PATH: &str = "/cocoon/.secret";
-const DEVICE_ID_PATH: &str = "/cocoon/.device_id";
+pub(crate) const SECRET_PATH: &str = "/cocoon/.secret";
+pub(crate) const DEVICE_ID_PATH: &str = "/cocoon/.device_id";
+
+// systemd credential names (`LoadCredential=cocoon-secret:/path/to/file`)
+const SECRET_CREDENTIAL: &str = "cocoon-secret";
+const DEVICE_ID_CREDENTIAL: &str = "cocoon-device-id";
 
 // Secret security requirements
 const MIN_SECRET_LENGTH: usize = 32;
@@ -85,9 +115,20 @@ enum SilkResponse {
         exit_code: i32,
         cwd: String,
     },
+    /// A session persisted by the previous run, ready to take commands again
+    #[serde(rename = "silk_session_restored")]
+    SessionRestored {
+        session_id: Uuid,
+        cwd: String,
+        shell: String,
+    },
+    #[serde(rename = "silk_list_sessions_response")]
+    SessionList { sessions: Vec<SilkSessionInfo> },
     #[serde(rename = "silk_session_closed")]
     SessionClosed {
         session_id: Uuid,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
     },
     #[serde(rename = "silk_pty_output")]
     PtyOutput {
@@ -107,20 +148,80 @@ enum SilkResponse {
     },
 }
 
+/// A live PTY, Silk or WebRTC session, as `list_sessions` reports it
+#[derive(Debug, Serialize)]
+struct ActiveSession {
+    session_id: String,
+    /// `pty`, `silk` or `webrtc`
+    kind: &'static str,
+    /// The PTY's command, or the commands running in a Silk session
+    commands: Vec<String>,
+    age_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct SilkSessionInfo {
+    session_id: Uuid,
+    cwd: String,
+    shell: String,
+    /// Carried over from before the cocoon restarted
+    restored: bool,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum CommandRequest {
     Execute {
         command: String,
         input: Option<String>,
+        /// Env vars for the child whose values are never logged or echoed in errors
+        #[serde(default)]
+        secret_env: HashMap<String, String>,
+        /// Empty the output directory first so `files` only holds this command's output
+        #[serde(default)]
+        clear_output: bool,
+        /// Kill the command and everything it started after this long
+        #[serde(default)]
+        timeout_ms: Option<u64>,
+        /// Id for `cancel_exec`, echoed in the result
+        #[serde(default)]
+        request_id: Option<String>,
     },
 
+    /// Signal a running `execute` started with this `request_id`
+    CancelExec {
+        request_id: String,
+        /// `SIGTERM` (default), `SIGINT`, `SIGHUP`, `SIGQUIT` or `SIGKILL`
+        #[serde(default)]
+        signal: Option<String>,
+    },
+
+    /// Empty the output directory
+    ClearOutput,
+
     AttachPty {
         command: String,
         cols: u16,
         rows: u16,
         #[serde(default)]
         env: HashMap<String, String>,
+        #[serde(default)]
+        secret_env: HashMap<String, String>,
+        /// Start from a minimal environment instead of inheriting the cocoon's
+        #[serde(default)]
+        clean_env: bool,
+        /// Starting directory; the cocoon's own when unset
+        #[serde(default)]
+        cwd: Option<String>,
+        /// Shell that runs `command`, e.g. `/bin/bash`; `/bin/sh` when unset
+        #[serde(default)]
+        shell: Option<String>,
+    },
+
+    /// Run `stages` as a pipeline, each stage's stdout feeding the next stage's stdin
+    Pipeline {
+        stages: Vec<String>,
+        input: Option<String>,
     },
 
     PtyInput { session_id: Uuid, data: String },
@@ -134,6 +235,9 @@ enum CommandRequest {
 
     PtyClose { session_id: Uuid },
 
+    /// Join an existing PTY; answered with a repaint of its recent output
+    PtySubscribe { session_id: Uuid },
+
     ProxyHttp {
         request_id: String,
         service_name: String,
@@ -141,6 +245,10 @@ enum CommandRequest {
         path: String,
         headers: HashMap<String, String>,
         body: Option<String>,
+        /// Headers added by the cocoon side (e.g. internal auth). They take
+        /// precedence over `headers`, and their values are never logged.
+        #[serde(default)]
+        inject_headers: Option<HashMap<String, String>>,
     },
 
     QueryLocal {
@@ -156,12 +264,22 @@ enum CommandRequest {
         env: HashMap<String, String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         shell: Option<String>,
+        /// Start commands from a minimal environment instead of inheriting the cocoon's
+        #[serde(default)]
+        clean_env: bool,
     },
 
     SilkExecute {
         session_id: Uuid,
         command: String,
         command_id: String,
+        /// WebRTC session whose open "terminal" channel should carry the output
+        #[serde(default)]
+        webrtc_session_id: Option<String>,
+        /// Run in a PTY (`true`) or with piped output (`false`) instead of
+        /// guessing from the command
+        #[serde(default)]
+        force_interactive: Option<bool>,
     },
 
     /// Send input to running Silk command (for interactive mode)
@@ -179,6 +297,79 @@ enum CommandRequest {
     },
 
     SilkCloseSession { session_id: Uuid },
+
+    /// Open Silk sessions, including ones restored from a previous run
+    SilkListSessions,
+
+    /// Reap defunct children that no session is tracking
+    ReapZombies,
+
+    /// Every open PTY, Silk and WebRTC session
+    ListSessions,
+
+    /// End a session of any kind, killing what runs in it
+    KillSession { session_id: String },
+
+    HealthCheck,
+
+    /// Report the effective security policy; `full` lists details instead of counts
+    GetSecurityPolicy {
+        #[serde(default)]
+        full: bool,
+    },
+
+    // Not `webrtc_*`: that prefix is routed to the WebRTC signaling handler
+    /// List active WebRTC sessions
+    #[serde(rename = "list_webrtc_sessions")]
+    WebRtcList,
+
+    /// Force-close a WebRTC session and the terminal sessions attached to it
+    #[serde(rename = "close_webrtc_session")]
+    WebRtcClose { session_id: String },
+
+    /// Connection statistics of a WebRTC session, e.g. whether it is relayed
+    #[serde(rename = "get_webrtc_stats")]
+    WebRtcStats { session_id: String },
+
+    /// Per-service `proxy_http` counters; `reset` starts a new measurement window
+    ProxyStats {
+        #[serde(default)]
+        reset: bool,
+    },
+
+    /// Replace the `proxy_http` service registry without a restart; every
+    /// service is on localhost. Requests already in flight keep their target.
+    UpdateServices { services: HashMap<String, u16> },
+
+    /// Version, commit, target and resolved path of the running binary
+    BuildInfo,
+
+    /// Point-in-time counters, open sessions and proxy stats
+    GetMetrics,
+
+    /// Stream `event` responses for these topics; `*` subscribes to all of them
+    SubscribeEvents { topics: Vec<String> },
+
+    UnsubscribeEvents { topics: Vec<String> },
+
+    /// Relay `events` of ADI service `service_id` (e.g. `adi.tasks`) as
+    /// `service_event` responses until unsubscribed. `peer_id` is the
+    /// subscriber's signaling peer id; its disconnect ends the subscription.
+    Subscribe {
+        service_id: String,
+        events: Vec<String>,
+        #[serde(default)]
+        peer_id: Option<String>,
+    },
+
+    Unsubscribe { subscription_id: Uuid },
+
+    /// Push a `notice` to every connected client, e.g. ahead of planned maintenance
+    Broadcast {
+        message: String,
+        #[serde(default)]
+        level: NoticeLevel,
+    },
 }
 
 #[derive(Debug, Serialize)]
@@ -192,13 +383,42 @@ enum CommandResponse {
         error: Option<ErrorInfo>,
         #[serde(default)]
         files: Vec<OutputFile>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        omitted_files: Option<OmittedFiles>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+
+    /// Whether `cancel_exec` found the `execute` and signaled its process
+    #[serde(rename = "cancel_exec_response")]
+    ExecCancelled {
+        request_id: String,
+        found: bool,
+        signaled: bool,
     },
 
     PtyCreated { session_id: Uuid },
 
-    PtyOutput { session_id: Uuid, data: String },
+    PtyOutput {
+        session_id: Uuid,
+        /// The output as text; lossy when `binary` is set
+        data: String,
+        /// The output was not valid UTF-8; the exact bytes are in `data_base64`
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        binary: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data_base64: Option<String>,
+    },
+
+    PtyExited {
+        session_id: Uuid,
+        exit_code: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
 
-    PtyExited { session_id: Uuid, exit_code: i32 },
+    /// Recent PTY output for a client that joined mid-stream
+    PtyRepaint { session_id: Uuid, data: String },
 
     ProxyResult {
         request_id: String,
@@ -207,6 +427,23 @@ enum CommandResponse {
         body: Option<String>,
     },
 
+    /// Part of a `proxy_http` body too large for one `proxy_result`. `seq`
+    /// counts from 0, and only that first chunk has `status_code` and `headers`.
+    ProxyResultChunk {
+        request_id: String,
+        seq: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_code: Option<u16>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        headers: Option<HashMap<String, String>>,
+        /// Base64 of this part of the body
+        data: String,
+        is_final: bool,
+        /// The upstream body broke off; set on the final chunk only
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+
     QueryResult {
         query_id: String,
         data: JsonValue,
@@ -215,10 +452,116 @@ enum CommandResponse {
 
     Error { code: String, message: String },
 
+    ZombiesReaped { reaped: usize },
+
+    OutputCleared { removed: usize },
+
+    HealthStatus {
+        version: String,
+        /// Secret and device id survive restarts
+        persistent: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        storage_error: Option<String>,
+    },
+
+    SecurityPolicy(crate::policy::SecurityPolicy),
+
+    #[serde(rename = "list_webrtc_sessions_response")]
+    WebRtcSessions { sessions: Vec<crate::webrtc::WebRtcSessionInfo> },
+
+    #[serde(rename = "close_webrtc_session_response")]
+    WebRtcSessionClosed {
+        session_id: String,
+        /// Silk and PTY sessions that ended with it
+        terminal_sessions: Vec<String>,
+    },
+
+    #[serde(rename = "get_webrtc_stats_response")]
+    WebRtcStats(crate::webrtc::WebRtcSessionStats),
+
+    #[serde(rename = "list_sessions_response")]
+    Sessions { sessions: Vec<ActiveSession> },
+
+    #[serde(rename = "proxy_stats_response")]
+    ProxyStats { services: Vec<crate::proxy_stats::ServiceProxyStats> },
+
+    /// Registry in effect after `update_services`
+    #[serde(rename = "update_services_response")]
+    ServicesUpdated { services: HashMap<String, u16> },
+
+    #[serde(rename = "build_info_response")]
+    BuildInfo(crate::build_info::BuildInfo),
+
+    #[serde(rename = "metrics_response")]
+    Metrics(crate::metrics::MetricsSnapshot),
+
+    /// Topics subscribed after a `subscribe_events` or `unsubscribe_events`
+    EventSubscriptions { topics: Vec<String> },
+
+    Event { topic: String, data: JsonValue },
+
+    #[serde(rename = "subscribe_response")]
+    Subscribed {
+        subscription_id: Uuid,
+        service_id: String,
+        events: Vec<String>,
+    },
+
+    #[serde(rename = "unsubscribe_response")]
+    Unsubscribed { subscription_id: Uuid, found: bool },
+
+    /// One event relayed from an ADI service subscription
+    ServiceEvent {
+        subscription_id: Uuid,
+        service_id: String,
+        event: String,
+        data: JsonValue,
+    },
+
+    /// Operator message for every client; the same `notice_id` may arrive on
+    /// both signaling and a WebRTC channel
+    Notice {
+        notice_id: Uuid,
+        message: String,
+        level: NoticeLevel,
+    },
+
+    /// Outcome of a one-shot job; `files` only when outputs are uploaded
+    JobCompleted {
+        exit_code: i32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<ErrorInfo>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        files: Vec<OutputFile>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        omitted_files: Option<OmittedFiles>,
+    },
+
     #[serde(untagged)]
     SilkResponse(SilkResponse),
 }
 
+impl CommandResponse {
+    fn priority(&self) -> Priority {
+        match self {
+            CommandResponse::PtyOutput { .. }
+            | CommandResponse::SilkResponse(SilkResponse::PtyOutput { .. }) => Priority::Droppable,
+            _ => Priority::Control,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NoticeLevel {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
 #[derive(Debug, Serialize)]
 struct ErrorInfo {
     code: String,
@@ -231,6 +574,27 @@ struct OutputFile {
     path: String,
     content: String,
     binary: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated: Option<Truncation>,
+}
+
+/// Output files left out of a result by the `COCOON_OUTPUT_MAX_*` caps
+#[derive(Debug, Default, Serialize)]
+struct OmittedFiles {
+    count: usize,
+    /// Combined size of the omitted files
+    bytes: u64,
+    /// Paths of the omitted files, newest first; at most [`OMITTED_PATHS_LIMIT`]
+    paths: Vec<String>,
+}
+
+const OMITTED_PATHS_LIMIT: usize = 1000;
+
+/// How much of a text field was cut to keep a result within `COCOON_MAX_RESULT_BYTES`
+#[derive(Debug, Serialize)]
+struct Truncation {
+    original_bytes: usize,
+    omitted_bytes: usize,
 }
 
 struct PtySession {
@@ -239,1686 +603,6152 @@ struct PtySession {
     pair: portable_pty::PtyPair,
     child: Box<dyn portable_pty::Child + Send>,
     writer: Box<dyn std::io::Write + Send>,
+    replay: Arc<std::sync::Mutex<ReplayBuffer>>,
+    command: String,
+    created_at: std::time::Instant,
+    activity: crate::reaper::Activity,
 }
 
-type SharedWriter = Arc<
-    Mutex<
-        futures::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<
-                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
-            >,
-            Message,
-        >,
-    >,
->;
+/// How much recent PTY output is kept for repainting newly joined clients
+const PTY_REPLAY_BYTES: usize = 64 * 1024;
 
-async fn collect_output_files(dir: &str) -> Vec<OutputFile> {
-    let mut files = Vec::new();
-    let output_path = Path::new(dir);
+/// Tail of a PTY's output, replayed to clients that join mid-stream
+struct ReplayBuffer {
+    data: std::collections::VecDeque<u8>,
+    truncated: bool,
+}
 
-    if !output_path.exists() {
-        return files;
+impl ReplayBuffer {
+    fn new() -> Self {
+        Self {
+            data: std::collections::VecDeque::with_capacity(PTY_REPLAY_BYTES),
+            truncated: false,
+        }
     }
 
-    for entry in walkdir::WalkDir::new(dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter(|e| e.path().to_string_lossy() != RESPONSE_PATH)
-    {
-        let path = entry.path();
-        let rel_path = path
-            .strip_prefix(dir)
-            .map(|p| p.to_string_lossy().to_string())
-            .unwrap_or_else(|_| path.to_string_lossy().to_string());
-
-        match tokio::fs::read(path).await {
-            Ok(content) => {
-                let is_binary = content.contains(&0);
-                let content_str = if is_binary {
-                    base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &content)
-                } else {
-                    String::from_utf8_lossy(&content).to_string()
-                };
-
-                files.push(OutputFile {
-                    path: rel_path,
-                    content: content_str,
-                    binary: is_binary,
-                });
-            }
-            Err(_) => continue,
+    fn push(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        if self.data.len() > PTY_REPLAY_BYTES {
+            let excess = self.data.len() - PTY_REPLAY_BYTES;
+            self.data.drain(..excess);
+            self.truncated = true;
         }
     }
 
-    files
+    /// The buffered output as text. Once older output has been dropped the tail
+    /// starts at the next line, so replay never begins inside a character or
+    /// escape sequence cut in half.
+    fn snapshot(&self) -> String {
+        let bytes: Vec<u8> = self.data.iter().copied().collect();
+        let start = if self.truncated {
+            bytes.iter().position(|&b| b == b'\n').map(|i| i + 1).unwrap_or(0)
+        } else {
+            0
+        };
+        String::from_utf8_lossy(&bytes[start..]).to_string()
+    }
 }
 
-async fn execute_command(command: &str, input: Option<&str>) -> CommandResponse {
-    let _ = tokio::fs::create_dir_all(OUTPUT_DIR).await;
+/// PIDs of every child a PTY or Silk session owns, which the zombie reaper must leave alone
+async fn tracked_child_pids(
+    pty_sessions: &Mutex<HashMap<Uuid, PtySession>>,
+    silk_sessions: &Mutex<HashMap<Uuid, SilkSession>>,
+    webrtc: &crate::webrtc::WebRtcManager,
+) -> HashSet<u32> {
+    let mut pids: HashSet<u32> = pty_sessions
+        .lock()
+        .await
+        .values()
+        .filter_map(|s| s.child.process_id())
+        .collect();
+    pids.extend(silk_sessions.lock().await.values().flat_map(SilkSession::command_pids));
+    pids.extend(webrtc.child_pids().await);
+    pids
+}
 
-    let mut child = match tokio::process::Command::new("/bin/sh")
-        .arg("-c")
-        .arg(command)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-    {
-        Ok(child) => child,
-        Err(e) => {
-            return CommandResponse::ExecuteResult {
-                success: false,
-                data: None,
-                error: Some(ErrorInfo {
-                    code: "spawn_failed".into(),
-                    details: Some(e.to_string()),
-                }),
-                files: vec![],
-            };
-        }
-    };
+type WsError = tokio_tungstenite::tungstenite::Error;
 
-    if let Some(input_str) = input {
-        if let Some(mut stdin) = child.stdin.take() {
-            let _ = stdin.write_all(input_str.as_bytes()).await;
-            let _ = stdin.shutdown().await;
-        }
-    }
+/// Write half of the signaling WebSocket, erased over the underlying transport (TCP/TLS or Unix socket)
+pub(crate) type WsSink = Pin<Box<dyn futures::Sink<Message, Error = WsError> + Send>>;
 
-    let output = match child.wait_with_output().await {
-        Ok(output) => output,
-        Err(e) => {
-            return CommandResponse::ExecuteResult {
-                success: false,
-                data: None,
-                error: Some(ErrorInfo {
-                    code: "execution_failed".into(),
-                    details: Some(e.to_string()),
-                }),
-                files: vec![],
-            };
-        }
-    };
+/// Read half of the signaling WebSocket
+pub(crate) type WsStream = Pin<Box<dyn futures::Stream<Item = Result<Message, WsError>> + Send>>;
 
-    let files = collect_output_files(OUTPUT_DIR).await;
-    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+type SharedWriter = Arc<SignalingWriter>;
 
-    if output.status.success() {
-        CommandResponse::ExecuteResult {
-            success: true,
-            data: Some(serde_json::json!({
-                "stdout": stdout,
-                "stderr": stderr,
-                "exit_code": 0
-            })),
-            error: None,
-            files,
-        }
-    } else {
-        let exit_code = output.status.code().unwrap_or(-1);
-        CommandResponse::ExecuteResult {
-            success: false,
-            data: Some(serde_json::json!({
-                "stdout": stdout,
-                "stderr": stderr,
-                "exit_code": exit_code
-            })),
-            error: Some(ErrorInfo {
-                code: "command_failed".into(),
-                details: Some(format!("exit code: {}", exit_code)),
-            }),
-            files,
+/// Longest a frame may wait for and occupy the writer before the link counts as stalled
+const SEND_STALL_LIMIT: Duration = Duration::from_secs(15);
+/// How long a droppable frame waits for a busy writer before it is dropped
+const DROPPABLE_WAIT: Duration = Duration::from_millis(200);
+/// Sends slower than this are logged
+const SLOW_SEND: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Priority {
+    /// Responses, registration and control events; these must arrive
+    Control,
+    /// Frames a client can do without, e.g. PTY output it can repaint with `pty_subscribe`
+    Droppable,
+}
+
+/// The signaling write half, shared by every task that sends.
+///
+/// On a slow link a single `send` can block for a long time, and everything
+/// queued on the writer behind it stalls too. Droppable frames give up when
+/// the writer stays busy past [`DROPPABLE_WAIT`]. A frame that needs more than
+/// [`SEND_STALL_LIMIT`] to get through marks the link stalled, and the main loop
+/// drops the connection instead of letting the whole cocoon hang.
+struct SignalingWriter {
+    sink: Mutex<WsSink>,
+    stalled: tokio::sync::Notify,
+    dropped: AtomicU64,
+}
+
+impl SignalingWriter {
+    fn new(sink: WsSink) -> Self {
+        Self {
+            sink: Mutex::new(sink),
+            stalled: tokio::sync::Notify::new(),
+            dropped: AtomicU64::new(0),
         }
     }
-}
 
-async fn create_pty_session(
-    command: &str,
-    cols: u16,
-    rows: u16,
-    env: &HashMap<String, String>,
-    writer: SharedWriter,
-) -> Result<(Uuid, PtySession), String> {
-    let session_id = Uuid::new_v4();
-    let pty_system = portable_pty::native_pty_system();
+    async fn send(&self, text: String, priority: Priority) -> Result<(), String> {
+        self.send_message(Message::Text(text), priority).await
+    }
 
-    let pair = pty_system
-        .openpty(PtySize {
-            rows,
-            cols,
-            pixel_width: 0,
-            pixel_height: 0,
-        })
-        .map_err(|e| format!("Failed to open PTY: {}", e))?;
+    async fn send_message(&self, message: Message, priority: Priority) -> Result<(), String> {
+        let started = Instant::now();
+        let wait = match priority {
+            Priority::Control => SEND_STALL_LIMIT,
+            Priority::Droppable => DROPPABLE_WAIT,
+        };
 
-    let mut cmd = CommandBuilder::new("/bin/sh");
-    cmd.arg("-c");
-    cmd.arg(command);
+        let mut sink = match tokio::time::timeout(wait, self.sink.lock()).await {
+            Ok(sink) => sink,
+            Err(_) if priority == Priority::Droppable => {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    tracing::warn!("🐢 Signaling writer busy, dropped {} low-priority frame(s) so far", dropped);
+                }
+                return Err("writer busy, frame dropped".to_string());
+            }
+            Err(_) => {
+                self.mark_stalled(started);
+                return Err("writer stalled".to_string());
+            }
+        };
 
-    for (key, value) in env {
-        cmd.env(key, value);
+        let remaining = SEND_STALL_LIMIT.saturating_sub(started.elapsed());
+        crate::metrics::add(crate::metrics::Counter::SignalingBytesSent, message.len() as u64);
+        match tokio::time::timeout(remaining, sink.send(message)).await {
+            Ok(result) => {
+                let elapsed = started.elapsed();
+                if elapsed > SLOW_SEND {
+                    tracing::warn!("🐢 Signaling send took {:.1}s", elapsed.as_secs_f64());
+                }
+                result.map_err(|e| e.to_string())
+            }
+            Err(_) => {
+                self.mark_stalled(started);
+                Err("send stalled".to_string())
+            }
+        }
     }
 
-    // Set TERM for proper terminal support
-    cmd.env("TERM", "xterm-256color");
-
-    let child = pair
-        .slave
-        .spawn_command(cmd)
-        .map_err(|e| format!("Failed to spawn command: {}", e))?;
+    fn mark_stalled(&self, started: Instant) {
+        tracing::error!(
+            "❌ Signaling send stalled for {:.1}s, dropping the connection",
+            started.elapsed().as_secs_f64()
+        );
+        self.stalled.notify_one();
+    }
 
-    let mut reader = pair
-        .master
-        .try_clone_reader()
-        .map_err(|e| format!("Failed to clone reader: {}", e))?;
+    /// Resolves once a send has stalled past [`SEND_STALL_LIMIT`]
+    async fn stalled(&self) {
+        self.stalled.notified().await;
+    }
 
-    let session_id_clone = session_id;
-    tokio::task::spawn_blocking(move || {
-        let mut buffer = [0u8; 4096];
-        loop {
-            match reader.read(&mut buffer) {
-                Ok(0) => break,
-                Ok(n) => {
-                    let data = String::from_utf8_lossy(&buffer[..n]).to_string();
-                    let response = CommandResponse::PtyOutput {
-                        session_id: session_id_clone,
-                        data,
-                    };
+    async fn replace(&self, sink: WsSink) {
+        *self.sink.lock().await = sink;
+    }
+}
 
-                    let msg = SignalingMessage::SyncData {
-                        payload: serde_json::to_value(&response)
-                            .expect("CommandResponse serialization cannot fail"),
-                    };
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
 
-                    let writer_clone = writer.clone();
-                    tokio::spawn(async move {
-                        let mut w = writer_clone.lock().await;
-                        let _ = w
-                            .send(Message::Text(
-                                serde_json::to_string(&msg)
-                                    .expect("SignalingMessage serialization cannot fail"),
-                            ))
-                            .await;
-                    });
-                }
-                Err(e) => {
-                    tracing::warn!("PTY read error: {}", e);
-                    break;
-                }
-            }
-        }
+/// How often to ping the signaling server; `None` when `COCOON_PING_INTERVAL` is 0
+fn ping_interval() -> Option<Duration> {
+    let secs = env_opt(EnvVar::CocoonPingInterval.as_str())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_PING_INTERVAL.as_secs());
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
 
-        tracing::info!("PTY session {} reader task ended", session_id_clone);
-    });
+/// Tracks whether the signaling server still answers. A half-dead TCP
+/// connection (NAT timeout, partition) delivers nothing and fails no writes,
+/// so the only sign is a ping that goes unanswered.
+#[derive(Default)]
+struct Heartbeat {
+    /// When the ping still waiting for an answer went out
+    awaiting_since: Option<Instant>,
+}
 
-    let pty_writer = pair
-        .master
-        .take_writer()
-        .map_err(|e| format!("Failed to take PTY writer: {}", e))?;
+impl Heartbeat {
+    /// Any frame from the server, pongs included, shows the link is alive
+    fn frame_received(&mut self) {
+        self.awaiting_since = None;
+    }
 
-    Ok((
-        session_id,
-        PtySession {
-            id: session_id,
-            pair,
-            child,
-            writer: pty_writer,
-        },
-    ))
+    /// Called each interval before the next ping. `Err` with how long the
+    /// previous ping has gone unanswered means the link is dead.
+    fn ping_due(&mut self) -> Result<(), Duration> {
+        if let Some(since) = self.awaiting_since {
+            return Err(since.elapsed());
+        }
+        self.awaiting_since = Some(Instant::now());
+        Ok(())
+    }
 }
 
-async fn handle_proxy_request(
-    request_id: String,
-    service_name: String,
-    method: String,
-    path: String,
-    headers: HashMap<String, String>,
-    body: Option<String>,
-    services: &HashMap<String, u16>,
-) -> CommandResponse {
-    let port = match services.get(&service_name) {
-        Some(port) => *port,
-        None => {
-            tracing::warn!("Service not found: {}", service_name);
-            return CommandResponse::ProxyResult {
-                request_id,
-                status_code: 404,
-                headers: HashMap::new(),
-                body: Some(format!("Service not found: {}", service_name)),
-            };
+/// Open the signaling WebSocket.
+///
+/// `ws://` and `wss://` connect over TCP (with TLS for `wss`, configured by `tls`
+/// when set). `unix:///path/to/socket` connects over a Unix domain socket and
+/// performs the WebSocket handshake against `/ws` on it, carrying over the URL's
+/// query string.
+async fn connect_signaling(
+    url: &str,
+    tls: Option<&tokio_tungstenite::Connector>,
+) -> Result<(WsSink, WsStream), String> {
+    let parsed = crate::signaling_url::normalize_signaling_url(url)?;
+
+    match parsed.scheme() {
+        "ws" | "wss" => {
+            let (ws_stream, _) = match tls {
+                Some(tls) => {
+                    tokio_tungstenite::connect_async_tls_with_config(parsed.as_str(), None, false, Some(tls.clone()))
+                        .await
+                }
+                None => connect_async(parsed.as_str()).await,
+            }
+            .map_err(|e| e.to_string())?;
+            let (write, read) = ws_stream.split();
+            Ok((Box::pin(write), Box::pin(read)))
         }
-    };
-
-    let url = format!("http://localhost:{}{}", port, path);
-    tracing::debug!("Proxying {} {} to {}", method, path, url);
-
-    let client = reqwest::Client::new();
+        #[cfg(unix)]
+        "unix" => {
+            let socket_path = parsed.path();
+            let stream = tokio::net::UnixStream::connect(socket_path)
+                .await
+                .map_err(|e| format!("Failed to connect to Unix socket {}: {}", socket_path, e))?;
 
-    let http_method = match method.to_uppercase().as_str() {
-        "GET" => reqwest::Method::GET,
-        "POST" => reqwest::Method::POST,
-        "PUT" => reqwest::Method::PUT,
-        "DELETE" => reqwest::Method::DELETE,
-        "PATCH" => reqwest::Method::PATCH,
-        "HEAD" => reqwest::Method::HEAD,
-        "OPTIONS" => reqwest::Method::OPTIONS,
-        _ => {
-            tracing::warn!("Unsupported HTTP method: {}", method);
-            return CommandResponse::ProxyResult {
-                request_id,
-                status_code: 405,
-                headers: HashMap::new(),
-                body: Some(format!("Unsupported method: {}", method)),
+            let request = match parsed.query() {
+                Some(query) => format!("ws://localhost/ws?{}", query),
+                None => "ws://localhost/ws".to_string(),
             };
+            let (ws_stream, _) = tokio_tungstenite::client_async(request, stream)
+                .await
+                .map_err(|e| e.to_string())?;
+            let (write, read) = ws_stream.split();
+            Ok((Box::pin(write), Box::pin(read)))
         }
-    };
+        other => unreachable!("normalize_signaling_url rejects scheme '{}'", other),
+    }
+}
 
-    let mut request_builder = client.request(http_method, &url);
+/// Opens connections to the signaling server.
+///
+/// Production dials the configured URL; integration tests hand `run_with` an
+/// in-memory transport wired to a mock server instead.
+#[async_trait::async_trait]
+pub(crate) trait SignalingConnector: Send + Sync {
+    async fn connect(&self) -> Result<(WsSink, WsStream), String>;
+}
 
-    for (key, value) in headers {
-        request_builder = request_builder.header(&key, &value);
+/// Dials a `ws://`, `wss://` or `unix://` signaling URL
+struct UrlConnector {
+    url: String,
+    tls: Option<tokio_tungstenite::Connector>,
+}
+
+#[async_trait::async_trait]
+impl SignalingConnector for UrlConnector {
+    async fn connect(&self) -> Result<(WsSink, WsStream), String> {
+        connect_signaling(&self.url, self.tls.as_ref()).await
     }
+}
 
-    if let Some(body_str) = body {
-        request_builder = request_builder.body(body_str);
+/// Connect to the signaling server, retrying with `backoff` until it succeeds
+async fn connect_with_backoff(
+    connector: &dyn SignalingConnector,
+    backoff: &mut Backoff,
+) -> (WsSink, WsStream) {
+    loop {
+        match connector.connect().await {
+            Ok(conn) => {
+                crate::health::mark_connected();
+                return conn;
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                tracing::warn!(
+                    "⚠️ Failed to connect to signaling server: {} (retrying in {:.1}s)",
+                    e,
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
     }
+}
 
-    match request_builder
-        .timeout(std::time::Duration::from_secs(30))
-        .send()
-        .await
-    {
-        Ok(response) => {
-            let status_code = response.status().as_u16();
-            let mut response_headers = HashMap::new();
+/// Phrases in a registration rejection that mean retrying can never succeed
+const PERMANENT_REJECTIONS: &[&str] = &[
+    "invalid secret",
+    "secret mismatch",
+    "unauthorized",
+    "forbidden",
+    "revoked",
+    "banned",
+    "blocked",
+    "device id mismatch",
+    "invalid setup token",
+];
+
+/// Why registration with the signaling server did not complete
+#[derive(Debug)]
+pub enum RegistrationError {
+    /// The server refused to register this cocoon
+    Rejected { reason: String, permanent: bool },
+    /// The connection dropped before the server answered
+    Disconnected(String),
+}
 
-            for (key, value) in response.headers() {
-                if let Ok(value_str) = value.to_str() {
-                    response_headers.insert(key.to_string(), value_str.to_string());
-                }
-            }
+impl RegistrationError {
+    fn rejected(reason: String) -> Self {
+        let lower = reason.to_lowercase();
+        let permanent = PERMANENT_REJECTIONS.iter().any(|phrase| lower.contains(phrase));
+        Self::Rejected { reason, permanent }
+    }
 
-            let response_body = match response.text().await {
-                Ok(text) => Some(text),
-                Err(e) => {
-                    tracing::warn!("Failed to read response body: {}", e);
-                    None
-                }
-            };
+    /// Whether the same secret and device ID will be refused again
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::Rejected { permanent: true, .. })
+    }
+}
 
-            CommandResponse::ProxyResult {
-                request_id,
-                status_code,
-                headers: response_headers,
-                body: response_body,
+impl std::fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rejected { reason, permanent: true } => {
+                write!(f, "Registration permanently rejected by signaling server: {}", reason)
             }
-        }
-        Err(e) => {
-            tracing::error!("HTTP proxy request failed: {}", e);
-            CommandResponse::ProxyResult {
-                request_id,
-                status_code: 502,
-                headers: HashMap::new(),
-                body: Some(format!("Proxy error: {}", e)),
+            Self::Rejected { reason, permanent: false } => {
+                write!(f, "Registration rejected by signaling server: {}", reason)
             }
+            Self::Disconnected(detail) => write!(f, "Registration did not complete: {}", detail),
         }
     }
 }
 
-async fn handle_query_local(
-    query_id: String,
-    query_type: QueryType,
-    params: JsonValue,
-) -> CommandResponse {
-    match query_type {
-        QueryType::ListTasks => {
-            tracing::debug!("Listing local tasks with params: {:?}", params);
+impl std::error::Error for RegistrationError {}
 
-            CommandResponse::QueryResult {
-                query_id,
-                data: serde_json::json!({
-                    "tasks": [],
-                    "total": 0,
-                    "source": "cocoon-local"
-                }),
-                is_final: true,
+/// Wait for the server's answer to `DeviceRegister`
+async fn await_registration(
+    read: &mut WsStream,
+) -> Result<(String, Option<HashMap<String, String>>), RegistrationError> {
+    while let Some(Ok(msg)) = read.next().await {
+        let text = match msg {
+            Message::Text(t) => t,
+            Message::Close(_) => {
+                return Err(RegistrationError::Disconnected("connection closed".to_string()))
             }
+            _ => continue,
+        };
+        if let Some(mismatch) = crate::protocol_version::ProtocolMismatch::parse(&text) {
+            crate::protocol_version::handle(&mismatch).await;
+            return Err(RegistrationError::Rejected {
+                reason: mismatch.to_string(),
+                permanent: true,
+            });
         }
-        QueryType::GetTaskStats => {
-            tracing::debug!("Getting task stats");
-
-            CommandResponse::QueryResult {
-                query_id,
-                data: serde_json::json!({
-                    "pending": 0,
-                    "running": 0,
-                    "completed": 0,
-                    "failed": 0,
-                    "total": 0
-                }),
-                is_final: true,
+        match serde_json::from_str::<SignalingMessage>(&text) {
+            Ok(SignalingMessage::DeviceRegisterResponse { device_id, tags }) => {
+                return Ok((device_id, tags));
             }
-        }
-        QueryType::SearchTasks => {
-            let query = params.get("query").and_then(|v| v.as_str()).unwrap_or("");
-            tracing::debug!("Searching tasks for: {}", query);
-
-            CommandResponse::QueryResult {
-                query_id,
-                data: serde_json::json!({
-                    "tasks": [],
-                    "query": query,
-                    "total": 0
-                }),
-                is_final: true,
+            Ok(SignalingMessage::SystemError { message }) => {
+                return Err(RegistrationError::rejected(message));
             }
+            _ => continue,
         }
-        QueryType::SearchKnowledgebase => {
-            let query = params.get("query").and_then(|v| v.as_str()).unwrap_or("");
-            tracing::debug!("Searching knowledgebase for: {}", query);
+    }
+    Err(RegistrationError::Disconnected("connection closed".to_string()))
+}
 
-            CommandResponse::QueryResult {
-                query_id,
-                data: serde_json::json!({
-                    "results": [],
-                    "query": query,
-                    "total": 0
-                }),
-                is_final: true,
-            }
-        }
-        QueryType::Custom { query_name } => {
-            tracing::warn!("Custom query not implemented: {}", query_name);
+/// Notice sent to clients when the cocoon is asked to stop
+const SHUTDOWN_NOTICE: &str = "Cocoon is shutting down; sessions will be closed";
 
-            CommandResponse::QueryResult {
-                query_id,
-                data: serde_json::json!({
-                    "error": format!("Custom query '{}' not implemented", query_name)
-                }),
-                is_final: true,
-            }
+/// Messages the WebRTC manager sends over signaling that are also `webrtc` events
+const WEBRTC_EVENT_TYPES: &[&str] = &["webrtc_session_ended", "webrtc_channel_open", "webrtc_channel_close"];
+
+async fn send_response(writer: &SharedWriter, response: &CommandResponse) {
+    let msg = SignalingMessage::SyncData {
+        payload: serde_json::to_value(response).expect("CommandResponse serialization cannot fail"),
+    };
+    let text = serde_json::to_string(&msg).expect("SignalingMessage serialization cannot fail");
+    let priority = response.priority();
+    if let Err(e) = writer.send(text, priority).await {
+        if priority == Priority::Control {
+            tracing::error!("❌ Failed to send response: {}", e);
         }
     }
 }
 
-fn validate_secret(secret: &str) -> Result<(), String> {
-    if secret.len() < MIN_SECRET_LENGTH {
-        return Err(format!(
-            "Secret too short: {} characters (minimum: {})",
-            secret.len(),
-            MIN_SECRET_LENGTH
-        ));
-    }
+/// Delivers the frames of a single Silk command.
+///
+/// The link is picked once when the command starts: an open WebRTC "terminal"
+/// channel if the client named one, otherwise signaling. Every frame is awaited
+/// in sequence, so `CommandStarted`, `Output` and `CommandCompleted` keep their
+/// order. If the data channel fails mid-command the remaining frames fall back
+/// to signaling for good rather than flapping between links.
+struct SilkOutputSink {
+    writer: SharedWriter,
+    data_channel: Option<Arc<RTCDataChannel>>,
+}
 
-    if secret.chars().all(|c| c.is_numeric()) {
-        return Err("Secret must not be only numbers".to_string());
+impl SilkOutputSink {
+    async fn new(
+        writer: SharedWriter,
+        webrtc: &crate::webrtc::WebRtcManager,
+        webrtc_session_id: Option<&str>,
+    ) -> Self {
+        let data_channel = match webrtc_session_id {
+            Some(id) => webrtc.open_data_channel(id, "terminal").await,
+            None => None,
+        };
+        if let Some(id) = webrtc_session_id {
+            if data_channel.is_none() {
+                tracing::debug!("🧵 No open terminal channel for WebRTC session {}, using signaling", id);
+            }
+        }
+        Self { writer, data_channel }
     }
 
-    if secret.to_lowercase() == secret && secret.chars().all(|c| c.is_alphabetic()) {
-        return Err("Secret must not be only lowercase letters".to_string());
+    async fn send(&mut self, response: SilkResponse) {
+        let response = CommandResponse::SilkResponse(response);
+        if let Some(dc) = &self.data_channel {
+            let json = serde_json::to_string(&response).expect("CommandResponse serialization cannot fail");
+            match dc.send(&json.into_bytes().into()).await {
+                Ok(_) => return,
+                Err(e) => {
+                    tracing::warn!("⚠️ Terminal data channel send failed, falling back to signaling: {}", e);
+                    self.data_channel = None;
+                }
+            }
+        }
+        send_response(&self.writer, &response).await;
     }
+}
 
-    if secret.chars().all(|c| Some(c) == secret.chars().next()) {
-        return Err("Secret must not be repetitive characters".to_string());
-    }
+/// Caps on what `collect_output_files` reads, so a huge output can't exhaust memory
+#[derive(Debug, Clone, Copy)]
+struct OutputLimits {
+    max_file_bytes: u64,
+    max_total_bytes: u64,
+    max_files: usize,
+}
 
-    let lower = secret.to_lowercase();
-    let weak_patterns = ["password", "secret", "admin", "12345", "qwerty", "test"];
-    for pattern in &weak_patterns {
-        if lower.contains(pattern) {
-            return Err(format!("Secret contains weak pattern: {}", pattern));
+impl OutputLimits {
+    const DEFAULT_MAX_FILE_BYTES: u64 = 16 * 1024 * 1024;
+    const DEFAULT_MAX_TOTAL_BYTES: u64 = 64 * 1024 * 1024;
+    const DEFAULT_MAX_FILES: usize = 1000;
+
+    /// Limits from `COCOON_OUTPUT_MAX_*`, falling back to the defaults
+    fn from_env() -> Self {
+        let limit = |var: EnvVar| env_opt(var.as_str()).and_then(|v| v.trim().parse::<u64>().ok());
+        Self {
+            max_file_bytes: limit(EnvVar::CocoonOutputMaxFileBytes).unwrap_or(Self::DEFAULT_MAX_FILE_BYTES),
+            max_total_bytes: limit(EnvVar::CocoonOutputMaxTotalBytes).unwrap_or(Self::DEFAULT_MAX_TOTAL_BYTES),
+            max_files: limit(EnvVar::CocoonOutputMaxFiles)
+                .map(|n| n as usize)
+                .unwrap_or(Self::DEFAULT_MAX_FILES),
         }
     }
+}
 
-    Ok(())
+async fn collect_output_files(dir: &str) -> (Vec<OutputFile>, Option<OmittedFiles>) {
+    collect_output_files_within(dir, OutputLimits::from_env()).await
 }
 
-fn generate_strong_secret() -> String {
-    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
-    let mut rng = rand::rng();
+/// Read the files under `dir`, newest first so the caps keep the most recent
+/// output. A file over `max_file_bytes` is listed without content, marked
+/// truncated with its size. Once `max_files` entries are collected, or a file
+/// would pass `max_total_bytes`, that file and every older one is reported in
+/// the omitted summary instead. The total counts `content` as returned, so a
+/// binary file counts at its base64 length.
+async fn collect_output_files_within(dir: &str, limits: OutputLimits) -> (Vec<OutputFile>, Option<OmittedFiles>) {
+    let mut files = Vec::new();
+    let output_path = Path::new(dir);
 
-    (0..GENERATED_SECRET_LENGTH)
-        .map(|_| {
-            let idx = rng.random_range(0..CHARSET.len());
-            CHARSET[idx] as char
+    if !output_path.exists() {
+        return (files, None);
+    }
+
+    // Only metadata here, so a directory of thousands of files stays cheap to walk
+    let mut entries: Vec<(std::path::PathBuf, String, u64, std::time::SystemTime)> = walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.path().to_string_lossy() != RESPONSE_PATH)
+        .filter_map(|entry| {
+            let meta = entry.metadata().ok()?;
+            let path = entry.path().to_path_buf();
+            let rel_path = path
+                .strip_prefix(dir)
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_else(|_| path.to_string_lossy().to_string());
+            let modified = meta.modified().unwrap_or(std::time::UNIX_EPOCH);
+            Some((path, rel_path, meta.len(), modified))
         })
-        .collect()
-}
+        .collect();
+    entries.sort_by(|a, b| b.3.cmp(&a.3).then_with(|| a.1.cmp(&b.1)));
+
+    let mut omitted = OmittedFiles::default();
+    let mut total_bytes = 0u64;
+    let omit = |omitted: &mut OmittedFiles, rel_path: String, size: u64| {
+        omitted.count += 1;
+        omitted.bytes += size;
+        if omitted.paths.len() < OMITTED_PATHS_LIMIT {
+            omitted.paths.push(rel_path);
+        }
+    };
+    for (path, rel_path, size, _) in entries {
+        // The size on disk is a lower bound on the returned content, so a file
+        // it already rules out is never read
+        let full = omitted.count > 0
+            || files.len() >= limits.max_files
+            || (size <= limits.max_file_bytes && total_bytes + size > limits.max_total_bytes);
+        if full {
+            omit(&mut omitted, rel_path, size);
+            continue;
+        }
 
-async fn load_device_id() -> Option<String> {
-    match tokio::fs::read_to_string(DEVICE_ID_PATH).await {
-        Ok(device_id) => {
-            let device_id = device_id.trim().to_string();
-            if device_id.is_empty() {
-                None
-            } else {
-                tracing::info!("📱 Loaded existing device ID from {}", DEVICE_ID_PATH);
-                Some(device_id)
+        if size > limits.max_file_bytes {
+            tracing::warn!("📁 Output file {} is {} bytes, sending it without content", rel_path, size);
+            files.push(OutputFile {
+                path: rel_path,
+                content: String::new(),
+                binary: false,
+                truncated: Some(Truncation {
+                    original_bytes: size as usize,
+                    omitted_bytes: size as usize,
+                }),
+            });
+            continue;
+        }
+
+        match tokio::fs::read(&path).await {
+            Ok(content) => {
+                let is_binary = content.contains(&0);
+                let content_str = if is_binary {
+                    base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &content)
+                } else {
+                    String::from_utf8_lossy(&content).to_string()
+                };
+                if total_bytes + content_str.len() as u64 > limits.max_total_bytes {
+                    omit(&mut omitted, rel_path, size);
+                    continue;
+                }
+                total_bytes += content_str.len() as u64;
+
+                files.push(OutputFile {
+                    path: rel_path,
+                    content: content_str,
+                    binary: is_binary,
+                    truncated: None,
+                });
             }
+            Err(_) => continue,
         }
-        Err(_) => None,
     }
-}
 
-async fn save_device_id(device_id: &str) {
-    if let Err(e) = tokio::fs::write(DEVICE_ID_PATH, device_id).await {
-        tracing::warn!("⚠️ Could not save device ID to {}: {}", DEVICE_ID_PATH, e);
-        tracing::warn!("💡 Mount volume at /cocoon for persistent device ID");
-    } else {
-        tracing::info!(
-            "💾 Saved device ID to {} for reconnection verification",
-            DEVICE_ID_PATH
-        );
+    if omitted.count == 0 {
+        return (files, None);
     }
+    tracing::warn!(
+        "📁 Output capped at {} file(s); omitted {} more ({} bytes)",
+        files.len(),
+        omitted.count,
+        omitted.bytes
+    );
+    (files, Some(omitted))
 }
 
-async fn send_deregister(writer: &SharedWriter, device_id: &str, reason: Option<&str>) {
-    let deregister_msg = SignalingMessage::DeviceDeregister {
-        device_id: device_id.to_string(),
-        reason: reason.map(|r| r.to_string()),
-    };
-
-    let mut w = writer.lock().await;
-    if let Err(e) = w
-        .send(Message::Text(
-            serde_json::to_string(&deregister_msg)
-                .expect("SignalingMessage serialization cannot fail"),
-        ))
-        .await
-    {
-        tracing::warn!("⚠️ Failed to send deregister message: {}", e);
-    } else {
-        tracing::info!("📤 Sent deregister message to server");
-    }
+/// Remove everything inside `dir`, leaving the directory itself.
+///
+/// Symlinks are removed as links and never followed, so nothing outside `dir`
+/// is touched. A `dir` that is itself a symlink is refused. Returns how many
+/// top-level entries were removed.
+async fn clear_output_dir(dir: &Path) -> Result<usize, String> {
+    remove_output_entries(dir, None).await
 }
 
-async fn get_or_create_secret() -> Result<(String, Option<String>), Box<dyn std::error::Error>> {
-    let device_id = load_device_id().await;
-
-    // Try environment variable first (for manual management)
-    if let Some(secret) = env_opt(EnvVar::CocoonSecret.as_str()) {
-        tracing::info!("📋 Using secret from COCOON_SECRET environment variable");
+/// Remove the top-level entries of `dir` in which nothing has been modified
+/// for `ttl`, with the same symlink handling as [`clear_output_dir`]
+async fn expire_output_dir(dir: &Path, ttl: Duration) -> Result<usize, String> {
+    let cutoff = std::time::SystemTime::now().checked_sub(ttl).unwrap_or(std::time::UNIX_EPOCH);
+    remove_output_entries(dir, Some(cutoff)).await
+}
 
-        if let Err(e) = validate_secret(&secret) {
-            tracing::error!("❌ Invalid secret from COCOON_SECRET: {}", e);
-            tracing::error!("💡 Secret requirements:");
-            tracing::error!("   - Minimum {} characters", MIN_SECRET_LENGTH);
-            tracing::error!("   - Must be random and unpredictable");
-            tracing::error!("   - Avoid common patterns, dictionary words");
-            tracing::error!("   - Use: openssl rand -base64 36");
-            return Err(format!("Invalid COCOON_SECRET: {}", e).into());
+/// Remove the entries of `dir`, or with `older_than` only those last modified before it
+async fn remove_output_entries(dir: &Path, older_than: Option<std::time::SystemTime>) -> Result<usize, String> {
+    match tokio::fs::symlink_metadata(dir).await {
+        Ok(meta) if meta.file_type().is_symlink() => {
+            return Err(format!("{} is a symlink, refusing to clear it", dir.display()));
         }
-
-        return Ok((secret, device_id));
+        Ok(meta) if !meta.is_dir() => {
+            return Err(format!("{} is not a directory", dir.display()));
+        }
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(format!("Failed to inspect {}: {}", dir.display(), e)),
     }
 
-    match tokio::fs::read_to_string(SECRET_PATH).await {
-        Ok(secret) => {
-            let secret = secret.trim().to_string();
-
-            if let Err(e) = validate_secret(&secret) {
-                tracing::error!("❌ Invalid secret from {}: {}", SECRET_PATH, e);
-                tracing::error!("💡 Deleting weak secret and generating new one");
-                let _ = tokio::fs::remove_file(SECRET_PATH).await;
-                // Also delete device_id since secret changed
-                let _ = tokio::fs::remove_file(DEVICE_ID_PATH).await;
-            } else {
-                tracing::info!("🔑 Loaded existing secret from {}", SECRET_PATH);
-                return Ok((secret, device_id));
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+    let mut removed = 0;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+    {
+        let path = entry.path();
+        if let Some(cutoff) = older_than {
+            let scan = path.clone();
+            let modified = tokio::task::spawn_blocking(move || crate::output_policy::last_modified(&scan))
+                .await
+                .map_err(|e| format!("Failed to inspect {}: {}", path.display(), e))?;
+            if modified >= cutoff {
+                continue;
             }
         }
-        Err(_) => {}
+        // DirEntry::file_type does not follow symlinks
+        let file_type = entry
+            .file_type()
+            .await
+            .map_err(|e| format!("Failed to inspect {}: {}", path.display(), e))?;
+        // remove_dir_all does not follow symlinks inside the tree either
+        let result = if file_type.is_dir() {
+            tokio::fs::remove_dir_all(&path).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        };
+        result.map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+        removed += 1;
     }
 
-    let secret = generate_strong_secret();
-    tracing::info!(
-        "🆕 Generated new cryptographically strong secret ({} chars, {} bits entropy)",
-        GENERATED_SECRET_LENGTH,
-        GENERATED_SECRET_LENGTH * 6
-    );
+    Ok(removed)
+}
 
-    // Try to save it (may fail in read-only containers, that's ok)
-    if let Err(e) = tokio::fs::write(SECRET_PATH, &secret).await {
-        tracing::warn!(
-            "⚠️ Could not save secret to {} (ephemeral session): {}",
-            SECRET_PATH,
-            e
-        );
-        tracing::warn!(
-            "💡 Set COCOON_SECRET env var or mount volume at /cocoon for persistent sessions"
-        );
-    } else {
-        tracing::info!("💾 Saved secret to {} for persistent sessions", SECRET_PATH);
-    }
+/// Headroom left under `COCOON_MAX_RESULT_BYTES` for the truncation metadata itself
+const TRUNCATION_METADATA_RESERVE: usize = 512;
 
-    // New secret means no device_id yet (first registration)
-    Ok((secret, None))
+/// Cap on a serialized `ExecuteResult` from `COCOON_MAX_RESULT_BYTES`; unset or 0 means unlimited
+fn max_result_bytes() -> Option<usize> {
+    env_opt(EnvVar::CocoonMaxResultBytes.as_str())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|limit| *limit > 0)
 }
 
-async fn handle_cocoon_webrtc(
-    msg: CocoonMessage,
-    webrtc: Arc<crate::webrtc::WebRtcManager>,
-    writer: SharedWriter,
-) {
-    async fn send_cocoon_msg(writer: &SharedWriter, msg: &CocoonMessage) {
-        let sync_msg = SignalingMessage::SyncData {
-            payload: serde_json::to_value(msg).expect("CocoonMessage serialization cannot fail"),
-        };
-        let mut w = writer.lock().await;
-        let _ = w
-            .send(Message::Text(
-                serde_json::to_string(&sync_msg).expect("SignalingMessage serialization cannot fail"),
-            ))
-            .await;
+/// Apply the configured result size limit, if any. Only `execute` and `pipeline`
+/// results are cut: tool calls are ADI frames on the WebRTC `adi` channel, which
+/// never cross signaling, and their payload is the tool's own encoding, so a cut
+/// frame would not decode. ADI services stream large output as several frames.
+fn limit_result(mut response: CommandResponse) -> CommandResponse {
+    if let Some(limit) = max_result_bytes() {
+        limit_result_size(&mut response, limit);
     }
+    response
+}
 
-    match msg {
-        CocoonMessage::WebrtcStartSession {
-            session_id,
-            device_id: client_id,
-            user_id,
-            data_channels,
-        } => {
-            if let Some(ref channels) = data_channels {
-                tracing::info!("🎥 WebRTC session request from {}: {} (user_id={:?}, data_channels={:?})", client_id, session_id, user_id, channels);
-            } else {
-                tracing::info!("🎥 WebRTC session request from {}: {} (user_id={:?})", client_id, session_id, user_id);
-            }
-            match webrtc.create_session(session_id.clone(), user_id).await {
-                Ok(()) => {
-                    tracing::info!("✅ WebRTC session {} created", session_id);
-                }
-                Err(e) => {
-                    tracing::error!("❌ Failed to create WebRTC session: {}", e);
-                    send_cocoon_msg(&writer, &CocoonMessage::WebrtcError {
-                        session_id,
-                        code: "session_create_failed".to_string(),
-                        message: e,
-                    }).await;
-                }
-            }
+/// Cut `text` to at most `max` bytes without splitting a UTF-8 sequence
+fn truncate_utf8(text: &mut String, max: usize) {
+    if text.len() <= max {
+        return;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+}
+
+/// Largest per-field length that keeps the sum of `lens` within `total`
+fn fair_share_cap(lens: &[usize], total: usize) -> usize {
+    let mut sorted = lens.to_vec();
+    sorted.sort_unstable();
+    let mut remaining = total;
+    for (i, len) in sorted.iter().enumerate() {
+        let share = remaining / (sorted.len() - i);
+        if *len > share {
+            return share;
         }
+        remaining -= len;
+    }
+    usize::MAX
+}
 
-        CocoonMessage::WebrtcOffer { session_id, sdp } => {
-            tracing::info!("📥 WebRTC offer received for session {}", session_id);
-            match webrtc.handle_offer(&session_id, &sdp).await {
-                Ok(answer_sdp) => {
-                    tracing::info!("📤 Sending WebRTC answer for session {}", session_id);
-                    send_cocoon_msg(&writer, &CocoonMessage::WebrtcAnswer {
-                        session_id,
-                        sdp: answer_sdp,
-                    }).await;
+/// A truncatable text field of an `ExecuteResult`
+enum ResultField {
+    /// Key in `data`, e.g. `stdout` or `stages[1].stderr`
+    Data(String),
+    /// Index into `files`
+    File(usize),
+}
+
+/// The text fields of an `ExecuteResult`, with the alignment their length must keep
+fn result_text_fields<'a>(
+    data: &'a mut Option<JsonValue>,
+    files: &'a mut [OutputFile],
+) -> Vec<(ResultField, &'a mut String, usize)> {
+    let mut fields = Vec::new();
+    if let Some(JsonValue::Object(map)) = data {
+        for (key, value) in map.iter_mut() {
+            match (key.as_str(), value) {
+                ("stdout" | "stderr", JsonValue::String(text)) => {
+                    fields.push((ResultField::Data(key.clone()), text, 1));
                 }
-                Err(e) => {
-                    tracing::error!("❌ Failed to handle WebRTC offer: {}", e);
-                    send_cocoon_msg(&writer, &CocoonMessage::WebrtcError {
-                        session_id,
-                        code: "offer_failed".to_string(),
-                        message: e,
-                    }).await;
+                ("stages", JsonValue::Array(stages)) => {
+                    for (i, stage) in stages.iter_mut().enumerate() {
+                        if let Some(JsonValue::String(text)) = stage.get_mut("stderr") {
+                            fields.push((ResultField::Data(format!("stages[{}].stderr", i)), text, 1));
+                        }
+                    }
                 }
+                _ => {}
             }
         }
+    }
+    for (i, file) in files.iter_mut().enumerate() {
+        // Binary content is base64, which only decodes in whole 4-char groups
+        let align = if file.binary { 4 } else { 1 };
+        fields.push((ResultField::File(i), &mut file.content, align));
+    }
+    fields
+}
 
-        CocoonMessage::WebrtcIceCandidate {
-            session_id,
-            candidate,
-            sdp_mid,
-            sdp_mline_index,
-        } => {
-            tracing::debug!("🧊 ICE candidate received for session {}", session_id);
-            if let Err(e) = webrtc
-                .add_ice_candidate(
-                    &session_id,
-                    &candidate,
-                    sdp_mid.as_deref(),
-                    sdp_mline_index.map(|i| i as u32),
-                )
-                .await
-            {
-                tracing::warn!("⚠️ Failed to add ICE candidate: {}", e);
-            }
+/// Shrink the text fields of an `ExecuteResult` until it serializes within `limit`
+/// bytes, sharing the space fairly so one huge field doesn't starve the others.
+/// Truncated fields are reported in `data.truncation` and on each output file.
+fn limit_result_size(response: &mut CommandResponse, limit: usize) {
+    let budget = limit.saturating_sub(TRUNCATION_METADATA_RESERVE);
+    let mut originals: Option<Vec<usize>> = None;
+
+    // Escaping makes serialized size differ from raw length, so converge in a few passes
+    for _ in 0..4 {
+        let size = serde_json::to_vec(&*response).map(|v| v.len()).unwrap_or(0);
+        if size <= budget {
+            break;
         }
-
-        CocoonMessage::WebrtcSessionEnded { session_id, reason } => {
-            let reason_str = reason.as_deref().unwrap_or("not specified");
-            if reason_str == "session_replaced" {
-                tracing::info!("🔄 WebRTC session {} replaced by newer session from same client", session_id);
-            } else {
-                tracing::info!("🔌 WebRTC session {} ended (reason: {})", session_id, reason_str);
-            }
-            let _ = webrtc.close_session(&session_id).await;
+        let CommandResponse::ExecuteResult { data, files, .. } = response else {
+            return;
+        };
+        let mut fields = result_text_fields(data, files);
+        let lens: Vec<usize> = fields.iter().map(|(_, text, _)| text.len()).collect();
+        originals.get_or_insert_with(|| lens.clone());
+        let total: usize = lens.iter().sum();
+        if total == 0 {
+            break;
+        }
+        let cap = fair_share_cap(&lens, total.saturating_sub(size - budget));
+        for (_, text, align) in fields.iter_mut() {
+            truncate_utf8(text, cap - cap % *align);
         }
+    }
 
-        CocoonMessage::WebrtcData {
-            session_id: _,
-            channel,
-            data,
-            binary,
-        } => {
-            tracing::debug!("📦 WebRTC data received: {} bytes on channel {}", data.len(), channel);
-            match channel.as_str() {
-                "terminal" => tracing::debug!("Terminal data: {}", data),
-                "file-transfer" => tracing::debug!("File transfer data: {} bytes, binary: {}", data.len(), binary),
-                _ => tracing::debug!("Unknown channel: {}", channel),
+    let Some(originals) = originals else {
+        return;
+    };
+    let CommandResponse::ExecuteResult { data, files, .. } = response else {
+        return;
+    };
+
+    let mut truncation = serde_json::Map::new();
+    let mut truncated_files = Vec::new();
+    for ((field, text, _), original_bytes) in result_text_fields(data, files).into_iter().zip(originals) {
+        let omitted_bytes = original_bytes - text.len();
+        if omitted_bytes == 0 {
+            continue;
+        }
+        let info = Truncation { original_bytes, omitted_bytes };
+        match field {
+            ResultField::Data(key) => {
+                truncation.insert(key, serde_json::to_value(info).unwrap_or_default());
             }
+            ResultField::File(i) => truncated_files.push((i, info)),
         }
+    }
+    for (i, info) in truncated_files {
+        files[i].truncated = Some(info);
+    }
 
-        CocoonMessage::WebrtcError { session_id, code, message } => {
-            tracing::error!("❌ WebRTC error for session {}: {} - {}", session_id, code, message);
-            let _ = webrtc.close_session(&session_id).await;
+    if let Some(JsonValue::Object(map)) = data {
+        map.insert("truncated".to_string(), JsonValue::Bool(true));
+        if !truncation.is_empty() {
+            map.insert("truncation".to_string(), JsonValue::Object(truncation));
         }
+    }
+}
 
-        _ => {
-            tracing::debug!("📨 Unhandled CocoonMessage in WebRTC handler");
+/// Secrets shorter than this are not redacted, since replacing them would mangle
+/// unrelated output
+const MIN_REDACTED_SECRET_LEN: usize = 4;
+
+/// Replace every secret env value in `text` with a placeholder
+pub(crate) fn redact_secrets(text: &str, secret_env: &HashMap<String, String>) -> String {
+    let mut redacted = text.to_string();
+    for value in secret_env.values() {
+        if value.len() >= MIN_REDACTED_SECRET_LEN {
+            redacted = redacted.replace(value.as_str(), "[redacted]");
         }
     }
+    redacted
 }
 
-pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive("cocoon=info".parse().expect("valid tracing directive")),
-        )
-        .try_init();
+/// Log suffix naming the secret env keys (never their values)
+fn secret_env_note(secret_env: &HashMap<String, String>) -> String {
+    if secret_env.is_empty() {
+        return String::new();
+    }
+    let mut keys: Vec<&str> = secret_env.keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    format!(" [secret env: {}]", keys.join(", "))
+}
 
-    tracing::info!("🐛 Cocoon starting (v{})", env!("CARGO_PKG_VERSION"));
+/// How long to keep reading a killed command's pipes, in case a process that
+/// escaped the kill still holds them open
+const KILLED_PIPE_DRAIN: Duration = Duration::from_secs(1);
 
-    let (secret, device_id) = get_or_create_secret().await?;
+/// A running `execute` that `cancel_exec` can signal
+#[derive(Default)]
+struct ExecHandle {
+    pid: std::sync::OnceLock<u32>,
+    cancelled: std::sync::atomic::AtomicBool,
+}
 
-    let base_url = env_or(EnvVar::SignalingServerUrl.as_str(), "ws://localhost:8080/ws");
-    let signaling_url = if base_url.contains('?') {
-        format!("{}&kind=cocoon", base_url)
-    } else {
-        format!("{}?kind=cocoon", base_url)
-    };
+/// Running `execute`s by `request_id`
+type RunningExecs = Arc<std::sync::Mutex<HashMap<String, Arc<ExecHandle>>>>;
 
-    tracing::info!("🔗 Connecting to signaling server: {}", signaling_url);
+/// An `execute`'s entry in [`RunningExecs`], removed when dropped
+struct ExecRegistration {
+    execs: RunningExecs,
+    request_id: String,
+    handle: Arc<ExecHandle>,
+}
 
-    let (ws_stream, _) = match connect_async(&signaling_url).await {
-        Ok(conn) => conn,
-        Err(e) => {
-            tracing::error!("❌ Failed to connect to signaling server: {}", e);
-            return Err(format!("Failed to connect to signaling server: {}", e).into());
+impl ExecRegistration {
+    /// `None` when an `execute` with this id is still running
+    fn new(execs: &RunningExecs, request_id: &str) -> Option<Self> {
+        let mut running = execs.lock().unwrap_or_else(|e| e.into_inner());
+        if running.contains_key(request_id) {
+            return None;
         }
-    };
+        let handle = Arc::new(ExecHandle::default());
+        running.insert(request_id.to_string(), handle.clone());
+        Some(Self {
+            execs: execs.clone(),
+            request_id: request_id.to_string(),
+            handle,
+        })
+    }
+}
 
-    let (write, mut read) = ws_stream.split();
-    let writer = Arc::new(Mutex::new(write));
+impl Drop for ExecRegistration {
+    fn drop(&mut self) {
+        self.execs.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.request_id);
+    }
+}
 
-    let pty_sessions: Arc<Mutex<HashMap<Uuid, PtySession>>> = Arc::new(Mutex::new(HashMap::new()));
+/// Signal number for a `cancel_exec` signal name, with or without the `SIG` prefix
+fn parse_signal(name: &str) -> Option<i32> {
+    let name = name.trim().to_ascii_uppercase();
+    match name.strip_prefix("SIG").unwrap_or(&name) {
+        "TERM" => Some(libc::SIGTERM),
+        "INT" => Some(libc::SIGINT),
+        "HUP" => Some(libc::SIGHUP),
+        "QUIT" => Some(libc::SIGQUIT),
+        "KILL" => Some(libc::SIGKILL),
+        _ => None,
+    }
+}
 
-    let silk_sessions: Arc<Mutex<HashMap<Uuid, SilkSession>>> =
-        Arc::new(Mutex::new(HashMap::new()));
+async fn execute_command(
+    command: &str,
+    input: Option<&str>,
+    secret_env: &HashMap<String, String>,
+    timeout: Option<Duration>,
+    cancel: Option<&ExecHandle>,
+) -> CommandResponse {
+    let _ = tokio::fs::create_dir_all(OUTPUT_DIR).await;
 
-    let adi_router = {
-        let mut router = AdiRouter::new();
+    let spawn_failed = |details: String| CommandResponse::ExecuteResult {
+        success: false,
+        data: None,
+        error: Some(ErrorInfo {
+            code: "spawn_failed".into(),
+            details: Some(redact_secrets(&details, secret_env)),
+        }),
+        files: vec![],
+        omitted_files: None,
+        request_id: None,
+    };
 
-        #[cfg(feature = "tasks-core")]
-        {
-            match tasks_core::TasksService::new_global() {
-                Ok(tasks_service) => {
-                    router.register(std::sync::Arc::new(tasks_service));
-                    tracing::info!("📦 Registered ADI plugin: adi.tasks");
-                }
-                Err(e) => {
-                    tracing::warn!("⚠️ Failed to initialize tasks plugin: {}", e);
-                }
-            }
+    // Held until the command is done, so a script file outlives it
+    let shell_command = match crate::command_script::ShellCommand::prepare(command) {
+        Ok(shell_command) => shell_command,
+        Err(e) => return spawn_failed(e),
+    };
+
+    let mut child = match tokio::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&*crate::exec_wrapper::wrap("/bin/sh", shell_command.as_str()))
+        .env_clear()
+        .envs(crate::child_env::child_env(false, secret_env))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => return spawn_failed(e.to_string()),
+    };
+
+    if let (Some(handle), Some(pid)) = (cancel, child.id()) {
+        let _ = handle.pid.set(pid);
+        // Cancelled before there was a process to signal
+        if handle.cancelled.load(Ordering::SeqCst) {
+            crate::input_wait::kill_tree(pid);
         }
+    }
 
-        #[cfg(feature = "kb-core")]
-        {
-            match knowledgebase_core::service::KnowledgebaseService::open_default().await {
-                Ok(kb_service) => {
-                    router.register(std::sync::Arc::new(kb_service));
-                    tracing::info!("📦 Registered ADI plugin: adi.knowledgebase");
+    if let Some(mut stdin) = child.stdin.take() {
+        if let Some(input_str) = input {
+            let _ = stdin.write_all(input_str.as_bytes()).await;
+            let _ = stdin.shutdown().await;
+        }
+    }
+
+    let output_bytes = Arc::new(AtomicU64::new(0));
+    let stdout_reader = tokio::spawn(read_counting(child.stdout.take(), output_bytes.clone()));
+    let stderr_reader = tokio::spawn(read_counting(child.stderr.take(), output_bytes.clone()));
+
+    let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+    let past_deadline = async {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(past_deadline);
+
+    // With stdin closed, a command that goes quiet while a process in its tree
+    // is reading the terminal would otherwise never return. The whole tree is
+    // killed, so shells and pipelines started by the command go with it.
+    let mut killed: Option<ErrorInfo> = None;
+    let status = loop {
+        let seen = output_bytes.load(Ordering::Relaxed);
+        tokio::select! {
+            status = child.wait() => break status,
+            _ = &mut past_deadline => {
+                let timeout_ms = timeout.unwrap_or_default().as_millis();
+                tracing::warn!(
+                    "⏱️ Command timed out after {} ms, killing it: {}",
+                    timeout_ms,
+                    crate::log_redact::redact(command)
+                );
+                if let Some(pid) = child.id() {
+                    crate::input_wait::kill_tree(pid);
                 }
-                Err(e) => {
-                    tracing::warn!("⚠️ Failed to initialize knowledgebase plugin: {}", e);
+                let _ = child.start_kill();
+                killed = Some(ErrorInfo {
+                    code: "timeout".into(),
+                    details: Some(format!("command exceeded timeout_ms ({})", timeout_ms)),
+                });
+                break child.wait().await;
+            }
+            _ = tokio::time::sleep(crate::input_wait::GRACE_PERIOD) => {
+                let Some(pid) = child.id() else { continue };
+                if output_bytes.load(Ordering::Relaxed) == seen
+                    && crate::input_wait::awaiting_input(pid)
+                {
+                    tracing::warn!(
+                        "⌨️ Command is waiting for terminal input, killing it: {}",
+                        crate::log_redact::redact(command)
+                    );
+                    crate::input_wait::kill_tree(pid);
+                    killed = Some(ErrorInfo {
+                        code: crate::input_wait::AWAITING_INPUT.into(),
+                        details: Some(
+                            "command was waiting for interactive input (e.g. a password prompt); \
+                             run it in a PTY session (attach_pty) or pass the input via `input`"
+                                .into(),
+                        ),
+                    });
+                    break child.wait().await;
                 }
             }
         }
+    };
+    if killed.is_none() && cancel.is_some_and(|handle| handle.cancelled.load(Ordering::SeqCst)) {
+        tracing::info!("🛑 Command cancelled: {}", crate::log_redact::redact(command));
+        killed = Some(ErrorInfo {
+            code: "cancelled".into(),
+            details: Some("command was cancelled by cancel_exec".into()),
+        });
+    }
 
-        #[cfg(feature = "tools-core")]
-        {
-            let tools_service = tools_core::ToolsService::new();
-            let tool_count = tools_service.list_all_tools().len();
-            router.register(std::sync::Arc::new(tools_service));
-            tracing::info!("📦 Registered ADI plugin: adi.tools ({} tools)", tool_count);
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            return CommandResponse::ExecuteResult {
+                success: false,
+                data: None,
+                error: Some(ErrorInfo {
+                    code: "execution_failed".into(),
+                    details: Some(redact_secrets(&e.to_string(), secret_env)),
+                }),
+                files: vec![],
+                omitted_files: None,
+                request_id: None,
+            };
         }
+    };
 
-        router
+    let (stdout, stderr) = if killed.is_some() {
+        let drain = |reader: tokio::task::JoinHandle<Vec<u8>>| async move {
+            tokio::time::timeout(KILLED_PIPE_DRAIN, reader)
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .unwrap_or_default()
+        };
+        (drain(stdout_reader).await, drain(stderr_reader).await)
+    } else {
+        (
+            stdout_reader.await.unwrap_or_default(),
+            stderr_reader.await.unwrap_or_default(),
+        )
     };
+    let (files, omitted_files) = collect_output_files(OUTPUT_DIR).await;
+    let stdout = String::from_utf8_lossy(&stdout).to_string();
+    let stderr = redact_secrets(&String::from_utf8_lossy(&stderr), secret_env);
 
-    let adi_plugins: Vec<String> = adi_router
-        .list_plugins()
-        .iter()
-        .map(|s| format!("{}:{}", s.id, s.version))
-        .collect();
+    if let Some(error) = killed {
+        return CommandResponse::ExecuteResult {
+            success: false,
+            data: Some(serde_json::json!({
+                "stdout": stdout,
+                "stderr": stderr,
+            })),
+            error: Some(error),
+            files,
+            omitted_files,
+            request_id: None,
+        };
+    }
 
-    let adi_router = Arc::new(Mutex::new(adi_router));
+    if status.success() {
+        CommandResponse::ExecuteResult {
+            success: true,
+            data: Some(serde_json::json!({
+                "stdout": stdout,
+                "stderr": stderr,
+                "exit_code": 0
+            })),
+            error: None,
+            files,
+            omitted_files,
+            request_id: None,
+        }
+    } else {
+        let exit_code = status.code().unwrap_or(-1);
+        if let Some(binary) = detect_missing_command(command, exit_code, &stderr) {
+            tracing::warn!("⚠️ Command not found: {}", binary);
+            return CommandResponse::ExecuteResult {
+                success: false,
+                data: Some(serde_json::json!({
+                    "stdout": stdout,
+                    "stderr": stderr,
+                    "exit_code": exit_code,
+                    "binary": binary,
+                    "path": env_opt(EnvVar::Path.as_str()).unwrap_or_default(),
+                })),
+                error: Some(ErrorInfo {
+                    code: "command_not_found".into(),
+                    details: Some(missing_command_hint(&binary)),
+                }),
+                files,
+                omitted_files,
+                request_id: None,
+            };
+        }
 
-    let (webrtc_tx, mut webrtc_rx) = tokio::sync::mpsc::unbounded_channel::<SignalingMessage>();
+        CommandResponse::ExecuteResult {
+            success: false,
+            data: Some(serde_json::json!({
+                "stdout": stdout,
+                "stderr": stderr,
+                "exit_code": exit_code
+            })),
+            error: Some(ErrorInfo {
+                code: "command_failed".into(),
+                details: Some(format!("exit code: {}", exit_code)),
+            }),
+            files,
+            omitted_files,
+            request_id: None,
+        }
+    }
+}
 
-    let webrtc_manager = Arc::new(crate::webrtc::WebRtcManager::with_adi_router(
-        webrtc_tx,
-        adi_router,
-    ));
+/// Read a child pipe to the end, counting bytes into `counter` as they arrive
+async fn read_counting<R>(pipe: Option<R>, counter: Arc<AtomicU64>) -> Vec<u8>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
 
-    let writer_for_webrtc = writer.clone();
-    tokio::spawn(async move {
-        while let Some(msg) = webrtc_rx.recv().await {
-            let mut w = writer_for_webrtc.lock().await;
-            if let Err(e) = w
-                .send(Message::Text(
-                    serde_json::to_string(&msg).unwrap_or_default(),
-                ))
-                .await
-            {
-                tracing::warn!("⚠️ Failed to send WebRTC signaling message: {}", e);
+    let mut out = Vec::new();
+    let Some(mut pipe) = pipe else { return out };
+    let mut buf = [0u8; 8192];
+    loop {
+        match pipe.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                out.extend_from_slice(&buf[..n]);
+                counter.fetch_add(n as u64, Ordering::Relaxed);
             }
         }
-    });
+    }
+    out
+}
 
-    // Serialized WebRTC message channel — processes signaling messages one at a time
-    // so create_session() always completes before handle_offer() runs for the same session.
-    let (webrtc_msg_tx, mut webrtc_msg_rx) =
-        tokio::sync::mpsc::unbounded_channel::<CocoonMessage>();
-    let webrtc_manager_for_task = webrtc_manager.clone();
-    let writer_for_webrtc_msgs = writer.clone();
-    tokio::spawn(async move {
-        while let Some(msg) = webrtc_msg_rx.recv().await {
-            handle_cocoon_webrtc(msg, webrtc_manager_for_task.clone(), writer_for_webrtc_msgs.clone()).await;
-        }
-    });
+/// Exit status and stderr of one pipeline stage
+#[derive(Debug, Serialize)]
+struct PipelineStage {
+    command: String,
+    exit_code: i32,
+    stderr: String,
+}
 
-    // Service registry - parse from COCOON_SERVICES env var
-    // Format: "service1:port1,service2:port2"
-    // Example: "flowmap-api:8092,postgres:5432"
-    let mut services = HashMap::new();
-    if let Some(services_str) = env_opt(EnvVar::CocoonServices.as_str()) {
-        for service_def in services_str.split(',') {
-            let parts: Vec<&str> = service_def.trim().split(':').collect();
-            if parts.len() == 2 {
-                if let Ok(port) = parts[1].parse::<u16>() {
-                    services.insert(parts[0].to_string(), port);
-                    tracing::info!("📦 Registered service: {} → localhost:{}", parts[0], port);
-                } else {
-                    tracing::warn!("⚠️ Invalid port for service {}: {}", parts[0], parts[1]);
+/// Spawn every stage with its stdin wired to the previous stage's stdout, and
+/// wait for all of them. Returns the last stage's stdout.
+fn run_pipeline(stages: &[String], input: Option<&str>) -> Result<(String, Vec<PipelineStage>), String> {
+    use std::io::Write;
+
+    let mut children: Vec<std::process::Child> = Vec::new();
+    let mut stderr_readers = Vec::new();
+    let mut shell_commands = Vec::new();
+    let mut first_stdin = None;
+    let mut previous_stdout: Option<std::process::ChildStdout> = None;
+
+    for (index, stage) in stages.iter().enumerate() {
+        let stdin = match previous_stdout.take() {
+            Some(stdout) => Stdio::from(stdout),
+            None => Stdio::piped(),
+        };
+        let shell_command = match crate::command_script::ShellCommand::prepare(stage) {
+            Ok(shell_command) => shell_command,
+            Err(e) => {
+                for child in &mut children {
+                    let _ = child.kill();
+                    let _ = child.wait();
                 }
-            } else {
-                tracing::warn!("⚠️ Invalid service definition: {}", service_def);
+                return Err(format!("stage {}: {}", index + 1, e));
+            }
+        };
+        let spawned = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&*crate::exec_wrapper::wrap("/bin/sh", shell_command.as_str()))
+            .env_clear()
+            .envs(crate::child_env::child_env(false, &HashMap::new()))
+            .stdin(stdin)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn();
+        // Scripts stay until every stage is done
+        shell_commands.push(shell_command);
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                for child in &mut children {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Err(format!("stage {} ({}): {}", index + 1, stage, e));
             }
+        };
+
+        if index == 0 {
+            first_stdin = child.stdin.take();
         }
+        previous_stdout = child.stdout.take();
+        // Drain stderr concurrently so a chatty stage can't block on a full pipe
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        stderr_readers.push(std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stderr.read_to_end(&mut buf);
+            String::from_utf8_lossy(&buf).to_string()
+        }));
+        children.push(child);
     }
-    let services = Arc::new(services);
 
-    let setup_token = env_opt(EnvVar::CocoonSetupToken.as_str());
-    let cocoon_name = env_opt(EnvVar::CocoonName.as_str());
+    // Dropping the handle after writing closes the first stage's stdin
+    let input = input.unwrap_or_default().to_string();
+    let writer = first_stdin.map(|mut stdin| {
+        std::thread::spawn(move || {
+            let _ = stdin.write_all(input.as_bytes());
+        })
+    });
 
-    let cocoon_version = env!("CARGO_PKG_VERSION").to_string();
-    let mut tags = std::collections::HashMap::new();
-    if let Some(ref token) = setup_token {
-        tracing::info!("🎫 Using setup token for auto-registration");
-        tags.insert("setup_token".to_string(), token.clone());
+    let mut stdout = Vec::new();
+    if let Some(mut last) = previous_stdout {
+        let _ = last.read_to_end(&mut stdout);
     }
-    if let Some(ref name) = cocoon_name {
-        tags.insert("name".to_string(), name.clone());
+    if let Some(writer) = writer {
+        let _ = writer.join();
     }
-    let protocols: Vec<String> = env_opt(EnvVar::CocoonProtocols.as_str())
-        .map(|s| s.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect())
-        .unwrap_or_else(|| vec!["silk".to_string()]);
 
-    let device_config = Some(serde_json::json!({
-        "adi_plugins": adi_plugins,
-        "protocols": protocols,
-    }));
+    let results = children
+        .into_iter()
+        .zip(stderr_readers)
+        .zip(stages)
+        .map(|((mut child, stderr), command)| PipelineStage {
+            command: command.clone(),
+            exit_code: child.wait().ok().and_then(|s| s.code()).unwrap_or(-1),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+        .collect();
 
-    let register_msg = SignalingMessage::DeviceRegister {
-        secret,
-        device_id: device_id.clone(),
-        version: cocoon_version,
-        tags: if tags.is_empty() { None } else { Some(tags) },
-        device_type: Some("cocoon".to_string()),
-        device_config,
-    };
+    Ok((String::from_utf8_lossy(&stdout).to_string(), results))
+}
 
-    let current_device_id: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+async fn execute_pipeline(stages: Vec<String>, input: Option<String>) -> CommandResponse {
+    let failure = |code: &str, details: String| CommandResponse::ExecuteResult {
+        success: false,
+        data: None,
+        error: Some(ErrorInfo {
+            code: code.into(),
+            details: Some(details),
+        }),
+        files: vec![],
+        omitted_files: None,
+        request_id: None,
+    };
 
-    // Send DeviceRegister immediately (cocoon endpoint skips auth)
-    tracing::info!("⏳ Registering with signaling server...");
-    {
-        let mut w = writer.lock().await;
-        w.send(Message::Text(
-            serde_json::to_string(&register_msg).unwrap(),
-        ))
-        .await
-        .map_err(|e| format!("Failed to send register: {}", e))?;
+    if stages.is_empty() {
+        return failure("invalid_pipeline", "pipeline has no stages".to_string());
     }
 
-    let mut registered = false;
-    while let Some(Ok(msg)) = read.next().await {
-        let text = match msg {
-            Message::Text(t) => t,
-            Message::Close(_) => return Err("Connection closed during registration".into()),
-            _ => continue,
-        };
-        let parsed: SignalingMessage = match serde_json::from_str(&text) {
-            Ok(m) => m,
-            Err(_) => continue,
+    let _ = tokio::fs::create_dir_all(OUTPUT_DIR).await;
+
+    let (stdout, results) =
+        match tokio::task::spawn_blocking(move || run_pipeline(&stages, input.as_deref())).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(e)) => return failure("spawn_failed", e),
+            Err(e) => return failure("execution_failed", e.to_string()),
         };
-        match parsed {
-            SignalingMessage::DeviceRegisterResponse { device_id: assigned_id, tags } => {
-                registered = true;
-                tracing::info!("✅ Registration confirmed");
-                tracing::info!("🆔 Device ID: {}", assigned_id);
-
-                if let Some(ref t) = tags {
-                    if let Some(owner_id) = t.get("owner_id") {
-                        tracing::info!("👤 Owner: {}", owner_id);
-                        if let Some(name) = t.get("name") {
-                            tracing::info!("📛 Name: {}", name);
-                        }
-                        tracing::info!("🎉 Cocoon is ready and claimed by your account!");
-                    }
-                }
 
-                save_device_id(&assigned_id).await;
-                *current_device_id.lock().await = Some(assigned_id);
-                break;
-            }
-            SignalingMessage::SystemError { message } => {
-                tracing::error!("❌ Server error during registration: {}", message);
-                return Err(format!("Server error: {}", message).into());
-            }
-            _ => continue,
+    let (files, omitted_files) = collect_output_files(OUTPUT_DIR).await;
+    let exit_codes: Vec<i32> = results.iter().map(|stage| stage.exit_code).collect();
+    // pipefail: the pipeline fails with the rightmost non-zero exit code
+    let failed_stage = exit_codes.iter().rposition(|code| *code != 0);
+    let exit_code = failed_stage.map(|i| exit_codes[i]).unwrap_or(0);
+
+    let data = serde_json::json!({
+        "stdout": stdout,
+        "exit_code": exit_code,
+        "exit_codes": exit_codes,
+        "stages": results,
+    });
+
+    match failed_stage {
+        None => CommandResponse::ExecuteResult {
+            success: true,
+            data: Some(data),
+            error: None,
+            files,
+            omitted_files,
+            request_id: None,
+        },
+        Some(index) => CommandResponse::ExecuteResult {
+            success: false,
+            data: Some(data),
+            error: Some(ErrorInfo {
+                code: "command_failed".into(),
+                details: Some(format!("stage {} exit code: {}", index + 1, exit_code)),
+            }),
+            files,
+            omitted_files,
+            request_id: None,
+        },
+    }
+}
+
+const DEFAULT_MAX_PTY_SESSIONS: usize = 64;
+
+/// Open PTYs, counted against `COCOON_MAX_PTY_SESSIONS` so a client can't
+/// exhaust file descriptors by opening thousands of them
+pub(crate) struct PtySlots {
+    open: AtomicUsize,
+    /// 0 means unlimited
+    pub(crate) max: usize,
+}
+
+impl PtySlots {
+    pub(crate) fn new(max: usize) -> Self {
+        Self {
+            open: AtomicUsize::new(0),
+            max,
         }
     }
 
-    if !registered {
-        return Err("Connection closed before registration completed".into());
+    fn from_env() -> Self {
+        Self::new(
+            env_opt(EnvVar::CocoonMaxPtySessions.as_str())
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .unwrap_or(DEFAULT_MAX_PTY_SESSIONS),
+        )
     }
 
-    let current_device_id_for_loop = current_device_id.clone();
+    pub(crate) fn acquire(self: &Arc<Self>) -> Option<PtySlot> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (self.max == 0 || open < self.max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| PtySlot(self.clone()))
+    }
 
-    let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<()>(1);
-    let writer_for_shutdown = writer.clone();
-    let device_id_for_shutdown = current_device_id.clone();
+    fn open(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+}
 
-    tokio::spawn(async move {
-        #[cfg(unix)]
-        {
-            use tokio::signal::unix::{signal, SignalKind};
-            let mut sigterm =
-                signal(SignalKind::terminate()).expect("Failed to create SIGTERM handler");
-            let mut sigint =
-                signal(SignalKind::interrupt()).expect("Failed to create SIGINT handler");
+/// One open PTY, held by its reader task and released when the reader ends
+pub(crate) struct PtySlot(Arc<PtySlots>);
 
-            tokio::select! {
-                _ = sigterm.recv() => {
-                    tracing::info!("📥 Received SIGTERM, initiating graceful shutdown...");
-                }
-                _ = sigint.recv() => {
-                    tracing::info!("📥 Received SIGINT, initiating graceful shutdown...");
-                }
-            }
-        }
+impl Drop for PtySlot {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
 
-        #[cfg(not(unix))]
-        {
-            let _ = tokio::signal::ctrl_c().await;
-            tracing::info!("📥 Received Ctrl+C, initiating graceful shutdown...");
+/// Why `create_pty_session` did not start a PTY
+#[derive(Debug)]
+struct PtyCreateError {
+    code: &'static str,
+    message: String,
+}
+
+impl From<String> for PtyCreateError {
+    fn from(message: String) -> Self {
+        Self {
+            code: "pty_create_failed",
+            message,
         }
+    }
+}
 
-        if let Some(device_id) = device_id_for_shutdown.lock().await.as_ref() {
-            send_deregister(&writer_for_shutdown, device_id, Some("shutdown")).await;
+/// How a PTY's command is started
+struct PtyOptions<'a> {
+    env: &'a HashMap<String, String>,
+    clean_env: bool,
+    /// Starting directory; the cocoon's own when unset
+    cwd: Option<&'a str>,
+    /// Shell that runs the command; `/bin/sh` when unset
+    shell: Option<&'a str>,
+}
+
+/// Login shells of the system; a PTY's `shell` must be one of them
+const SHELLS_FILE: &str = "/etc/shells";
+
+/// Whether `/etc/shells` style `contents` list `shell`
+fn is_listed_shell(contents: &str, shell: &str) -> bool {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .any(|line| line == shell)
+}
+
+/// `shell` is an executable file listed in `/etc/shells`, and on
+/// `COCOON_CMD_ALLOWLIST` when one is set
+fn check_shell(shell: &str) -> Result<(), PtyCreateError> {
+    let invalid = |message: String| PtyCreateError { code: "invalid_shell", message };
+    let metadata =
+        std::fs::metadata(shell).map_err(|e| invalid(format!("Shell not found: {} ({})", shell, e)))?;
+    if !metadata.is_file() {
+        return Err(invalid(format!("Shell is not a file: {}", shell)));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(invalid(format!("Shell is not executable: {}", shell)));
         }
+    }
+    let shells = std::fs::read_to_string(SHELLS_FILE).unwrap_or_default();
+    if !is_listed_shell(&shells, shell) {
+        return Err(invalid(format!("Shell is not listed in {}: {}", SHELLS_FILE, shell)));
+    }
+    if crate::command_filter::has_allowlist() {
+        crate::command_filter::check(shell).map_err(|reason| PtyCreateError {
+            code: COMMAND_FORBIDDEN,
+            message: format!("Shell not allowed: {}", reason),
+        })?;
+    }
+    Ok(())
+}
 
-        let _ = shutdown_tx.send(());
+/// `cwd` is an existing directory
+fn check_cwd(cwd: &str) -> Result<(), PtyCreateError> {
+    if Path::new(cwd).is_dir() {
+        Ok(())
+    } else {
+        Err(PtyCreateError {
+            code: "invalid_cwd",
+            message: format!("Working directory not found: {}", cwd),
+        })
+    }
+}
+
+async fn create_pty_session(
+    command: &str,
+    cols: u16,
+    rows: u16,
+    options: PtyOptions<'_>,
+    responder: Responder,
+    slots: &Arc<PtySlots>,
+) -> Result<(Uuid, PtySession), PtyCreateError> {
+    let shell = options.shell.unwrap_or("/bin/sh");
+    if options.shell.is_some() {
+        check_shell(shell)?;
+    }
+    if let Some(cwd) = options.cwd {
+        check_cwd(cwd)?;
+    }
+
+    if let Err(reason) = crate::command_filter::check(command) {
+        tracing::warn!("🔒 Refusing PTY: {}", reason);
+        return Err(PtyCreateError {
+            code: COMMAND_FORBIDDEN,
+            message: format!("Command not allowed: {}", reason),
+        });
+    }
+
+    let Some(slot) = slots.acquire() else {
+        tracing::warn!("⚠️ Refusing PTY: {} sessions already open", slots.max);
+        return Err(PtyCreateError {
+            code: "pty_limit_reached",
+            message: format!("PTY limit reached ({} open sessions, COCOON_MAX_PTY_SESSIONS)", slots.max),
+        });
+    };
+
+    let session_id = Uuid::new_v4();
+    let pty_system = portable_pty::native_pty_system();
+
+    let pair = pty_system
+        .openpty(PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        })
+        .map_err(|e| format!("Failed to open PTY: {}", e))?;
+
+    let shell_command = crate::command_script::ShellCommand::prepare(command)?;
+    let mut cmd = CommandBuilder::new(shell);
+    cmd.arg("-c");
+    cmd.arg(&*crate::exec_wrapper::wrap(shell, shell_command.as_str()));
+    if let Some(cwd) = options.cwd {
+        cmd.cwd(cwd);
+    }
+
+    cmd.env_clear();
+    for (key, value) in crate::child_env::child_env(options.clean_env, options.env) {
+        cmd.env(key, value);
+    }
+
+    // Set TERM for proper terminal support
+    cmd.env("TERM", "xterm-256color");
+
+    let child = pair
+        .slave
+        .spawn_command(cmd)
+        .map_err(|e| format!("Failed to spawn command: {}", e))?;
+
+    let mut reader = pair
+        .master
+        .try_clone_reader()
+        .map_err(|e| format!("Failed to clone reader: {}", e))?;
+
+    let replay = Arc::new(std::sync::Mutex::new(ReplayBuffer::new()));
+    let replay_for_reader = replay.clone();
+    let activity = crate::reaper::Activity::new();
+    let activity_for_reader = activity.clone();
+
+    let session_id_clone = session_id;
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        let _slot = slot;
+        // The PTY closes once its shell exits, so a script file outlives it
+        let _shell_command = shell_command;
+        let mut buffer = [0u8; 4096];
+        let mut framer = crate::pty_output::Utf8Framer::new();
+        let send = |frame: crate::pty_output::Frame| {
+            let response = CommandResponse::PtyOutput {
+                session_id: session_id_clone,
+                data: frame.data,
+                binary: frame.binary,
+                data_base64: frame.data_base64,
+            };
+
+            let responder = responder.clone();
+            tokio::spawn(async move { responder.send(&response).await }.in_current_span());
+        };
+        loop {
+            match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    activity_for_reader.touch();
+                    if let Ok(mut replay) = replay_for_reader.lock() {
+                        replay.push(&buffer[..n]);
+                    }
+                    if let Some(frame) = framer.push(&buffer[..n]) {
+                        send(frame);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("PTY read error: {}", e);
+                    break;
+                }
+            }
+        }
+        if let Some(frame) = framer.finish() {
+            send(frame);
+        }
+
+        tracing::info!("PTY session {} reader task ended", session_id_clone);
     });
 
-    loop {
-        tokio::select! {
-            _ = shutdown_rx.recv() => {
-                tracing::info!("🛑 Shutdown signal received, exiting main loop...");
-                break;
-            }
-            msg_result = read.next() => {
-                let msg = match msg_result {
-                    Some(Ok(msg)) => msg,
-                    Some(Err(e)) => {
-                        tracing::error!("❌ WebSocket error: {}", e);
-                        break;
-                    }
-                    None => {
-                        tracing::info!("🔌 Connection closed by server");
-                        break;
-                    }
-                };
+    let pty_writer = pair
+        .master
+        .take_writer()
+        .map_err(|e| format!("Failed to take PTY writer: {}", e))?;
+
+    crate::metrics::increment(crate::metrics::Counter::PtySessionsOpened);
+    Ok((
+        session_id,
+        PtySession {
+            id: session_id,
+            pair,
+            child,
+            writer: pty_writer,
+            replay,
+            command: command.to_string(),
+            created_at: std::time::Instant::now(),
+            activity,
+        },
+    ))
+}
+
+/// Merge client and injected proxy headers.
+///
+/// Injected headers win: any client header with the same name (case-insensitive)
+/// is dropped, so a client can neither override nor duplicate an injected value.
+/// Each entry is flagged with whether it was injected.
+fn merge_proxy_headers(
+    client: HashMap<String, String>,
+    injected: HashMap<String, String>,
+) -> Vec<(String, String, bool)> {
+    let mut merged: Vec<(String, String, bool)> = client
+        .into_iter()
+        .filter(|(key, _)| !injected.keys().any(|k| k.eq_ignore_ascii_case(key)))
+        .map(|(key, value)| (key, value, false))
+        .collect();
+    merged.extend(injected.into_iter().map(|(key, value)| (key, value, true)));
+    merged
+}
+
+fn redacted_headers(headers: &[(String, String, bool)]) -> Vec<(&str, &str)> {
+    headers
+        .iter()
+        .map(|(key, value, injected)| {
+            (key.as_str(), if *injected { "[redacted]" } else { value.as_str() })
+        })
+        .collect()
+}
+
+/// Where `proxy_http` sends a registered service's requests
+#[derive(Debug, Clone)]
+struct ServiceTarget {
+    host: String,
+    port: u16,
+}
+
+impl ServiceTarget {
+    /// `port` or `host:port` from a `COCOON_SERVICES` entry
+    fn parse(spec: &str) -> Option<Self> {
+        let (host, port) = spec.rsplit_once(':').unwrap_or(("localhost", spec));
+        if host.is_empty() {
+            return None;
+        }
+        Some(Self {
+            host: host.to_string(),
+            port: port.parse().ok()?,
+        })
+    }
+
+    fn local(port: u16) -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port,
+        }
+    }
+}
+
+/// Where the responses to a command go, streamed ones included
+#[derive(Clone)]
+enum ResponseRoute {
+    Signaling,
+    /// The "terminal" channel of WebRTC session `session_id` the command arrived on
+    DataChannel {
+        session_id: String,
+        channel: Arc<RTCDataChannel>,
+    },
+}
+
+/// Sends a command's responses on its route, falling back to signaling
+#[derive(Clone)]
+struct Responder {
+    writer: SharedWriter,
+    route: ResponseRoute,
+}
+
+impl Responder {
+    async fn send(&self, response: &CommandResponse) {
+        if let ResponseRoute::DataChannel { channel, .. } = &self.route {
+            let json = serde_json::to_string(response).expect("CommandResponse serialization cannot fail");
+            match channel.send(&json.into_bytes().into()).await {
+                Ok(_) => return,
+                Err(e) => tracing::warn!("⚠️ Terminal data channel send failed, falling back to signaling: {}", e),
+            }
+        }
+        send_response(&self.writer, response).await;
+    }
+}
+
+/// Parse `payload` as a command and handle it in a task of its own, answering
+/// on `route`. Shared by signaling and WebRTC "terminal" channels.
+fn dispatch_command(payload: JsonValue, ctx: &CommandContext, route: ResponseRoute) {
+    let span = tracing::info_span!(
+        "command",
+        command = payload.get("type").and_then(|v| v.as_str()),
+        session_id = payload.get("session_id").and_then(|v| v.as_str()),
+    );
+    let request: CommandRequest = match serde_json::from_value(payload) {
+        Ok(request) => request,
+        Err(e) => {
+            tracing::warn!("⚠️ Invalid command request: {}", e);
+            // Signaling clients have always been left to time out; a data
+            // channel peer gets told, as nothing else would reach it
+            if let ResponseRoute::DataChannel { .. } = route {
+                reject_request(ctx, route, e.to_string());
+            }
+            return;
+        }
+    };
+
+    let ctx = CommandContext { route, ..ctx.clone() };
+    tokio::spawn(
+        async move {
+            if let Some(response) = handle_command(request, &ctx).await {
+                ctx.own_terminal_session(&response).await;
+                ctx.respond(&response).await;
+            }
+        }
+        .instrument(span),
+    );
+}
+
+/// Handle a command read off a WebRTC "terminal" channel. Silk output goes
+/// back over the same session unless the request names another one.
+fn dispatch_terminal_request(request: crate::webrtc::TerminalRequest, ctx: &CommandContext) {
+    let route = ResponseRoute::DataChannel {
+        session_id: request.session_id.clone(),
+        channel: request.reply,
+    };
+    match terminal_payload(&request.payload, &request.session_id) {
+        Ok(payload) => dispatch_command(payload, ctx, route),
+        Err(e) => {
+            tracing::warn!("⚠️ Invalid terminal channel message: {}", e);
+            reject_request(ctx, route, e);
+        }
+    }
+}
+
+/// Command JSON from a "terminal" channel message of WebRTC session `session_id`
+fn terminal_payload(text: &str, session_id: &str) -> Result<JsonValue, String> {
+    let mut payload: JsonValue = serde_json::from_str(text).map_err(|e| format!("not JSON: {}", e))?;
+    if let Some(fields) = payload.as_object_mut() {
+        if fields.get("type").and_then(|v| v.as_str()) == Some("silk_execute")
+            && fields.get("webrtc_session_id").map_or(true, JsonValue::is_null)
+        {
+            fields.insert("webrtc_session_id".to_string(), JsonValue::String(session_id.to_string()));
+        }
+    }
+    Ok(payload)
+}
+
+/// Answer an unparseable request with `invalid_request`
+fn reject_request(ctx: &CommandContext, route: ResponseRoute, reason: String) {
+    let ctx = CommandContext { route, ..ctx.clone() };
+    let response = CommandResponse::Error {
+        code: "invalid_request".to_string(),
+        message: format!("Invalid command request: {}", reason),
+    };
+    tokio::spawn(async move { ctx.respond(&response).await }.in_current_span());
+}
+
+/// `command_forbidden` error when the command filter refuses `command`
+fn command_forbidden(command: &str) -> Option<CommandResponse> {
+    let reason = crate::command_filter::check(command).err()?;
+    tracing::warn!("🔒 Refusing command: {}", reason);
+    Some(CommandResponse::Error {
+        code: COMMAND_FORBIDDEN.to_string(),
+        message: format!("Command not allowed: {}", reason),
+    })
+}
+
+/// `rate_limited` error when `COCOON_EXEC_RATE` allows no command right now
+fn exec_rate_limited() -> Option<CommandResponse> {
+    let wait = crate::rate_limit::acquire_exec().err()?;
+    tracing::warn!("🚦 Command rejected by COCOON_EXEC_RATE");
+    Some(CommandResponse::Error {
+        code: "rate_limited".to_string(),
+        message: format!("Too many commands; retry in {} ms", wait.as_millis().max(1)),
+    })
+}
+
+/// Relays of one ADI service subscription: the peer that opened it, and each
+/// event name's router subscription with the task forwarding it
+struct ServiceSubscription {
+    peer_id: Option<String>,
+    relays: Vec<(Uuid, tokio::task::JoinHandle<()>)>,
+}
+
+type ServiceSubscriptions = Arc<std::sync::Mutex<HashMap<Uuid, ServiceSubscription>>>;
+
+/// Open one router subscription per event and relay everything they deliver.
+/// All of them are opened or none: a failure closes the ones already open.
+async fn subscribe_service_events(
+    service_id: String,
+    events: Vec<String>,
+    peer_id: Option<String>,
+    ctx: &CommandContext,
+) -> CommandResponse {
+    let mut names: Vec<String> = Vec::new();
+    for event in events.into_iter().map(|e| e.trim().to_string()).filter(|e| !e.is_empty()) {
+        if !names.contains(&event) {
+            names.push(event);
+        }
+    }
+    if names.is_empty() {
+        return CommandResponse::Error {
+            code: "invalid_request".to_string(),
+            message: "subscribe needs at least one event name".to_string(),
+        };
+    }
+
+    let subscription_id = Uuid::new_v4();
+    let mut relays = Vec::new();
+    {
+        let router = ctx.adi_router.lock().await;
+        for event in &names {
+            match router.open_subscription(&service_id, event, None).await {
+                Ok((router_id, receiver)) => {
+                    let relay = spawn_service_relay(
+                        subscription_id,
+                        service_id.clone(),
+                        event.clone(),
+                        receiver,
+                        ctx.responder(),
+                    );
+                    relays.push((router_id, relay));
+                }
+                Err((code, message)) => {
+                    for (router_id, relay) in relays {
+                        relay.abort();
+                        router.close_subscription(router_id).await;
+                    }
+                    return CommandResponse::Error { code, message };
+                }
+            }
+        }
+    }
+
+    tracing::info!("📣 Subscribed to {} events: {}", service_id, names.join(", "));
+    ctx.service_subscriptions
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(subscription_id, ServiceSubscription { peer_id, relays });
+    CommandResponse::Subscribed {
+        subscription_id,
+        service_id,
+        events: names,
+    }
+}
+
+/// Forward what `receiver` delivers as `service_event` responses until the
+/// service closes it
+fn spawn_service_relay<T: serde::Serialize + Clone + Send + 'static>(
+    subscription_id: Uuid,
+    service_id: String,
+    event: String,
+    mut receiver: broadcast::Receiver<T>,
+    responder: Responder,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(data) => {
+                    let data = match serde_json::to_value(&data) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            tracing::warn!("⚠️ Unserializable {} event from {}: {}", event, service_id, e);
+                            continue;
+                        }
+                    };
+                    let response = CommandResponse::ServiceEvent {
+                        subscription_id,
+                        service_id: service_id.clone(),
+                        event: event.clone(),
+                        data,
+                    };
+                    responder.send(&response).await;
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("📣 {} {} relay lagged, dropped {} event(s)", service_id, event, missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }.in_current_span())
+}
+
+/// Registered services by name, replaced as a whole by `update_services`
+type ServiceRegistry = Arc<std::sync::RwLock<HashMap<String, ServiceTarget>>>;
+
+/// Check an `update_services` registry: names usable in `COCOON_SERVICES`,
+/// non-zero ports, and no port claimed by two services
+fn validate_services(services: &HashMap<String, u16>) -> Result<(), String> {
+    let mut by_port: HashMap<u16, &str> = HashMap::new();
+    let mut names: Vec<&String> = services.keys().collect();
+    names.sort();
+    for name in names {
+        let port = services[name];
+        if name.is_empty() || name.chars().any(|c| c == ',' || c == ':' || c.is_whitespace()) {
+            return Err(format!("Invalid service name '{}'", name));
+        }
+        if port == 0 {
+            return Err(format!("Invalid port 0 for service '{}'", name));
+        }
+        if let Some(other) = by_port.insert(port, name) {
+            return Err(format!("Port {} is given to both '{}' and '{}'", port, other, name));
+        }
+    }
+    Ok(())
+}
+
+async fn handle_proxy_request(
+    request_id: String,
+    service_name: String,
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Option<String>,
+    inject_headers: Option<HashMap<String, String>>,
+    ctx: &CommandContext,
+) -> CommandResponse {
+    let stats = &ctx.proxy_stats;
+    let target = ctx.services.read().unwrap_or_else(|e| e.into_inner()).get(&service_name).cloned();
+    let target = match target {
+        Some(target) => target,
+        None => {
+            tracing::warn!("Service not found: {}", service_name);
+            return CommandResponse::ProxyResult {
+                request_id,
+                status_code: 404,
+                headers: HashMap::new(),
+                body: Some(format!("Service not found: {}", service_name)),
+            };
+        }
+    };
+
+    let url = format!("http://{}:{}{}", target.host, target.port, path);
+    tracing::debug!("Proxying {} {} to {}", method, path, url);
+
+    let client = crate::host_aliases::http_client();
+
+    let http_method = match method.to_uppercase().as_str() {
+        "GET" => reqwest::Method::GET,
+        "POST" => reqwest::Method::POST,
+        "PUT" => reqwest::Method::PUT,
+        "DELETE" => reqwest::Method::DELETE,
+        "PATCH" => reqwest::Method::PATCH,
+        "HEAD" => reqwest::Method::HEAD,
+        "OPTIONS" => reqwest::Method::OPTIONS,
+        _ => {
+            tracing::warn!("Unsupported HTTP method: {}", method);
+            return CommandResponse::ProxyResult {
+                request_id,
+                status_code: 405,
+                headers: HashMap::new(),
+                body: Some(format!("Unsupported method: {}", method)),
+            };
+        }
+    };
+
+    let mut request_builder = client.request(http_method, &url);
+
+    let outgoing = merge_proxy_headers(headers, inject_headers.unwrap_or_default());
+    tracing::debug!("Proxy headers: {:?}", redacted_headers(&outgoing));
+
+    for (key, value, _) in outgoing {
+        request_builder = request_builder.header(&key, &value);
+    }
+
+    let request_bytes = body.as_ref().map_or(0, |b| b.len());
+    if let Some(body_str) = body {
+        request_builder = request_builder.body(body_str);
+    }
+
+    let started = std::time::Instant::now();
+    match request_builder
+        .timeout(std::time::Duration::from_secs(30))
+        .send()
+        .await
+    {
+        Ok(mut response) => {
+            let status_code = response.status().as_u16();
+            let mut response_headers = HashMap::new();
+
+            for (key, value) in response.headers() {
+                if let Ok(value_str) = value.to_str() {
+                    response_headers.insert(key.to_string(), value_str.to_string());
+                }
+            }
+
+            // Buffer up to the threshold; a body known or found to be larger is streamed
+            let threshold = proxy_stream_threshold();
+            let mut buffered: Vec<u8> = Vec::new();
+            let mut read_error = None;
+            let mut oversized = response.content_length().is_some_and(|len| len > threshold as u64);
+            while !oversized {
+                match response.chunk().await {
+                    Ok(Some(chunk)) => {
+                        buffered.extend_from_slice(&chunk);
+                        oversized = buffered.len() > threshold;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        read_error = Some(e);
+                        break;
+                    }
+                }
+            }
+
+            if oversized {
+                tracing::debug!("Streaming proxy response {} from {}", request_id, service_name);
+                let (last, response_bytes, complete) =
+                    stream_proxy_response(&ctx.responder(), request_id, status_code, response_headers, buffered, response).await;
+                stats.record(
+                    &service_name,
+                    request_bytes,
+                    response_bytes,
+                    started.elapsed(),
+                    status_code >= 500 || !complete,
+                );
+                return last;
+            }
+
+            let response_body = match read_error {
+                None => Some(String::from_utf8_lossy(&buffered).into_owned()),
+                Some(e) => {
+                    tracing::warn!("Failed to read response body: {}", e);
+                    None
+                }
+            };
+
+            stats.record(
+                &service_name,
+                request_bytes,
+                response_body.as_ref().map_or(0, |b| b.len()),
+                started.elapsed(),
+                status_code >= 500,
+            );
+
+            CommandResponse::ProxyResult {
+                request_id,
+                status_code,
+                headers: response_headers,
+                body: response_body,
+            }
+        }
+        Err(e) => {
+            tracing::error!("HTTP proxy request failed: {}", e);
+            stats.record(&service_name, request_bytes, 0, started.elapsed(), true);
+            CommandResponse::ProxyResult {
+                request_id,
+                status_code: 502,
+                headers: HashMap::new(),
+                body: Some(format!("Proxy error: {}", e)),
+            }
+        }
+    }
+}
+
+const DEFAULT_PROXY_STREAM_THRESHOLD: usize = 1024 * 1024;
+
+/// Body bytes per `proxy_result_chunk`
+const PROXY_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Bodies larger than this many bytes are streamed as `proxy_result_chunk`s
+fn proxy_stream_threshold() -> usize {
+    env_opt(EnvVar::CocoonProxyStreamThreshold.as_str())
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_PROXY_STREAM_THRESHOLD)
+}
+
+/// Send `buffered` and the rest of `response` as numbered chunks. All but the
+/// last go out here; the last, with `is_final` set, is returned along with the
+/// body size and whether the body was read to the end.
+async fn stream_proxy_response(
+    responder: &Responder,
+    request_id: String,
+    status_code: u16,
+    headers: HashMap<String, String>,
+    buffered: Vec<u8>,
+    mut response: reqwest::Response,
+) -> (CommandResponse, usize, bool) {
+    let mut head = Some((status_code, headers));
+    let mut seq = 0u64;
+    let mut chunk = |data: &[u8], is_final: bool, error: Option<String>| {
+        let (status_code, headers) = head.take().unzip();
+        let response = CommandResponse::ProxyResultChunk {
+            request_id: request_id.clone(),
+            seq,
+            status_code,
+            headers,
+            data: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, data),
+            is_final,
+            error,
+        };
+        seq += 1;
+        response
+    };
+
+    let mut total = buffered.len();
+    let mut pending = buffered;
+    let error = loop {
+        while pending.len() >= PROXY_CHUNK_BYTES {
+            let rest = pending.split_off(PROXY_CHUNK_BYTES);
+            responder.send(&chunk(&pending, false, None)).await;
+            pending = rest;
+        }
+        match response.chunk().await {
+            Ok(Some(data)) => {
+                total += data.len();
+                pending.extend_from_slice(&data);
+            }
+            Ok(None) => break None,
+            Err(e) => {
+                tracing::warn!("Proxy response {} cut off after {} bytes: {}", request_id, total, e);
+                break Some(format!("Proxy error: {}", e));
+            }
+        }
+    };
+
+    let complete = error.is_none();
+    (chunk(&pending, true, error), total, complete)
+}
+
+async fn handle_query_local(
+    query_id: String,
+    query_type: QueryType,
+    params: JsonValue,
+) -> CommandResponse {
+    match query_type {
+        QueryType::ListTasks => {
+            tracing::debug!("Listing local tasks with params: {:?}", params);
+
+            CommandResponse::QueryResult {
+                query_id,
+                data: serde_json::json!({
+                    "tasks": [],
+                    "total": 0,
+                    "source": "cocoon-local"
+                }),
+                is_final: true,
+            }
+        }
+        QueryType::GetTaskStats => {
+            tracing::debug!("Getting task stats");
+
+            CommandResponse::QueryResult {
+                query_id,
+                data: serde_json::json!({
+                    "pending": 0,
+                    "running": 0,
+                    "completed": 0,
+                    "failed": 0,
+                    "total": 0
+                }),
+                is_final: true,
+            }
+        }
+        QueryType::SearchTasks => {
+            let query = params.get("query").and_then(|v| v.as_str()).unwrap_or("");
+            tracing::debug!("Searching tasks for: {}", query);
+
+            CommandResponse::QueryResult {
+                query_id,
+                data: serde_json::json!({
+                    "tasks": [],
+                    "query": query,
+                    "total": 0
+                }),
+                is_final: true,
+            }
+        }
+        QueryType::SearchKnowledgebase => {
+            let query = params.get("query").and_then(|v| v.as_str()).unwrap_or("");
+            tracing::debug!("Searching knowledgebase for: {}", query);
+
+            CommandResponse::QueryResult {
+                query_id,
+                data: serde_json::json!({
+                    "results": [],
+                    "query": query,
+                    "total": 0
+                }),
+                is_final: true,
+            }
+        }
+        QueryType::Custom { query_name } => {
+            tracing::warn!("Custom query not implemented: {}", query_name);
+
+            CommandResponse::QueryResult {
+                query_id,
+                data: serde_json::json!({
+                    "error": format!("Custom query '{}' not implemented", query_name)
+                }),
+                is_final: true,
+            }
+        }
+    }
+}
+
+fn validate_secret(secret: &str) -> Result<(), String> {
+    if secret.len() < MIN_SECRET_LENGTH {
+        return Err(format!(
+            "Secret too short: {} characters (minimum: {})",
+            secret.len(),
+            MIN_SECRET_LENGTH
+        ));
+    }
+
+    if secret.chars().all(|c| c.is_numeric()) {
+        return Err("Secret must not be only numbers".to_string());
+    }
+
+    if secret.to_lowercase() == secret && secret.chars().all(|c| c.is_alphabetic()) {
+        return Err("Secret must not be only lowercase letters".to_string());
+    }
+
+    if secret.chars().all(|c| Some(c) == secret.chars().next()) {
+        return Err("Secret must not be repetitive characters".to_string());
+    }
+
+    let lower = secret.to_lowercase();
+    let weak_patterns = ["password", "secret", "admin", "12345", "qwerty", "test"];
+    for pattern in &weak_patterns {
+        if lower.contains(pattern) {
+            return Err(format!("Secret contains weak pattern: {}", pattern));
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn generate_strong_secret() -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut rng = rand::rng();
+
+    (0..GENERATED_SECRET_LENGTH)
+        .map(|_| {
+            let idx = rng.random_range(0..CHARSET.len());
+            CHARSET[idx] as char
+        })
+        .collect()
+}
+
+/// Whether the data dir can hold the secret and device id across restarts
+#[derive(Debug, Clone)]
+enum DataDirStatus {
+    Writable,
+    Unavailable(String),
+}
+
+/// Probe the data dir by writing and removing a temp file
+async fn probe_data_dir(dir: &Path) -> DataDirStatus {
+    match tokio::fs::metadata(dir).await {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => return DataDirStatus::Unavailable(format!("{} is not a directory", dir.display())),
+        Err(e) => return DataDirStatus::Unavailable(format!("{} is missing: {}", dir.display(), e)),
+    }
+
+    let probe = dir.join(format!(".write-probe-{}", Uuid::new_v4()));
+    match tokio::fs::write(&probe, b"probe").await {
+        Ok(()) => {
+            let _ = tokio::fs::remove_file(&probe).await;
+            DataDirStatus::Writable
+        }
+        Err(e) => DataDirStatus::Unavailable(format!("{} is not writable: {}", dir.display(), e)),
+    }
+}
+
+/// Running under a service manager, which stops the cocoon with SIGTERM only
+fn service_mode() -> bool {
+    env_opt(EnvVar::CocoonServiceMode.as_str())
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+fn require_persistence() -> bool {
+    env_opt(EnvVar::CocoonRequirePersistence.as_str())
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Read a credential systemd passed via `LoadCredential=`/`ImportCredential=`.
+///
+/// These live in `$CREDENTIALS_DIRECTORY`, which only the service can read, so
+/// the value never shows up in the process environment.
+async fn read_credential(name: &str) -> Option<String> {
+    let dir = env_opt(EnvVar::CredentialsDirectory.as_str())?;
+    read_credential_in(Path::new(&dir), name).await
+}
+
+async fn read_credential_in(dir: &Path, name: &str) -> Option<String> {
+    let value = tokio::fs::read_to_string(dir.join(name)).await.ok()?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+async fn load_device_id() -> Option<String> {
+    if let Some(device_id) = read_credential(DEVICE_ID_CREDENTIAL).await {
+        tracing::info!("📱 Loaded device ID from systemd credential {}", DEVICE_ID_CREDENTIAL);
+        return Some(device_id);
+    }
+
+    match tokio::fs::read_to_string(DEVICE_ID_PATH).await {
+        Ok(device_id) => {
+            let device_id = device_id.trim().to_string();
+            if device_id.is_empty() {
+                None
+            } else {
+                tracing::info!("📱 Loaded existing device ID from {}", DEVICE_ID_PATH);
+                Some(device_id)
+            }
+        }
+        Err(_) => None,
+    }
+}
+
+async fn save_device_id(device_id: &str) {
+    if let Err(e) = tokio::fs::write(DEVICE_ID_PATH, device_id).await {
+        tracing::warn!("⚠️ Could not save device ID to {}: {}", DEVICE_ID_PATH, e);
+        tracing::warn!("💡 Mount volume at /cocoon for persistent device ID");
+    } else {
+        tracing::info!(
+            "💾 Saved device ID to {} for reconnection verification",
+            DEVICE_ID_PATH
+        );
+    }
+}
+
+async fn send_deregister(writer: &SharedWriter, device_id: &str, reason: Option<&str>) {
+    let deregister_msg = SignalingMessage::DeviceDeregister {
+        device_id: device_id.to_string(),
+        reason: reason.map(|r| r.to_string()),
+    };
+
+    let text = serde_json::to_string(&deregister_msg)
+        .expect("SignalingMessage serialization cannot fail");
+    if let Err(e) = writer.send(text, Priority::Control).await {
+        tracing::warn!("⚠️ Failed to send deregister message: {}", e);
+    } else {
+        tracing::info!("📤 Sent deregister message to server");
+    }
+}
+
+fn strict_secret() -> bool {
+    env_opt(EnvVar::CocoonStrictSecret.as_str())
+        .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false)
+}
+
+/// Resolve a disagreement between a secret supplied from outside (`source`) and
+/// the one stored at `secret_path`.
+///
+/// The supplied secret always wins, but the stored device ID was assigned to the
+/// old secret and would fail reconnection verification. So the old secret is
+/// moved aside to `<secret_path>.stale` and the device ID is removed, leaving the
+/// data directory consistent for the next start. With `strict` the disagreement
+/// is an error instead and nothing is touched.
+async fn reconcile_disk_secret(
+    secret: &str,
+    source: &str,
+    secret_path: &Path,
+    device_id_path: &Path,
+    strict: bool,
+) -> Result<(), String> {
+    let stored = match tokio::fs::read_to_string(secret_path).await {
+        Ok(stored) => stored.trim().to_string(),
+        Err(_) => return Ok(()),
+    };
+    if stored.is_empty() || stored == secret {
+        return Ok(());
+    }
+
+    tracing::warn!("⚠️ {} differs from the secret stored in {}", source, secret_path.display());
+    if strict {
+        return Err(format!(
+            "{} differs from {} and COCOON_STRICT_SECRET is set; remove the stale file or unset {}",
+            source,
+            secret_path.display(),
+            source
+        ));
+    }
+
+    let stale_path = secret_path.with_extension("stale");
+    tracing::warn!(
+        "💡 Using {}; moving the old secret to {} and discarding the device ID registered with it",
+        source,
+        stale_path.display()
+    );
+    if let Err(e) = tokio::fs::rename(secret_path, &stale_path).await {
+        tracing::warn!("⚠️ Could not move {} aside: {}", secret_path.display(), e);
+    }
+    let _ = tokio::fs::remove_file(device_id_path).await;
+    Ok(())
+}
+
+/// Load the secret and the device ID registered with it.
+///
+/// Precedence: systemd credential, then `COCOON_SECRET`, then `SECRET_PATH`,
+/// and finally a newly generated secret.
+async fn get_or_create_secret() -> Result<(String, Option<String>), Box<dyn std::error::Error>> {
+    // systemd credentials first: they never appear in the environment
+    if let Some(secret) = read_credential(SECRET_CREDENTIAL).await {
+        tracing::info!("📋 Using secret from systemd credential {}", SECRET_CREDENTIAL);
+
+        if let Err(e) = validate_secret(&secret) {
+            tracing::error!("❌ Invalid secret from credential {}: {}", SECRET_CREDENTIAL, e);
+            tracing::error!("💡 Use: openssl rand -base64 36");
+            return Err(format!("Invalid {} credential: {}", SECRET_CREDENTIAL, e).into());
+        }
+
+        let source = format!("credential {}", SECRET_CREDENTIAL);
+        reconcile_disk_secret(
+            &secret,
+            &source,
+            Path::new(SECRET_PATH),
+            Path::new(DEVICE_ID_PATH),
+            strict_secret(),
+        )
+        .await?;
+        return Ok((secret, load_device_id().await));
+    }
+
+    // Then the environment variable (for manual management)
+    if let Some(secret) = env_opt(EnvVar::CocoonSecret.as_str()) {
+        tracing::info!("📋 Using secret from COCOON_SECRET environment variable");
+
+        if let Err(e) = validate_secret(&secret) {
+            tracing::error!("❌ Invalid secret from COCOON_SECRET: {}", e);
+            tracing::error!("💡 Secret requirements:");
+            tracing::error!("   - Minimum {} characters", MIN_SECRET_LENGTH);
+            tracing::error!("   - Must be random and unpredictable");
+            tracing::error!("   - Avoid common patterns, dictionary words");
+            tracing::error!("   - Use: openssl rand -base64 36");
+            return Err(format!("Invalid COCOON_SECRET: {}", e).into());
+        }
+
+        reconcile_disk_secret(
+            &secret,
+            "COCOON_SECRET",
+            Path::new(SECRET_PATH),
+            Path::new(DEVICE_ID_PATH),
+            strict_secret(),
+        )
+        .await?;
+        return Ok((secret, load_device_id().await));
+    }
+
+    match tokio::fs::read_to_string(SECRET_PATH).await {
+        Ok(secret) => {
+            let secret = secret.trim().to_string();
+
+            if let Err(e) = validate_secret(&secret) {
+                tracing::error!("❌ Invalid secret from {}: {}", SECRET_PATH, e);
+                tracing::error!("💡 Deleting weak secret and generating new one");
+                let _ = tokio::fs::remove_file(SECRET_PATH).await;
+                // Also delete device_id since secret changed
+                let _ = tokio::fs::remove_file(DEVICE_ID_PATH).await;
+            } else {
+                tracing::info!("🔑 Loaded existing secret from {}", SECRET_PATH);
+                return Ok((secret, load_device_id().await));
+            }
+        }
+        Err(_) => {}
+    }
+
+    let secret = generate_strong_secret();
+    tracing::info!(
+        "🆕 Generated new cryptographically strong secret ({} chars, {} bits entropy)",
+        GENERATED_SECRET_LENGTH,
+        GENERATED_SECRET_LENGTH * 6
+    );
+
+    // Try to save it (may fail in read-only containers, that's ok)
+    if let Err(e) = tokio::fs::write(SECRET_PATH, &secret).await {
+        tracing::warn!(
+            "⚠️ Could not save secret to {} (ephemeral session): {}",
+            SECRET_PATH,
+            e
+        );
+        tracing::warn!(
+            "💡 Set COCOON_SECRET env var or mount volume at /cocoon for persistent sessions"
+        );
+    } else {
+        tracing::info!("💾 Saved secret to {} for persistent sessions", SECRET_PATH);
+    }
+
+    // New secret means no device_id yet (first registration)
+    Ok((secret, None))
+}
+
+async fn handle_cocoon_webrtc(
+    msg: CocoonMessage,
+    webrtc: Arc<crate::webrtc::WebRtcManager>,
+    writer: SharedWriter,
+) {
+    async fn send_cocoon_msg(writer: &SharedWriter, msg: &CocoonMessage) {
+        let sync_msg = SignalingMessage::SyncData {
+            payload: serde_json::to_value(msg).expect("CocoonMessage serialization cannot fail"),
+        };
+        let text = serde_json::to_string(&sync_msg).expect("SignalingMessage serialization cannot fail");
+        let _ = writer.send(text, Priority::Control).await;
+    }
+
+    match msg {
+        CocoonMessage::WebrtcStartSession {
+            session_id,
+            device_id: client_id,
+            user_id,
+            data_channels,
+        } => {
+            if let Some(ref channels) = data_channels {
+                tracing::info!("🎥 WebRTC session request from {}: {} (user_id={:?}, data_channels={:?})", client_id, session_id, user_id, channels);
+            } else {
+                tracing::info!("🎥 WebRTC session request from {}: {} (user_id={:?})", client_id, session_id, user_id);
+            }
+            match webrtc.create_session(session_id.clone(), user_id).await {
+                Ok(()) => {
+                    tracing::info!("✅ WebRTC session {} created", session_id);
+                }
+                Err(e) => {
+                    tracing::error!("❌ Failed to create WebRTC session: {}", e);
+                    send_cocoon_msg(&writer, &CocoonMessage::WebrtcError {
+                        session_id,
+                        code: "session_create_failed".to_string(),
+                        message: e,
+                    }).await;
+                }
+            }
+        }
+
+        CocoonMessage::WebrtcOffer { session_id, sdp } => {
+            tracing::info!("📥 WebRTC offer received for session {}", session_id);
+            match webrtc.handle_offer(&session_id, &sdp).await {
+                Ok(answer_sdp) => {
+                    tracing::info!("📤 Sending WebRTC answer for session {}", session_id);
+                    send_cocoon_msg(&writer, &CocoonMessage::WebrtcAnswer {
+                        session_id,
+                        sdp: answer_sdp,
+                    }).await;
+                }
+                Err(e) => {
+                    tracing::error!("❌ Failed to handle WebRTC offer: {}", e);
+                    send_cocoon_msg(&writer, &CocoonMessage::WebrtcError {
+                        session_id,
+                        code: "offer_failed".to_string(),
+                        message: e,
+                    }).await;
+                }
+            }
+        }
+
+        CocoonMessage::WebrtcIceCandidate {
+            session_id,
+            candidate,
+            sdp_mid,
+            sdp_mline_index,
+        } => {
+            tracing::debug!("🧊 ICE candidate received for session {}", session_id);
+            if let Err(e) = webrtc
+                .add_ice_candidate(
+                    &session_id,
+                    &candidate,
+                    sdp_mid.as_deref(),
+                    sdp_mline_index.map(|i| i as u32),
+                )
+                .await
+            {
+                tracing::warn!("⚠️ Failed to add ICE candidate: {}", e);
+            }
+        }
+
+        CocoonMessage::WebrtcSessionEnded { session_id, reason } => {
+            let reason_str = reason.as_deref().unwrap_or("not specified");
+            if reason_str == "session_replaced" {
+                tracing::info!("🔄 WebRTC session {} replaced by newer session from same client", session_id);
+            } else {
+                tracing::info!("🔌 WebRTC session {} ended (reason: {})", session_id, reason_str);
+            }
+            let _ = webrtc.close_session(&session_id).await;
+        }
+
+        CocoonMessage::WebrtcData {
+            session_id: _,
+            channel,
+            data,
+            binary,
+        } => {
+            tracing::debug!("📦 WebRTC data received: {} bytes on channel {}", data.len(), channel);
+            match channel.as_str() {
+                "terminal" => tracing::debug!("Terminal data: {}", data),
+                "file-transfer" => tracing::debug!("File transfer data: {} bytes, binary: {}", data.len(), binary),
+                _ => tracing::debug!("Unknown channel: {}", channel),
+            }
+        }
+
+        CocoonMessage::WebrtcError { session_id, code, message } => {
+            tracing::error!("❌ WebRTC error for session {}: {} - {}", session_id, code, message);
+            let _ = webrtc.close_session(&session_id).await;
+        }
+
+        _ => {
+            tracing::debug!("📨 Unhandled CocoonMessage in WebRTC handler");
+        }
+    }
+}
+
+/// Shared state a signaling command may touch while it is handled
+#[derive(Clone)]
+struct CommandContext {
+    writer: SharedWriter,
+    pty_sessions: Arc<Mutex<HashMap<Uuid, PtySession>>>,
+    pty_slots: Arc<PtySlots>,
+    silk_sessions: Arc<Mutex<HashMap<Uuid, SilkSession>>>,
+    services: ServiceRegistry,
+    proxy_stats: Arc<crate::proxy_stats::ProxyStats>,
+    events: Arc<crate::events::EventBus>,
+    webrtc: Arc<crate::webrtc::WebRtcManager>,
+    data_dir: DataDirStatus,
+    output_policy: crate::output_policy::OutputPolicy,
+    execs: RunningExecs,
+    adi_router: Arc<Mutex<AdiRouter>>,
+    service_subscriptions: ServiceSubscriptions,
+    /// PTY and Silk sessions opened over each WebRTC session's "terminal" channel
+    terminal_sessions: Arc<std::sync::Mutex<HashMap<String, Vec<Uuid>>>>,
+    route: ResponseRoute,
+}
+
+impl CommandContext {
+    /// Stop relaying a service subscription; `false` if it was not open
+    async fn end_service_subscription(&self, subscription_id: Uuid) -> bool {
+        let subscription = self
+            .service_subscriptions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&subscription_id);
+        let Some(subscription) = subscription else {
+            return false;
+        };
+        let router = self.adi_router.lock().await;
+        for (router_id, relay) in subscription.relays {
+            relay.abort();
+            router.close_subscription(router_id).await;
+        }
+        true
+    }
+
+    /// Stop the service subscriptions a disconnecting peer opened. Ones opened
+    /// without a `peer_id` cannot be attributed, so any disconnect ends them.
+    async fn end_peer_service_subscriptions(&self, peer_id: &str) -> usize {
+        let ids: Vec<Uuid> = self
+            .service_subscriptions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|(_, subscription)| subscription.peer_id.as_deref().map_or(true, |owner| owner == peer_id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            self.end_service_subscription(*id).await;
+        }
+        ids.len()
+    }
+
+    /// Registered services in `COCOON_SERVICES` form (`name:port` or
+    /// `name:host:port`), sorted by name
+    fn service_definitions(&self) -> Vec<String> {
+        let services = self.services.read().unwrap_or_else(|e| e.into_inner());
+        let mut definitions: Vec<String> = services
+            .iter()
+            .map(|(name, target)| match target.host.as_str() {
+                "localhost" => format!("{}:{}", name, target.port),
+                host => format!("{}:{}:{}", name, host, target.port),
+            })
+            .collect();
+        definitions.sort();
+        definitions
+    }
+
+    /// Send a response, also publishing it on the `sessions` topic when it
+    /// starts or ends a terminal session. Sessions on a WebRTC `silk` channel
+    /// never pass through here, so they are not published.
+    async fn respond(&self, response: &CommandResponse) {
+        if let Some(data) = session_event(response) {
+            self.events.publish("sessions", data);
+        }
+        self.responder().send(response).await;
+    }
+
+    /// Sender for output streamed after the command's own response
+    fn responder(&self) -> Responder {
+        Responder {
+            writer: self.writer.clone(),
+            route: self.route.clone(),
+        }
+    }
+
+    /// Tie a PTY or Silk session opened over a "terminal" channel to its WebRTC
+    /// session, ending it at once if that session closed in the meantime
+    async fn own_terminal_session(&self, response: &CommandResponse) {
+        let ResponseRoute::DataChannel { session_id: webrtc_session_id, .. } = &self.route else {
+            return;
+        };
+        let session_id = match response {
+            CommandResponse::PtyCreated { session_id }
+            | CommandResponse::SilkResponse(SilkResponse::SessionCreated { session_id, .. }) => *session_id,
+            _ => return,
+        };
+        self.terminal_sessions
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(webrtc_session_id.clone())
+            .or_default()
+            .push(session_id);
+        if !self.webrtc.session_exists(webrtc_session_id).await {
+            end_terminal_sessions(webrtc_session_id, TERMINAL_CLOSED_REASON, self).await;
+        }
+    }
+
+    /// Send a notice to every WebRTC session's silk channel and return it for
+    /// the signaling link, which reaches clients without a WebRTC session
+    async fn notice(&self, message: String, level: NoticeLevel) -> CommandResponse {
+        let notice = CommandResponse::Notice {
+            notice_id: Uuid::new_v4(),
+            message,
+            level,
+        };
+        let reached = self.webrtc.broadcast("silk", &notice).await;
+        if let CommandResponse::Notice { message, .. } = &notice {
+            tracing::info!("📢 Notice ({} WebRTC session(s)): {}", reached, message);
+        }
+        notice
+    }
+}
+
+fn session_event(response: &CommandResponse) -> Option<JsonValue> {
+    match response {
+        CommandResponse::PtyCreated { .. }
+        | CommandResponse::PtyExited { .. }
+        | CommandResponse::WebRtcSessionClosed { .. }
+        | CommandResponse::SilkResponse(SilkResponse::SessionCreated { .. })
+        | CommandResponse::SilkResponse(SilkResponse::SessionRestored { .. })
+        | CommandResponse::SilkResponse(SilkResponse::SessionClosed { .. }) => {
+            serde_json::to_value(response).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Count an `execute` or `pipeline` run, and whether it failed
+fn count_execution(response: &CommandResponse) {
+    crate::metrics::increment(crate::metrics::Counter::CommandsExecuted);
+    if !matches!(response, CommandResponse::ExecuteResult { success: true, .. }) {
+        crate::metrics::increment(crate::metrics::Counter::CommandsFailed);
+    }
+}
+
+async fn metrics_snapshot(ctx: &CommandContext) -> crate::metrics::MetricsSnapshot {
+    let gauges = crate::metrics::Gauges {
+        pty_sessions: ctx.pty_sessions.lock().await.len(),
+        silk_sessions: ctx.silk_sessions.lock().await.len(),
+        webrtc_sessions: ctx.webrtc.session_count().await,
+    };
+    crate::metrics::MetricsSnapshot::new(gauges, ctx.proxy_stats.snapshot(false))
+}
+
+/// Empty the output dir before an `execute` or `pipeline` when the client
+/// asked for it or `COCOON_OUTPUT_POLICY` is `clear_before`. The policy is not
+/// applied while `/cocoon` is not writable.
+async fn prepare_output_dir(ctx: &CommandContext, requested: bool) -> Result<(), CommandResponse> {
+    let by_policy = ctx.output_policy == crate::output_policy::OutputPolicy::ClearBefore
+        && matches!(ctx.data_dir, DataDirStatus::Writable);
+    if !requested && !by_policy {
+        return Ok(());
+    }
+    clear_output_dir(Path::new(OUTPUT_DIR))
+        .await
+        .map(|_| ())
+        .map_err(|e| CommandResponse::Error {
+            code: "clear_output_failed".to_string(),
+            message: e,
+        })
+}
+
+/// Handle one command request from the signaling channel.
+///
+/// Returns the response to send back, or `None` when there is nothing to send
+/// (successful input/resize) or output is delivered asynchronously.
+async fn handle_command(request: CommandRequest, ctx: &CommandContext) -> Option<CommandResponse> {
+    match request {
+        CommandRequest::Execute {
+            command,
+            input,
+            secret_env,
+            clear_output,
+            timeout_ms,
+            request_id,
+        } => {
+            tracing::info!(
+                "🚀 Executing: {}{}",
+                crate::log_redact::redact(&command),
+                secret_env_note(&secret_env)
+            );
+            if let Some(forbidden) = command_forbidden(&command) {
+                return Some(forbidden);
+            }
+            if let Some(limited) = exec_rate_limited() {
+                return Some(limited);
+            }
+            let registration = match &request_id {
+                Some(id) => match ExecRegistration::new(&ctx.execs, id) {
+                    Some(registration) => Some(registration),
+                    None => {
+                        return Some(CommandResponse::Error {
+                            code: "duplicate_request_id".to_string(),
+                            message: format!("An execute with request_id {} is already running", id),
+                        })
+                    }
+                },
+                None => None,
+            };
+            crate::history::record(&command, &secret_env);
+            if let Err(e) = prepare_output_dir(ctx, clear_output).await {
+                return Some(e);
+            }
+            let timeout = timeout_ms.map(Duration::from_millis);
+            let cancel = registration.as_ref().map(|r| &*r.handle);
+            let mut response = execute_command(&command, input.as_deref(), &secret_env, timeout, cancel).await;
+            count_execution(&response);
+            if let CommandResponse::ExecuteResult { request_id: id, .. } = &mut response {
+                *id = request_id;
+            }
+            Some(limit_result(response))
+        }
+
+        CommandRequest::CancelExec { request_id, signal } => {
+            let signal_name = signal.as_deref().unwrap_or("SIGTERM");
+            let Some(signo) = parse_signal(signal_name) else {
+                return Some(CommandResponse::Error {
+                    code: "invalid_signal".to_string(),
+                    message: format!("Unknown signal: {}", signal_name),
+                });
+            };
+            let handle = ctx.execs.lock().unwrap_or_else(|e| e.into_inner()).get(&request_id).cloned();
+            let signaled = handle.as_ref().is_some_and(|handle| {
+                handle.cancelled.store(true, Ordering::SeqCst);
+                handle.pid.get().is_some_and(|pid| crate::input_wait::signal_tree(*pid, signo))
+            });
+            tracing::info!("🛑 cancel_exec {} ({}): signaled={}", request_id, signal_name, signaled);
+            Some(CommandResponse::ExecCancelled {
+                request_id,
+                found: handle.is_some(),
+                signaled,
+            })
+        }
+
+        CommandRequest::ClearOutput => match clear_output_dir(Path::new(OUTPUT_DIR)).await {
+            Ok(removed) => {
+                tracing::info!("🧹 Cleared {} entries from {}", removed, OUTPUT_DIR);
+                Some(CommandResponse::OutputCleared { removed })
+            }
+            Err(e) => Some(CommandResponse::Error {
+                code: "clear_output_failed".to_string(),
+                message: e,
+            }),
+        },
+
+        CommandRequest::Pipeline { stages, input } => {
+            tracing::info!("🚀 Executing pipeline: {}", crate::log_redact::redact(&stages.join(" | ")));
+            if let Some(forbidden) = stages.iter().find_map(|stage| command_forbidden(stage)) {
+                return Some(forbidden);
+            }
+            if let Some(limited) = exec_rate_limited() {
+                return Some(limited);
+            }
+            crate::history::record(&stages.join(" | "), &HashMap::new());
+            if let Err(e) = prepare_output_dir(ctx, false).await {
+                return Some(e);
+            }
+            let response = execute_pipeline(stages, input).await;
+            count_execution(&response);
+            Some(limit_result(response))
+        }
+
+        CommandRequest::AttachPty {
+            command,
+            cols,
+            rows,
+            mut env,
+            secret_env,
+            clean_env,
+            cwd,
+            shell,
+        } => {
+            tracing::info!(
+                "🔗 Attaching PTY: {} ({}x{}){}",
+                crate::log_redact::redact(&command),
+                cols,
+                rows,
+                secret_env_note(&secret_env)
+            );
+            env.extend(secret_env.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+            let options = PtyOptions {
+                env: &env,
+                clean_env,
+                cwd: cwd.as_deref(),
+                shell: shell.as_deref(),
+            };
+            match create_pty_session(&command, cols, rows, options, ctx.responder(), &ctx.pty_slots).await {
+                Ok((session_id, session)) => {
+                    ctx.pty_sessions.lock().await.insert(session_id, session);
+                    Some(CommandResponse::PtyCreated { session_id })
+                }
+                Err(e) => Some(CommandResponse::Error {
+                    code: e.code.into(),
+                    message: redact_secrets(&e.message, &secret_env),
+                }),
+            }
+        }
+
+        CommandRequest::PtyInput { session_id, data } => {
+            let mut sessions = ctx.pty_sessions.lock().await;
+            if let Some(session) = sessions.get_mut(&session_id) {
+                session.activity.touch();
+                if let Err(e) =
+                    std::io::Write::write_all(&mut session.writer, data.as_bytes())
+                {
+                    Some(CommandResponse::Error {
+                        code: "pty_write_failed".into(),
+                        message: e.to_string(),
+                    })
+                } else {
+                    let _ = std::io::Write::flush(&mut session.writer);
+                    None // No response needed for successful input
+                }
+            } else {
+                Some(CommandResponse::Error {
+                    code: "session_not_found".into(),
+                    message: format!("PTY session {} not found", session_id),
+                })
+            }
+        }
+
+        CommandRequest::PtyResize {
+            session_id,
+            cols,
+            rows,
+        } => {
+            tracing::info!("📐 Resizing PTY {} to {}x{}", session_id, cols, rows);
+            let sessions = ctx.pty_sessions.lock().await;
+            if let Some(session) = sessions.get(&session_id) {
+                if let Err(e) = session.pair.master.resize(PtySize {
+                    rows,
+                    cols,
+                    pixel_width: 0,
+                    pixel_height: 0,
+                }) {
+                    Some(CommandResponse::Error {
+                        code: "resize_failed".into(),
+                        message: e.to_string(),
+                    })
+                } else {
+                    None // No response needed for successful resize
+                }
+            } else {
+                Some(CommandResponse::Error {
+                    code: "session_not_found".into(),
+                    message: format!("PTY session {} not found", session_id),
+                })
+            }
+        }
+
+        CommandRequest::PtyClose { session_id } => {
+            tracing::info!("🔌 Closing PTY session {}", session_id);
+            // Out of the map first, so the lock is not held while the child is reaped
+            let session = ctx.pty_sessions.lock().await.remove(&session_id);
+            match session {
+                Some(session) => Some(kill_pty(session, None)),
+                None => Some(CommandResponse::Error {
+                    code: "session_not_found".into(),
+                    message: format!("PTY session {} not found", session_id),
+                }),
+            }
+        }
+
+        CommandRequest::PtySubscribe { session_id } => {
+            let sessions = ctx.pty_sessions.lock().await;
+            match sessions.get(&session_id) {
+                Some(session) => {
+                    tracing::info!("👀 Client joined PTY session {}", session_id);
+                    session.activity.touch();
+                    let data = session
+                        .replay
+                        .lock()
+                        .map(|replay| replay.snapshot())
+                        .unwrap_or_default();
+                    Some(CommandResponse::PtyRepaint { session_id, data })
+                }
+                None => Some(CommandResponse::Error {
+                    code: "session_not_found".into(),
+                    message: format!("PTY session {} not found", session_id),
+                }),
+            }
+        }
+
+        CommandRequest::ProxyHttp {
+            request_id,
+            service_name,
+            method,
+            path,
+            headers,
+            body,
+            inject_headers,
+        } => {
+            tracing::info!(
+                "🔀 Proxying HTTP {} {} to service {}",
+                method,
+                path,
+                service_name
+            );
+            Some(
+                handle_proxy_request(
+                    request_id,
+                    service_name,
+                    method,
+                    path,
+                    headers,
+                    body,
+                    inject_headers,
+                    ctx,
+                )
+                .await,
+            )
+        }
+
+        CommandRequest::QueryLocal {
+            query_id,
+            query_type,
+            params,
+        } => {
+            tracing::info!("📊 Processing query: {:?}", query_type);
+            Some(handle_query_local(query_id, query_type, params).await)
+        }
+
+        CommandRequest::SilkCreateSession { cwd, env, shell, clean_env } => {
+            tracing::info!("🧵 Creating Silk session");
+            match SilkSession::new(cwd, env, shell, clean_env) {
+                Ok(session) => {
+                    let response = SilkResponse::SessionCreated {
+                        session_id: session.id,
+                        cwd: session.cwd.clone(),
+                        shell: session.shell.clone(),
+                    };
+                    let snapshot = {
+                        let mut silk_sessions = ctx.silk_sessions.lock().await;
+                        silk_sessions.insert(session.id, session);
+                        crate::silk_store::snapshot(&silk_sessions)
+                    };
+                    crate::silk_store::persist(snapshot).await;
+                    Some(CommandResponse::SilkResponse(response))
+                }
+                Err(e) => {
+                    Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                        session_id: None,
+                        command_id: None,
+                        code: "session_create_failed".to_string(),
+                        message: e,
+                    }))
+                }
+            }
+        }
+
+        CommandRequest::SilkExecute {
+            session_id,
+            command,
+            command_id,
+            webrtc_session_id,
+            force_interactive,
+        } => {
+            tracing::info!("🧵 Silk execute: {} (session {})", crate::log_redact::redact(&command), session_id);
+            let mut silk_sessions = ctx.silk_sessions.lock().await;
+
+            if let Some(session) = silk_sessions.get_mut(&session_id) {
+                session.activity.touch();
+                if session.is_running(&command_id) {
+                    return Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                        session_id: Some(session_id),
+                        code: DUPLICATE_COMMAND_ID.to_string(),
+                        message: format!(
+                            "Command {} is still running in session {}; reuse its id once it completes",
+                            command_id, session_id
+                        ),
+                        command_id: Some(command_id),
+                    }));
+                }
+
+                if let Err(reason) = crate::command_filter::check(&command) {
+                    tracing::warn!("🔒 Refusing Silk command: {}", reason);
+                    return Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                        session_id: Some(session_id),
+                        command_id: Some(command_id),
+                        code: COMMAND_FORBIDDEN.to_string(),
+                        message: format!("Command not allowed: {}", reason),
+                    }));
+                }
+
+                crate::history::record(&command, &HashMap::new());
+                match session.execute(&command, command_id.clone(), force_interactive) {
+                    Ok((interactive, child_opt)) => {
+                        crate::metrics::increment(crate::metrics::Counter::SilkCommands);
+                        if interactive {
+                            let mut env = session.env.clone();
+                            let clean_env = session.clean_env;
+                            drop(silk_sessions); // Release lock before async call
+
+                            env.insert(
+                                "TERM".to_string(),
+                                "xterm-256color".to_string(),
+                            );
+
+                            let options = PtyOptions {
+                                env: &env,
+                                clean_env,
+                                cwd: None,
+                                shell: None,
+                            };
+                            match create_pty_session(
+                                &command,
+                                80,
+                                24,
+                                options,
+                                ctx.responder(),
+                                &ctx.pty_slots,
+                            )
+                            .await
+                            {
+                                Ok((pty_session_id, pty_session)) => {
+                                    ctx.pty_sessions
+                                        .lock()
+                                        .await
+                                        .insert(pty_session_id, pty_session);
+
+                                    if let Some(s) = ctx.silk_sessions
+                                        .lock()
+                                        .await
+                                        .get_mut(&session_id)
+                                    {
+                                        s.set_pty_session(
+                                            command_id.clone(),
+                                            pty_session_id,
+                                        );
+                                    }
+
+                                    Some(CommandResponse::SilkResponse(
+                                        SilkResponse::InteractiveRequired {
+                                            session_id,
+                                            command_id,
+                                            reason: format!(
+                                                "Command '{}' requires interactive mode",
+                                                command
+                                                    .split_whitespace()
+                                                    .next()
+                                                    .unwrap_or(&command)
+                                            ),
+                                            pty_session_id,
+                                        },
+                                    ))
+                                }
+                                Err(e) => Some(CommandResponse::SilkResponse(
+                                    SilkResponse::Error {
+                                        session_id: Some(session_id),
+                                        command_id: Some(command_id),
+                                        code: e.code.to_string(),
+                                        message: e.message,
+                                    },
+                                )),
+                            }
+                        } else if let Some(mut child) = child_opt {
+                            let activity = session.activity.clone();
+                            drop(silk_sessions);
+                            let sessions_for_cwd = ctx.silk_sessions.clone();
+                            let cmd_for_cwd = command.clone();
+                            let command_id_for_spawn = command_id.clone();
+
+                            let mut sink = SilkOutputSink::new(
+                                ctx.writer.clone(),
+                                &ctx.webrtc,
+                                webrtc_session_id.as_deref(),
+                            )
+                            .await;
+
+                            sink.send(SilkResponse::CommandStarted {
+                                session_id,
+                                command_id,
+                                interactive: false,
+                            })
+                            .await;
+
+                            if let Some(stdin) = child.stdin.take() {
+                                let mut silk_lock = ctx.silk_sessions.lock().await;
+                                if let Some(session) = silk_lock.get_mut(&session_id) {
+                                    if let Some(cmd) = session.running_commands.get_mut(&command_id_for_spawn) {
+                                        cmd.stdin = Some(stdin);
+                                    }
+                                }
+                            }
+
+                            tokio::spawn(async move {
+                                let command_id = command_id_for_spawn;
+                                let mut stdout_reader = std::io::BufReader::new(
+                                    child.stdout.take().expect("child stdout is piped"),
+                                );
+                                let mut stderr_reader = std::io::BufReader::new(
+                                    child.stderr.take().expect("child stderr is piped"),
+                                );
+
+                                let mut buf = [0u8; 4096];
+                                loop {
+                                    match stdout_reader.get_mut().read(&mut buf) {
+                                        Ok(0) => break,
+                                        Ok(n) => {
+                                            activity.touch();
+                                            let data =
+                                                String::from_utf8_lossy(&buf[..n])
+                                                    .to_string();
+                                            let html = AnsiToHtml::convert(&data);
+                                            sink.send(SilkResponse::Output {
+                                                session_id,
+                                                command_id: command_id.clone(),
+                                                stream: SilkStream::Stdout,
+                                                data,
+                                                html: Some(html),
+                                            })
+                                            .await;
+                                        }
+                                        Err(_) => break,
+                                    }
+                                }
+
+                                let mut stderr_buf = Vec::new();
+                                let _ = stderr_reader.read_to_end(&mut stderr_buf);
+                                let stderr = String::from_utf8_lossy(&stderr_buf)
+                                    .to_string();
+                                if !stderr.is_empty() {
+                                    let html = AnsiToHtml::convert(&stderr);
+                                    sink.send(SilkResponse::Output {
+                                        session_id,
+                                        command_id: command_id.clone(),
+                                        stream: SilkStream::Stderr,
+                                        data: stderr.clone(),
+                                        html: Some(html),
+                                    })
+                                    .await;
+                                }
+
+                                let exit_code = child
+                                    .wait()
+                                    .map(|s| s.code().unwrap_or(-1))
+                                    .unwrap_or(-1);
+
+                                if let Some(binary) =
+                                    detect_missing_command(&cmd_for_cwd, exit_code, &stderr)
+                                {
+                                    sink.send(SilkResponse::Error {
+                                        session_id: Some(session_id),
+                                        command_id: Some(command_id.clone()),
+                                        code: "command_not_found".to_string(),
+                                        message: missing_command_hint(&binary),
+                                    })
+                                    .await;
+                                }
+
+                                let (completed, snapshot) = {
+                                    let mut sessions =
+                                        sessions_for_cwd.lock().await;
+                                    let completed = sessions.get_mut(&session_id).map(|s| {
+                                        let cwd_before = s.cwd.clone();
+                                        s.update_cwd_if_cd(&cmd_for_cwd);
+                                        s.complete_command(command_id.clone());
+                                        let completed = SilkResponse::CommandCompleted {
+                                            session_id,
+                                            command_id,
+                                            exit_code,
+                                            cwd: s.cwd.clone(),
+                                        };
+                                        (completed, s.cwd != cwd_before)
+                                    });
+                                    let snapshot = match completed {
+                                        Some((_, true)) => crate::silk_store::snapshot(&sessions),
+                                        _ => None,
+                                    };
+                                    (completed.map(|(completed, _)| completed), snapshot)
+                                };
+                                crate::silk_store::persist(snapshot).await;
+                                if let Some(completed) = completed {
+                                    sink.send(completed).await;
+                                }
+                            }.in_current_span());
+
+                            None // Response sent asynchronously
+                        } else {
+                            Some(CommandResponse::SilkResponse(
+                                SilkResponse::Error {
+                                    session_id: Some(session_id),
+                                    command_id: Some(command_id),
+                                    code: "execute_failed".to_string(),
+                                    message: "No child process created".to_string(),
+                                },
+                            ))
+                        }
+                    }
+                    Err(e) => {
+                        Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                            session_id: Some(session_id),
+                            command_id: Some(command_id),
+                            code: "execute_failed".to_string(),
+                            message: e,
+                        }))
+                    }
+                }
+            } else {
+                Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                    session_id: Some(session_id),
+                    command_id: Some(command_id),
+                    code: "session_not_found".to_string(),
+                    message: format!("Silk session {} not found", session_id),
+                }))
+            }
+        }
+
+        CommandRequest::SilkInput {
+            session_id,
+            command_id,
+            data,
+        } => {
+            let mut silk_sessions = ctx.silk_sessions.lock().await;
+            if let Some(session) = silk_sessions.get_mut(&session_id) {
+                session.activity.touch();
+                if let Some(cmd) = session.running_commands.get_mut(&command_id) {
+                    if let Some(pty_session_id) = cmd.pty_session_id {
+                        drop(silk_sessions);
+                        let mut pty_sessions = ctx.pty_sessions.lock().await;
+                        if let Some(pty) = pty_sessions.get_mut(&pty_session_id) {
+                            pty.activity.touch();
+                            if let Err(e) = std::io::Write::write_all(
+                                &mut pty.writer,
+                                data.as_bytes(),
+                            ) {
+                                Some(CommandResponse::SilkResponse(
+                                    SilkResponse::Error {
+                                        session_id: Some(session_id),
+                                        command_id: Some(command_id),
+                                        code: "input_failed".to_string(),
+                                        message: e.to_string(),
+                                    },
+                                ))
+                            } else {
+                                let _ = std::io::Write::flush(&mut pty.writer);
+                                None
+                            }
+                        } else {
+                            Some(CommandResponse::SilkResponse(
+                                SilkResponse::Error {
+                                    session_id: Some(session_id),
+                                    command_id: Some(command_id),
+                                    code: "pty_not_found".to_string(),
+                                    message: "PTY session not found".to_string(),
+                                },
+                            ))
+                        }
+                    } else if let Some(ref mut stdin) = cmd.stdin {
+                        use std::io::Write;
+                        if let Err(e) = writeln!(stdin, "{}", data) {
+                            Some(CommandResponse::SilkResponse(
+                                SilkResponse::Error {
+                                    session_id: Some(session_id),
+                                    command_id: Some(command_id),
+                                    code: "input_failed".to_string(),
+                                    message: e.to_string(),
+                                },
+                            ))
+                        } else {
+                            let _ = stdin.flush();
+                            None
+                        }
+                    } else {
+                        Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                            session_id: Some(session_id),
+                            command_id: Some(command_id),
+                            code: "stdin_closed".to_string(),
+                            message: "Command stdin is not available"
+                                .to_string(),
+                        }))
+                    }
+                } else {
+                    Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                        session_id: Some(session_id),
+                        command_id: Some(command_id),
+                        code: "command_not_found".to_string(),
+                        message: "Command not found in session".to_string(),
+                    }))
+                }
+            } else {
+                Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                    session_id: Some(session_id),
+                    command_id: Some(command_id),
+                    code: "session_not_found".to_string(),
+                    message: format!("Silk session {} not found", session_id),
+                }))
+            }
+        }
+
+        CommandRequest::SilkResize {
+            session_id,
+            command_id,
+            cols,
+            rows,
+        } => {
+            let silk_sessions = ctx.silk_sessions.lock().await;
+            if let Some(session) = silk_sessions.get(&session_id) {
+                if let Some(cmd) = session.running_commands.get(&command_id) {
+                    if let Some(pty_session_id) = cmd.pty_session_id {
+                        drop(silk_sessions);
+                        let pty_sessions = ctx.pty_sessions.lock().await;
+                        if let Some(pty) = pty_sessions.get(&pty_session_id) {
+                            if let Err(e) = pty.pair.master.resize(PtySize {
+                                rows,
+                                cols,
+                                pixel_width: 0,
+                                pixel_height: 0,
+                            }) {
+                                Some(CommandResponse::SilkResponse(
+                                    SilkResponse::Error {
+                                        session_id: Some(session_id),
+                                        command_id: Some(command_id),
+                                        code: "resize_failed".to_string(),
+                                        message: e.to_string(),
+                                    },
+                                ))
+                            } else {
+                                None
+                            }
+                        } else {
+                            None // PTY may have closed already
+                        }
+                    } else {
+                        None // Not interactive, no resize needed
+                    }
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        }
+
+        CommandRequest::ReapZombies => {
+            let tracked = tracked_child_pids(&ctx.pty_sessions, &ctx.silk_sessions, &ctx.webrtc);
+            let reaped = crate::reaper::reap_zombies(tracked).await;
+            tracing::info!("🧟 Reaped {} zombie process(es) on request", reaped);
+            Some(CommandResponse::ZombiesReaped { reaped })
+        }
+
+        CommandRequest::HealthCheck => {
+            let storage_error = match &ctx.data_dir {
+                DataDirStatus::Writable => None,
+                DataDirStatus::Unavailable(reason) => Some(format!(
+                    "{}; mount a writable volume at {} for a persistent device ID",
+                    reason, DATA_DIR
+                )),
+            };
+            Some(CommandResponse::HealthStatus {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                persistent: storage_error.is_none(),
+                storage_error,
+            })
+        }
+
+        CommandRequest::GetSecurityPolicy { full } => Some(CommandResponse::SecurityPolicy(
+            crate::policy::SecurityPolicy::current(full).with_services(ctx.service_definitions(), full),
+        )),
+
+        CommandRequest::WebRtcList => Some(CommandResponse::WebRtcSessions {
+            sessions: ctx.webrtc.describe_sessions().await,
+        }),
+
+        CommandRequest::WebRtcClose { session_id } => {
+            match ctx.webrtc.terminate_session(&session_id, "closed_by_operator").await {
+                Some(terminal_sessions) => Some(CommandResponse::WebRtcSessionClosed {
+                    session_id,
+                    terminal_sessions,
+                }),
+                None => Some(CommandResponse::Error {
+                    code: "session_not_found".to_string(),
+                    message: format!("WebRTC session {} not found", session_id),
+                }),
+            }
+        }
+
+        CommandRequest::WebRtcStats { session_id } => Some(match ctx.webrtc.get_session_stats(&session_id).await {
+            Some(stats) => CommandResponse::WebRtcStats(stats),
+            None => CommandResponse::Error {
+                code: "session_not_found".to_string(),
+                message: format!("WebRTC session {} not found", session_id),
+            },
+        }),
+
+        CommandRequest::BuildInfo => Some(CommandResponse::BuildInfo(crate::build_info::BuildInfo::current())),
+
+        CommandRequest::GetMetrics => Some(CommandResponse::Metrics(metrics_snapshot(ctx).await)),
+
+        CommandRequest::ProxyStats { reset } => Some(CommandResponse::ProxyStats {
+            services: ctx.proxy_stats.snapshot(reset),
+        }),
+
+        CommandRequest::UpdateServices { services } => Some(match validate_services(&services) {
+            Ok(()) => {
+                let registry = services
+                    .iter()
+                    .map(|(name, port)| (name.clone(), ServiceTarget::local(*port)))
+                    .collect();
+                *ctx.services.write().unwrap_or_else(|e| e.into_inner()) = registry;
+                ctx.proxy_stats.set_services(&services);
+                tracing::info!("📦 Service registry replaced: {} service(s)", services.len());
+                CommandResponse::ServicesUpdated { services }
+            }
+            Err(message) => {
+                tracing::warn!("⚠️ Rejected service registry: {}", message);
+                CommandResponse::Error {
+                    code: "invalid_services".to_string(),
+                    message,
+                }
+            }
+        }),
+
+        CommandRequest::Broadcast { message, level } => Some(ctx.notice(message, level).await),
+
+        CommandRequest::SubscribeEvents { topics } => Some(match ctx.events.subscribe(&topics) {
+            Ok(topics) => {
+                tracing::info!("📣 Subscribed to events: {}", topics.join(", "));
+                CommandResponse::EventSubscriptions { topics }
+            }
+            Err(message) => CommandResponse::Error {
+                code: "unknown_topic".to_string(),
+                message,
+            },
+        }),
+
+        CommandRequest::UnsubscribeEvents { topics } => Some(match ctx.events.unsubscribe(&topics) {
+            Ok(topics) => CommandResponse::EventSubscriptions { topics },
+            Err(message) => CommandResponse::Error {
+                code: "unknown_topic".to_string(),
+                message,
+            },
+        }),
+
+        CommandRequest::Subscribe {
+            service_id,
+            events,
+            peer_id,
+        } => Some(subscribe_service_events(service_id, events, peer_id, ctx).await),
+
+        CommandRequest::Unsubscribe { subscription_id } => {
+            let found = ctx.end_service_subscription(subscription_id).await;
+            Some(CommandResponse::Unsubscribed { subscription_id, found })
+        }
+
+        CommandRequest::ListSessions => Some(CommandResponse::Sessions {
+            sessions: list_sessions(ctx).await,
+        }),
+
+        CommandRequest::KillSession { session_id } => Some(kill_session(&session_id, ctx).await),
+
+        CommandRequest::SilkListSessions => {
+            let mut sessions: Vec<SilkSessionInfo> = ctx
+                .silk_sessions
+                .lock()
+                .await
+                .values()
+                .map(|s| SilkSessionInfo {
+                    session_id: s.id,
+                    cwd: s.cwd.clone(),
+                    shell: s.shell.clone(),
+                    restored: s.restored,
+                })
+                .collect();
+            sessions.sort_by_key(|s| s.session_id);
+            Some(CommandResponse::SilkResponse(SilkResponse::SessionList { sessions }))
+        }
+
+        CommandRequest::SilkCloseSession { session_id } => {
+            tracing::info!("🧵 Closing Silk session {}", session_id);
+            let removed = {
+                let mut silk_sessions = ctx.silk_sessions.lock().await;
+                silk_sessions.remove(&session_id).map(|_| crate::silk_store::snapshot(&silk_sessions))
+            };
+            if let Some(snapshot) = removed {
+                crate::silk_store::persist(snapshot).await;
+                Some(CommandResponse::SilkResponse(SilkResponse::SessionClosed {
+                    session_id,
+                    reason: None,
+                }))
+            } else {
+                Some(CommandResponse::SilkResponse(SilkResponse::Error {
+                    session_id: Some(session_id),
+                    command_id: None,
+                    code: "session_not_found".to_string(),
+                    message: format!("Silk session {} not found", session_id),
+                }))
+            }
+        }
+    }
+}
+
+/// Close PTY, Silk and WebRTC sessions older than `max_lifetime`, reporting each
+/// with the `max_lifetime` exit reason
+async fn expire_sessions(ctx: &CommandContext, max_lifetime: std::time::Duration) {
+    let reason = crate::reaper::MAX_LIFETIME_REASON;
+
+    let (expired_silk, snapshot) = {
+        let mut silk_sessions = ctx.silk_sessions.lock().await;
+        let ids: Vec<Uuid> = silk_sessions
+            .iter()
+            .filter(|(_, s)| s.created_at.elapsed() >= max_lifetime)
+            .map(|(id, _)| *id)
+            .collect();
+        let expired: Vec<SilkSession> = ids.iter().filter_map(|id| silk_sessions.remove(id)).collect();
+        let snapshot = if expired.is_empty() { None } else { crate::silk_store::snapshot(&silk_sessions) };
+        (expired, snapshot)
+    };
+    crate::silk_store::persist(snapshot).await;
+
+    // PTYs of an expired Silk session go with it, however young they are
+    let mut pty_ids: HashSet<Uuid> = expired_silk
+        .iter()
+        .flat_map(|s| s.running_commands.values())
+        .filter_map(|c| c.pty_session_id)
+        .collect();
+
+    let expired_ptys: Vec<PtySession> = {
+        let mut pty_sessions = ctx.pty_sessions.lock().await;
+        pty_ids.extend(
+            pty_sessions
+                .iter()
+                .filter(|(_, s)| s.created_at.elapsed() >= max_lifetime)
+                .map(|(id, _)| *id),
+        );
+        pty_ids.iter().filter_map(|id| pty_sessions.remove(id)).collect()
+    };
+
+    for pty in expired_ptys {
+        tracing::info!("⏰ PTY session {} reached its maximum lifetime", pty.id);
+        ctx.respond(&kill_pty(pty, Some(reason))).await;
+    }
+
+    for silk in expired_silk {
+        tracing::info!("⏰ Silk session {} reached its maximum lifetime", silk.id);
+        ctx.respond(&kill_silk(silk, reason)).await;
+    }
+
+    ctx.webrtc.expire_sessions(max_lifetime, reason).await;
+}
+
+/// Close PTY and Silk sessions with no input or output for `idle_timeout`,
+/// reporting each with the `idle` exit reason. A Silk session stays open while
+/// any of its interactive PTYs is active, and its PTYs close with it.
+async fn close_idle_sessions(ctx: &CommandContext, idle_timeout: std::time::Duration) {
+    let reason = crate::reaper::IDLE_REASON;
+    let is_idle = |activity: &crate::reaper::Activity| activity.idle_for() >= idle_timeout;
+
+    // Lock order as in `expire_sessions`: Silk, then PTY
+    let mut silk_sessions = ctx.silk_sessions.lock().await;
+    let mut pty_sessions = ctx.pty_sessions.lock().await;
+
+    let pty_idle = |id: &Uuid| pty_sessions.get(id).is_none_or(|pty| is_idle(&pty.activity));
+    // A quiet command that is still running counts as activity, e.g. a long build
+    let silk_ids: Vec<Uuid> = silk_sessions
+        .values()
+        .filter(|s| {
+            is_idle(&s.activity)
+                && s.command_pids().next().is_none()
+                && s.running_commands.values().filter_map(|c| c.pty_session_id.as_ref()).all(pty_idle)
+        })
+        .map(|s| s.id)
+        .collect();
+    let idle_silk: Vec<SilkSession> = silk_ids.iter().filter_map(|id| silk_sessions.remove(id)).collect();
+    let snapshot = if idle_silk.is_empty() { None } else { crate::silk_store::snapshot(&silk_sessions) };
+
+    // PTYs of a Silk session that is still open belong to it
+    let owned: HashSet<Uuid> = silk_sessions
+        .values()
+        .flat_map(|s| s.running_commands.values())
+        .filter_map(|c| c.pty_session_id)
+        .collect();
+    let mut pty_ids: HashSet<Uuid> = idle_silk
+        .iter()
+        .flat_map(|s| s.running_commands.values())
+        .filter_map(|c| c.pty_session_id)
+        .collect();
+    pty_ids.extend(
+        pty_sessions
+            .iter()
+            .filter(|(id, s)| !owned.contains(id) && is_idle(&s.activity))
+            .map(|(id, _)| *id),
+    );
+    let idle_ptys: Vec<PtySession> = pty_ids.iter().filter_map(|id| pty_sessions.remove(id)).collect();
+    drop(pty_sessions);
+    drop(silk_sessions);
+    crate::silk_store::persist(snapshot).await;
+
+    for pty in idle_ptys {
+        tracing::info!("💤 PTY session {} was idle for {}s", pty.id, idle_timeout.as_secs());
+        ctx.respond(&kill_pty(pty, Some(reason))).await;
+    }
+
+    for silk in idle_silk {
+        tracing::info!("💤 Silk session {} was idle for {}s", silk.id, idle_timeout.as_secs());
+        ctx.respond(&kill_silk(silk, reason)).await;
+    }
+}
+
+/// Kill a PTY already taken out of the session map; its `pty_exited` report,
+/// with no reason for a client's own `pty_close`
+fn kill_pty(mut pty: PtySession, reason: Option<&str>) -> CommandResponse {
+    let _ = pty.child.kill();
+    let exit_code = pty.child.wait().map(|s| s.exit_code() as i32).unwrap_or(-1);
+    CommandResponse::PtyExited {
+        session_id: pty.id,
+        exit_code,
+        reason: reason.map(str::to_string),
+    }
+}
+
+/// Kill the commands of a Silk session already taken out of the session map;
+/// its `silk_session_closed` report. Its interactive PTYs are the caller's.
+fn kill_silk(silk: SilkSession, reason: &str) -> CommandResponse {
+    for command in silk.running_commands.values() {
+        command.signal(libc::SIGKILL);
+    }
+    CommandResponse::SilkResponse(SilkResponse::SessionClosed {
+        session_id: silk.id,
+        reason: Some(reason.to_string()),
+    })
+}
+
+/// Exit reason of sessions ended by `kill_session`
+const KILLED_REASON: &str = "killed";
+
+async fn list_sessions(ctx: &CommandContext) -> Vec<ActiveSession> {
+    let mut sessions: Vec<ActiveSession> = ctx
+        .pty_sessions
+        .lock()
+        .await
+        .values()
+        .map(|s| ActiveSession {
+            session_id: s.id.to_string(),
+            kind: "pty",
+            commands: vec![crate::log_redact::redact(&s.command)],
+            age_secs: s.created_at.elapsed().as_secs(),
+        })
+        .collect();
+    sessions.extend(ctx.silk_sessions.lock().await.values().map(|s| {
+        let mut commands: Vec<String> =
+            s.running_commands.values().map(|c| crate::log_redact::redact(&c.command)).collect();
+        commands.sort();
+        ActiveSession {
+            session_id: s.id.to_string(),
+            kind: "silk",
+            commands,
+            age_secs: s.created_at.elapsed().as_secs(),
+        }
+    }));
+    sessions.extend(ctx.webrtc.describe_sessions().await.into_iter().map(|s| ActiveSession {
+        session_id: s.session_id,
+        kind: "webrtc",
+        commands: Vec::new(),
+        age_secs: s.age_secs,
+    }));
+    sessions.sort_by(|a, b| (a.kind, &a.session_id).cmp(&(b.kind, &b.session_id)));
+    sessions
+}
+
+/// End the PTY, Silk or WebRTC session `session_id`, answered with the report
+/// its kind normally ends with
+async fn kill_session(session_id: &str, ctx: &CommandContext) -> CommandResponse {
+    if let Ok(id) = session_id.parse::<Uuid>() {
+        if let Some(response) = end_session(id, KILLED_REASON, ctx).await {
+            return response;
+        }
+    }
+
+    match ctx.webrtc.terminate_session(session_id, KILLED_REASON).await {
+        Some(terminal_sessions) => CommandResponse::WebRtcSessionClosed {
+            session_id: session_id.to_string(),
+            terminal_sessions,
+        },
+        None => CommandResponse::Error {
+            code: "session_not_found".to_string(),
+            message: format!("No PTY, Silk or WebRTC session {}", session_id),
+        },
+    }
+}
+
+/// End PTY or Silk session `id`, a Silk session's interactive PTYs included.
+/// Returns its exit report, or `None` if there is no such session.
+async fn end_session(id: Uuid, reason: &str, ctx: &CommandContext) -> Option<CommandResponse> {
+    let pty = ctx.pty_sessions.lock().await.remove(&id);
+    if let Some(pty) = pty {
+        tracing::info!("🔪 Ending PTY session {} ({})", id, reason);
+        return Some(kill_pty(pty, Some(reason)));
+    }
+
+    let (silk, snapshot) = {
+        let mut silk_sessions = ctx.silk_sessions.lock().await;
+        let removed = silk_sessions.remove(&id)?;
+        (removed, crate::silk_store::snapshot(&silk_sessions))
+    };
+    crate::silk_store::persist(snapshot).await;
+    tracing::info!("🔪 Ending Silk session {} ({})", id, reason);
+    let ptys: Vec<PtySession> = {
+        let mut pty_sessions = ctx.pty_sessions.lock().await;
+        silk.running_commands
+            .values()
+            .filter_map(|c| c.pty_session_id)
+            .filter_map(|pty_id| pty_sessions.remove(&pty_id))
+            .collect()
+    };
+    for pty in ptys {
+        ctx.respond(&kill_pty(pty, Some(reason))).await;
+    }
+    Some(kill_silk(silk, reason))
+}
+
+/// Exit reason of sessions whose WebRTC session closed without naming one
+const TERMINAL_CLOSED_REASON: &str = "webrtc_session_closed";
+
+/// End the PTY and Silk sessions opened over WebRTC session `webrtc_session_id`'s
+/// "terminal" channel, reporting each over signaling since the channel is gone
+async fn end_terminal_sessions(webrtc_session_id: &str, reason: &str, ctx: &CommandContext) {
+    let ids = ctx
+        .terminal_sessions
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(webrtc_session_id)
+        .unwrap_or_default();
+    let ctx = CommandContext {
+        route: ResponseRoute::Signaling,
+        ..ctx.clone()
+    };
+    for id in ids {
+        if let Some(response) = end_session(id, reason, &ctx).await {
+            ctx.respond(&response).await;
+        }
+    }
+}
+
+/// Exit reason of sessions closed because the cocoon is stopping
+const SHUTDOWN_REASON: &str = "shutdown";
+
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_millis(2000);
+
+/// How long session children get to exit after SIGHUP, from `COCOON_SHUTDOWN_GRACE_MS`
+fn shutdown_grace() -> Duration {
+    env_opt(EnvVar::CocoonShutdownGraceMs.as_str())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_SHUTDOWN_GRACE)
+}
+
+#[cfg(unix)]
+fn hang_up(pid: u32) {
+    // SAFETY: plain kill(2) on a child this cocoon spawned
+    unsafe {
+        libc::kill(pid as i32, libc::SIGHUP);
+    }
+}
+
+#[cfg(not(unix))]
+fn hang_up(_pid: u32) {}
+
+/// Silk sessions persisted by the previous run, when `COCOON_PERSIST_SILK_SESSIONS` is set
+fn restore_silk_sessions() -> HashMap<Uuid, SilkSession> {
+    let Some(path) = crate::silk_store::path() else {
+        return HashMap::new();
+    };
+    let mut sessions = HashMap::new();
+    for record in crate::silk_store::load(&path) {
+        let id = record.id;
+        match SilkSession::restore(record) {
+            Ok(session) => {
+                sessions.insert(id, session);
+            }
+            Err(e) => tracing::warn!("⚠️ Not restoring Silk session {}: {}", id, e),
+        }
+    }
+    if !sessions.is_empty() {
+        tracing::info!("🧵 Restored {} Silk session(s) from {}", sessions.len(), path.display());
+    }
+    sessions
+}
+
+/// Tell clients which sessions survived the restart so they can re-attach by id
+async fn announce_restored_silk_sessions(ctx: &CommandContext) {
+    let restored: Vec<SilkResponse> = ctx
+        .silk_sessions
+        .lock()
+        .await
+        .values()
+        .filter(|s| s.restored)
+        .map(|s| SilkResponse::SessionRestored {
+            session_id: s.id,
+            cwd: s.cwd.clone(),
+            shell: s.shell.clone(),
+        })
+        .collect();
+    for response in restored {
+        ctx.respond(&CommandResponse::SilkResponse(response)).await;
+    }
+}
+
+/// Close every PTY, Silk and WebRTC session before the cocoon exits: send SIGHUP
+/// to each child, SIGKILL whatever is still running after `grace`, and report
+/// the sessions with the `shutdown` reason when `notify` is set
+async fn drain_sessions(ctx: &CommandContext, grace: Duration, notify: bool) {
+    let mut ptys: Vec<PtySession> = ctx.pty_sessions.lock().await.drain().map(|(_, s)| s).collect();
+    // Not persisted: the saved Silk sessions are what the next run restores
+    let silks: Vec<SilkSession> = ctx.silk_sessions.lock().await.drain().map(|(_, s)| s).collect();
+
+    if !ptys.is_empty() || !silks.is_empty() {
+        tracing::info!("🛑 Closing {} PTY and {} Silk session(s)", ptys.len(), silks.len());
+    }
+
+    for pid in ptys.iter().filter_map(|p| p.child.process_id()) {
+        hang_up(pid);
+    }
+    // Silk commands' children belong to the tasks reading their output, so
+    // they are signaled by process group and counted as running while it exists
+    for command in silks.iter().flat_map(|s| s.running_commands.values()) {
+        command.signal(libc::SIGHUP);
+    }
+
+    let deadline = Instant::now() + grace;
+    let mut exit_codes: Vec<Option<i32>> = vec![None; ptys.len()];
+    loop {
+        let mut running = false;
+        for (pty, exit_code) in ptys.iter_mut().zip(exit_codes.iter_mut()) {
+            if exit_code.is_none() {
+                match pty.child.try_wait() {
+                    Ok(Some(status)) => *exit_code = Some(status.exit_code() as i32),
+                    Ok(None) => running = true,
+                    Err(_) => *exit_code = Some(-1),
+                }
+            }
+        }
+        for command in silks.iter().flat_map(|s| s.running_commands.values()) {
+            running |= command.signal(0);
+        }
+        if !running || Instant::now() >= deadline {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    for (pty, exit_code) in ptys.iter_mut().zip(exit_codes.iter_mut()) {
+        if exit_code.is_none() {
+            tracing::warn!("🔪 PTY session {} ignored SIGHUP, killing it", pty.id);
+            let _ = pty.child.kill();
+            *exit_code = Some(pty.child.wait().map(|s| s.exit_code() as i32).unwrap_or(-1));
+        }
+    }
+    for command in silks.iter().flat_map(|s| s.running_commands.values()) {
+        if command.signal(0) {
+            tracing::warn!("🔪 Silk command {} ignored SIGHUP, killing it", command.id);
+            command.signal(libc::SIGKILL);
+        }
+    }
+
+    if notify {
+        for (pty, exit_code) in ptys.iter().zip(exit_codes) {
+            ctx.respond(&CommandResponse::PtyExited {
+                session_id: pty.id,
+                exit_code: exit_code.unwrap_or(-1),
+                reason: Some(SHUTDOWN_REASON.to_string()),
+            })
+            .await;
+        }
+        for silk in &silks {
+            ctx.respond(&CommandResponse::SilkResponse(SilkResponse::SessionClosed {
+                session_id: silk.id,
+                reason: Some(SHUTDOWN_REASON.to_string()),
+            }))
+            .await;
+        }
+    }
+
+    ctx.webrtc.close_all_sessions(SHUTDOWN_REASON).await;
+}
+
+/// A one-shot job: once registered, the cocoon runs `script`, reports the
+/// result, deregisters and exits with the script's exit code.
+pub struct Job {
+    pub script: String,
+    /// Include the files collected from the output directory in the report
+    pub upload_outputs: bool,
+}
+
+impl Job {
+    /// Job configured by `COCOON_JOB_SCRIPT` / `COCOON_JOB_UPLOAD`, if any
+    pub fn from_env() -> Option<Self> {
+        let script = env_opt(EnvVar::CocoonJobScript.as_str()).filter(|s| !s.trim().is_empty())?;
+        let upload_outputs = env_opt(EnvVar::CocoonJobUpload.as_str())
+            .map(|v| matches!(v.to_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        Some(Self { script, upload_outputs })
+    }
+}
+
+/// Run the job, report it over signaling, deregister, and hand the exit code to the main loop
+async fn run_job_script(
+    job: Job,
+    writer: SharedWriter,
+    device_id: Arc<Mutex<Option<String>>>,
+    done: tokio::sync::mpsc::Sender<i32>,
+) {
+    tracing::info!("🏃 Running job: {}", crate::log_redact::redact(&job.script));
+    let CommandResponse::ExecuteResult { success, data, error, files, omitted_files, .. } =
+        execute_command(&job.script, None, &HashMap::new(), None, None).await
+    else {
+        unreachable!("execute_command always returns ExecuteResult");
+    };
+
+    let exit_code = data
+        .as_ref()
+        .and_then(|d| d["exit_code"].as_i64())
+        .map(|code| code as i32)
+        .unwrap_or(if success { 0 } else { 1 });
+    if success {
+        tracing::info!("✅ Job finished ({} output file(s))", files.len());
+    } else {
+        let details = error.as_ref().and_then(|e| e.details.as_deref()).unwrap_or("");
+        tracing::error!("❌ Job failed with exit code {} {}", exit_code, details);
+    }
+
+    let report = CommandResponse::JobCompleted {
+        exit_code,
+        data,
+        error,
+        files: if job.upload_outputs { files } else { vec![] },
+        omitted_files: omitted_files.filter(|_| job.upload_outputs),
+    };
+    send_response(&writer, &report).await;
+
+    if let Some(device_id) = device_id.lock().await.as_ref() {
+        send_deregister(&writer, device_id, Some("job_completed")).await;
+    }
+    let _ = done.send(exit_code).await;
+}
+
+/// Secret, known device ID and setup token a cocoon registers with
+pub(crate) struct Identity {
+    pub secret: String,
+    pub device_id: Option<String>,
+    pub setup_token: Option<String>,
+}
+
+/// Overrides for [`run_with`]; anything left unset comes from the environment
+#[derive(Default)]
+pub(crate) struct Launch {
+    pub job: Option<Job>,
+    pub connector: Option<Arc<dyn SignalingConnector>>,
+    /// Registered as-is and never written to the data directory
+    pub identity: Option<Identity>,
+}
+
+pub async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    guard_nested_run()?;
+    run_with(Launch::default()).await.map(|_| ())
+}
+
+/// Register, run `job`, deregister, and return the job's exit code
+pub async fn run_job(job: Job) -> Result<i32, Box<dyn std::error::Error>> {
+    guard_nested_run()?;
+    let launch = Launch {
+        job: Some(job),
+        ..Launch::default()
+    };
+    run_with(launch)
+        .await?
+        .ok_or_else(|| "Connection ended before the job finished".into())
+}
+
+fn init_tracing() {
+    crate::log_format::init(
+        tracing_subscriber::EnvFilter::from_default_env()
+            .add_directive("cocoon=info".parse().expect("valid tracing directive")),
+        true,
+    );
+}
+
+/// Set on every command the cocoon runs (see `child_env`), to spot a cocoon
+/// started from inside another one
+pub(crate) const ACTIVE_MARKER: &str = "COCOON_ACTIVE";
+
+/// Warn when this process already runs inside a cocoon, or refuse to start with
+/// `COCOON_STRICT_NESTED`
+fn guard_nested_run() -> Result<(), String> {
+    init_tracing();
+    if env_opt(ACTIVE_MARKER).is_none() {
+        return Ok(());
+    }
+
+    let strict = env_opt(EnvVar::CocoonStrictNested.as_str())
+        .is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes"));
+    if strict {
+        return Err(format!(
+            "{} is set: refusing to start a cocoon inside another cocoon (COCOON_STRICT_NESTED)",
+            ACTIVE_MARKER
+        ));
+    }
+    tracing::warn!("⚠️ Starting a cocoon inside another cocoon ({} is set)", ACTIVE_MARKER);
+    tracing::warn!("⚠️ Unset {} if this is intended, or set COCOON_STRICT_NESTED=1 to refuse", ACTIVE_MARKER);
+    Ok(())
+}
+
+pub(crate) async fn run_with(launch: Launch) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+    init_tracing();
+
+    // Every line logged on behalf of this cocoon carries its device id once
+    // registration has assigned one
+    let span = tracing::info_span!("cocoon", device_id = tracing::field::Empty);
+    run_instrumented(launch).instrument(span).await
+}
+
+async fn run_instrumented(launch: Launch) -> Result<Option<i32>, Box<dyn std::error::Error>> {
+    tracing::info!("🐛 Cocoon starting (v{})", env!("CARGO_PKG_VERSION"));
+    crate::metrics::mark_started();
+
+    let data_dir_status = probe_data_dir(Path::new(DATA_DIR)).await;
+    if let DataDirStatus::Unavailable(reason) = &data_dir_status {
+        tracing::error!("❌ Data directory unusable: {}", reason);
+        tracing::error!("❌ Secret and device ID will NOT persist; this cocoon is ephemeral and will re-register as a new device on restart");
+        tracing::error!("💡 Mount a writable volume at {} (e.g. docker run -v cocoon-data:{} ...)", DATA_DIR, DATA_DIR);
+        if require_persistence() {
+            return Err(format!(
+                "COCOON_REQUIRE_PERSISTENCE is set but {} is unusable: {}",
+                DATA_DIR, reason
+            )
+            .into());
+        }
+    }
+
+    let persist_identity = launch.identity.is_none();
+    let Identity {
+        secret,
+        device_id,
+        setup_token,
+    } = match launch.identity {
+        Some(identity) => identity,
+        None => {
+            let (secret, device_id) = get_or_create_secret().await?;
+            Identity {
+                secret,
+                device_id,
+                setup_token: env_opt(EnvVar::CocoonSetupToken.as_str()),
+            }
+        }
+    };
+    crate::log_redact::set_secret(&secret);
+
+    match crate::host_aliases::from_env() {
+        Ok(aliases) if !aliases.is_empty() => tracing::info!("🗺️ Host aliases: {}", aliases.env_value()),
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!("❌ {}", e);
+            return Err(e.into());
+        }
+    }
+
+    let output_policy = match crate::output_policy::from_env() {
+        Ok(policy) => policy,
+        Err(e) => {
+            tracing::error!("❌ {}", e);
+            return Err(e.into());
+        }
+    };
+
+    if let Err(e) = crate::rate_limit::init_from_env() {
+        tracing::error!("❌ {}", e);
+        return Err(e.into());
+    }
+    if let Err(e) = crate::command_filter::init_from_env() {
+        tracing::error!("❌ {}", e);
+        return Err(e.into());
+    }
+    let health_port = match crate::health::port_from_env() {
+        Ok(port) => port,
+        Err(e) => {
+            tracing::error!("❌ {}", e);
+            return Err(e.into());
+        }
+    };
+
+    let connector: Arc<dyn SignalingConnector> = match launch.connector {
+        Some(connector) => connector,
+        None => {
+            let base_url = env_or(EnvVar::SignalingServerUrl.as_str(), "ws://localhost:8080/ws");
+            let mut signaling_url = match crate::signaling_url::normalize_signaling_url(&base_url) {
+                Ok(url) => url,
+                Err(e) => {
+                    tracing::error!("❌ {}", e);
+                    return Err(e.into());
+                }
+            };
+            signaling_url.query_pairs_mut().append_pair("kind", "cocoon");
+            let signaling_url = signaling_url.to_string();
+
+            tracing::info!("🔗 Connecting to signaling server: {}", signaling_url);
+            let tls = match crate::tls::connector_from_env() {
+                Ok(tls) => tls,
+                Err(e) => {
+                    tracing::error!("❌ {}", e);
+                    return Err(e.into());
+                }
+            };
+            Arc::new(UrlConnector { url: signaling_url, tls })
+        }
+    };
+
+    // Retry with jittered backoff so a fleet doesn't stampede a restarting server
+    let mut backoff = Backoff::from_env(device_id.as_deref());
+    let (write, mut read) = connect_with_backoff(connector.as_ref(), &mut backoff).await;
+
+    let writer: SharedWriter = Arc::new(SignalingWriter::new(write));
+
+    let pty_sessions: Arc<Mutex<HashMap<Uuid, PtySession>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    let silk_sessions: Arc<Mutex<HashMap<Uuid, SilkSession>>> =
+        Arc::new(Mutex::new(restore_silk_sessions()));
+
+    let adi_router = {
+        let mut router = AdiRouter::new();
+
+        #[cfg(feature = "tasks-core")]
+        {
+            match tasks_core::TasksService::new_global() {
+                Ok(tasks_service) => {
+                    router.register(std::sync::Arc::new(tasks_service));
+                    tracing::info!("📦 Registered ADI plugin: adi.tasks");
+                }
+                Err(e) => {
+                    tracing::warn!("⚠️ Failed to initialize tasks plugin: {}", e);
+                }
+            }
+        }
+
+        #[cfg(feature = "kb-core")]
+        {
+            match knowledgebase_core::service::KnowledgebaseService::open_default().await {
+                Ok(kb_service) => {
+                    router.register(std::sync::Arc::new(kb_service));
+                    tracing::info!("📦 Registered ADI plugin: adi.knowledgebase");
+                }
+                Err(e) => {
+                    tracing::warn!("⚠️ Failed to initialize knowledgebase plugin: {}", e);
+                }
+            }
+        }
+
+        #[cfg(feature = "tools-core")]
+        {
+            let tools_service = tools_core::ToolsService::new();
+            let tool_count = tools_service.list_all_tools().len();
+            router.register(std::sync::Arc::new(tools_service));
+            tracing::info!("📦 Registered ADI plugin: adi.tools ({} tools)", tool_count);
+        }
+
+        router
+    };
+
+    let adi_plugins: Vec<String> = adi_router
+        .list_plugins()
+        .iter()
+        .map(|s| format!("{}:{}", s.id, s.version))
+        .collect();
+
+    let adi_router = Arc::new(Mutex::new(adi_router));
+
+    let events = Arc::new(crate::events::EventBus::new());
+    let mut events_rx = events.receiver();
+    let writer_for_events = writer.clone();
+    tokio::spawn(async move {
+        loop {
+            match events_rx.recv().await {
+                Ok(event) => {
+                    let response = CommandResponse::Event {
+                        topic: event.topic.to_string(),
+                        data: event.data,
+                    };
+                    send_response(&writer_for_events, &response).await;
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!("📣 Event forwarder lagged, dropped {} event(s)", missed);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }.in_current_span());
+
+    let (webrtc_tx, mut webrtc_rx) = tokio::sync::mpsc::unbounded_channel::<SignalingMessage>();
+
+    let (terminal_tx, mut terminal_rx) = tokio::sync::mpsc::unbounded_channel::<crate::webrtc::TerminalEvent>();
+    // Shared with the WebRTC manager, whose Silk PTYs count against the same cap
+    let pty_slots = Arc::new(PtySlots::from_env());
+    let webrtc_manager = Arc::new(
+        crate::webrtc::WebRtcManager::with_adi_router(webrtc_tx, adi_router.clone())
+            .with_terminal_requests(terminal_tx)
+            .with_pty_slots(pty_slots.clone()),
+    );
+
+    let writer_for_webrtc = writer.clone();
+    let events_for_webrtc = events.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = webrtc_rx.recv().await {
+            if let SignalingMessage::SyncData { payload } = &msg {
+                let type_str = payload.get("type").and_then(|v| v.as_str()).unwrap_or("");
+                if WEBRTC_EVENT_TYPES.contains(&type_str) {
+                    events_for_webrtc.publish("webrtc", payload.clone());
+                }
+            }
+            let text = serde_json::to_string(&msg).unwrap_or_default();
+            if let Err(e) = writer_for_webrtc.send(text, Priority::Control).await {
+                tracing::warn!("⚠️ Failed to send WebRTC signaling message: {}", e);
+            }
+        }
+    }.in_current_span());
+
+    // Serialized WebRTC message channel — processes signaling messages one at a time
+    // so create_session() always completes before handle_offer() runs for the same session.
+    let (webrtc_msg_tx, mut webrtc_msg_rx) =
+        tokio::sync::mpsc::unbounded_channel::<CocoonMessage>();
+    let webrtc_manager_for_task = webrtc_manager.clone();
+    let writer_for_webrtc_msgs = writer.clone();
+    tokio::spawn(async move {
+        while let Some(msg) = webrtc_msg_rx.recv().await {
+            handle_cocoon_webrtc(msg, webrtc_manager_for_task.clone(), writer_for_webrtc_msgs.clone()).await;
+        }
+    }.in_current_span());
+
+    // Background zombie reaper
+    let pty_sessions_for_reaper = pty_sessions.clone();
+    let silk_sessions_for_reaper = silk_sessions.clone();
+    let webrtc_manager_for_reaper = webrtc_manager.clone();
+    let events_for_reaper = events.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(crate::reaper::REAP_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let tracked = tracked_child_pids(
+                &pty_sessions_for_reaper,
+                &silk_sessions_for_reaper,
+                &webrtc_manager_for_reaper,
+            );
+            let reaped = crate::reaper::reap_zombies(tracked).await;
+            if reaped > 0 {
+                tracing::warn!("🧟 Reaped {} orphaned zombie process(es)", reaped);
+                events_for_reaper.publish("health", serde_json::json!({"zombies_reaped": reaped}));
+            }
+        }
+    }.in_current_span());
+
+    // Service registry - parse from COCOON_SERVICES env var
+    // Format: "service1:port1,service2:host:port2"
+    // Example: "flowmap-api:8092,postgres:db.internal:5432"
+    let mut services = HashMap::new();
+    if let Some(services_str) = env_opt(EnvVar::CocoonServices.as_str()) {
+        for service_def in services_str.split(',') {
+            match service_def.trim().split_once(':') {
+                Some((name, spec)) => match ServiceTarget::parse(spec) {
+                    Some(target) => {
+                        tracing::info!("📦 Registered service: {} → {}:{}", name, target.host, target.port);
+                        services.insert(name.to_string(), target);
+                    }
+                    None => tracing::warn!("⚠️ Invalid address for service {}: {}", name, spec),
+                },
+                None => tracing::warn!("⚠️ Invalid service definition: {}", service_def),
+            }
+        }
+    }
+    let ports: HashMap<String, u16> = services.iter().map(|(name, t)| (name.clone(), t.port)).collect();
+    let proxy_stats = Arc::new(crate::proxy_stats::ProxyStats::new(&ports));
+    let services = Arc::new(std::sync::RwLock::new(services));
+
+    let command_ctx = CommandContext {
+        writer: writer.clone(),
+        pty_sessions,
+        pty_slots,
+        silk_sessions,
+        services,
+        proxy_stats,
+        events,
+        webrtc: webrtc_manager,
+        data_dir: data_dir_status,
+        output_policy,
+        execs: Default::default(),
+        adi_router,
+        service_subscriptions: Default::default(),
+        terminal_sessions: Default::default(),
+        route: ResponseRoute::Signaling,
+    };
+
+    // Commands sent straight over a WebRTC "terminal" channel, answered on it
+    let ctx_for_terminal = command_ctx.clone();
+    tokio::spawn(async move {
+        while let Some(event) = terminal_rx.recv().await {
+            match event {
+                crate::webrtc::TerminalEvent::Request(request) => dispatch_terminal_request(request, &ctx_for_terminal),
+                crate::webrtc::TerminalEvent::SessionEnded { session_id, reason } => {
+                    let reason = reason.as_deref().unwrap_or(TERMINAL_CLOSED_REASON);
+                    end_terminal_sessions(&session_id, reason, &ctx_for_terminal).await;
+                }
+            }
+        }
+    }.in_current_span());
+
+    // Snapshot for `adi cocoon metrics`, which has no signaling link to ask over.
+    // Without a /cocoon volume this is a machine cocoon, whose data is in ~/.config/cocoon.
+    let snapshot_path = match &command_ctx.data_dir {
+        DataDirStatus::Writable => Ok(std::path::PathBuf::from(crate::metrics::CONTAINER_SNAPSHOT_PATH)),
+        DataDirStatus::Unavailable(_) => crate::metrics::machine_snapshot_path(),
+    };
+    match snapshot_path {
+        Ok(path) => {
+            let ctx = command_ctx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(crate::metrics::SNAPSHOT_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = crate::metrics::persist(&metrics_snapshot(&ctx).await, &path).await {
+                        tracing::debug!("Metrics snapshot not saved: {}", e);
+                    }
+                }
+            }.in_current_span());
+        }
+        Err(e) => tracing::warn!("⚠️ Metrics snapshot disabled, adi cocoon metrics will have nothing to show: {}", e),
+    }
+
+    if let Some(port) = health_port {
+        let ctx = command_ctx.clone();
+        crate::health::spawn(port, move || {
+            let ctx = ctx.clone();
+            async move { metrics_snapshot(&ctx).await }
+        });
+    }
+
+    if let crate::output_policy::OutputPolicy::Ttl(ttl) = output_policy {
+        if matches!(command_ctx.data_dir, DataDirStatus::Writable) {
+            tracing::info!("🧹 Output entries untouched for {}s are removed from {}", ttl.as_secs(), OUTPUT_DIR);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(crate::output_policy::sweep_interval(ttl));
+                loop {
+                    interval.tick().await;
+                    match expire_output_dir(Path::new(OUTPUT_DIR), ttl).await {
+                        Ok(0) => {}
+                        Ok(removed) => tracing::info!("🧹 Removed {} expired entries from {}", removed, OUTPUT_DIR),
+                        Err(e) => tracing::warn!("⚠️ Output cleanup failed: {}", e),
+                    }
+                }
+            }.in_current_span());
+        } else {
+            tracing::warn!("⚠️ {} is not writable, COCOON_OUTPUT_POLICY cleanup is disabled", DATA_DIR);
+        }
+    }
+
+    if let Some(max_lifetime) = crate::reaper::max_session_lifetime() {
+        tracing::info!("⏰ Sessions are closed after {}s regardless of activity", max_lifetime.as_secs());
+        let ctx = command_ctx.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(crate::reaper::lifetime_check_interval(max_lifetime));
+            loop {
+                interval.tick().await;
+                expire_sessions(&ctx, max_lifetime).await;
+            }
+        }.in_current_span());
+    }
+
+    if let Some(idle_timeout) = crate::reaper::session_idle_timeout() {
+        tracing::info!("💤 PTY and Silk sessions are closed after {}s without input or output", idle_timeout.as_secs());
+        let ctx = command_ctx.clone();
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(crate::reaper::lifetime_check_interval(idle_timeout));
+            loop {
+                interval.tick().await;
+                close_idle_sessions(&ctx, idle_timeout).await;
+            }
+        }.in_current_span());
+    }
+
+    let cocoon_name = env_opt(EnvVar::CocoonName.as_str());
+
+    let cocoon_version = env!("CARGO_PKG_VERSION").to_string();
+    let mut tags = std::collections::HashMap::new();
+    if let Some(ref token) = setup_token {
+        tracing::info!("🎫 Using setup token for auto-registration");
+        tags.insert("setup_token".to_string(), token.clone());
+    }
+    if let Some(ref name) = cocoon_name {
+        tags.insert("name".to_string(), name.clone());
+    }
+    let protocols: Vec<String> = env_opt(EnvVar::CocoonProtocols.as_str())
+        .map(|s| s.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect())
+        .unwrap_or_else(|| vec!["silk".to_string()]);
+
+    let device_config = Some(serde_json::json!({
+        "adi_plugins": adi_plugins,
+        "protocols": protocols,
+        "protocol_version": crate::protocol_version::PROTOCOL_VERSION,
+    }));
+
+    let register_msg = SignalingMessage::DeviceRegister {
+        secret,
+        device_id: device_id.clone(),
+        version: cocoon_version,
+        tags: if tags.is_empty() { None } else { Some(tags) },
+        device_type: Some("cocoon".to_string()),
+        device_config,
+    };
+
+    let current_device_id: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+
+    // Send DeviceRegister immediately (cocoon endpoint skips auth). Transient
+    // failures reconnect and try again; permanent rejections end the process.
+    let register_text = serde_json::to_string(&register_msg).unwrap();
+    let (assigned_id, tags) = loop {
+        tracing::info!("⏳ Registering with signaling server...");
+        let sent = writer.send(register_text.clone(), Priority::Control).await;
+        let outcome = match sent {
+            Ok(()) => await_registration(&mut read).await,
+            Err(e) => Err(RegistrationError::Disconnected(format!("failed to send register: {}", e))),
+        };
+
+        match outcome {
+            Ok(registration) => break registration,
+            Err(e) if e.is_permanent() => {
+                tracing::error!("❌ {}", e);
+                tracing::error!("🛑 Not retrying: the server will never accept this secret/device");
+                return Err(e.into());
+            }
+            Err(e) => {
+                crate::health::mark_disconnected();
+                let delay = backoff.next_delay();
+                tracing::warn!("⚠️ {} (retrying in {:.1}s)", e, delay.as_secs_f64());
+                tokio::time::sleep(delay).await;
+                let (write, new_read) = connect_with_backoff(connector.as_ref(), &mut backoff).await;
+                writer.replace(write).await;
+                read = new_read;
+            }
+        }
+    };
+
+    tracing::info!("✅ Registration confirmed");
+    tracing::Span::current().record("device_id", assigned_id.as_str());
+    tracing::info!("🆔 Device ID: {}", assigned_id);
+
+    if let Some(ref t) = tags {
+        if let Some(owner_id) = t.get("owner_id") {
+            tracing::info!("👤 Owner: {}", owner_id);
+            if let Some(name) = t.get("name") {
+                tracing::info!("📛 Name: {}", name);
+            }
+            tracing::info!("🎉 Cocoon is ready and claimed by your account!");
+        }
+    }
+
+    if persist_identity {
+        save_device_id(&assigned_id).await;
+    }
+    crate::health::mark_registered(&assigned_id);
+    *current_device_id.lock().await = Some(assigned_id);
+    // Tells a waiting `adi cocoon update` that this version came up healthy
+    crate::self_update::confirm_running_version();
+
+    announce_restored_silk_sessions(&command_ctx).await;
+
+    let current_device_id_for_loop = current_device_id.clone();
+
+    let (shutdown_tx, mut shutdown_rx) = broadcast::channel::<()>(1);
+    let writer_for_shutdown = writer.clone();
+    let device_id_for_shutdown = current_device_id.clone();
+    let ctx_for_shutdown = command_ctx.clone();
+
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sigterm =
+                signal(SignalKind::terminate()).expect("Failed to create SIGTERM handler");
+            let mut sigint =
+                signal(SignalKind::interrupt()).expect("Failed to create SIGINT handler");
+            // A stray SIGINT must not stop a supervised cocoon; its manager uses SIGTERM
+            let ignore_sigint = service_mode();
+
+            loop {
+                tokio::select! {
+                    _ = sigterm.recv() => {
+                        tracing::info!("📥 Received SIGTERM, initiating graceful shutdown...");
+                        break;
+                    }
+                    _ = sigint.recv() => {
+                        if ignore_sigint {
+                            tracing::warn!("📥 Ignoring SIGINT in service mode (COCOON_SERVICE_MODE); send SIGTERM to stop");
+                            continue;
+                        }
+                        tracing::info!("📥 Received SIGINT, initiating graceful shutdown...");
+                        break;
+                    }
+                }
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            tracing::info!("📥 Received Ctrl+C, initiating graceful shutdown...");
+        }
+
+        // Updates, rekeys and restarts all stop the cocoon this way
+        let notice = ctx_for_shutdown
+            .notice(SHUTDOWN_NOTICE.to_string(), NoticeLevel::Warning)
+            .await;
+        send_response(&writer_for_shutdown, &notice).await;
+
+        if let Some(device_id) = device_id_for_shutdown.lock().await.as_ref() {
+            send_deregister(&writer_for_shutdown, device_id, Some("shutdown")).await;
+        }
+
+        let _ = shutdown_tx.send(());
+    }.in_current_span());
+
+    let (job_tx, mut job_rx) = tokio::sync::mpsc::channel::<i32>(1);
+    if let Some(job) = launch.job {
+        tokio::spawn(run_job_script(job, writer.clone(), current_device_id.clone(), job_tx).in_current_span());
+    } else {
+        drop(job_tx);
+    }
+    let mut job_exit_code = None;
+
+    let mut stalled = false;
+    let mut link_lost = None;
+    let ping_every = ping_interval();
+    let mut heartbeat = Heartbeat::default();
+    let mut ping_timer = tokio::time::interval(ping_every.unwrap_or(DEFAULT_PING_INTERVAL));
+    ping_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ping_timer.tick().await;
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv() => {
+                tracing::info!("🛑 Shutdown signal received, exiting main loop...");
+                break;
+            }
+            Some(exit_code) = job_rx.recv() => {
+                job_exit_code = Some(exit_code);
+                break;
+            }
+            _ = writer.stalled() => {
+                stalled = true;
+                break;
+            }
+            _ = ping_timer.tick(), if ping_every.is_some() => {
+                if let Err(unanswered) = heartbeat.ping_due() {
+                    tracing::error!(
+                        "❌ No pong from signaling server for {:.0}s, dropping the connection",
+                        unanswered.as_secs_f64()
+                    );
+                    link_lost = Some(unanswered);
+                    break;
+                }
+                let writer = writer.clone();
+                tokio::spawn(async move {
+                    let _ = writer.send_message(Message::Ping(Vec::new()), Priority::Control).await;
+                }.in_current_span());
+            }
+            msg_result = read.next() => {
+                let msg = match msg_result {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        tracing::error!("❌ WebSocket error: {}", e);
+                        break;
+                    }
+                    None => {
+                        tracing::info!("🔌 Connection closed by server");
+                        break;
+                    }
+                };
+
+                heartbeat.frame_received();
+                crate::metrics::add(crate::metrics::Counter::SignalingBytesReceived, msg.len() as u64);
+                let text = match msg {
+                    Message::Text(t) => t,
+                    Message::Ping(data) => {
+                        let writer = writer.clone();
+                        tokio::spawn(async move {
+                            let _ = writer.send_message(Message::Pong(data), Priority::Control).await;
+                        }.in_current_span());
+                        continue;
+                    }
+                    Message::Close(_) => {
+                        tracing::info!("🔌 Connection closed");
+                        break;
+                    }
+                    _ => continue,
+                };
+
+                if let Some(mismatch) = crate::protocol_version::ProtocolMismatch::parse(&text) {
+                    tokio::spawn(async move {
+                        crate::protocol_version::handle(&mismatch).await;
+                    }.in_current_span());
+                    continue;
+                }
+
+                let message: SignalingMessage = match serde_json::from_str(&text) {
+                    Ok(m) => m,
+                    Err(e) => {
+                        tracing::warn!("⚠️ Invalid message: {}", e);
+                        continue;
+                    }
+                };
+
+                match message {
+                    SignalingMessage::DeviceRegisterResponse {
+                        device_id: assigned_id,
+                        tags,
+                    } => {
+                        tracing::info!("✅ Registration confirmed");
+                        tracing::Span::current().record("device_id", assigned_id.as_str());
+                        tracing::info!("🆔 Device ID: {}", assigned_id);
+
+                            if let Some(ref t) = tags {
+                            if let Some(owner_id) = t.get("owner_id") {
+                                tracing::info!("👤 Owner: {}", owner_id);
+                                if let Some(name) = t.get("name") {
+                                    tracing::info!("📛 Name: {}", name);
+                                }
+                                tracing::info!("");
+                                tracing::info!("🎉 Cocoon is ready and claimed by your account!");
+                            }
+                        } else {
+                            tracing::info!("");
+                            tracing::info!("📋 To claim ownership:");
+                            tracing::info!(
+                                "   Anyone with this secret can become an owner (co-ownership supported)"
+                            );
+                            if let Some(secret) = crate::log_redact::masked_secret() {
+                                tracing::info!("   🔑 Secret: {}", secret);
+                            }
+                            tracing::info!("");
+                            tracing::info!("   ⚠️  Share this secret only with trusted co-owners!");
+                        }
+                        tracing::info!("");
+
+                        crate::health::mark_registered(&assigned_id);
+                        *current_device_id_for_loop.lock().await = Some(assigned_id.clone());
+                        if persist_identity {
+                            save_device_id(&assigned_id).await;
+                        }
+                    }
+
+                    SignalingMessage::DeviceDeregisterResponse { device_id } => {
+                        tracing::info!("✅ Deregistration confirmed for device: {}", device_id);
+                    }
+
+                    SignalingMessage::SyncData { payload } => {
+                        let type_str = payload.get("type").and_then(|v| v.as_str()).unwrap_or("");
+                        if type_str.starts_with("webrtc_") {
+                            match serde_json::from_value::<CocoonMessage>(payload) {
+                                Ok(cocoon_msg) => {
+                                    let _ = webrtc_msg_tx.send(cocoon_msg);
+                                    continue;
+                                }
+                                Err(e) => {
+                                    tracing::warn!("⚠️ Invalid CocoonMessage: {}", e);
+                                    continue;
+                                }
+                            }
+                        }
+
+                        // Handle query protocol messages (query_query_local → query_query_result)
+                        if type_str == "query_query_local" {
+                            let query_id = payload.get("query_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
+                            let params = payload.get("params").cloned().unwrap_or(serde_json::json!({}));
+
+                            let query_type: QueryType = match payload.get("query_type").cloned() {
+                                Some(v) => match serde_json::from_value(v) {
+                                    Ok(qt) => qt,
+                                    Err(e) => {
+                                        tracing::warn!("⚠️ Invalid query_type: {}", e);
+                                        continue;
+                                    }
+                                }
+                                None => {
+                                    tracing::warn!("⚠️ Missing query_type in query_query_local");
+                                    continue;
+                                }
+                            };
+
+                            let writer_clone = writer.clone();
+                            tokio::spawn(async move {
+                                let result = handle_query_local(query_id, query_type, params).await;
+                                if let CommandResponse::QueryResult { query_id, data, is_final } = result {
+                                    let response = serde_json::json!({
+                                        "type": "query_query_result",
+                                        "query_id": query_id,
+                                        "data": data,
+                                        "is_final": is_final,
+                                    });
+                                    let sync_msg = SignalingMessage::SyncData { payload: response };
+                                    let text = serde_json::to_string(&sync_msg).expect("serialization cannot fail");
+                                    let _ = writer_clone.send(text, Priority::Control).await;
+                                }
+                            }.in_current_span());
+                            continue;
+                        }
+
+                        dispatch_command(payload, &command_ctx, ResponseRoute::Signaling);
+                    }
+
+                    SignalingMessage::DevicePeerConnected { peer_id } => {
+                        tracing::info!("👋 Peer connected: {}", peer_id);
+                    }
+
+                    SignalingMessage::DevicePeerDisconnected { peer_id } => {
+                        tracing::info!("👋 Peer disconnected: {}", peer_id);
+                        let ended = command_ctx.end_peer_service_subscriptions(&peer_id).await;
+                        if ended > 0 {
+                            tracing::info!("📣 Ended {} service subscription(s)", ended);
+                        }
+                    }
+
+                    SignalingMessage::SystemError { message } => {
+                        tracing::error!("❌ Server error: {}", message);
+                    }
+
+                    _ => {
+                        tracing::debug!("📨 Other message: {:?}", message);
+                    }
+                }
+            }
+        }
+    }
+
+    crate::health::mark_disconnected();
+
+    // A stalled or dead link would hold each report for the full send timeout
+    drain_sessions(&command_ctx, shutdown_grace(), !stalled && link_lost.is_none()).await;
+
+    if let Some(unanswered) = link_lost {
+        // Exit non-zero so the supervisor restarts the cocoon with a fresh connection
+        return Err(format!(
+            "Signaling connection lost: no pong for {}s",
+            unanswered.as_secs()
+        )
+        .into());
+    }
+
+    if stalled {
+        // Exit non-zero so the supervisor restarts the cocoon with a fresh connection
+        return Err(format!(
+            "Signaling connection stalled: a send made no progress for {}s",
+            SEND_STALL_LIMIT.as_secs()
+        )
+        .into());
+    }
+
+    tracing::info!("🐛 Cocoon shutting down");
+    Ok(job_exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dispatch context whose signaling writer discards everything it is sent
+    fn test_context() -> CommandContext {
+        let sink = futures::sink::drain::<Message>()
+            .sink_map_err(|e: std::convert::Infallible| -> WsError { match e {} });
+        let (signaling_tx, _signaling_rx) = tokio::sync::mpsc::unbounded_channel();
+        CommandContext {
+            writer: Arc::new(SignalingWriter::new(Box::pin(sink))),
+            pty_sessions: Arc::new(Mutex::new(HashMap::new())),
+            pty_slots: Arc::new(PtySlots::new(DEFAULT_MAX_PTY_SESSIONS)),
+            silk_sessions: Arc::new(Mutex::new(HashMap::new())),
+            services: Default::default(),
+            proxy_stats: Arc::new(crate::proxy_stats::ProxyStats::new(&HashMap::new())),
+            events: Arc::new(crate::events::EventBus::new()),
+            webrtc: Arc::new(crate::webrtc::WebRtcManager::new(signaling_tx)),
+            data_dir: DataDirStatus::Writable,
+            output_policy: Default::default(),
+            execs: Default::default(),
+            adi_router: Arc::new(Mutex::new(AdiRouter::new())),
+            service_subscriptions: Default::default(),
+            terminal_sessions: Default::default(),
+            route: ResponseRoute::Signaling,
+        }
+    }
+
+    fn request(json: serde_json::Value) -> CommandRequest {
+        serde_json::from_value(json).expect("valid command request")
+    }
+
+    #[tokio::test]
+    async fn test_large_proxy_response_is_streamed_in_chunks() {
+        let body: Vec<u8> = (0..DEFAULT_PROXY_STREAM_THRESHOLD + 100_000).map(|i| (i % 251) as u8).collect();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let served = body.clone();
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nX-Test: yes\r\nConnection: close\r\n\r\n",
+                served.len()
+            );
+            socket.write_all(head.as_bytes()).await.unwrap();
+            socket.write_all(&served).await.unwrap();
+        });
+
+        let (tx, mut rx) = futures::channel::mpsc::unbounded::<Message>();
+        let mut ctx = test_context();
+        ctx.writer = Arc::new(SignalingWriter::new(Box::pin(
+            tx.sink_map_err(|_| WsError::ConnectionClosed),
+        )));
+        ctx.services = Arc::new(std::sync::RwLock::new(HashMap::from([(
+            "big".to_string(),
+            ServiceTarget { host: "127.0.0.1".to_string(), port },
+        )])));
+
+        let last = handle_command(
+            request(serde_json::json!({
+                "type": "proxy_http",
+                "request_id": "r1",
+                "service_name": "big",
+                "method": "GET",
+                "path": "/export",
+                "headers": {},
+            })),
+            &ctx,
+        )
+        .await
+        .expect("final chunk");
+        drop(ctx);
+
+        let mut chunks: Vec<JsonValue> = Vec::new();
+        while let Some(message) = rx.next().await {
+            let Message::Text(text) = message else { continue };
+            let msg: JsonValue = serde_json::from_str(&text).unwrap();
+            chunks.push(msg["payload"].clone());
+        }
+        chunks.push(serde_json::to_value(&last).unwrap());
+
+        assert!(chunks.len() > 2);
+        assert_eq!(chunks[0]["status_code"], 200);
+        assert_eq!(chunks[0]["headers"]["x-test"], "yes");
+        let mut received = Vec::new();
+        for (seq, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk["type"], "proxy_result_chunk");
+            assert_eq!(chunk["seq"], seq as u64);
+            assert_eq!(chunk["is_final"], seq == chunks.len() - 1);
+            if seq > 0 {
+                assert!(chunk.get("status_code").is_none());
+            }
+            let data = chunk["data"].as_str().unwrap();
+            received.extend(base64::Engine::decode(&base64::engine::general_purpose::STANDARD, data).unwrap());
+        }
+        assert_eq!(received, body);
+    }
+
+    #[test]
+    fn test_heartbeat_detects_unanswered_ping() {
+        let mut heartbeat = Heartbeat::default();
+        assert!(heartbeat.ping_due().is_ok());
+        heartbeat.frame_received();
+        assert!(heartbeat.ping_due().is_ok());
+        assert!(heartbeat.ping_due().is_err(), "second ping without any frame in between");
+        heartbeat.frame_received();
+        assert!(heartbeat.ping_due().is_ok());
+    }
+
+    #[test]
+    fn test_service_target_parse() {
+        let local = ServiceTarget::parse("8092").unwrap();
+        assert_eq!((local.host.as_str(), local.port), ("localhost", 8092));
+        let aliased = ServiceTarget::parse("db.internal:5432").unwrap();
+        assert_eq!((aliased.host.as_str(), aliased.port), ("db.internal", 5432));
+        assert!(ServiceTarget::parse(":5432").is_none());
+        assert!(ServiceTarget::parse("db.internal:http").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_execute_success() {
+        let ctx = test_context();
+        let response = handle_command(
+            request(serde_json::json!({"type": "execute", "command": "echo hello"})),
+            &ctx,
+        )
+        .await;
+
+        match response {
+            Some(CommandResponse::ExecuteResult { success, data, error, .. }) => {
+                assert!(success);
+                assert!(error.is_none());
+                let data = data.unwrap();
+                assert_eq!(data["stdout"], "hello\n");
+                assert_eq!(data["exit_code"], 0);
+            }
+            other => panic!("Expected ExecuteResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout_kills_command() {
+        let ctx = test_context();
+        let started = Instant::now();
+        let response = handle_command(
+            request(serde_json::json!({
+                "type": "execute",
+                "command": "echo before; sh -c 'sleep 30'; echo after",
+                "timeout_ms": 300,
+            })),
+            &ctx,
+        )
+        .await;
+
+        assert!(started.elapsed() < Duration::from_secs(10));
+        match response {
+            Some(CommandResponse::ExecuteResult { success, data, error, .. }) => {
+                assert!(!success);
+                assert_eq!(error.unwrap().code, "timeout");
+                assert_eq!(data.unwrap()["stdout"], "before\n");
+            }
+            other => panic!("Expected ExecuteResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_failure() {
+        let ctx = test_context();
+        let response = handle_command(
+            request(serde_json::json!({"type": "execute", "command": "exit 3"})),
+            &ctx,
+        )
+        .await;
+
+        match response {
+            Some(CommandResponse::ExecuteResult { success, data, error, .. }) => {
+                assert!(!success);
+                assert_eq!(data.unwrap()["exit_code"], 3);
+                assert_eq!(error.unwrap().code, "command_failed");
+            }
+            other => panic!("Expected ExecuteResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_secret_env_is_redacted_from_errors() {
+        let ctx = test_context();
+        let response = handle_command(
+            request(serde_json::json!({
+                "type": "execute",
+                "command": "echo \"token=$API_TOKEN\" >&2; exit 1",
+                "secret_env": {"API_TOKEN": "s3cr3t-value"},
+            })),
+            &ctx,
+        )
+        .await;
+
+        match response {
+            Some(CommandResponse::ExecuteResult { success, data, .. }) => {
+                assert!(!success);
+                let stderr = data.unwrap()["stderr"].as_str().unwrap().to_string();
+                assert_eq!(stderr, "token=[redacted]\n");
+            }
+            other => panic!("Expected ExecuteResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_wires_stages() {
+        let ctx = test_context();
+        let response = handle_command(
+            request(serde_json::json!({
+                "type": "pipeline",
+                "stages": ["cat", "tr a-z A-Z", "rev"],
+                "input": "hello",
+            })),
+            &ctx,
+        )
+        .await;
+
+        match response {
+            Some(CommandResponse::ExecuteResult { success, data, .. }) => {
+                assert!(success);
+                let data = data.unwrap();
+                assert_eq!(data["stdout"], "OLLEH");
+                assert_eq!(data["exit_codes"], serde_json::json!([0, 0, 0]));
+            }
+            other => panic!("Expected ExecuteResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_reports_failed_stage() {
+        let ctx = test_context();
+        let response = handle_command(
+            request(serde_json::json!({
+                "type": "pipeline",
+                "stages": ["echo oops >&2; exit 2", "cat"],
+            })),
+            &ctx,
+        )
+        .await;
+
+        match response {
+            Some(CommandResponse::ExecuteResult { success, data, error, .. }) => {
+                assert!(!success);
+                let data = data.unwrap();
+                assert_eq!(data["exit_code"], 2);
+                assert_eq!(data["exit_codes"], serde_json::json!([2, 0]));
+                assert_eq!(data["stages"][0]["stderr"], "oops\n");
+                assert_eq!(error.unwrap().details.unwrap(), "stage 1 exit code: 2");
+            }
+            other => panic!("Expected ExecuteResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect_output_files_respects_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.txt"), "0123456789").unwrap();
+        std::fs::write(dir.path().join("b.bin"), [0u8; 21]).unwrap();
+        std::fs::create_dir(dir.path().join("c")).unwrap();
+        std::fs::write(dir.path().join("c/nested.txt"), "012345678901234").unwrap();
+        std::fs::write(dir.path().join("d.txt"), "0123456789").unwrap();
+        // Newest first is d.txt, c/nested.txt, b.bin, a.txt
+        let base = std::time::SystemTime::now() - Duration::from_secs(60);
+        for (i, name) in ["a.txt", "b.bin", "c/nested.txt", "d.txt"].iter().enumerate() {
+            let file = std::fs::File::options().write(true).open(dir.path().join(name)).unwrap();
+            file.set_modified(base + Duration::from_secs(i as u64)).unwrap();
+        }
+        let dir_str = dir.path().to_str().unwrap();
+        let limits = OutputLimits {
+            max_file_bytes: 20,
+            max_total_bytes: 30,
+            max_files: 10,
+        };
+
+        // a.txt would bring the total to 35
+        let (files, omitted) = collect_output_files_within(dir_str, limits).await;
+        let paths: Vec<&str> = files.iter().map(|f| f.path.trim_start_matches('/')).collect();
+        assert_eq!(paths, vec!["d.txt", "c/nested.txt", "b.bin"]);
+        assert_eq!(files[0].content, "0123456789");
+        assert!(files[0].truncated.is_none());
+        assert_eq!(files[1].content.len(), 15);
+        assert!(files[2].content.is_empty());
+        assert_eq!(files[2].truncated.as_ref().unwrap().original_bytes, 21);
+        let omitted = omitted.unwrap();
+        assert_eq!((omitted.count, omitted.bytes), (1, 10));
+        assert_eq!(omitted.paths.len(), 1);
+        assert!(omitted.paths[0].ends_with("a.txt"));
+
+        let (files, omitted) = collect_output_files_within(dir_str, OutputLimits { max_files: 2, ..limits }).await;
+        assert_eq!(files.len(), 2);
+        let omitted = omitted.unwrap();
+        assert_eq!((omitted.count, omitted.bytes), (2, 31));
+
+        let (files, omitted) =
+            collect_output_files_within(dir_str, OutputLimits { max_file_bytes: 21, ..limits }).await;
+        assert_eq!(files.len(), 2, "b.bin fits the file cap but not the total cap");
+        assert_eq!(omitted.unwrap().count, 2);
+
+        // b.bin's 21 bytes would fit, but its 28 base64 characters don't
+        let (files, omitted) = collect_output_files_within(
+            dir_str,
+            OutputLimits { max_file_bytes: 21, max_total_bytes: 50, ..limits },
+        )
+        .await;
+        assert_eq!(files.len(), 2);
+        let omitted = omitted.unwrap();
+        assert_eq!((omitted.count, omitted.bytes), (2, 31));
+        assert!(omitted.paths[0].ends_with("b.bin"));
+
+        let (files, omitted) = collect_output_files_within(dir_str, OutputLimits { max_total_bytes: 1000, ..limits }).await;
+        assert_eq!(files.len(), 4);
+        assert!(omitted.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_clear_output_dir_does_not_follow_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("keep.txt"), "keep").unwrap();
+
+        let output = tempfile::tempdir().unwrap();
+        std::fs::write(output.path().join("stale.txt"), "old").unwrap();
+        std::fs::create_dir(output.path().join("nested")).unwrap();
+        std::fs::write(output.path().join("nested/old.bin"), [0u8, 1]).unwrap();
+        std::os::unix::fs::symlink(outside.path(), output.path().join("escape")).unwrap();
+
+        assert_eq!(clear_output_dir(output.path()).await.unwrap(), 3);
+        assert_eq!(std::fs::read_dir(output.path()).unwrap().count(), 0);
+        assert!(outside.path().join("keep.txt").exists());
+
+        let link = output.path().join("linked-output");
+        std::os::unix::fs::symlink(outside.path(), &link).unwrap();
+        assert!(clear_output_dir(&link).await.is_err());
+        assert!(outside.path().join("keep.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_expire_output_dir_keeps_recent_entries() {
+        let output = tempfile::tempdir().unwrap();
+        let old = std::time::SystemTime::now() - Duration::from_secs(7200);
+        std::fs::write(output.path().join("fresh.txt"), "new").unwrap();
+        std::fs::write(output.path().join("stale.txt"), "old").unwrap();
+        std::fs::create_dir_all(output.path().join("stale_dir/inner")).unwrap();
+        std::fs::write(output.path().join("stale_dir/inner/a.txt"), "old").unwrap();
+        // Old directory, but something inside it was just written
+        std::fs::create_dir(output.path().join("live_dir")).unwrap();
+        std::fs::write(output.path().join("live_dir/b.txt"), "new").unwrap();
+        for name in ["stale.txt", "stale_dir/inner/a.txt", "stale_dir/inner", "stale_dir", "live_dir"] {
+            let file = std::fs::File::open(output.path().join(name)).unwrap();
+            file.set_modified(old).unwrap();
+        }
+
+        assert_eq!(expire_output_dir(output.path(), Duration::from_secs(3600)).await.unwrap(), 2);
+        let mut left: Vec<String> = std::fs::read_dir(output.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["fresh.txt", "live_dir"]);
+    }
+
+    /// Sink whose sends never complete, like a WebSocket on a wedged link
+    struct WedgedSink;
+
+    impl futures::Sink<Message> for WedgedSink {
+        type Error = WsError;
+
+        fn poll_ready(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), WsError>> {
+            std::task::Poll::Pending
+        }
+
+        fn start_send(self: Pin<&mut Self>, _item: Message) -> Result<(), WsError> {
+            Ok(())
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), WsError>> {
+            std::task::Poll::Pending
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), WsError>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_signaling_writer_drops_frames_then_reports_stall() {
+        let writer = Arc::new(SignalingWriter::new(Box::pin(WedgedSink)));
+
+        let control = {
+            let writer = writer.clone();
+            tokio::spawn(async move { writer.send("control".to_string(), Priority::Control).await })
+        };
+        tokio::task::yield_now().await;
+
+        // The control frame holds the writer, so droppable frames give up
+        let dropped = writer.send("pty".to_string(), Priority::Droppable).await;
+        assert!(dropped.is_err());
+        assert_eq!(writer.dropped.load(Ordering::Relaxed), 1);
+
+        assert!(control.await.unwrap().is_err());
+        tokio::time::timeout(Duration::from_secs(1), writer.stalled())
+            .await
+            .expect("stall should be reported");
+    }
+
+    #[tokio::test]
+    async fn test_job_reports_exit_code() {
+        let ctx = test_context();
+        let (done_tx, mut done_rx) = tokio::sync::mpsc::channel(1);
+        let job = Job {
+            script: "echo building; exit 4".to_string(),
+            upload_outputs: false,
+        };
+        run_job_script(job, ctx.writer.clone(), Arc::new(Mutex::new(None)), done_tx).await;
+        assert_eq!(done_rx.recv().await, Some(4));
+    }
+
+    #[test]
+    fn test_truncate_utf8_keeps_char_boundary() {
+        let mut text = "héllo".to_string();
+        truncate_utf8(&mut text, 2);
+        assert_eq!(text, "h");
+    }
+
+    #[test]
+    fn test_limit_result_size_truncates_largest_field() {
+        let mut response = CommandResponse::ExecuteResult {
+            success: true,
+            data: Some(serde_json::json!({
+                "stdout": "é".repeat(5000),
+                "stderr": "warning",
+                "exit_code": 0,
+            })),
+            error: None,
+            files: vec![],
+            omitted_files: None,
+            request_id: None,
+        };
+        limit_result_size(&mut response, 2000);
+
+        assert!(serde_json::to_vec(&response).unwrap().len() <= 2000);
+        let CommandResponse::ExecuteResult { data, .. } = response else {
+            panic!("Expected ExecuteResult");
+        };
+        let data = data.unwrap();
+        assert_eq!(data["truncated"], true);
+        assert_eq!(data["stderr"], "warning");
+        let kept = data["stdout"].as_str().unwrap().len();
+        assert_eq!(data["truncation"]["stdout"]["original_bytes"], 10000);
+        assert_eq!(data["truncation"]["stdout"]["omitted_bytes"], 10000 - kept);
+        assert!(data["truncation"].get("stderr").is_none());
+    }
+
+    #[test]
+    fn test_limit_result_size_leaves_other_responses_alone() {
+        let mut response = CommandResponse::QueryResult {
+            query_id: "q-1".to_string(),
+            data: serde_json::json!({"stdout": "x".repeat(5000)}),
+            is_final: true,
+        };
+        limit_result_size(&mut response, 2000);
+
+        let CommandResponse::QueryResult { data, .. } = response else {
+            panic!("Expected QueryResult");
+        };
+        assert_eq!(data["stdout"].as_str().unwrap().len(), 5000);
+        assert!(data.get("truncated").is_none());
+    }
+
+    #[test]
+    fn test_registration_rejection_classification() {
+        assert!(RegistrationError::rejected("Invalid secret".to_string()).is_permanent());
+        assert!(RegistrationError::rejected("Device revoked by owner".to_string()).is_permanent());
+        assert!(!RegistrationError::rejected("Rate limited, try again later".to_string()).is_permanent());
+        assert!(!RegistrationError::Disconnected("connection closed".to_string()).is_permanent());
+    }
+
+    #[tokio::test]
+    async fn test_pty_close_unknown_session() {
+        let ctx = test_context();
+        let response = handle_command(CommandRequest::PtyClose { session_id: Uuid::new_v4() }, &ctx).await;
+
+        match response {
+            Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "session_not_found"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_replay_buffer_keeps_tail() {
+        let mut replay = ReplayBuffer::new();
+        replay.push(b"$ ls\r\n");
+        assert_eq!(replay.snapshot(), "$ ls\r\n");
+
+        let line = vec![b'x'; 1023];
+        for _ in 0..(PTY_REPLAY_BYTES / 1024 + 1) {
+            replay.push(&line);
+            replay.push(b"\n");
+        }
+        replay.push(b"prompt> ");
+
+        let snapshot = replay.snapshot();
+        assert!(snapshot.len() <= PTY_REPLAY_BYTES);
+        assert!(snapshot.starts_with('x'));
+        assert!(snapshot.ends_with("\nprompt> "));
+    }
+
+    #[tokio::test]
+    async fn test_pty_subscribe_unknown_session() {
+        let ctx = test_context();
+        let response =
+            handle_command(CommandRequest::PtySubscribe { session_id: Uuid::new_v4() }, &ctx).await;
+
+        match response {
+            Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "session_not_found"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(probe_data_dir(dir.path()).await, DataDirStatus::Writable));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let missing = dir.path().join("missing");
+        assert!(matches!(probe_data_dir(&missing).await, DataDirStatus::Unavailable(_)));
+    }
+
+    #[tokio::test]
+    async fn test_health_check_reports_ephemeral_storage() {
+        let mut ctx = test_context();
+        ctx.data_dir = DataDirStatus::Unavailable("/cocoon is not writable".to_string());
+
+        match handle_command(CommandRequest::HealthCheck, &ctx).await {
+            Some(CommandResponse::HealthStatus { persistent, storage_error, .. }) => {
+                assert!(!persistent);
+                assert!(storage_error.unwrap().contains("/cocoon is not writable"));
+            }
+            other => panic!("Expected HealthStatus, got {:?}", other),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_cancel_exec_signals_running_execute() {
+        let ctx = test_context();
+        let execute = |ctx: CommandContext| async move {
+            handle_command(
+                request(serde_json::json!({"type": "execute", "command": "echo started; sleep 30", "request_id": "job-1"})),
+                &ctx,
+            )
+            .await
+        };
+        let running = tokio::spawn(execute(ctx.clone()));
+
+        let started = Instant::now();
+        while !ctx.execs.lock().unwrap().get("job-1").is_some_and(|h| h.pid.get().is_some()) {
+            assert!(started.elapsed() < Duration::from_secs(5), "execute never started");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // The id is taken while the first one runs
+        match execute(ctx.clone()).await {
+            Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "duplicate_request_id"),
+            other => panic!("Expected duplicate_request_id, got {:?}", other),
+        }
+
+        let cancel = handle_command(request(serde_json::json!({"type": "cancel_exec", "request_id": "job-1"})), &ctx).await;
+        let json = serde_json::to_value(cancel.expect("response")).unwrap();
+        assert_eq!(json, serde_json::json!({"type": "cancel_exec_response", "request_id": "job-1", "found": true, "signaled": true}));
+
+        let result = tokio::time::timeout(Duration::from_secs(5), running).await.unwrap().unwrap();
+        let json = serde_json::to_value(result.expect("result")).unwrap();
+        assert_eq!(json["success"], false);
+        assert_eq!(json["error"]["code"], "cancelled");
+        assert_eq!(json["request_id"], "job-1");
+        assert_eq!(json["data"]["stdout"], "started\n");
+        assert!(ctx.execs.lock().unwrap().is_empty());
+
+        let gone = handle_command(request(serde_json::json!({"type": "cancel_exec", "request_id": "job-1", "signal": "KILL"})), &ctx).await;
+        let json = serde_json::to_value(gone.expect("response")).unwrap();
+        assert_eq!((json["found"].as_bool(), json["signaled"].as_bool()), (Some(false), Some(false)));
+
+        let bad = handle_command(request(serde_json::json!({"type": "cancel_exec", "request_id": "job-1", "signal": "SIGFOO"})), &ctx).await;
+        assert!(matches!(bad, Some(CommandResponse::Error { code, .. }) if code == "invalid_signal"));
+    }
+
+    #[tokio::test]
+    async fn test_get_metrics_counts_executions() {
+        let ctx = test_context();
+        handle_command(request(serde_json::json!({"type": "execute", "command": "exit 3"})), &ctx).await;
+
+        let response = handle_command(request(serde_json::json!({"type": "get_metrics"})), &ctx).await;
+        let json = serde_json::to_value(response.expect("response")).unwrap();
+        assert_eq!(json["type"], "metrics_response");
+        assert!(chrono::DateTime::parse_from_rfc3339(json["timestamp"].as_str().unwrap()).is_ok());
+        assert!(json["uptime_secs"].is_u64());
+        // Counters are process-wide, so other tests may have added to them
+        assert!(json["counters"]["commands_executed"].as_u64().unwrap() >= 1);
+        assert!(json["counters"]["commands_failed"].as_u64().unwrap() >= 1);
+        assert_eq!(json["gauges"]["pty_sessions"], 0);
+        assert_eq!(json["gauges"]["silk_sessions"], 0);
+        assert!(json["proxy"].is_array());
+    }
+
+    #[tokio::test]
+    async fn test_build_info_names_the_running_binary() {
+        let ctx = test_context();
+        let response = handle_command(request(serde_json::json!({"type": "build_info"})), &ctx).await;
+        let json = serde_json::to_value(response.expect("response")).unwrap();
+        assert_eq!(json["type"], "build_info_response");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(!json["git_commit"].as_str().unwrap().is_empty());
+        assert!(!json["target"].as_str().unwrap().is_empty());
+        let exe = std::env::current_exe().unwrap().canonicalize().unwrap();
+        assert_eq!(json["executable"], exe.display().to_string());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_attach_pty_checks_cwd_and_shell() {
+        let ctx = test_context();
+        let attach = |extra: serde_json::Value| {
+            let mut json = serde_json::json!({"type": "attach_pty", "command": "sleep 30", "cols": 80, "rows": 24});
+            json.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+            request(json)
+        };
+        let error_code = |response: Option<CommandResponse>| match response {
+            Some(CommandResponse::Error { code, .. }) => code,
+            other => panic!("Expected an error, got {:?}", other),
+        };
+
+        let response = handle_command(attach(serde_json::json!({"cwd": "/no/such/dir"})), &ctx).await;
+        assert_eq!(error_code(response), "invalid_cwd");
+        let response = handle_command(attach(serde_json::json!({"shell": "/no/such/shell"})), &ctx).await;
+        assert_eq!(error_code(response), "invalid_shell");
+        let response = handle_command(attach(serde_json::json!({"shell": "/etc/hosts"})), &ctx).await;
+        assert_eq!(error_code(response), "invalid_shell");
+        // Executable, but not a login shell
+        let response = handle_command(attach(serde_json::json!({"shell": "/usr/bin/env"})), &ctx).await;
+        assert_eq!(error_code(response), "invalid_shell");
+        assert!(is_listed_shell("# comment\n/bin/sh\n  /bin/bash  \n", "/bin/bash"));
+        assert!(!is_listed_shell("#/usr/bin/env\n/bin/sh\n", "/usr/bin/env"));
+        assert!(ctx.pty_sessions.lock().await.is_empty());
+
+        let response = handle_command(attach(serde_json::json!({"cwd": "/tmp", "shell": "/bin/sh"})), &ctx).await;
+        assert!(matches!(response, Some(CommandResponse::PtyCreated { .. })), "{:?}", response);
+        drain_sessions(&ctx, Duration::ZERO, false).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_pty_limit_rejects_sessions_over_the_cap() {
+        let mut ctx = test_context();
+        ctx.pty_slots = Arc::new(PtySlots::new(2));
+        let attach = || request(serde_json::json!({"type": "attach_pty", "command": "sleep 30", "cols": 80, "rows": 24}));
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            match handle_command(attach(), &ctx).await {
+                Some(CommandResponse::PtyCreated { session_id }) => ids.push(session_id),
+                other => panic!("Expected PtyCreated, got {:?}", other),
+            }
+        }
+        match handle_command(attach(), &ctx).await {
+            Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "pty_limit_reached"),
+            other => panic!("Expected pty_limit_reached, got {:?}", other),
+        }
+        assert_eq!(ctx.pty_slots.open(), 2);
+
+        // Closing a PTY ends its reader, which frees the slot
+        let mut closed = ctx.pty_sessions.lock().await.remove(&ids[0]).unwrap();
+        let _ = closed.child.kill();
+        drop(closed);
+        for _ in 0..100 {
+            if ctx.pty_slots.open() < 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(matches!(handle_command(attach(), &ctx).await, Some(CommandResponse::PtyCreated { .. })));
+
+        drain_sessions(&ctx, Duration::ZERO, false).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_drain_sessions_kills_children_that_ignore_sighup() {
+        let ctx = test_context();
+        for command in ["sleep 30", "trap '' HUP; sleep 30"] {
+            let options = PtyOptions {
+                env: &HashMap::new(),
+                clean_env: false,
+                cwd: None,
+                shell: None,
+            };
+            let (id, session) = create_pty_session(command, 80, 24, options, ctx.responder(), &ctx.pty_slots)
+                .await
+                .unwrap();
+            ctx.pty_sessions.lock().await.insert(id, session);
+        }
+        let pids: Vec<u32> = ctx
+            .pty_sessions
+            .lock()
+            .await
+            .values()
+            .filter_map(|s| s.child.process_id())
+            .collect();
+        assert_eq!(pids.len(), 2);
+
+        let started = Instant::now();
+        drain_sessions(&ctx, Duration::from_millis(300), true).await;
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(ctx.pty_sessions.lock().await.is_empty());
+        for pid in pids {
+            // SAFETY: signal 0 only checks whether the (reaped) child still exists
+            let alive = unsafe { libc::kill(pid as i32, 0) } == 0;
+            assert!(!alive, "PTY child {} survived the drain", pid);
+        }
+    }
+
+    /// Run `sleep 30` in Silk session `session_id`; its PID
+    async fn start_silk_sleep(ctx: &CommandContext, session_id: Uuid) -> u32 {
+        let execute = serde_json::json!({
+            "type": "silk_execute",
+            "session_id": session_id,
+            "command": "sleep 30",
+            "command_id": "sleep",
+        });
+        assert!(handle_command(request(execute), ctx).await.is_none());
+        ctx.silk_sessions.lock().await[&session_id].running_commands["sleep"]
+            .pid
+            .expect("non-interactive command has a pid")
+    }
+
+    /// Whether `pid` is gone within a few seconds, as a killed Silk command's
+    /// owner reaps it once the signal lands
+    async fn process_exits(pid: u32) -> bool {
+        for _ in 0..50 {
+            // SAFETY: signal 0 only checks that the process exists
+            if unsafe { libc::kill(pid as i32, 0) } != 0 {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain_sessions_stops_running_silk_commands() {
+        let ctx = test_context();
+        let session = SilkSession::new(None, HashMap::new(), Some("/bin/sh".to_string()), false).unwrap();
+        let session_id = session.id;
+        ctx.silk_sessions.lock().await.insert(session_id, session);
+        let pid = start_silk_sleep(&ctx, session_id).await;
+
+        drain_sessions(&ctx, Duration::from_secs(2), false).await;
+        assert!(ctx.silk_sessions.lock().await.is_empty());
+        assert!(process_exits(pid).await, "sleep outlived the shutdown");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reaper_leaves_silk_commands_to_their_owner() {
+        let ctx = test_context();
+        let mut session = SilkSession::new(None, HashMap::new(), Some("/bin/sh".to_string()), false).unwrap();
+        let (_, child) = session.execute("exit 3", "cmd-1".to_string(), Some(false)).unwrap();
+        let mut child = child.expect("non-interactive command has a child");
+        ctx.silk_sessions.lock().await.insert(session.id, session);
+
+        // The command exits before its owner gets round to waiting for it
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let tracked = tracked_child_pids(&ctx.pty_sessions, &ctx.silk_sessions, &ctx.webrtc);
+        crate::reaper::reap_zombies(tracked).await;
+
+        assert_eq!(child.wait().unwrap().code(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn test_expire_sessions_closes_old_silk_sessions() {
+        let ctx = test_context();
+        let session = SilkSession::new(None, HashMap::new(), None, false).unwrap();
+        let session_id = session.id;
+        ctx.silk_sessions.lock().await.insert(session_id, session);
+
+        expire_sessions(&ctx, std::time::Duration::from_secs(3600)).await;
+        assert!(ctx.silk_sessions.lock().await.contains_key(&session_id));
+
+        expire_sessions(&ctx, std::time::Duration::ZERO).await;
+        assert!(ctx.silk_sessions.lock().await.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_expire_sessions_kills_running_silk_commands() {
+        let ctx = test_context();
+        let session = SilkSession::new(None, HashMap::new(), Some("/bin/sh".to_string()), false).unwrap();
+        let session_id = session.id;
+        ctx.silk_sessions.lock().await.insert(session_id, session);
+        let pid = start_silk_sleep(&ctx, session_id).await;
+
+        expire_sessions(&ctx, std::time::Duration::ZERO).await;
+        assert!(process_exits(pid).await, "sleep outlived its expired Silk session");
+    }
+
+    #[tokio::test]
+    async fn test_close_idle_sessions_spares_active_ones() {
+        let ctx = test_context();
+        let idle = SilkSession::new(None, HashMap::new(), None, false).unwrap();
+        let active = SilkSession::new(None, HashMap::new(), None, false).unwrap();
+        let (idle_id, active_id) = (idle.id, active.id);
+        let active_activity = active.activity.clone();
+        ctx.silk_sessions.lock().await.extend([(idle_id, idle), (active_id, active)]);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        // Output from the session's command, seen through a clone of its handle
+        active_activity.touch();
+        close_idle_sessions(&ctx, Duration::from_millis(100)).await;
+
+        let sessions = ctx.silk_sessions.lock().await;
+        assert!(!sessions.contains_key(&idle_id));
+        assert!(sessions.contains_key(&active_id));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_close_idle_sessions_spares_running_commands() {
+        let ctx = test_context();
+        let session = SilkSession::new(None, HashMap::new(), Some("/bin/sh".to_string()), false).unwrap();
+        let session_id = session.id;
+        ctx.silk_sessions.lock().await.insert(session_id, session);
+        let pid = start_silk_sleep(&ctx, session_id).await;
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        close_idle_sessions(&ctx, Duration::from_millis(100)).await;
+        assert!(ctx.silk_sessions.lock().await.contains_key(&session_id));
+
+        kill_session(&session_id.to_string(), &ctx).await;
+        assert!(process_exits(pid).await);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_unknown_service() {
+        let ctx = test_context();
+        let response = handle_command(
+            request(serde_json::json!({
+                "type": "proxy_http",
+                "request_id": "req-1",
+                "service_name": "missing",
+                "method": "GET",
+                "path": "/",
+                "headers": {},
+                "body": null,
+            })),
+            &ctx,
+        )
+        .await;
+
+        match response {
+            Some(CommandResponse::ProxyResult { request_id, status_code, .. }) => {
+                assert_eq!(request_id, "req-1");
+                assert_eq!(status_code, 404);
+            }
+            other => panic!("Expected ProxyResult, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_services_replaces_registry() {
+        let ctx = test_context();
+        let update = |services: serde_json::Value| {
+            request(serde_json::json!({"type": "update_services", "services": services}))
+        };
+
+        match handle_command(update(serde_json::json!({"api": 8080, "web": 3000})), &ctx).await {
+            Some(CommandResponse::ServicesUpdated { services }) => assert_eq!(services.len(), 2),
+            other => panic!("Expected ServicesUpdated, got {:?}", other),
+        }
+        assert_eq!(ctx.service_definitions(), vec!["api:8080", "web:3000"]);
+        assert_eq!(ctx.proxy_stats.snapshot(false).len(), 2);
+
+        for bad in [
+            serde_json::json!({"api": 0}),
+            serde_json::json!({"api": 8080, "admin": 8080}),
+            serde_json::json!({"a:b": 8080}),
+        ] {
+            match handle_command(update(bad), &ctx).await {
+                Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "invalid_services"),
+                other => panic!("Expected Error, got {:?}", other),
+            }
+        }
+        // A rejected update leaves the previous registry in place
+        assert_eq!(ctx.service_definitions(), vec!["api:8080", "web:3000"]);
+    }
+
+    #[tokio::test]
+    async fn test_service_subscription_errors() {
+        let ctx = test_context();
+
+        let response = handle_command(
+            request(serde_json::json!({"type": "subscribe", "service_id": "adi.tasks", "events": ["task_created"]})),
+            &ctx,
+        )
+        .await;
+        match response {
+            Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "plugin_not_found"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+
+        let response = handle_command(
+            request(serde_json::json!({"type": "subscribe", "service_id": "adi.tasks", "events": [" "]})),
+            &ctx,
+        )
+        .await;
+        match response {
+            Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "invalid_request"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+        assert!(ctx.service_subscriptions.lock().unwrap().is_empty());
 
-                let text = match msg {
-                    Message::Text(t) => t,
-                    Message::Close(_) => {
-                        tracing::info!("🔌 Connection closed");
-                        break;
-                    }
-                    _ => continue,
-                };
+        let subscription_id = Uuid::new_v4();
+        let response = handle_command(
+            request(serde_json::json!({"type": "unsubscribe", "subscription_id": subscription_id})),
+            &ctx,
+        )
+        .await;
+        match response {
+            Some(CommandResponse::Unsubscribed { found, .. }) => assert!(!found),
+            other => panic!("Expected Unsubscribed, got {:?}", other),
+        }
+    }
 
-                let message: SignalingMessage = match serde_json::from_str(&text) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        tracing::warn!("⚠️ Invalid message: {}", e);
-                        continue;
-                    }
-                };
+    #[tokio::test]
+    async fn test_service_events_reach_the_subscriber_past_other_disconnects() {
+        let (tx, mut rx) = futures::channel::mpsc::unbounded::<Message>();
+        let mut ctx = test_context();
+        ctx.writer = Arc::new(SignalingWriter::new(Box::pin(
+            tx.sink_map_err(|_| WsError::ConnectionClosed),
+        )));
+        async fn next_event(rx: &mut futures::channel::mpsc::UnboundedReceiver<Message>) -> JsonValue {
+            let message = tokio::time::timeout(Duration::from_secs(5), rx.next())
+                .await
+                .expect("relayed event")
+                .expect("writer open");
+            let Message::Text(text) = message else { panic!("Expected text, got {:?}", message) };
+            serde_json::from_str::<JsonValue>(&text).unwrap()["payload"].clone()
+        }
 
-                match message {
-                    SignalingMessage::DeviceRegisterResponse {
-                        device_id: assigned_id,
-                        tags,
-                    } => {
-                        tracing::info!("✅ Registration confirmed");
-                        tracing::info!("🆔 Device ID: {}", assigned_id);
+        let (events, receiver) = broadcast::channel::<JsonValue>(8);
+        let subscription_id = Uuid::new_v4();
+        let relay = spawn_service_relay(
+            subscription_id,
+            "adi.tasks".to_string(),
+            "task_created".to_string(),
+            receiver,
+            ctx.responder(),
+        );
+        ctx.service_subscriptions.lock().unwrap().insert(
+            subscription_id,
+            ServiceSubscription {
+                peer_id: Some("alice".to_string()),
+                relays: vec![(Uuid::new_v4(), relay)],
+            },
+        );
 
-                            if let Some(ref t) = tags {
-                            if let Some(owner_id) = t.get("owner_id") {
-                                tracing::info!("👤 Owner: {}", owner_id);
-                                if let Some(name) = t.get("name") {
-                                    tracing::info!("📛 Name: {}", name);
-                                }
-                                tracing::info!("");
-                                tracing::info!("🎉 Cocoon is ready and claimed by your account!");
-                            }
-                        } else {
-                            tracing::info!("");
-                            tracing::info!("📋 To claim ownership:");
-                            tracing::info!(
-                                "   Anyone with this secret can become an owner (co-ownership supported)"
-                            );
-                            tracing::info!("");
-                            tracing::info!("   ⚠️  Share this secret only with trusted co-owners!");
-                        }
-                        tracing::info!("");
+        events.send(serde_json::json!({"task_id": 1})).unwrap();
+        let event = next_event(&mut rx).await;
+        assert_eq!(event["type"], "service_event");
+        assert_eq!(event["subscription_id"], subscription_id.to_string());
+        assert_eq!(event["service_id"], "adi.tasks");
+        assert_eq!(event["event"], "task_created");
+        assert_eq!(event["data"]["task_id"], 1);
+
+        // Another peer leaving doesn't touch alice's relay
+        assert_eq!(ctx.end_peer_service_subscriptions("bob").await, 0);
+        events.send(serde_json::json!({"task_id": 2})).unwrap();
+        assert_eq!(next_event(&mut rx).await["data"]["task_id"], 2);
+
+        assert_eq!(ctx.end_peer_service_subscriptions("alice").await, 1);
+        assert!(ctx.service_subscriptions.lock().unwrap().is_empty());
+    }
 
-                        *current_device_id_for_loop.lock().await = Some(assigned_id.clone());
-                        save_device_id(&assigned_id).await;
-                    }
+    #[tokio::test]
+    async fn test_peer_disconnect_ends_only_its_subscriptions() {
+        let ctx = test_context();
+        let open = |peer_id: Option<&str>| {
+            let id = Uuid::new_v4();
+            let relay = tokio::spawn(std::future::pending::<()>());
+            ctx.service_subscriptions.lock().unwrap().insert(
+                id,
+                ServiceSubscription {
+                    peer_id: peer_id.map(str::to_string),
+                    relays: vec![(Uuid::new_v4(), relay)],
+                },
+            );
+            id
+        };
+        let _alice = open(Some("alice"));
+        let bob = open(Some("bob"));
+        let _unowned = open(None);
 
-                    SignalingMessage::DeviceDeregisterResponse { device_id } => {
-                        tracing::info!("✅ Deregistration confirmed for device: {}", device_id);
-                    }
+        assert_eq!(ctx.end_peer_service_subscriptions("alice").await, 2);
+        let remaining: Vec<Uuid> = ctx.service_subscriptions.lock().unwrap().keys().copied().collect();
+        assert_eq!(remaining, vec![bob]);
 
-                    SignalingMessage::SyncData { payload } => {
-                        let type_str = payload.get("type").and_then(|v| v.as_str()).unwrap_or("");
-                        if type_str.starts_with("webrtc_") {
-                            match serde_json::from_value::<CocoonMessage>(payload) {
-                                Ok(cocoon_msg) => {
-                                    let _ = webrtc_msg_tx.send(cocoon_msg);
-                                    continue;
-                                }
-                                Err(e) => {
-                                    tracing::warn!("⚠️ Invalid CocoonMessage: {}", e);
-                                    continue;
-                                }
-                            }
-                        }
+        assert_eq!(ctx.end_peer_service_subscriptions("carol").await, 0);
+        assert_eq!(ctx.end_peer_service_subscriptions("bob").await, 1);
+        assert!(ctx.service_subscriptions.lock().unwrap().is_empty());
+    }
 
-                        // Handle query protocol messages (query_query_local → query_query_result)
-                        if type_str == "query_query_local" {
-                            let query_id = payload.get("query_id").and_then(|v| v.as_str()).unwrap_or("").to_string();
-                            let params = payload.get("params").cloned().unwrap_or(serde_json::json!({}));
+    #[tokio::test]
+    async fn test_broadcast_returns_notice() {
+        let ctx = test_context();
+        let response = handle_command(
+            request(serde_json::json!({"type": "broadcast", "message": "restarting in 30s", "level": "warning"})),
+            &ctx,
+        )
+        .await
+        .expect("notice");
 
-                            let query_type: QueryType = match payload.get("query_type").cloned() {
-                                Some(v) => match serde_json::from_value(v) {
-                                    Ok(qt) => qt,
-                                    Err(e) => {
-                                        tracing::warn!("⚠️ Invalid query_type: {}", e);
-                                        continue;
-                                    }
-                                }
-                                None => {
-                                    tracing::warn!("⚠️ Missing query_type in query_query_local");
-                                    continue;
-                                }
-                            };
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["type"], "notice");
+        assert_eq!(json["message"], "restarting in 30s");
+        assert_eq!(json["level"], "warning");
+        assert!(json["notice_id"].is_string());
 
-                            let writer_clone = writer.clone();
-                            tokio::spawn(async move {
-                                let result = handle_query_local(query_id, query_type, params).await;
-                                if let CommandResponse::QueryResult { query_id, data, is_final } = result {
-                                    let response = serde_json::json!({
-                                        "type": "query_query_result",
-                                        "query_id": query_id,
-                                        "data": data,
-                                        "is_final": is_final,
-                                    });
-                                    let sync_msg = SignalingMessage::SyncData { payload: response };
-                                    let mut w = writer_clone.lock().await;
-                                    let _ = w.send(Message::Text(
-                                        serde_json::to_string(&sync_msg).expect("serialization cannot fail"),
-                                    )).await;
-                                }
-                            });
-                            continue;
-                        }
+        let default_level = handle_command(request(serde_json::json!({"type": "broadcast", "message": "hi"})), &ctx).await;
+        assert!(matches!(default_level, Some(CommandResponse::Notice { level: NoticeLevel::Info, .. })));
+    }
 
-                        let request: CommandRequest = match serde_json::from_value(payload) {
-                            Ok(req) => req,
-                            Err(e) => {
-                                tracing::warn!("⚠️ Invalid command request: {}", e);
-                                continue;
-                            }
-                        };
+    #[tokio::test]
+    async fn test_session_events_follow_subscription() {
+        let ctx = test_context();
+        let mut events = ctx.events.receiver();
 
-                        let writer_clone = writer.clone();
-                        let sessions_clone = pty_sessions.clone();
-                        let services_clone = services.clone();
-                        let silk_sessions_clone = silk_sessions.clone();
+        let response = handle_command(
+            request(serde_json::json!({"type": "subscribe_events", "topics": ["sessions"]})),
+            &ctx,
+        )
+        .await;
+        assert!(matches!(response, Some(CommandResponse::EventSubscriptions { ref topics }) if topics == &["sessions"]));
+
+        let session_id = Uuid::new_v4();
+        ctx.respond(&CommandResponse::PtyCreated { session_id }).await;
+        let event = events.try_recv().expect("sessions event");
+        assert_eq!(event.topic, "sessions");
+        assert_eq!(event.data["type"], "pty_created");
+        assert_eq!(event.data["session_id"], session_id.to_string());
+
+        handle_command(
+            request(serde_json::json!({"type": "unsubscribe_events", "topics": ["*"]})),
+            &ctx,
+        )
+        .await;
+        ctx.respond(&CommandResponse::PtyCreated { session_id }).await;
+        assert!(events.try_recv().is_err());
+    }
 
-                        tokio::spawn(async move {
-                            let response: Option<CommandResponse> = match request {
-                                CommandRequest::Execute { command, input } => {
-                                    tracing::info!("🚀 Executing: {}", command);
-                                    Some(execute_command(&command, input.as_deref()).await)
-                                }
+    #[tokio::test]
+    async fn test_silk_session_lifecycle() {
+        let ctx = test_context();
+        let session_id = match handle_command(
+            request(serde_json::json!({"type": "silk_create_session"})),
+            &ctx,
+        )
+        .await
+        {
+            Some(CommandResponse::SilkResponse(SilkResponse::SessionCreated { session_id, .. })) => {
+                session_id
+            }
+            other => panic!("Expected SessionCreated, got {:?}", other),
+        };
+        assert!(ctx.silk_sessions.lock().await.contains_key(&session_id));
 
-                                CommandRequest::AttachPty {
-                                    command,
-                                    cols,
-                                    rows,
-                                    env,
-                                } => {
-                                    tracing::info!("🔗 Attaching PTY: {} ({}x{})", command, cols, rows);
-
-                                    match create_pty_session(
-                                        &command,
-                                        cols,
-                                        rows,
-                                        &env,
-                                        writer_clone.clone(),
-                                    )
-                                    .await
-                                    {
-                                        Ok((session_id, session)) => {
-                                            sessions_clone.lock().await.insert(session_id, session);
-                                            Some(CommandResponse::PtyCreated { session_id })
-                                        }
-                                        Err(e) => Some(CommandResponse::Error {
-                                            code: "pty_create_failed".into(),
-                                            message: e,
-                                        }),
-                                    }
-                                }
+        let close = CommandRequest::SilkCloseSession { session_id };
+        assert!(matches!(
+            handle_command(close, &ctx).await,
+            Some(CommandResponse::SilkResponse(SilkResponse::SessionClosed { .. }))
+        ));
 
-                                CommandRequest::PtyInput { session_id, data } => {
-                                    let mut sessions = sessions_clone.lock().await;
-                                    if let Some(session) = sessions.get_mut(&session_id) {
-                                        if let Err(e) =
-                                            std::io::Write::write_all(&mut session.writer, data.as_bytes())
-                                        {
-                                            Some(CommandResponse::Error {
-                                                code: "pty_write_failed".into(),
-                                                message: e.to_string(),
-                                            })
-                                        } else {
-                                            let _ = std::io::Write::flush(&mut session.writer);
-                                            None // No response needed for successful input
-                                        }
-                                    } else {
-                                        Some(CommandResponse::Error {
-                                            code: "session_not_found".into(),
-                                            message: format!("PTY session {} not found", session_id),
-                                        })
-                                    }
-                                }
+        let close_again = CommandRequest::SilkCloseSession { session_id };
+        assert!(matches!(
+            handle_command(close_again, &ctx).await,
+            Some(CommandResponse::SilkResponse(SilkResponse::Error { .. }))
+        ));
+    }
 
-                                CommandRequest::PtyResize {
-                                    session_id,
-                                    cols,
-                                    rows,
-                                } => {
-                                    tracing::info!("📐 Resizing PTY {} to {}x{}", session_id, cols, rows);
-                                    let sessions = sessions_clone.lock().await;
-                                    if let Some(session) = sessions.get(&session_id) {
-                                        if let Err(e) = session.pair.master.resize(PtySize {
-                                            rows,
-                                            cols,
-                                            pixel_width: 0,
-                                            pixel_height: 0,
-                                        }) {
-                                            Some(CommandResponse::Error {
-                                                code: "resize_failed".into(),
-                                                message: e.to_string(),
-                                            })
-                                        } else {
-                                            None // No response needed for successful resize
-                                        }
-                                    } else {
-                                        Some(CommandResponse::Error {
-                                            code: "session_not_found".into(),
-                                            message: format!("PTY session {} not found", session_id),
-                                        })
-                                    }
-                        }
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list_and_kill_sessions() {
+        let ctx = test_context();
+        let silk_id = match handle_command(
+            request(serde_json::json!({"type": "silk_create_session", "shell": "/bin/sh"})),
+            &ctx,
+        )
+        .await
+        {
+            Some(CommandResponse::SilkResponse(SilkResponse::SessionCreated { session_id, .. })) => session_id,
+            other => panic!("Expected SessionCreated, got {:?}", other),
+        };
 
-                        CommandRequest::PtyClose { session_id } => {
-                            tracing::info!("🔌 Closing PTY session {}", session_id);
-                            let mut sessions = sessions_clone.lock().await;
-                            if let Some(mut session) = sessions.remove(&session_id) {
-                                let exit_status = session.child.wait().ok();
-                                let exit_code =
-                                    exit_status.map(|s| s.exit_code() as i32).unwrap_or(-1);
-
-                                Some(CommandResponse::PtyExited {
-                                    session_id,
-                                    exit_code,
-                                })
-                            } else {
-                                Some(CommandResponse::Error {
-                                    code: "session_not_found".into(),
-                                    message: format!("PTY session {} not found", session_id),
-                                })
-                            }
-                        }
+        let sessions = match handle_command(request(serde_json::json!({"type": "list_sessions"})), &ctx).await {
+            Some(CommandResponse::Sessions { sessions }) => sessions,
+            other => panic!("Expected Sessions, got {:?}", other),
+        };
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, silk_id.to_string());
+        assert_eq!(sessions[0].kind, "silk");
 
-                        CommandRequest::ProxyHttp {
-                            request_id,
-                            service_name,
-                            method,
-                            path,
-                            headers,
-                            body,
-                        } => {
-                            tracing::info!(
-                                "🔀 Proxying HTTP {} {} to service {}",
-                                method,
-                                path,
-                                service_name
-                            );
-                            Some(
-                                handle_proxy_request(
-                                    request_id,
-                                    service_name,
-                                    method,
-                                    path,
-                                    headers,
-                                    body,
-                                    &services_clone,
-                                )
-                                .await,
-                            )
-                        }
+        let pid = start_silk_sleep(&ctx, silk_id).await;
 
-                        CommandRequest::QueryLocal {
-                            query_id,
-                            query_type,
-                            params,
-                        } => {
-                            tracing::info!("📊 Processing query: {:?}", query_type);
-                            Some(handle_query_local(query_id, query_type, params).await)
-                        }
+        let kill = serde_json::json!({"type": "kill_session", "session_id": silk_id.to_string()});
+        match handle_command(request(kill.clone()), &ctx).await {
+            Some(CommandResponse::SilkResponse(SilkResponse::SessionClosed { session_id, reason })) => {
+                assert_eq!(session_id, silk_id);
+                assert_eq!(reason.as_deref(), Some(KILLED_REASON));
+            }
+            other => panic!("Expected SessionClosed, got {:?}", other),
+        }
+        assert!(ctx.silk_sessions.lock().await.is_empty());
 
-                        CommandRequest::SilkCreateSession { cwd, env, shell } => {
-                            tracing::info!("🧵 Creating Silk session");
-                            match SilkSession::new(cwd, env, shell) {
-                                Ok(session) => {
-                                    let response = SilkResponse::SessionCreated {
-                                        session_id: session.id,
-                                        cwd: session.cwd.clone(),
-                                        shell: session.shell.clone(),
-                                    };
-                                    silk_sessions_clone.lock().await.insert(session.id, session);
-                                    Some(CommandResponse::SilkResponse(response))
-                                }
-                                Err(e) => {
-                                    Some(CommandResponse::SilkResponse(SilkResponse::Error {
-                                        session_id: None,
-                                        command_id: None,
-                                        code: "session_create_failed".to_string(),
-                                        message: e,
-                                    }))
-                                }
-                            }
-                        }
+        assert!(process_exits(pid).await, "sleep outlived its killed Silk session");
 
-                        CommandRequest::SilkExecute {
-                            session_id,
-                            command,
-                            command_id,
-                        } => {
-                            tracing::info!("🧵 Silk execute: {} (session {})", command, session_id);
-                            let mut silk_sessions = silk_sessions_clone.lock().await;
-
-                            if let Some(session) = silk_sessions.get_mut(&session_id) {
-                                match session.execute(&command, command_id.clone()) {
-                                    Ok((interactive, child_opt)) => {
-                                        if interactive {
-                                            drop(silk_sessions); // Release lock before async call
-
-                                            let mut env = HashMap::new();
-                                            env.insert(
-                                                "TERM".to_string(),
-                                                "xterm-256color".to_string(),
-                                            );
-
-                                            match create_pty_session(
-                                                &command,
-                                                80,
-                                                24,
-                                                &env,
-                                                writer_clone.clone(),
-                                            )
-                                            .await
-                                            {
-                                                Ok((pty_session_id, pty_session)) => {
-                                                    sessions_clone
-                                                        .lock()
-                                                        .await
-                                                        .insert(pty_session_id, pty_session);
-
-                                                    if let Some(s) = silk_sessions_clone
-                                                        .lock()
-                                                        .await
-                                                        .get_mut(&session_id)
-                                                    {
-                                                        s.set_pty_session(
-                                                            command_id.clone(),
-                                                            pty_session_id,
-                                                        );
-                                                    }
-
-                                                    Some(CommandResponse::SilkResponse(
-                                                        SilkResponse::InteractiveRequired {
-                                                            session_id,
-                                                            command_id,
-                                                            reason: format!(
-                                                                "Command '{}' requires interactive mode",
-                                                                command
-                                                                    .split_whitespace()
-                                                                    .next()
-                                                                    .unwrap_or(&command)
-                                                            ),
-                                                            pty_session_id,
-                                                        },
-                                                    ))
-                                                }
-                                                Err(e) => Some(CommandResponse::SilkResponse(
-                                                    SilkResponse::Error {
-                                                        session_id: Some(session_id),
-                                                        command_id: Some(command_id),
-                                                        code: "pty_create_failed".to_string(),
-                                                        message: e,
-                                                    },
-                                                )),
-                                            }
-                                        } else if let Some(mut child) = child_opt {
-                                            let writer_for_output = writer_clone.clone();
-                                            let sessions_for_cwd = silk_sessions_clone.clone();
-                                            let cmd_for_cwd = command.clone();
-                                            let command_id_for_spawn = command_id.clone();
-
-                                            let started = SilkResponse::CommandStarted {
-                                                session_id,
-                                                command_id,
-                                                interactive: false,
-                                            };
-                                            let started_msg = SignalingMessage::SyncData {
-                                                payload: serde_json::to_value(
-                                                    &CommandResponse::SilkResponse(started),
-                                                )
-                                                .expect("CommandResponse serialization cannot fail"),
-                                            };
-                                            let mut w = writer_clone.lock().await;
-                                            let _ = w
-                                                .send(Message::Text(
-                                                    serde_json::to_string(&started_msg).expect(
-                                                        "SignalingMessage serialization cannot fail",
-                                                    ),
-                                                ))
-                                                .await;
-                                            drop(w);
-
-                                            if let Some(stdin) = child.stdin.take() {
-                                                let mut silk_lock = silk_sessions_clone.lock().await;
-                                                if let Some(session) = silk_lock.get_mut(&session_id) {
-                                                    if let Some(cmd) = session.running_commands.get_mut(&command_id_for_spawn) {
-                                                        cmd.stdin = Some(stdin);
-                                                    }
-                                                }
-                                            }
-
-                                            tokio::spawn(async move {
-                                                let command_id = command_id_for_spawn;
-                                                let mut stdout_reader = std::io::BufReader::new(
-                                                    child.stdout.take().expect("child stdout is piped"),
-                                                );
-                                                let mut stderr_reader = std::io::BufReader::new(
-                                                    child.stderr.take().expect("child stderr is piped"),
-                                                );
-
-                                                let mut buf = [0u8; 4096];
-                                                loop {
-                                                    match stdout_reader.get_mut().read(&mut buf) {
-                                                        Ok(0) => break,
-                                                        Ok(n) => {
-                                                            let data =
-                                                                String::from_utf8_lossy(&buf[..n])
-                                                                    .to_string();
-                                                            let html = AnsiToHtml::convert(&data);
-                                                            let output = SilkResponse::Output {
-                                                                session_id,
-                                                                command_id: command_id.clone(),
-                                                                stream: SilkStream::Stdout,
-                                                                data: data.clone(),
-                                                                html: Some(html),
-                                                            };
-                                                            let msg = SignalingMessage::SyncData {
-                                                                payload: serde_json::to_value(
-                                                                    &CommandResponse::SilkResponse(
-                                                                        output,
-                                                                    ),
-                                                                )
-                                                                .expect("CommandResponse serialization cannot fail"),
-                                                            };
-                                                            let mut w =
-                                                                writer_for_output.lock().await;
-                                                            let _ = w
-                                                                .send(Message::Text(
-                                                                    serde_json::to_string(&msg)
-                                                                        .expect("SignalingMessage serialization cannot fail"),
-                                                                ))
-                                                                .await;
-                                                        }
-                                                        Err(_) => break,
-                                                    }
-                                                }
-
-                                                let mut stderr_buf = Vec::new();
-                                                let _ = stderr_reader.read_to_end(&mut stderr_buf);
-                                                if !stderr_buf.is_empty() {
-                                                    let data = String::from_utf8_lossy(&stderr_buf)
-                                                        .to_string();
-                                                    let html = AnsiToHtml::convert(&data);
-                                                    let output = SilkResponse::Output {
-                                                        session_id,
-                                                        command_id: command_id.clone(),
-                                                        stream: SilkStream::Stderr,
-                                                        data: data.clone(),
-                                                        html: Some(html),
-                                                    };
-                                                    let msg = SignalingMessage::SyncData {
-                                                        payload: serde_json::to_value(
-                                                            &CommandResponse::SilkResponse(output),
-                                                        )
-                                                        .expect("CommandResponse serialization cannot fail"),
-                                                    };
-                                                    let mut w = writer_for_output.lock().await;
-                                                    let _ = w
-                                                        .send(Message::Text(
-                                                            serde_json::to_string(&msg).expect(
-                                                                "SignalingMessage serialization cannot fail",
-                                                            ),
-                                                        ))
-                                                        .await;
-                                                }
-
-                                                let exit_code = child
-                                                    .wait()
-                                                    .map(|s| s.code().unwrap_or(-1))
-                                                    .unwrap_or(-1);
-
-                                                {
-                                                    let mut sessions =
-                                                        sessions_for_cwd.lock().await;
-                                                    if let Some(s) = sessions.get_mut(&session_id) {
-                                                        s.update_cwd_if_cd(&cmd_for_cwd);
-                                                        s.complete_command(command_id.clone());
-
-                                                        let completed =
-                                                            SilkResponse::CommandCompleted {
-                                                                session_id,
-                                                                command_id,
-                                                                exit_code,
-                                                                cwd: s.cwd.clone(),
-                                                            };
-                                                        let msg = SignalingMessage::SyncData {
-                                                            payload: serde_json::to_value(
-                                                                &CommandResponse::SilkResponse(
-                                                                    completed,
-                                                                ),
-                                                            )
-                                                            .expect("CommandResponse serialization cannot fail"),
-                                                        };
-                                                        let mut w = writer_for_output.lock().await;
-                                                        let _ = w
-                                                            .send(Message::Text(
-                                                                serde_json::to_string(&msg).expect(
-                                                                    "SignalingMessage serialization cannot fail",
-                                                                ),
-                                                            ))
-                                                            .await;
-                                                    }
-                                                }
-                                            });
-
-                                            None // Response sent asynchronously
-                                        } else {
-                                            Some(CommandResponse::SilkResponse(
-                                                SilkResponse::Error {
-                                                    session_id: Some(session_id),
-                                                    command_id: Some(command_id),
-                                                    code: "execute_failed".to_string(),
-                                                    message: "No child process created".to_string(),
-                                                },
-                                            ))
-                                        }
-                                    }
-                                    Err(e) => {
-                                        Some(CommandResponse::SilkResponse(SilkResponse::Error {
-                                            session_id: Some(session_id),
-                                            command_id: Some(command_id),
-                                            code: "execute_failed".to_string(),
-                                            message: e,
-                                        }))
-                                    }
-                                }
-                            } else {
-                                Some(CommandResponse::SilkResponse(SilkResponse::Error {
-                                    session_id: Some(session_id),
-                                    command_id: Some(command_id),
-                                    code: "session_not_found".to_string(),
-                                    message: format!("Silk session {} not found", session_id),
-                                }))
-                            }
-                        }
+        match handle_command(request(kill), &ctx).await {
+            Some(CommandResponse::Error { code, .. }) => assert_eq!(code, "session_not_found"),
+            other => panic!("Expected session_not_found, got {:?}", other),
+        }
+    }
 
-                        CommandRequest::SilkInput {
-                            session_id,
-                            command_id,
-                            data,
-                        } => {
-                            let mut silk_sessions = silk_sessions_clone.lock().await;
-                            if let Some(session) = silk_sessions.get_mut(&session_id) {
-                                if let Some(cmd) = session.running_commands.get_mut(&command_id) {
-                                    if let Some(pty_session_id) = cmd.pty_session_id {
-                                        drop(silk_sessions);
-                                        let mut pty_sessions = sessions_clone.lock().await;
-                                        if let Some(pty) = pty_sessions.get_mut(&pty_session_id) {
-                                            if let Err(e) = std::io::Write::write_all(
-                                                &mut pty.writer,
-                                                data.as_bytes(),
-                                            ) {
-                                                Some(CommandResponse::SilkResponse(
-                                                    SilkResponse::Error {
-                                                        session_id: Some(session_id),
-                                                        command_id: Some(command_id),
-                                                        code: "input_failed".to_string(),
-                                                        message: e.to_string(),
-                                                    },
-                                                ))
-                                            } else {
-                                                let _ = std::io::Write::flush(&mut pty.writer);
-                                                None
-                                            }
-                                        } else {
-                                            Some(CommandResponse::SilkResponse(
-                                                SilkResponse::Error {
-                                                    session_id: Some(session_id),
-                                                    command_id: Some(command_id),
-                                                    code: "pty_not_found".to_string(),
-                                                    message: "PTY session not found".to_string(),
-                                                },
-                                            ))
-                                        }
-                                    } else if let Some(ref mut stdin) = cmd.stdin {
-                                        use std::io::Write;
-                                        if let Err(e) = writeln!(stdin, "{}", data) {
-                                            Some(CommandResponse::SilkResponse(
-                                                SilkResponse::Error {
-                                                    session_id: Some(session_id),
-                                                    command_id: Some(command_id),
-                                                    code: "input_failed".to_string(),
-                                                    message: e.to_string(),
-                                                },
-                                            ))
-                                        } else {
-                                            let _ = stdin.flush();
-                                            None
-                                        }
-                                    } else {
-                                        Some(CommandResponse::SilkResponse(SilkResponse::Error {
-                                            session_id: Some(session_id),
-                                            command_id: Some(command_id),
-                                            code: "stdin_closed".to_string(),
-                                            message: "Command stdin is not available"
-                                                .to_string(),
-                                        }))
-                                    }
-                                } else {
-                                    Some(CommandResponse::SilkResponse(SilkResponse::Error {
-                                        session_id: Some(session_id),
-                                        command_id: Some(command_id),
-                                        code: "command_not_found".to_string(),
-                                        message: "Command not found in session".to_string(),
-                                    }))
-                                }
-                            } else {
-                                Some(CommandResponse::SilkResponse(SilkResponse::Error {
-                                    session_id: Some(session_id),
-                                    command_id: Some(command_id),
-                                    code: "session_not_found".to_string(),
-                                    message: format!("Silk session {} not found", session_id),
-                                }))
-                            }
-                        }
+    #[tokio::test]
+    async fn test_terminal_sessions_end_with_their_webrtc_session() {
+        let ctx = test_context();
+        let mut silk_ids = Vec::new();
+        for _ in 0..2 {
+            match handle_command(
+                request(serde_json::json!({"type": "silk_create_session", "shell": "/bin/sh"})),
+                &ctx,
+            )
+            .await
+            {
+                Some(CommandResponse::SilkResponse(SilkResponse::SessionCreated { session_id, .. })) => {
+                    silk_ids.push(session_id)
+                }
+                other => panic!("Expected SessionCreated, got {:?}", other),
+            }
+        }
+        ctx.terminal_sessions
+            .lock()
+            .unwrap()
+            .insert("rtc-1".to_string(), vec![silk_ids[0]]);
+
+        end_terminal_sessions("rtc-2", TERMINAL_CLOSED_REASON, &ctx).await;
+        assert_eq!(ctx.silk_sessions.lock().await.len(), 2);
+
+        end_terminal_sessions("rtc-1", TERMINAL_CLOSED_REASON, &ctx).await;
+        let remaining: Vec<Uuid> = ctx.silk_sessions.lock().await.keys().copied().collect();
+        assert_eq!(remaining, vec![silk_ids[1]]);
+        assert!(ctx.terminal_sessions.lock().unwrap().is_empty());
+    }
 
-                        CommandRequest::SilkResize {
-                            session_id,
-                            command_id,
-                            cols,
-                            rows,
-                        } => {
-                            let silk_sessions = silk_sessions_clone.lock().await;
-                            if let Some(session) = silk_sessions.get(&session_id) {
-                                if let Some(cmd) = session.running_commands.get(&command_id) {
-                                    if let Some(pty_session_id) = cmd.pty_session_id {
-                                        drop(silk_sessions);
-                                        let pty_sessions = sessions_clone.lock().await;
-                                        if let Some(pty) = pty_sessions.get(&pty_session_id) {
-                                            if let Err(e) = pty.pair.master.resize(PtySize {
-                                                rows,
-                                                cols,
-                                                pixel_width: 0,
-                                                pixel_height: 0,
-                                            }) {
-                                                Some(CommandResponse::SilkResponse(
-                                                    SilkResponse::Error {
-                                                        session_id: Some(session_id),
-                                                        command_id: Some(command_id),
-                                                        code: "resize_failed".to_string(),
-                                                        message: e.to_string(),
-                                                    },
-                                                ))
-                                            } else {
-                                                None
-                                            }
-                                        } else {
-                                            None // PTY may have closed already
-                                        }
-                                    } else {
-                                        None // Not interactive, no resize needed
-                                    }
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        }
+    #[tokio::test]
+    async fn test_silk_list_sessions_marks_restored_sessions() {
+        let ctx = test_context();
+        let record = crate::silk_store::SessionRecord {
+            id: Uuid::new_v4(),
+            cwd: "/tmp".to_string(),
+            env: HashMap::new(),
+            shell: "/bin/sh".to_string(),
+            clean_env: false,
+        };
+        let restored = SilkSession::restore(record.clone()).unwrap();
+        ctx.silk_sessions.lock().await.insert(restored.id, restored);
+        handle_command(request(serde_json::json!({"type": "silk_create_session", "shell": "/bin/sh"})), &ctx).await;
 
-                        CommandRequest::SilkCloseSession { session_id } => {
-                            tracing::info!("🧵 Closing Silk session {}", session_id);
-                            let mut silk_sessions = silk_sessions_clone.lock().await;
-                            if silk_sessions.remove(&session_id).is_some() {
-                                Some(CommandResponse::SilkResponse(SilkResponse::SessionClosed {
-                                    session_id,
-                                }))
-                            } else {
-                                Some(CommandResponse::SilkResponse(SilkResponse::Error {
-                                    session_id: Some(session_id),
-                                    command_id: None,
-                                    code: "session_not_found".to_string(),
-                                    message: format!("Silk session {} not found", session_id),
-                                }))
-                            }
-                        }
-                    };
+        let sessions = match handle_command(request(serde_json::json!({"type": "silk_list_sessions"})), &ctx).await {
+            Some(CommandResponse::SilkResponse(SilkResponse::SessionList { sessions })) => sessions,
+            other => panic!("Expected SessionList, got {:?}", other),
+        };
+        assert_eq!(sessions.len(), 2);
+        let entry = sessions.iter().find(|s| s.session_id == record.id).unwrap();
+        assert!(entry.restored);
+        assert_eq!(entry.cwd, "/tmp");
+        assert!(sessions.iter().any(|s| s.session_id != record.id && !s.restored));
+    }
 
-                                if let Some(response) = response {
-                                    let response_msg = SignalingMessage::SyncData {
-                                        payload: serde_json::to_value(&response)
-                                            .expect("CommandResponse serialization cannot fail"),
-                                    };
+    #[tokio::test]
+    async fn test_reconcile_disk_secret_moves_stale_identity_aside() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_path = dir.path().join(".secret");
+        let device_id_path = dir.path().join(".device_id");
+        tokio::fs::write(&secret_path, "old-secret\n").await.unwrap();
+        tokio::fs::write(&device_id_path, "old-device").await.unwrap();
+
+        let err = reconcile_disk_secret("new-secret", "COCOON_SECRET", &secret_path, &device_id_path, true)
+            .await
+            .unwrap_err();
+        assert!(err.contains("COCOON_STRICT_SECRET"));
+        assert!(device_id_path.exists());
+
+        reconcile_disk_secret("old-secret", "COCOON_SECRET", &secret_path, &device_id_path, false)
+            .await
+            .unwrap();
+        assert!(device_id_path.exists());
+
+        reconcile_disk_secret("new-secret", "COCOON_SECRET", &secret_path, &device_id_path, false)
+            .await
+            .unwrap();
+        assert!(!secret_path.exists());
+        assert!(!device_id_path.exists());
+        assert_eq!(
+            tokio::fs::read_to_string(dir.path().join(".secret.stale")).await.unwrap(),
+            "old-secret\n"
+        );
+    }
 
-                                    let mut w = writer_clone.lock().await;
-                                    if let Err(e) = w
-                                        .send(Message::Text(
-                                            serde_json::to_string(&response_msg)
-                                                .expect("SignalingMessage serialization cannot fail"),
-                                        ))
-                                        .await
-                                    {
-                                        tracing::error!("❌ Failed to send response: {}", e);
-                                    }
-                                }
-                            });
-                        }
+    #[tokio::test]
+    async fn test_read_credential_trims_and_skips_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        tokio::fs::write(dir.path().join(SECRET_CREDENTIAL), "s3cr3t-value\n").await.unwrap();
+        tokio::fs::write(dir.path().join(DEVICE_ID_CREDENTIAL), "  \n").await.unwrap();
 
-                    SignalingMessage::DevicePeerConnected { peer_id } => {
-                        tracing::info!("👋 Peer connected: {}", peer_id);
-                    }
+        assert_eq!(
+            read_credential_in(dir.path(), SECRET_CREDENTIAL).await.as_deref(),
+            Some("s3cr3t-value")
+        );
+        assert_eq!(read_credential_in(dir.path(), DEVICE_ID_CREDENTIAL).await, None);
+        assert_eq!(read_credential_in(dir.path(), "missing").await, None);
+    }
 
-                    SignalingMessage::DevicePeerDisconnected { peer_id } => {
-                        tracing::info!("👋 Peer disconnected: {}", peer_id);
-                    }
+    #[tokio::test]
+    async fn test_silk_rejects_duplicate_running_command_id() {
+        let ctx = test_context();
+        let session_id = match handle_command(
+            request(serde_json::json!({"type": "silk_create_session", "shell": "/bin/sh"})),
+            &ctx,
+        )
+        .await
+        {
+            Some(CommandResponse::SilkResponse(SilkResponse::SessionCreated { session_id, .. })) => {
+                session_id
+            }
+            other => panic!("Expected SessionCreated, got {:?}", other),
+        };
+        let execute = |command: &str| {
+            request(serde_json::json!({
+                "type": "silk_execute",
+                "session_id": session_id,
+                "command": command,
+                "command_id": "cmd-1",
+            }))
+        };
 
-                    SignalingMessage::SystemError { message } => {
-                        tracing::error!("❌ Server error: {}", message);
-                    }
+        assert!(handle_command(execute("sleep 1"), &ctx).await.is_none());
+        match handle_command(execute("echo clobber"), &ctx).await {
+            Some(CommandResponse::SilkResponse(SilkResponse::Error { code, command_id, .. })) => {
+                assert_eq!(code, DUPLICATE_COMMAND_ID);
+                assert_eq!(command_id.as_deref(), Some("cmd-1"));
+            }
+            other => panic!("Expected duplicate_command_id error, got {:?}", other),
+        }
 
-                    _ => {
-                        tracing::debug!("📨 Other message: {:?}", message);
-                    }
-                }
+        // Once the first command completes its id may be reused
+        for _ in 0..50 {
+            if !ctx.silk_sessions.lock().await[&session_id].is_running("cmd-1") {
+                break;
             }
+            tokio::time::sleep(Duration::from_millis(100)).await;
         }
+        assert!(handle_command(execute("true"), &ctx).await.is_none());
     }
 
-    tracing::info!("🐛 Cocoon shutting down");
-    Ok(())
+    #[test]
+    fn test_terminal_payload_defaults_silk_output_to_its_session() {
+        let payload = terminal_payload(
+            r#"{"type":"silk_execute","session_id":"00000000-0000-0000-0000-000000000000","command":"ls","command_id":"c1"}"#,
+            "rtc-1",
+        )
+        .unwrap();
+        assert_eq!(payload["webrtc_session_id"], "rtc-1");
+        assert!(matches!(
+            serde_json::from_value(payload).unwrap(),
+            CommandRequest::SilkExecute { webrtc_session_id: Some(id), .. } if id == "rtc-1"
+        ));
+
+        let named = terminal_payload(r#"{"type":"silk_execute","webrtc_session_id":"rtc-2"}"#, "rtc-1").unwrap();
+        assert_eq!(named["webrtc_session_id"], "rtc-2");
+
+        let execute = terminal_payload(r#"{"type":"execute","command":"ls"}"#, "rtc-1").unwrap();
+        assert!(execute.get("webrtc_session_id").is_none());
+
+        assert!(terminal_payload("ls -la", "rtc-1").unwrap_err().starts_with("not JSON"));
+    }
 }
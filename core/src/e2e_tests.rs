@@ -218,7 +218,7 @@ mod test_signaling {
 
 // ── Test helpers ────────────────────────────────────────────────────────────
 
-const TEST_SECRET: &str = "xK9mP2qR7wL4nJ6vB8cT3fY5hA0gD1eS_rUn";
+pub(crate) const TEST_SECRET: &str = "xK9mP2qR7wL4nJ6vB8cT3fY5hA0gD1eS_rUn";
 
 async fn ws_connect(
     url: &str,
@@ -1135,6 +1135,7 @@ async fn test_silk_command_execution_e2e() {
             cols: Some(80),
             rows: Some(24),
             env: None,
+            force_interactive: None,
         })
         .await;
 
@@ -1220,6 +1221,7 @@ async fn test_silk_session_close_e2e() {
     match harness.recv_silk().await {
         CocoonMessage::SilkSessionClosed {
             session_id: closed_id,
+            ..
         } => {
             assert_eq!(closed_id, session_id);
         }
@@ -1243,6 +1245,7 @@ async fn test_silk_execute_nonexistent_session_e2e() {
             cols: Some(80),
             rows: Some(24),
             env: None,
+            force_interactive: None,
         })
         .await;
 
@@ -1790,6 +1793,7 @@ async fn test_silk_command_nonzero_exit_e2e() {
             cols: Some(80),
             rows: Some(24),
             env: None,
+            force_interactive: None,
         })
         .await;
 
@@ -1846,6 +1850,7 @@ async fn test_silk_command_stderr_e2e() {
             cols: Some(80),
             rows: Some(24),
             env: None,
+            force_interactive: None,
         })
         .await;
 
@@ -1908,6 +1913,7 @@ async fn test_silk_multiple_commands_sequential_e2e() {
                 cols: Some(80),
                 rows: Some(24),
                 env: None,
+                force_interactive: None,
             })
             .await;
 
@@ -0,0 +1,113 @@
+//! Cocoon-originated events on one stream
+//!
+//! Subsystems publish to a topic and the signaling loop forwards every event
+//! on a subscribed topic as an `event` response, so a client gets session,
+//! WebRTC and health changes from one subscription instead of watching each
+//! subsystem's own messages. Subscriptions are per cocoon, like every other
+//! response on the signaling link. Publishing to a topic nobody subscribed
+//! to is a no-op.
+
+use serde_json::Value as JsonValue;
+use std::collections::BTreeSet;
+use tokio::sync::broadcast;
+
+/// Topics a client may subscribe to
+pub const TOPICS: &[&str] = &["sessions", "webrtc", "tasks", "updates", "health"];
+
+/// Subscribes to every topic
+pub const WILDCARD: &str = "*";
+
+/// Events buffered for the forwarder before the oldest are dropped
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub topic: &'static str,
+    pub data: JsonValue,
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<Event>,
+    subscribed: std::sync::Mutex<BTreeSet<&'static str>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            tx: broadcast::channel(CAPACITY).0,
+            subscribed: std::sync::Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// Receiver for the forwarding task
+    pub fn receiver(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        self.subscribed.lock().unwrap().contains(topic)
+    }
+
+    pub fn publish(&self, topic: &'static str, data: JsonValue) {
+        if self.is_subscribed(topic) {
+            let _ = self.tx.send(Event { topic, data });
+        }
+    }
+
+    /// Add `topics` (or all of them for `*`); returns the subscribed set
+    pub fn subscribe(&self, topics: &[String]) -> Result<Vec<String>, String> {
+        let resolved = resolve(topics)?;
+        let mut subscribed = self.subscribed.lock().unwrap();
+        subscribed.extend(resolved);
+        Ok(subscribed.iter().map(|t| t.to_string()).collect())
+    }
+
+    /// Remove `topics` (or all of them for `*`); returns what is still subscribed
+    pub fn unsubscribe(&self, topics: &[String]) -> Result<Vec<String>, String> {
+        let resolved = resolve(topics)?;
+        let mut subscribed = self.subscribed.lock().unwrap();
+        subscribed.retain(|t| !resolved.contains(t));
+        Ok(subscribed.iter().map(|t| t.to_string()).collect())
+    }
+}
+
+fn resolve(topics: &[String]) -> Result<Vec<&'static str>, String> {
+    let mut resolved = Vec::new();
+    for topic in topics {
+        if topic == WILDCARD {
+            resolved.extend_from_slice(TOPICS);
+            continue;
+        }
+        match TOPICS.iter().find(|t| **t == topic.as_str()) {
+            Some(t) => resolved.push(*t),
+            None => return Err(format!("unknown topic '{}' (expected one of: {}, or *)", topic, TOPICS.join(", "))),
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_only_subscribed_topics_are_delivered() {
+        let bus = EventBus::new();
+        let mut rx = bus.receiver();
+
+        assert_eq!(bus.subscribe(&["*".into()]).unwrap().len(), TOPICS.len());
+        assert_eq!(
+            bus.unsubscribe(&["tasks".into(), "updates".into()]).unwrap(),
+            vec!["health", "sessions", "webrtc"]
+        );
+        assert!(bus.subscribe(&["nope".into()]).is_err());
+
+        bus.publish("tasks", serde_json::json!({"n": 1}));
+        bus.publish("sessions", serde_json::json!({"n": 2}));
+
+        let event = rx.recv().await.unwrap();
+        assert_eq!(event.topic, "sessions");
+        assert_eq!(event.data["n"], 2);
+        assert!(rx.try_recv().is_err());
+    }
+}
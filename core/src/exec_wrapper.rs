@@ -0,0 +1,71 @@
+//! Operator-configured wrapper around every command the cocoon runs
+//!
+//! `COCOON_EXEC_WRAPPER` is a template such as `nice -n 10 {cmd}` or
+//! `firejail --quiet {cmd}`. Before a client's command reaches the shell, `{cmd}`
+//! is replaced with `<shell> -c '<command>'`, single-quoted so pipes, redirects
+//! and quotes in the command keep their meaning inside the wrapper. A template
+//! without `{cmd}` is a plain prefix and the command is appended to it. The
+//! result still runs under `<shell> -c`, so the template itself may use shell
+//! syntax. Applies to `execute`, `pipeline` stages, PTYs and Silk commands.
+
+use lib_env_parse::{env_opt, env_vars};
+use std::borrow::Cow;
+
+env_vars! {
+    CocoonExecWrapper => "COCOON_EXEC_WRAPPER",
+}
+
+const PLACEHOLDER: &str = "{cmd}";
+
+/// The configured wrapper template, if any
+pub fn template() -> Option<String> {
+    env_opt(EnvVar::CocoonExecWrapper.as_str()).filter(|t| !t.trim().is_empty())
+}
+
+/// `command` wrapped by `COCOON_EXEC_WRAPPER`, to be run as `<shell> -c <result>`
+pub fn wrap<'a>(shell: &str, command: &'a str) -> Cow<'a, str> {
+    match template() {
+        Some(template) => Cow::Owned(wrap_with(&template, shell, command)),
+        None => Cow::Borrowed(command),
+    }
+}
+
+fn wrap_with(template: &str, shell: &str, command: &str) -> String {
+    let inner = format!("{} -c {}", quote(shell), quote(command));
+    if template.contains(PLACEHOLDER) {
+        template.replace(PLACEHOLDER, &inner)
+    } else {
+        format!("{} {}", template.trim_end(), inner)
+    }
+}
+
+/// Single-quote `s` for a POSIX shell
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(script: &str) -> String {
+        let output = std::process::Command::new("/bin/sh").arg("-c").arg(script).output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_wrapped_command_keeps_shell_syntax() {
+        let command = r#"printf '%s\n' "it's" | tr a-z A-Z; echo $((1 + 2))"#;
+        assert_eq!(run(command), "IT'S\n3\n");
+
+        let placeholder = wrap_with("env WRAPPED=1 {cmd}", "/bin/sh", command);
+        assert_eq!(placeholder, format!("env WRAPPED=1 '/bin/sh' -c {}", quote(command)));
+        assert_eq!(run(&placeholder), "IT'S\n3\n");
+
+        let prefix = wrap_with("nice -n 5 ", "/bin/sh", command);
+        assert_eq!(run(&prefix), "IT'S\n3\n");
+
+        let wrapper_sees_command = wrap_with("echo begin; {cmd}; echo end", "/bin/sh", "echo 'a b'");
+        assert_eq!(run(&wrapper_sees_command), "begin\na b\nend\n");
+    }
+}
@@ -1,7 +1,11 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
 use walkdir::WalkDir;
 
 /// File system request messages (from web client)
@@ -35,6 +39,26 @@ pub enum FileSystemRequest {
         #[serde(default)]
         pattern: Option<String>,
     },
+
+    /// One piece of a file too large for `fs_read_file`
+    FsReadChunk {
+        request_id: String,
+        path: String,
+        offset: u64,
+        length: u64,
+    },
+
+    /// One piece of an upload; `transfer_id` ties the chunks of a file together
+    FsWriteChunk {
+        request_id: String,
+        transfer_id: String,
+        path: String,
+        offset: u64,
+        /// Base64-encoded bytes
+        data: String,
+        #[serde(default, rename = "final")]
+        is_final: bool,
+    },
 }
 
 /// File system response messages (to web client)
@@ -53,6 +77,9 @@ pub enum FileSystemResponse {
         content: String,
         encoding: String, // "utf8" or "base64"
         total_size: u64,
+        mime_type: String,
+        /// Content looks binary; render or download it rather than showing text
+        is_binary: bool,
     },
 
     FsFileStat {
@@ -68,6 +95,26 @@ pub enum FileSystemResponse {
         truncated: bool,
     },
 
+    FsChunk {
+        request_id: String,
+        path: String,
+        offset: u64,
+        /// Base64-encoded bytes
+        data: String,
+        length: u64,
+        total_size: u64,
+        eof: bool,
+    },
+
+    FsChunkWritten {
+        request_id: String,
+        transfer_id: String,
+        /// Bytes written so far by this transfer
+        bytes_written: u64,
+        #[serde(rename = "final")]
+        is_final: bool,
+    },
+
     FsError {
         request_id: String,
         code: String,
@@ -144,7 +191,86 @@ fn is_text_file(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
-pub async fn handle_request(request: FileSystemRequest) -> FileSystemResponse {
+const EXTENSION_MIME_TYPES: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("log", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("ts", "text/typescript"),
+    ("tsx", "text/typescript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("svg", "image/svg+xml"),
+    ("sh", "application/x-sh"),
+    ("sql", "application/sql"),
+];
+
+/// Sniff a MIME type from the file's magic bytes, falling back to its extension
+fn sniff_mime_type(path: &Path, data: &[u8], binary: bool) -> String {
+    if let Some(kind) = infer::get(data) {
+        return kind.mime_type().to_string();
+    }
+
+    let by_extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .and_then(|ext| {
+            EXTENSION_MIME_TYPES
+                .iter()
+                .find(|(e, _)| *e == ext)
+                .map(|(_, mime)| mime.to_string())
+        });
+
+    match by_extension {
+        Some(mime) => mime,
+        None if binary => "application/octet-stream".to_string(),
+        None => "text/plain".to_string(),
+    }
+}
+
+/// Largest chunk `fs_read_chunk` returns; longer requests get this much
+const MAX_CHUNK_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Uploads in progress on one WebRTC session, by transfer id. Chunks go to a
+/// `<target>.<uuid>.part` file next to the target, renamed over it on the final
+/// chunk, so an abandoned upload never leaves a half-written file under the real
+/// name and two uploads to the same path don't share a temp file. The session
+/// calls `abandon_all` when it closes; dropping this removes anything left over.
+#[derive(Debug, Default)]
+pub struct FileTransfers {
+    writes: Mutex<HashMap<String, OpenWrite>>,
+}
+
+#[derive(Debug)]
+struct OpenWrite {
+    path: String,
+    part_path: PathBuf,
+    file: fs::File,
+    written: u64,
+}
+
+impl Drop for FileTransfers {
+    fn drop(&mut self) {
+        for write in self.writes.get_mut().values() {
+            tracing::info!("🗑️ Abandoning upload of {} after {} bytes", write.path, write.written);
+            let _ = std::fs::remove_file(&write.part_path);
+        }
+    }
+}
+
+pub async fn handle_request(request: FileSystemRequest, transfers: &FileTransfers) -> FileSystemResponse {
     match request {
         FileSystemRequest::FsListDir { request_id, path } => {
             list_directory(&request_id, &path).await
@@ -164,6 +290,175 @@ pub async fn handle_request(request: FileSystemRequest) -> FileSystemResponse {
             max_depth,
             pattern,
         } => walk_directory(&request_id, &path, max_depth, pattern).await,
+        FileSystemRequest::FsReadChunk {
+            request_id,
+            path,
+            offset,
+            length,
+        } => read_chunk(&request_id, &path, offset, length).await,
+        FileSystemRequest::FsWriteChunk {
+            request_id,
+            transfer_id,
+            path,
+            offset,
+            data,
+            is_final,
+        } => {
+            transfers
+                .write_chunk(&request_id, transfer_id, path, offset, &data, is_final)
+                .await
+        }
+    }
+}
+
+fn fs_error(request_id: &str, code: &str, message: impl Into<String>) -> FileSystemResponse {
+    FileSystemResponse::FsError {
+        request_id: request_id.to_string(),
+        code: code.to_string(),
+        message: message.into(),
+    }
+}
+
+async fn read_chunk(request_id: &str, path: &str, offset: u64, length: u64) -> FileSystemResponse {
+    tracing::debug!("Reading chunk: {} (offset: {}, length: {})", path, offset, length);
+
+    let mut file = match fs::File::open(path).await {
+        Ok(f) => f,
+        Err(e) => return fs_error(request_id, &error_code(&e), e.to_string()),
+    };
+    let total_size = match file.metadata().await {
+        Ok(m) if m.is_file() => m.len(),
+        Ok(_) => return fs_error(request_id, "not_a_file", "Path is not a file"),
+        Err(e) => return fs_error(request_id, &error_code(&e), e.to_string()),
+    };
+
+    let length = length.min(MAX_CHUNK_BYTES);
+    let mut data = Vec::with_capacity(length.min(total_size.saturating_sub(offset)) as usize);
+    let read = async {
+        file.seek(SeekFrom::Start(offset)).await?;
+        (&mut file).take(length).read_to_end(&mut data).await
+    };
+    if let Err(e) = read.await {
+        tracing::error!("Failed to read chunk of {}: {}", path, e);
+        return fs_error(request_id, &error_code(&e), e.to_string());
+    }
+
+    let length = data.len() as u64;
+    FileSystemResponse::FsChunk {
+        request_id: request_id.to_string(),
+        path: path.to_string(),
+        offset,
+        data: base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &data),
+        length,
+        total_size,
+        eof: offset.saturating_add(length) >= total_size,
+    }
+}
+
+impl FileTransfers {
+    /// Drop every upload in progress and remove its `.part` file
+    pub async fn abandon_all(&self) {
+        let abandoned: Vec<OpenWrite> = self.writes.lock().await.drain().map(|(_, write)| write).collect();
+        for write in abandoned {
+            tracing::info!("🗑️ Abandoning upload of {} after {} bytes", write.path, write.written);
+            drop(write.file);
+            let _ = fs::remove_file(&write.part_path).await;
+        }
+    }
+
+    /// Append one chunk to the upload `transfer_id`, starting it when `offset`
+    /// is 0. Chunks must arrive in order; the final one makes the file visible.
+    async fn write_chunk(
+        &self,
+        request_id: &str,
+        transfer_id: String,
+        path: String,
+        offset: u64,
+        data: &str,
+        is_final: bool,
+    ) -> FileSystemResponse {
+        let bytes = match base64::Engine::decode(&base64::engine::general_purpose::STANDARD, data) {
+            Ok(b) => b,
+            Err(e) => return fs_error(request_id, "invalid_data", format!("Chunk is not valid base64: {}", e)),
+        };
+
+        let mut writes = self.writes.lock().await;
+        if !writes.contains_key(&transfer_id) {
+            if offset != 0 {
+                return fs_error(
+                    request_id,
+                    "unknown_transfer",
+                    format!("No upload {} in progress; start it at offset 0", transfer_id),
+                );
+            }
+            let part_path = PathBuf::from(format!("{}.{}.part", path, uuid::Uuid::new_v4().simple()));
+            let file = match fs::OpenOptions::new().write(true).create_new(true).open(&part_path).await {
+                Ok(f) => f,
+                Err(e) => {
+                    tracing::error!("Failed to start upload of {}: {}", path, e);
+                    return fs_error(request_id, &error_code(&e), e.to_string());
+                }
+            };
+            tracing::info!("📤 Upload {} started: {}", transfer_id, path);
+            writes.insert(
+                transfer_id.clone(),
+                OpenWrite {
+                    path: path.clone(),
+                    part_path,
+                    file,
+                    written: 0,
+                },
+            );
+        }
+
+        let Some(write) = writes.get_mut(&transfer_id) else {
+            return fs_error(request_id, "unknown_transfer", format!("No upload {} in progress", transfer_id));
+        };
+        if write.path != path {
+            return fs_error(
+                request_id,
+                "transfer_mismatch",
+                format!("Upload {} is writing {}, not {}", transfer_id, write.path, path),
+            );
+        }
+        if offset != write.written {
+            return fs_error(
+                request_id,
+                "unexpected_offset",
+                format!("Upload {} expects offset {}, got {}", transfer_id, write.written, offset),
+            );
+        }
+        if let Err(e) = write.file.write_all(&bytes).await {
+            tracing::error!("Failed to write chunk of {}: {}", path, e);
+            return fs_error(request_id, &error_code(&e), e.to_string());
+        }
+        write.written += bytes.len() as u64;
+        let bytes_written = write.written;
+
+        if is_final {
+            let Some(mut write) = writes.remove(&transfer_id) else {
+                return fs_error(request_id, "unknown_transfer", format!("No upload {} in progress", transfer_id));
+            };
+            drop(writes);
+            let finished = async {
+                write.file.flush().await?;
+                write.file.sync_all().await?;
+                fs::rename(&write.part_path, &write.path).await
+            };
+            if let Err(e) = finished.await {
+                tracing::error!("Failed to finish upload of {}: {}", path, e);
+                let _ = fs::remove_file(&write.part_path).await;
+                return fs_error(request_id, &error_code(&e), e.to_string());
+            }
+            tracing::info!("📥 Upload {} finished: {} ({} bytes)", transfer_id, path, bytes_written);
+        }
+
+        FileSystemResponse::FsChunkWritten {
+            request_id: request_id.to_string(),
+            transfer_id,
+            bytes_written,
+            is_final,
+        }
     }
 }
 
@@ -278,7 +573,12 @@ async fn read_file(
             let end = std::cmp::min(start + limit as usize, content.len());
             let slice = &content[start..end];
 
-            let (encoded_content, encoding) = if is_binary_content(slice) || !is_text_file(file_path) {
+            // A multi-byte char cut off by `limit` doesn't make the file binary
+            let invalid_utf8 = matches!(std::str::from_utf8(slice), Err(e) if e.error_len().is_some());
+            let is_binary = is_binary_content(&content) || invalid_utf8;
+            let mime_type = sniff_mime_type(file_path, &content, is_binary);
+
+            let (encoded_content, encoding) = if is_binary || !is_text_file(file_path) {
                 (base64::Engine::encode(&base64::engine::general_purpose::STANDARD, slice), "base64".to_string())
             } else {
                 match String::from_utf8(slice.to_vec()) {
@@ -295,6 +595,8 @@ async fn read_file(
                 content: encoded_content,
                 encoding,
                 total_size,
+                mime_type,
+                is_binary,
             }
         }
         Err(e) => {
@@ -460,7 +762,7 @@ mod tests {
             path: dir_path.to_string_lossy().to_string(),
         };
 
-        let response = handle_request(request).await;
+        let response = handle_request(request, &FileTransfers::default()).await;
 
         match response {
             FileSystemResponse::FsDirListing { entries, .. } => {
@@ -489,12 +791,40 @@ mod tests {
             limit: None,
         };
 
-        let response = handle_request(request).await;
+        let response = handle_request(request, &FileTransfers::default()).await;
 
         match response {
-            FileSystemResponse::FsFileContent { content: read_content, encoding, .. } => {
+            FileSystemResponse::FsFileContent { content: read_content, encoding, mime_type, is_binary, .. } => {
                 assert_eq!(encoding, "utf8");
                 assert_eq!(read_content, content);
+                assert_eq!(mime_type, "text/plain");
+                assert!(!is_binary);
+            }
+            _ => panic!("Expected FsFileContent response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_binary_file_sniffs_mime() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("image.dat");
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let mut file = File::create(&file_path).await.unwrap();
+        file.write_all(png).await.unwrap();
+
+        let request = FileSystemRequest::FsReadFile {
+            request_id: "test-5".to_string(),
+            path: file_path.to_string_lossy().to_string(),
+            offset: None,
+            limit: None,
+        };
+
+        match handle_request(request, &FileTransfers::default()).await {
+            FileSystemResponse::FsFileContent { encoding, mime_type, is_binary, .. } => {
+                assert_eq!(encoding, "base64");
+                assert_eq!(mime_type, "image/png");
+                assert!(is_binary);
             }
             _ => panic!("Expected FsFileContent response"),
         }
@@ -513,7 +843,7 @@ mod tests {
             path: file_path.to_string_lossy().to_string(),
         };
 
-        let response = handle_request(request).await;
+        let response = handle_request(request, &FileTransfers::default()).await;
 
         match response {
             FileSystemResponse::FsFileStat { stat, .. } => {
@@ -532,7 +862,7 @@ mod tests {
             path: "/nonexistent/path/that/does/not/exist".to_string(),
         };
 
-        let response = handle_request(request).await;
+        let response = handle_request(request, &FileTransfers::default()).await;
 
         match response {
             FileSystemResponse::FsError { code, .. } => {
@@ -541,4 +871,121 @@ mod tests {
             _ => panic!("Expected FsError response"),
         }
     }
+
+    #[tokio::test]
+    async fn test_chunked_upload_then_download() {
+        use base64::Engine;
+        let b64 = base64::engine::general_purpose::STANDARD;
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("upload.bin").to_string_lossy().to_string();
+        let transfers = FileTransfers::default();
+        let chunk = |offset: u64, data: &[u8], is_final: bool| FileSystemRequest::FsWriteChunk {
+            request_id: format!("w-{}", offset),
+            transfer_id: "t1".to_string(),
+            path: path.clone(),
+            offset,
+            data: b64.encode(data),
+            is_final,
+        };
+
+        match handle_request(chunk(0, b"hello ", false), &transfers).await {
+            FileSystemResponse::FsChunkWritten { bytes_written, is_final, .. } => {
+                assert_eq!(bytes_written, 6);
+                assert!(!is_final);
+            }
+            other => panic!("Expected FsChunkWritten, got {:?}", other),
+        }
+        // Nothing under the real name until the final chunk
+        assert!(!Path::new(&path).exists());
+
+        match handle_request(chunk(3, b"oops", false), &transfers).await {
+            FileSystemResponse::FsError { code, .. } => assert_eq!(code, "unexpected_offset"),
+            other => panic!("Expected FsError, got {:?}", other),
+        }
+        match handle_request(chunk(6, b"world", true), &transfers).await {
+            FileSystemResponse::FsChunkWritten { bytes_written, is_final, .. } => {
+                assert_eq!(bytes_written, 11);
+                assert!(is_final);
+            }
+            other => panic!("Expected FsChunkWritten, got {:?}", other),
+        }
+        assert_eq!(fs::read(&path).await.unwrap(), b"hello world");
+        assert!(transfers.writes.lock().await.is_empty());
+
+        let read = FileSystemRequest::FsReadChunk {
+            request_id: "r-1".to_string(),
+            path: path.clone(),
+            offset: 6,
+            length: 100,
+        };
+        match handle_request(read, &transfers).await {
+            FileSystemResponse::FsChunk { data, length, total_size, eof, .. } => {
+                assert_eq!(b64.decode(data).unwrap(), b"world");
+                assert_eq!(length, 5);
+                assert_eq!(total_size, 11);
+                assert!(eof);
+            }
+            other => panic!("Expected FsChunk, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_upload_is_removed() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("big.iso").to_string_lossy().to_string();
+        let transfers = FileTransfers::default();
+        let request = FileSystemRequest::FsWriteChunk {
+            request_id: "w-0".to_string(),
+            transfer_id: "t2".to_string(),
+            path: path.clone(),
+            offset: 0,
+            data: "AAAA".to_string(),
+            is_final: false,
+        };
+        handle_request(request, &transfers).await;
+        assert_eq!(part_files(dir.path()).len(), 1);
+
+        drop(transfers);
+        assert!(part_files(dir.path()).is_empty());
+        assert!(!Path::new(&path).exists());
+    }
+
+    fn part_files(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|p| p.to_string_lossy().ends_with(".part"))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_abandon_all_keeps_unrelated_part_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notes.txt").to_string_lossy().to_string();
+        let unrelated = format!("{}.part", path);
+        std::fs::write(&unrelated, b"someone else's").unwrap();
+
+        let transfers = FileTransfers::default();
+        for transfer_id in ["a", "b"] {
+            let request = FileSystemRequest::FsWriteChunk {
+                request_id: format!("w-{}", transfer_id),
+                transfer_id: transfer_id.to_string(),
+                path: path.clone(),
+                offset: 0,
+                data: "AAAA".to_string(),
+                is_final: false,
+            };
+            match handle_request(request, &transfers).await {
+                FileSystemResponse::FsChunkWritten { bytes_written, .. } => assert_eq!(bytes_written, 3),
+                other => panic!("Expected FsChunkWritten, got {:?}", other),
+            }
+        }
+        // Each upload has its own temp file, and the existing `.part` is untouched
+        assert_eq!(part_files(dir.path()).len(), 3);
+
+        transfers.abandon_all().await;
+        assert!(transfers.writes.lock().await.is_empty());
+        assert_eq!(part_files(dir.path()), vec![PathBuf::from(&unrelated)]);
+        assert_eq!(std::fs::read(&unrelated).unwrap(), b"someone else's");
+    }
 }
@@ -0,0 +1,329 @@
+//! Local liveness endpoint for fleet monitoring
+//!
+//! With `COCOON_HEALTH_PORT` set the cocoon serves three routes on
+//! `127.0.0.1:PORT`: `/healthz` answers 200 while the signaling link is up and
+//! registered and 503 otherwise, `/status` adds the connection uptime,
+//! reconnect count and open PTY/Silk/WebRTC sessions, and `/metrics` has all of
+//! that and the `get_metrics` counters in the Prometheus text format. Each
+//! carries the `device_id`, so a scraper can tell cocoons on one host apart.
+
+use lib_env_parse::{env_opt, env_vars};
+use serde::Serialize;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+env_vars! {
+    CocoonHealthPort => "COCOON_HEALTH_PORT",
+}
+
+/// The signaling link as the health endpoint reports it
+#[derive(Debug, Default)]
+struct LinkState {
+    connected_since: Option<Instant>,
+    registered: bool,
+    device_id: Option<String>,
+    connections: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthStatus {
+    /// `ok`, `unregistered` or `disconnected`
+    pub status: &'static str,
+    pub device_id: Option<String>,
+    pub version: &'static str,
+    pub connected: bool,
+    pub registered: bool,
+    /// Seconds since the current signaling connection was made
+    pub connection_uptime_secs: Option<u64>,
+    /// Connections made after the first one
+    pub reconnects: u64,
+}
+
+impl LinkState {
+    fn status(&self, now: Instant) -> HealthStatus {
+        let connected = self.connected_since.is_some();
+        let registered = connected && self.registered;
+        HealthStatus {
+            status: match (connected, registered) {
+                (true, true) => "ok",
+                (true, false) => "unregistered",
+                (false, _) => "disconnected",
+            },
+            device_id: self.device_id.clone(),
+            version: env!("CARGO_PKG_VERSION"),
+            connected,
+            registered,
+            connection_uptime_secs: self
+                .connected_since
+                .map(|since| now.saturating_duration_since(since).as_secs()),
+            reconnects: self.connections.saturating_sub(1),
+        }
+    }
+}
+
+static LINK: OnceLock<Mutex<LinkState>> = OnceLock::new();
+
+fn with_link<T>(f: impl FnOnce(&mut LinkState) -> T) -> T {
+    let mut link = LINK
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    f(&mut link)
+}
+
+/// A signaling connection was made; registration has not happened yet
+pub fn mark_connected() {
+    with_link(|link| {
+        link.connected_since = Some(Instant::now());
+        link.registered = false;
+        link.connections += 1;
+    });
+}
+
+pub fn mark_registered(device_id: &str) {
+    with_link(|link| {
+        link.registered = true;
+        link.device_id = Some(device_id.to_string());
+    });
+}
+
+pub fn mark_disconnected() {
+    with_link(|link| {
+        link.connected_since = None;
+        link.registered = false;
+    });
+}
+
+pub fn status() -> HealthStatus {
+    with_link(|link| link.status(Instant::now()))
+}
+
+#[derive(Debug, Serialize)]
+struct StatusReport {
+    #[serde(flatten)]
+    status: HealthStatus,
+    uptime_secs: u64,
+    sessions: crate::metrics::Gauges,
+}
+
+/// `value` as a quoted Prometheus label value
+fn label(value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// `snapshot` and `status` in the Prometheus text exposition format
+fn prometheus_text(snapshot: &crate::metrics::MetricsSnapshot, status: &HealthStatus) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{} {}", name, labels, value);
+        }
+    };
+    let plain = |value: u64| [(String::new(), value)];
+    let counters = &snapshot.counters;
+    let gauges = &snapshot.gauges;
+
+    metric(
+        "cocoon_info",
+        "gauge",
+        "Cocoon version and device id",
+        &[(
+            format!(
+                "{{version={},device_id={}}}",
+                label(status.version),
+                label(status.device_id.as_deref().unwrap_or(""))
+            ),
+            1,
+        )],
+    );
+    metric("cocoon_connected", "gauge", "1 while the signaling connection is up", &plain(status.connected as u64));
+    metric("cocoon_registered", "gauge", "1 while registered with the signaling server", &plain(status.registered as u64));
+    metric("cocoon_reconnects_total", "counter", "Signaling connections made after the first", &plain(status.reconnects));
+    metric(
+        "cocoon_connection_uptime_seconds",
+        "gauge",
+        "Age of the current signaling connection",
+        &plain(status.connection_uptime_secs.unwrap_or(0)),
+    );
+    metric("cocoon_uptime_seconds", "gauge", "Time since the cocoon started", &plain(snapshot.uptime_secs));
+    metric("cocoon_commands_executed_total", "counter", "execute commands and pipelines run", &plain(counters.commands_executed));
+    metric("cocoon_commands_failed_total", "counter", "execute commands and pipelines that did not succeed", &plain(counters.commands_failed));
+    metric("cocoon_pty_sessions_opened_total", "counter", "PTY sessions opened", &plain(counters.pty_sessions_opened));
+    metric("cocoon_webrtc_sessions_opened_total", "counter", "WebRTC sessions opened", &plain(counters.webrtc_sessions_opened));
+    metric("cocoon_silk_commands_total", "counter", "Silk commands run", &plain(counters.silk_commands));
+    metric("cocoon_signaling_bytes_sent_total", "counter", "Bytes sent to the signaling server", &plain(counters.signaling_bytes_sent));
+    metric(
+        "cocoon_signaling_bytes_received_total",
+        "counter",
+        "Bytes received from the signaling server",
+        &plain(counters.signaling_bytes_received),
+    );
+    metric(
+        "cocoon_sessions",
+        "gauge",
+        "Open sessions by type",
+        &[
+            ("{type=\"pty\"}".to_string(), gauges.pty_sessions as u64),
+            ("{type=\"silk\"}".to_string(), gauges.silk_sessions as u64),
+            ("{type=\"webrtc\"}".to_string(), gauges.webrtc_sessions as u64),
+        ],
+    );
+
+    let per_service = |value: fn(&crate::proxy_stats::ServiceProxyStats) -> u64| {
+        snapshot
+            .proxy
+            .iter()
+            .map(|s| (format!("{{service={}}}", label(&s.service)), value(s)))
+            .collect::<Vec<_>>()
+    };
+    metric("cocoon_proxy_requests_total", "counter", "proxy_http requests by service", &per_service(|s| s.requests));
+    metric("cocoon_proxy_errors_total", "counter", "proxy_http requests that failed or got a 5xx", &per_service(|s| s.errors));
+    metric("cocoon_proxy_request_bytes_total", "counter", "Request body bytes proxied to the service", &per_service(|s| s.request_bytes));
+    metric(
+        "cocoon_proxy_response_bytes_total",
+        "counter",
+        "Response body bytes proxied from the service",
+        &per_service(|s| s.response_bytes),
+    );
+    out
+}
+
+/// `COCOON_HEALTH_PORT`, or `None` when it is unset; anything but a port is an error
+pub fn port_from_env() -> Result<Option<u16>, String> {
+    let Some(value) = env_opt(EnvVar::CocoonHealthPort.as_str()).filter(|v| !v.trim().is_empty()) else {
+        return Ok(None);
+    };
+    match value.trim().parse::<u16>() {
+        Ok(port) if port > 0 => Ok(Some(port)),
+        _ => Err(format!("Invalid COCOON_HEALTH_PORT '{}': expected a port from 1 to 65535", value)),
+    }
+}
+
+/// Serve the endpoint in the background; `snapshot` takes the current metrics
+pub fn spawn<F, Fut>(port: u16, snapshot: F)
+where
+    F: Fn() -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = crate::metrics::MetricsSnapshot> + Send + 'static,
+{
+    let for_status = snapshot.clone();
+    let app = axum::Router::new()
+        .route("/healthz", axum::routing::get(healthz_handler))
+        .route(
+            "/status",
+            axum::routing::get(move || {
+                let snapshot = for_status.clone();
+                async move {
+                    let snapshot = snapshot().await;
+                    axum::Json(StatusReport {
+                        status: status(),
+                        uptime_secs: snapshot.uptime_secs,
+                        sessions: snapshot.gauges,
+                    })
+                }
+            }),
+        )
+        .route(
+            "/metrics",
+            axum::routing::get(move || {
+                let snapshot = snapshot.clone();
+                async move {
+                    let body = prometheus_text(&snapshot().await, &status());
+                    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+                }
+            }),
+        );
+
+    tokio::spawn(async move {
+        let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        let listener = match tokio::net::TcpListener::bind(addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("❌ Health endpoint could not bind {}: {}", addr, e);
+                return;
+            }
+        };
+        tracing::info!("🩺 Health endpoint on http://{}/healthz", addr);
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("❌ Health endpoint stopped: {}", e);
+        }
+    });
+}
+
+async fn healthz_handler() -> (axum::http::StatusCode, axum::Json<HealthStatus>) {
+    let status = status();
+    let code = if status.registered {
+        axum::http::StatusCode::OK
+    } else {
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, axum::Json(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_health_status_follows_the_link() {
+        let start = Instant::now();
+        let mut link = LinkState::default();
+        assert_eq!(link.status(start).status, "disconnected");
+
+        link.connected_since = Some(start);
+        link.connections = 1;
+        let status = link.status(start);
+        assert_eq!(status.status, "unregistered");
+        assert!(status.connected && !status.registered);
+
+        link.registered = true;
+        link.device_id = Some("dev-1".to_string());
+        let status = link.status(start + Duration::from_secs(42));
+        assert_eq!(status.status, "ok");
+        assert_eq!(status.connection_uptime_secs, Some(42));
+        assert_eq!(status.reconnects, 0);
+
+        // A dropped link keeps the device id; the next connection is a reconnect
+        link.connected_since = None;
+        link.registered = false;
+        assert_eq!(link.status(start).status, "disconnected");
+        link.connected_since = Some(start);
+        link.connections += 1;
+        let status = link.status(start);
+        assert_eq!(status.reconnects, 1);
+        assert_eq!(status.device_id.as_deref(), Some("dev-1"));
+    }
+
+    #[test]
+    fn test_prometheus_text() {
+        let mut link = LinkState {
+            connected_since: Some(Instant::now()),
+            registered: true,
+            device_id: Some("dev-\"1\"".to_string()),
+            connections: 3,
+        };
+        let gauges = crate::metrics::Gauges {
+            pty_sessions: 2,
+            silk_sessions: 1,
+            webrtc_sessions: 0,
+        };
+        let snapshot = crate::metrics::MetricsSnapshot::new(gauges, Vec::new());
+        let text = prometheus_text(&snapshot, &link.status(Instant::now()));
+
+        assert!(text.contains("# TYPE cocoon_reconnects_total counter\ncocoon_reconnects_total 2\n"), "{}", text);
+        assert!(text.contains("cocoon_registered 1\n"));
+        assert!(text.contains("cocoon_sessions{type=\"pty\"} 2\n"));
+        assert!(text.contains("device_id=\"dev-\\\"1\\\"\""));
+        // No services, so the proxy metrics have no samples
+        assert!(text.contains("# TYPE cocoon_proxy_requests_total counter\n# HELP"));
+
+        link.registered = false;
+        let text = prometheus_text(&snapshot, &link.status(Instant::now()));
+        assert!(text.contains("cocoon_registered 0\n"));
+    }
+}
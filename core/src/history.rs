@@ -0,0 +1,96 @@
+//! Shell history mirror
+//!
+//! With `COCOON_SHELL_HISTORY` set, every command a client runs through
+//! `execute`, `pipeline` or Silk is appended to that file in bash history
+//! format (`#<unix timestamp>` line, then the command), so it can be browsed
+//! or reused with `HISTFILE=... history` while debugging. This is a
+//! convenience for humans, not an audit trail: writes are best-effort and a
+//! failure is only logged.
+//!
+//! Secret env values are replaced with `[redacted]`, and the rest is masked as
+//! in the log (`log_redact`): the cocoon secret, sensitive environment values
+//! and `API_KEY=...` words. Like bash with `HISTCONTROL=ignorespace`, a command
+//! starting with a space is not recorded. Only commands that passed the filter
+//! and rate limit are recorded.
+
+use lib_env_parse::{env_opt, env_vars};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+env_vars! {
+    CocoonShellHistory => "COCOON_SHELL_HISTORY",
+}
+
+/// History file from `COCOON_SHELL_HISTORY`, if mirroring is enabled
+pub fn history_path() -> Option<PathBuf> {
+    env_opt(EnvVar::CocoonShellHistory.as_str())
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Bash history entry for `command`, or `None` if it must not be recorded
+fn format_entry(command: &str, timestamp: i64, secret_env: &HashMap<String, String>) -> Option<String> {
+    if command.starts_with(' ') || command.trim().is_empty() {
+        return None;
+    }
+    let command = crate::core::redact_secrets(command.trim_end(), secret_env);
+    Some(format!("#{}\n{}\n", timestamp, crate::log_redact::redact(&command)))
+}
+
+/// Append `entry` with a single write on an `O_APPEND` descriptor, so entries
+/// from concurrent commands (or another cocoon sharing the file) never interleave
+fn append_entry(path: &Path, entry: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(entry.as_bytes())
+}
+
+/// Mirror `command` to the history file in the background
+pub fn record(command: &str, secret_env: &HashMap<String, String>) {
+    let Some(path) = history_path() else {
+        return;
+    };
+    let Some(entry) = format_entry(command, chrono::Utc::now().timestamp(), secret_env) else {
+        return;
+    };
+
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = append_entry(&path, &entry) {
+            tracing::warn!("⚠️ Could not append to shell history {}: {}", path.display(), e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_bash_history_formatted_and_redacted() {
+        let secret_env: HashMap<String, String> =
+            [("API_TOKEN".to_string(), "tok_abcdef".to_string())].into_iter().collect();
+
+        assert_eq!(
+            format_entry("curl -H 'Authorization: tok_abcdef' api\n", 1700000000, &secret_env).as_deref(),
+            Some("#1700000000\ncurl -H 'Authorization: [redacted]' api\n")
+        );
+        assert_eq!(format_entry(" export API_TOKEN=tok_abcdef", 1700000000, &secret_env), None);
+        assert_eq!(
+            format_entry("API_KEY=sk-live-42 make deploy", 1700000000, &HashMap::new()).as_deref(),
+            Some("#1700000000\nAPI_KEY=s… (10 chars) make deploy\n")
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        append_entry(&path, "#1\nls\n").unwrap();
+        append_entry(&path, "#2\npwd\n").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "#1\nls\n#2\npwd\n");
+    }
+}
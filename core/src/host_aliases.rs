@@ -0,0 +1,202 @@
+//! Extra hostname → address mappings
+//!
+//! `COCOON_HOST_ALIASES` is a comma-separated list of `name=address` pairs, e.g.
+//! `db.internal=10.0.0.5,host.docker.internal=host-gateway`. The address is an
+//! IP, or `host-gateway` for the Docker host. Inside the cocoon the IP aliases
+//! override DNS for proxied requests, so a `COCOON_SERVICES` entry can point at
+//! `name:db.internal:5432`. When a cocoon container is created, every alias
+//! becomes an `--add-host` flag, which makes it visible to everything in the
+//! container, `host-gateway` ones included (on Podman, only from 5.3 on).
+
+use crate::container_engine::ContainerEngine;
+use lib_console_output::out_warn;
+use lib_env_parse::{env_opt, env_vars};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+
+env_vars! {
+    CocoonHostAliases => "COCOON_HOST_ALIASES",
+}
+
+/// Name of the env var, for passing the aliases on to a container
+pub const ENV_VAR: &str = "COCOON_HOST_ALIASES";
+
+/// Docker's placeholder for the host's address on the container network
+const HOST_GATEWAY: &str = "host-gateway";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Ip(IpAddr),
+    HostGateway,
+}
+
+impl Target {
+    fn address(&self) -> String {
+        match self {
+            Target::Ip(ip) => ip.to_string(),
+            Target::HostGateway => HOST_GATEWAY.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostAliases(Vec<(String, Target)>);
+
+impl HostAliases {
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut aliases: Vec<(String, Target)> = Vec::new();
+        for entry in list.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (name, address) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid host alias '{}': expected name=address", entry))?;
+            let name = name.trim().to_ascii_lowercase();
+            if !is_hostname(&name) {
+                return Err(format!("Invalid host alias '{}': '{}' is not a hostname", entry, name));
+            }
+            let address = address.trim();
+            let target = if address == HOST_GATEWAY {
+                Target::HostGateway
+            } else {
+                Target::Ip(address.parse().map_err(|_| {
+                    format!("Invalid host alias '{}': '{}' is not an IP or {}", entry, address, HOST_GATEWAY)
+                })?)
+            };
+            if aliases.iter().any(|(n, _)| *n == name) {
+                return Err(format!("Host alias '{}' is defined twice", name));
+            }
+            aliases.push((name, target));
+        }
+        Ok(Self(aliases))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The aliases in `COCOON_HOST_ALIASES` form, for passing on to a container
+    pub fn env_value(&self) -> String {
+        self.0
+            .iter()
+            .map(|(name, target)| format!("{}={}", name, target.address()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// `builder` with DNS overridden for every IP alias. `host-gateway` aliases
+    /// are left to the container's hosts file.
+    pub fn apply(&self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        for (name, target) in &self.0 {
+            if let Target::Ip(ip) = target {
+                // reqwest takes the port from the URL, this one is ignored
+                builder = builder.resolve(name, SocketAddr::new(*ip, 0));
+            }
+        }
+        builder
+    }
+
+    /// `docker run` flags mapping every alias, plus `host-gateway` for a
+    /// `.local` signaling host that has no alias of its own. Engines without
+    /// `host-gateway` support (Podman before 5.3) get the IP aliases only, and
+    /// a warning naming the ones that were left out.
+    pub fn docker_args(&self, signaling_url: Option<&str>, engine: ContainerEngine) -> Vec<String> {
+        let mut hosts: Vec<(String, Target)> = self.0.clone();
+
+        let signaling_host = signaling_url
+            .and_then(|url| url::Url::parse(url).ok())
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase));
+        if let Some(host) = signaling_host {
+            if host.ends_with(".local") && !self.0.iter().any(|(name, _)| *name == host) {
+                hosts.push((host, Target::HostGateway));
+            }
+        }
+
+        let uses_gateway = hosts.iter().any(|(_, target)| *target == Target::HostGateway);
+        if uses_gateway && !engine.supports_host_gateway() {
+            let (dropped, kept): (Vec<_>, Vec<_>) =
+                hosts.into_iter().partition(|(_, target)| *target == Target::HostGateway);
+            out_warn!(
+                "{} does not support {} here, so {} will not resolve in the container; \
+                 point them at host.containers.internal or an IP instead",
+                engine,
+                HOST_GATEWAY,
+                dropped.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", ")
+            );
+            hosts = kept;
+        }
+
+        hosts
+            .into_iter()
+            .flat_map(|(name, target)| ["--add-host".to_string(), format!("{}:{}", name, target.address())])
+            .collect()
+    }
+}
+
+fn is_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+}
+
+/// Aliases from `COCOON_HOST_ALIASES`; empty when unset
+pub fn from_env() -> Result<HostAliases, String> {
+    env_opt(EnvVar::CocoonHostAliases.as_str())
+        .map(|list| HostAliases::parse(&list))
+        .unwrap_or_else(|| Ok(HostAliases::default()))
+}
+
+/// HTTP client for requests the cocoon makes on a client's behalf. An invalid
+/// alias list is reported at startup, so it counts as empty here.
+pub fn http_client() -> reqwest::Client {
+    static ALIASES: OnceLock<HostAliases> = OnceLock::new();
+    let aliases = ALIASES.get_or_init(|| from_env().unwrap_or_default());
+    aliases
+        .apply(reqwest::Client::builder())
+        .build()
+        .unwrap_or_else(|_| reqwest::Client::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_validates_aliases() {
+        let aliases = HostAliases::parse(" db.internal=10.0.0.5, Host.Docker.Internal=host-gateway,,v6=::1").unwrap();
+        assert_eq!(
+            aliases,
+            HostAliases(vec![
+                ("db.internal".to_string(), Target::Ip("10.0.0.5".parse().unwrap())),
+                ("host.docker.internal".to_string(), Target::HostGateway),
+                ("v6".to_string(), Target::Ip("::1".parse().unwrap())),
+            ])
+        );
+        assert_eq!(aliases.env_value(), "db.internal=10.0.0.5,host.docker.internal=host-gateway,v6=::1");
+        assert!(HostAliases::parse("").unwrap().is_empty());
+
+        for bad in ["db", "db=", "db=not-an-ip", "-db=10.0.0.1", "a..b=10.0.0.1", "db=1.1.1.1,DB=2.2.2.2"] {
+            assert!(HostAliases::parse(bad).is_err(), "{} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_docker_args_generalize_local_signaling_host() {
+        let aliases = HostAliases::parse("db.internal=10.0.0.5").unwrap();
+        assert_eq!(
+            aliases.docker_args(Some("ws://adi.local:8080/ws"), ContainerEngine::Docker),
+            vec!["--add-host", "db.internal:10.0.0.5", "--add-host", "adi.local:host-gateway"]
+        );
+
+        let mapped = HostAliases::parse("adi.local=192.168.1.10").unwrap();
+        assert_eq!(
+            mapped.docker_args(Some("ws://adi.local:8080/ws"), ContainerEngine::Docker),
+            vec!["--add-host", "adi.local:192.168.1.10"]
+        );
+        assert!(HostAliases::default().docker_args(Some("wss://example.com/ws"), ContainerEngine::Docker).is_empty());
+    }
+}
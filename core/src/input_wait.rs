@@ -0,0 +1,162 @@
+//! Detection of commands stuck on an interactive prompt
+//!
+//! `execute` runs commands without a PTY and closes their stdin once the
+//! request's `input` is written. A program that still wants input (a `sudo`
+//! password, an `ssh` host key question) opens the terminal the cocoon was
+//! started from instead and waits there forever, without printing anything to
+//! its pipes. After a grace period without output, the command's process tree
+//! is checked for a process blocked in `read(2)` on a terminal; if one is found
+//! the command is killed and reported as `awaiting_input`. Commands that are
+//! merely slow or silent are never flagged.
+//!
+//! The check is Linux-only: it reads `/proc/<pid>/syscall` and the process's
+//! file descriptors under `/proc`, which other platforms don't have, and the
+//! `read` syscall number it looks for is only known for x86_64 and aarch64.
+
+use std::time::Duration;
+
+/// Error code for a command killed while waiting for interactive input
+pub const AWAITING_INPUT: &str = "awaiting_input";
+
+/// How long a command may go without output before it is checked
+pub const GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+#[cfg(target_arch = "x86_64")]
+const SYS_READ: u64 = 0;
+#[cfg(target_arch = "aarch64")]
+const SYS_READ: u64 = 63;
+
+/// Syscall number and first argument from `/proc/<pid>/syscall`, which reads
+/// `running` (or `-1 ...`) unless the process is blocked in a syscall
+fn parse_syscall(contents: &str) -> Option<(u64, u64)> {
+    let mut fields = contents.split_whitespace();
+    let nr = fields.next()?.parse::<u64>().ok()?;
+    let arg0 = u64::from_str_radix(fields.next()?.trim_start_matches("0x"), 16).ok()?;
+    Some((nr, arg0))
+}
+
+fn is_terminal(path: &str) -> bool {
+    path.starts_with("/dev/pts/") || path.starts_with("/dev/tty") || path == "/dev/console"
+}
+
+/// `root` and all of its descendants
+#[cfg(target_os = "linux")]
+fn process_tree(root: i32) -> Vec<i32> {
+    let mut parents = Vec::new();
+    if let Ok(entries) = std::fs::read_dir("/proc") {
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) else {
+                continue;
+            };
+            let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+                continue;
+            };
+            // The command name may contain spaces or parens, so parse after the last ')'
+            let ppid = stat
+                .rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+                .and_then(|p| p.parse::<i32>().ok());
+            if let Some(ppid) = ppid {
+                parents.push((pid, ppid));
+            }
+        }
+    }
+
+    let mut tree = vec![root];
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i];
+        tree.extend(parents.iter().filter(|(_, ppid)| *ppid == parent).map(|(pid, _)| *pid));
+        i += 1;
+    }
+    tree
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn reading_terminal(pid: i32) -> bool {
+    let Some((nr, fd)) = std::fs::read_to_string(format!("/proc/{}/syscall", pid))
+        .ok()
+        .as_deref()
+        .and_then(parse_syscall)
+    else {
+        return false;
+    };
+    nr == SYS_READ
+        && std::fs::read_link(format!("/proc/{}/fd/{}", pid, fd))
+            .is_ok_and(|target| is_terminal(&target.to_string_lossy()))
+}
+
+/// Whether any process in the tree rooted at `pid` is blocked reading a terminal
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn awaiting_input(pid: u32) -> bool {
+    process_tree(pid as i32).into_iter().any(reading_terminal)
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn awaiting_input(_pid: u32) -> bool {
+    false
+}
+
+/// SIGKILL the tree rooted at `pid`, so a prompting grandchild does not outlive its shell
+#[cfg(target_os = "linux")]
+pub fn kill_tree(pid: u32) {
+    signal_tree(pid, libc::SIGKILL);
+}
+
+/// Send `signal` to the tree rooted at `pid`; whether `pid` itself got it
+#[cfg(target_os = "linux")]
+pub fn signal_tree(pid: u32, signal: i32) -> bool {
+    let mut root_signaled = false;
+    for member in process_tree(pid as i32) {
+        // SAFETY: plain kill(2) on processes spawned by this command
+        let sent = unsafe { libc::kill(member, signal) } == 0;
+        if member == pid as i32 {
+            root_signaled = sent;
+        }
+    }
+    root_signaled
+}
+
+/// Without `/proc` only `pid` itself is signaled
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn signal_tree(pid: u32, signal: i32) -> bool {
+    // SAFETY: plain kill(2) on a child this cocoon spawned
+    unsafe { libc::kill(pid as i32, signal) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn kill_tree(_pid: u32) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_blocked_syscall() {
+        assert_eq!(parse_syscall("0 0x3 0x7ffc1a2b 0x1 0x0 0x0 0x0 0x7ffc 0x7f12\n"), Some((0, 3)));
+        assert_eq!(parse_syscall("running\n"), None);
+        assert_eq!(parse_syscall("-1 0x7ffc 0x7f12\n"), None);
+
+        assert!(is_terminal("/dev/pts/3"));
+        assert!(is_terminal("/dev/tty"));
+        assert!(!is_terminal("pipe:[12345]"));
+        assert!(!is_terminal("/dev/null"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_silent_command_is_not_awaiting_input() {
+        let mut child = std::process::Command::new("/bin/sh")
+            .args(["-c", "sleep 5"])
+            .stdin(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+
+        assert!(!awaiting_input(child.id()));
+        assert!(process_tree(child.id() as i32).len() >= 2, "sh and its sleep");
+
+        kill_tree(child.id());
+        assert!(!child.wait().unwrap().success());
+    }
+}
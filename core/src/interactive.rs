@@ -197,7 +197,7 @@ fn handle_status_interactive(manager: &RuntimeManager) -> Result<(), String> {
     };
     let mut kv = KeyValue::new()
         .entry("Cocoon", &info.name)
-        .entry("Runtime", info.runtime.to_string())
+        .entry("Runtime", info.runtime.label())
         .entry("Status", styled_status);
     if let Some(image) = &info.image {
         kv = kv.entry("Image", image);
@@ -359,12 +359,14 @@ fn create_docker_cocoon_interactive() -> Result<(), String> {
         .default("ws://localhost:8080/ws")
         .run()
         .ok_or_else(|| "Cancelled".to_string())?;
+    let signaling_url = crate::signaling_url::normalize_signaling_url(&signaling_url)?.to_string();
 
     let setup_token = Input::new("Setup token (optional):")
         .run()
         .ok_or_else(|| "Cancelled".to_string())?;
 
-    let mut docker_cmd = std::process::Command::new("docker");
+    let engine = crate::container_engine::current();
+    let mut docker_cmd = engine.command();
     docker_cmd
         .arg("run")
         .arg("-d")
@@ -373,16 +375,8 @@ fn create_docker_cocoon_interactive() -> Result<(), String> {
         .arg("--name")
         .arg(&name);
 
-    // Add host mapping for .local domains
-    if let Ok(url) = url::Url::parse(&signaling_url) {
-        if let Some(host) = url.host_str() {
-            if host.ends_with(".local") {
-                docker_cmd
-                    .arg("--add-host")
-                    .arg(format!("{}:host-gateway", host));
-            }
-        }
-    }
+    let host_aliases = crate::host_aliases::from_env()?;
+    docker_cmd.args(host_aliases.docker_args(Some(&signaling_url), engine));
 
     docker_cmd
         .arg("-e")
@@ -390,6 +384,15 @@ fn create_docker_cocoon_interactive() -> Result<(), String> {
         .arg("-v")
         .arg(format!("{}:/cocoon", name));
 
+    // Kept in the container so updates can recreate it with the same mappings
+    if !host_aliases.is_empty() {
+        docker_cmd.arg("-e").arg(format!(
+            "{}={}",
+            crate::host_aliases::ENV_VAR,
+            host_aliases.env_value()
+        ));
+    }
+
     if !setup_token.is_empty() {
         docker_cmd
             .arg("-e")
@@ -410,9 +413,9 @@ fn create_docker_cocoon_interactive() -> Result<(), String> {
         }
         Ok(output) => {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(format!("Docker failed: {}", stderr))
+            Err(format!("{} failed: {}", engine, stderr))
         }
-        Err(e) => Err(format!("Failed to start Docker: {}", e)),
+        Err(e) => Err(format!("Failed to run {}: {}", engine, e)),
     }
 }
 
@@ -421,6 +424,7 @@ fn create_machine_cocoon_interactive() -> Result<(), String> {
         .default("ws://localhost:8080/ws")
         .run()
         .ok_or_else(|| "Cancelled".to_string())?;
+    let signaling_url = crate::signaling_url::normalize_signaling_url(&signaling_url)?.to_string();
 
     // Set env var for install function
     std::env::set_var("SIGNALING_SERVER_URL", &signaling_url);
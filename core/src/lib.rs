@@ -28,21 +28,53 @@ impl Default for protocol::types::AdiPluginCapabilities {
 
 pub mod adi_frame;
 pub mod adi_router;
+mod backoff;
+pub mod build_info;
+mod child_env;
+mod command_filter;
+mod command_script;
+pub mod container_engine;
 mod core;
+mod events;
+mod exec_wrapper;
 pub mod filesystem;
+mod health;
+mod history;
+pub mod host_aliases;
+mod input_wait;
 mod interactive;
+pub mod log_format;
+mod log_redact;
+pub mod metrics;
+mod output_policy;
+pub mod policy;
+mod protocol_version;
+mod proxy_stats;
+mod pty_output;
+mod rate_limit;
+mod reaper;
 mod runtime;
 mod self_update;
+mod service;
 mod setup;
+pub mod signaling_url;
 pub mod silk;
+mod silk_store;
+mod tls;
+mod turn_rest;
 pub mod webrtc;
 
 pub use adi_router::{
     create_stream_channel, AdiCallerContext, AdiHandleResult, AdiRouter, AdiService,
     AdiServiceError, StreamSender,
 };
-pub use core::run;
-pub use runtime::{CocoonInfo, CocoonStatus, Runtime, RuntimeManager, RuntimeType};
+pub use core::{run, run_job, Job, RegistrationError};
+pub use runtime::{
+    claim_container_name, docker_container_names, is_name_conflict, next_container_name,
+    validate_name_prefix, CocoonInfo, CocoonStatus, ContainerMappings, FindCocoonError,
+    ResourceLimits, Runtime, RuntimeManager, RuntimeType,
+};
+pub use service::service_uninstall;
 pub use silk::{AnsiToHtml, SilkSession};
 pub use webrtc::WebRtcManager;
 
@@ -52,6 +84,8 @@ pub use tasks_core::TasksService;
 pub use interactive::{handle_list, run_interactive};
 pub use setup::run_setup;
 
+#[cfg(test)]
+mod claim_flow_tests;
 #[cfg(test)]
 mod e2e_tests;
 
@@ -101,23 +135,20 @@ pub(crate) async fn start_cocoon_daemon(
         return Ok(());
     }
 
-    let config = if extra_env.is_empty() {
-        None
-    } else {
-        let exe = std::env::current_exe()
-            .map_err(|e| format!("Failed to get exe path: {}", e))?;
-        let mut cfg = lib_daemon_client::ServiceConfig::new(exe.display().to_string())
-            .args(["daemon", "run-service", "adi.cocoon"])
-            .env("RUST_LOG", "info,webrtc_ice=warn,webrtc_sctp=warn,webrtc_dtls=warn,webrtc_mdns=warn");
-        for &(key, value) in extra_env {
-            cfg = cfg.env(key, value);
-        }
-        Some(cfg)
-    };
+    // Always a config of our own: the daemon's default would leave out service mode
+    let exe = std::env::current_exe()
+        .map_err(|e| format!("Failed to get exe path: {}", e))?;
+    let mut config = lib_daemon_client::ServiceConfig::new(exe.display().to_string())
+        .args(["daemon", "run-service", "adi.cocoon"])
+        .env("RUST_LOG", "info,webrtc_ice=warn,webrtc_sctp=warn,webrtc_dtls=warn,webrtc_mdns=warn")
+        .env("COCOON_SERVICE_MODE", "true");
+    for &(key, value) in extra_env {
+        config = config.env(key, value);
+    }
 
     out_info!("Starting cocoon service...");
     client
-        .start_service("adi.cocoon", config)
+        .start_service("adi.cocoon", Some(config))
         .await
         .map_err(|e| format!("Failed to start cocoon service: {}", e))?;
 
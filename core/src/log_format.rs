@@ -0,0 +1,78 @@
+//! How the cocoon writes its log
+//!
+//! `COCOON_LOG_FORMAT=json` writes one JSON object per line for log collectors
+//! such as Loki or CloudWatch, with the fields of the current spans (the
+//! cocoon's `device_id`, a command's `command` and `session_id`) as keys of
+//! their own. Anything else, or unset, keeps the readable format. `RUST_LOG`
+//! filters both the same way.
+
+use lib_env_parse::{env_opt, env_vars};
+use tracing_subscriber::EnvFilter;
+
+env_vars! {
+    CocoonLogFormat => "COCOON_LOG_FORMAT",
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "" | "pretty" | "text" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            other => Err(format!(
+                "Invalid COCOON_LOG_FORMAT '{}': expected pretty or json",
+                other
+            )),
+        }
+    }
+}
+
+/// Install the global subscriber with `filter`. `ansi` colours the readable
+/// format; JSON lines never have colour codes.
+pub fn init(filter: EnvFilter, ansi: bool) {
+    let (format, invalid) = match env_opt(EnvVar::CocoonLogFormat.as_str()) {
+        Some(value) => match LogFormat::parse(&value) {
+            Ok(format) => (format, None),
+            Err(e) => (LogFormat::Pretty, Some(e)),
+        },
+        None => (LogFormat::Pretty, None),
+    };
+
+    let installed = match format {
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_env_filter(filter)
+            .try_init(),
+        LogFormat::Pretty => tracing_subscriber::fmt()
+            .with_env_filter(filter)
+            .with_ansi(ansi)
+            .try_init(),
+    };
+
+    // Only the first call installs a subscriber, so only it reports
+    if let (Ok(()), Some(e)) = (installed, invalid) {
+        tracing::warn!("⚠️ {}; using the readable format", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format_parse() {
+        assert_eq!(LogFormat::parse(" JSON ").unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::parse("pretty").unwrap(), LogFormat::Pretty);
+        assert_eq!(LogFormat::parse("").unwrap(), LogFormat::Pretty);
+        assert!(LogFormat::parse("logfmt").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Keeping secrets out of the log
+//!
+//! Logs often end up somewhere shared (journald, `/tmp/cocoon.log` on macOS),
+//! so commands and other client text pass through `redact` before they reach
+//! `tracing`. It masks the cocoon's own secret, the value of every environment
+//! variable whose name looks sensitive, and `NAME=value` words in the text with
+//! such a name. Names matching `*_TOKEN`, `*_SECRET`, `*_KEY` or `*_PASSWORD`
+//! are sensitive, case-insensitively; `COCOON_REDACT_KEYS` adds more glob
+//! patterns, comma-separated, e.g. `*_PAT,DATABASE_URL`.
+
+use glob::Pattern;
+use lib_env_parse::{env_opt, env_vars};
+use std::sync::OnceLock;
+
+env_vars! {
+    CocoonRedactKeys => "COCOON_REDACT_KEYS",
+}
+
+const DEFAULT_KEYS: &[&str] = &["*_TOKEN", "*_SECRET", "*_KEY", "*_PASSWORD"];
+
+/// Values shorter than this are left alone, since masking them would mangle
+/// unrelated text
+const MIN_REDACTED_LEN: usize = 4;
+
+/// `value` as its first few characters and its length, e.g. `Xk3f… (48 chars)`
+pub fn mask(value: &str) -> String {
+    let len = value.chars().count();
+    let prefix: String = value.chars().take((len / 8).min(4)).collect();
+    format!("{}… ({} chars)", prefix, len)
+}
+
+struct Redactor {
+    keys: Vec<Pattern>,
+    /// Values of sensitive environment variables, longest first
+    values: Vec<String>,
+}
+
+impl Redactor {
+    fn new(extra_keys: Option<&str>, env: impl IntoIterator<Item = (String, String)>) -> Self {
+        let keys = DEFAULT_KEYS
+            .iter()
+            .copied()
+            .chain(extra_keys.unwrap_or_default().split(','))
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .filter_map(|k| match Pattern::new(&k.to_ascii_uppercase()) {
+                Ok(pattern) => Some(pattern),
+                Err(e) => {
+                    tracing::warn!("⚠️ Ignoring COCOON_REDACT_KEYS pattern '{}': {}", k, e);
+                    None
+                }
+            })
+            .collect();
+        let mut redactor = Self { keys, values: Vec::new() };
+        let mut values: Vec<String> = env
+            .into_iter()
+            .filter(|(key, value)| redactor.is_sensitive(key) && value.len() >= MIN_REDACTED_LEN)
+            .map(|(_, value)| value)
+            .collect();
+        values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+        values.dedup();
+        redactor.values = values;
+        redactor
+    }
+
+    fn is_sensitive(&self, key: &str) -> bool {
+        let key = key.to_ascii_uppercase();
+        self.keys.iter().any(|pattern| pattern.matches(&key))
+    }
+
+    fn redact(&self, text: &str, secret: Option<&str>) -> String {
+        let mut redacted = text
+            .split(' ')
+            .map(|word| match word.split_once('=') {
+                Some((key, value)) if !value.is_empty() && self.is_sensitive(key) => {
+                    format!("{}={}", key, mask(value))
+                }
+                _ => word.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        for value in secret.into_iter().chain(self.values.iter().map(String::as_str)) {
+            if value.len() >= MIN_REDACTED_LEN && redacted.contains(value) {
+                redacted = redacted.replace(value, &mask(value));
+            }
+        }
+        redacted
+    }
+}
+
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+static SECRET: OnceLock<String> = OnceLock::new();
+
+fn redactor() -> &'static Redactor {
+    REDACTOR.get_or_init(|| {
+        let extra = env_opt(EnvVar::CocoonRedactKeys.as_str());
+        Redactor::new(extra.as_deref(), std::env::vars())
+    })
+}
+
+/// Mask the cocoon secret wherever it shows up in later log lines
+pub fn set_secret(secret: &str) {
+    let _ = SECRET.set(secret.to_string());
+}
+
+/// The cocoon secret as `mask` shows it, once `set_secret` has been called
+pub fn masked_secret() -> Option<String> {
+    SECRET.get().map(|s| mask(s))
+}
+
+/// `text` with the secret and sensitive values masked, for logging
+pub fn redact(text: &str) -> String {
+    redactor().redact(text, SECRET.get().map(String::as_str))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_for_log() {
+        let env = [
+            ("GITHUB_TOKEN", "ghp_abcdefghijklmnop"),
+            ("db_password", "hunter2hunter2"),
+            ("HOME", "/home/cocoon"),
+            ("SHORT_KEY", "abc"),
+            ("MY_PAT", "pat-0123456789"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let redactor = Redactor::new(Some(" *_PAT, "), env);
+
+        assert_eq!(mask("ghp_abcdefghijklmnop"), "gh… (20 chars)");
+        assert_eq!(mask("short"), "… (5 chars)");
+
+        assert_eq!(
+            redactor.redact("curl -H 'Bearer ghp_abcdefghijklmnop' $HOME", None),
+            "curl -H 'Bearer gh… (20 chars)' $HOME"
+        );
+        assert_eq!(
+            redactor.redact("psql -W hunter2hunter2 && use pat-0123456789", None),
+            "psql -W h… (14 chars) && use p… (14 chars)"
+        );
+        assert_eq!(
+            redactor.redact("API_KEY=sk-live-42 Deploy_Secret=xyz make deploy LEVEL=3", None),
+            "API_KEY=s… (10 chars) Deploy_Secret=… (3 chars) make deploy LEVEL=3"
+        );
+        // A sensitive value set inline is masked only once
+        assert_eq!(
+            redactor.redact("GITHUB_TOKEN=ghp_abcdefghijklmnop git push", None),
+            "GITHUB_TOKEN=gh… (20 chars) git push"
+        );
+
+        let secret = "Zq8s0mN3pLr7vXc2Wb6yKd4Tf9Gh1Jk5";
+        let line = redactor.redact(&format!("secret is {}", secret), Some(secret));
+        assert_eq!(line, "secret is Zq8s… (32 chars)");
+        assert!(!line.contains(secret));
+    }
+}
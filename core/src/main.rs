@@ -1,4 +1,16 @@
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    cocoon_core::run().await
+async fn main() {
+    let result = match cocoon_core::Job::from_env() {
+        Some(job) => cocoon_core::run_job(job).await,
+        None => cocoon_core::run().await.map(|()| 0),
+    };
+
+    // Print the reason with Display so it reads cleanly in container/Machine logs
+    match result {
+        Ok(exit_code) => std::process::exit(exit_code),
+        Err(e) => {
+            eprintln!("cocoon exited: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
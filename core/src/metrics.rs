@@ -0,0 +1,162 @@
+//! Process-wide counters for `get_metrics`
+//!
+//! Counters only ever grow and are plain atomics, so recording is lock-free.
+//! Gauges such as open sessions are read from their owners when a snapshot is
+//! taken. The snapshot is also written to the runtime's data directory every
+//! [`SNAPSHOT_INTERVAL`], which is where `adi cocoon metrics` reads it from: a
+//! container cocoon's `/cocoon` volume ([`CONTAINER_SNAPSHOT_PATH`]), or
+//! `~/.config/cocoon` for a machine cocoon ([`machine_snapshot_path`]).
+
+use lib_env_parse::{env_opt, env_vars};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+env_vars! {
+    Home => "HOME",
+}
+
+/// Snapshot of a container cocoon, on its data volume
+pub const CONTAINER_SNAPSHOT_PATH: &str = "/cocoon/.metrics.json";
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Snapshot of a machine cocoon, next to the rest of its state in `~/.config/cocoon`
+pub fn machine_snapshot_path() -> Result<PathBuf, String> {
+    let home = env_opt(EnvVar::Home.as_str()).ok_or_else(|| "HOME not set".to_string())?;
+    Ok(PathBuf::from(home).join(".config/cocoon/metrics.json"))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    /// `execute` commands and pipelines run
+    CommandsExecuted,
+    /// Of those, the ones that did not succeed
+    CommandsFailed,
+    PtySessionsOpened,
+    WebrtcSessionsOpened,
+    SilkCommands,
+    SignalingBytesSent,
+    SignalingBytesReceived,
+}
+
+static COMMANDS_EXECUTED: AtomicU64 = AtomicU64::new(0);
+static COMMANDS_FAILED: AtomicU64 = AtomicU64::new(0);
+static PTY_SESSIONS_OPENED: AtomicU64 = AtomicU64::new(0);
+static WEBRTC_SESSIONS_OPENED: AtomicU64 = AtomicU64::new(0);
+static SILK_COMMANDS: AtomicU64 = AtomicU64::new(0);
+static SIGNALING_BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static SIGNALING_BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+impl Counter {
+    fn cell(self) -> &'static AtomicU64 {
+        match self {
+            Counter::CommandsExecuted => &COMMANDS_EXECUTED,
+            Counter::CommandsFailed => &COMMANDS_FAILED,
+            Counter::PtySessionsOpened => &PTY_SESSIONS_OPENED,
+            Counter::WebrtcSessionsOpened => &WEBRTC_SESSIONS_OPENED,
+            Counter::SilkCommands => &SILK_COMMANDS,
+            Counter::SignalingBytesSent => &SIGNALING_BYTES_SENT,
+            Counter::SignalingBytesReceived => &SIGNALING_BYTES_RECEIVED,
+        }
+    }
+}
+
+pub fn add(counter: Counter, n: u64) {
+    counter.cell().fetch_add(n, Ordering::Relaxed);
+}
+
+pub fn increment(counter: Counter) {
+    add(counter, 1);
+}
+
+fn get(counter: Counter) -> u64 {
+    counter.cell().load(Ordering::Relaxed)
+}
+
+/// Start the uptime clock; later calls keep the first start
+pub fn mark_started() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.get_or_init(Instant::now).elapsed()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CounterValues {
+    pub commands_executed: u64,
+    pub commands_failed: u64,
+    pub pty_sessions_opened: u64,
+    /// Absent from snapshots written before it was counted
+    #[serde(default)]
+    pub webrtc_sessions_opened: u64,
+    pub silk_commands: u64,
+    pub signaling_bytes_sent: u64,
+    pub signaling_bytes_received: u64,
+}
+
+impl CounterValues {
+    pub fn current() -> Self {
+        Self {
+            commands_executed: get(Counter::CommandsExecuted),
+            commands_failed: get(Counter::CommandsFailed),
+            pty_sessions_opened: get(Counter::PtySessionsOpened),
+            webrtc_sessions_opened: get(Counter::WebrtcSessionsOpened),
+            silk_commands: get(Counter::SilkCommands),
+            signaling_bytes_sent: get(Counter::SignalingBytesSent),
+            signaling_bytes_received: get(Counter::SignalingBytesReceived),
+        }
+    }
+}
+
+/// Sessions open at the time of the snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Gauges {
+    pub pty_sessions: usize,
+    pub silk_sessions: usize,
+    pub webrtc_sessions: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// RFC 3339
+    pub timestamp: String,
+    pub uptime_secs: u64,
+    pub version: String,
+    pub counters: CounterValues,
+    pub gauges: Gauges,
+    pub proxy: Vec<crate::proxy_stats::ServiceProxyStats>,
+}
+
+impl MetricsSnapshot {
+    pub fn new(gauges: Gauges, proxy: Vec<crate::proxy_stats::ServiceProxyStats>) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            uptime_secs: uptime().as_secs(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            counters: CounterValues::current(),
+            gauges,
+            proxy,
+        }
+    }
+}
+
+/// Write `snapshot` to `path`, replacing the previous one atomically
+pub async fn persist(snapshot: &MetricsSnapshot, path: &Path) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(snapshot).map_err(|e| e.to_string())?;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp, json)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
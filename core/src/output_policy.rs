@@ -0,0 +1,92 @@
+//! How `/cocoon/output` is managed around `execute`
+//!
+//! `COCOON_OUTPUT_POLICY` is one of:
+//! - `keep` (default): nothing is removed unless a client asks for it
+//! - `clear_before`: the directory is emptied before every `execute` and `pipeline`
+//! - `ttl:<secs>`: entries untouched for longer than that are removed by a
+//!   periodic sweep
+//!
+//! The explicit `clear_output` command and `clear_output: true` on `execute`
+//! work under every policy. No policy cleanup runs when `/cocoon` is not
+//! writable.
+
+use lib_env_parse::{env_opt, env_vars};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+env_vars! {
+    CocoonOutputPolicy => "COCOON_OUTPUT_POLICY",
+}
+
+/// Upper bound on the time between TTL sweeps
+const MAX_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputPolicy {
+    #[default]
+    Keep,
+    ClearBefore,
+    Ttl(Duration),
+}
+
+impl OutputPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let lower = value.to_ascii_lowercase();
+        match lower.as_str() {
+            "" | "keep" => return Ok(Self::Keep),
+            "clear_before" => return Ok(Self::ClearBefore),
+            _ => {}
+        }
+        let secs = lower
+            .strip_prefix("ttl:")
+            .ok_or_else(|| format!("Invalid output policy '{}': expected keep, clear_before or ttl:<secs>", value))?;
+        match secs.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(Self::Ttl(Duration::from_secs(secs))),
+            _ => Err(format!("Invalid output policy '{}': ttl needs a positive number of seconds", value)),
+        }
+    }
+}
+
+/// Policy from `COCOON_OUTPUT_POLICY`; `keep` when unset
+pub fn from_env() -> Result<OutputPolicy, String> {
+    env_opt(EnvVar::CocoonOutputPolicy.as_str())
+        .map(|value| OutputPolicy::parse(&value))
+        .unwrap_or(Ok(OutputPolicy::Keep))
+}
+
+/// How often to look for entries past `ttl`
+pub fn sweep_interval(ttl: Duration) -> Duration {
+    (ttl / 2).clamp(Duration::from_secs(1), MAX_SWEEP_INTERVAL)
+}
+
+/// Latest modification time of `path` and, for a directory, everything under
+/// it. Symlinks count by their own time and are not followed.
+pub fn last_modified(path: &Path) -> SystemTime {
+    walkdir::WalkDir::new(path)
+        .follow_root_links(false)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok()?.modified().ok())
+        .max()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(OutputPolicy::parse("keep").unwrap(), OutputPolicy::Keep);
+        assert_eq!(OutputPolicy::parse(" Clear_Before ").unwrap(), OutputPolicy::ClearBefore);
+        assert_eq!(OutputPolicy::parse("ttl:3600").unwrap(), OutputPolicy::Ttl(Duration::from_secs(3600)));
+        for bad in ["clear", "ttl:", "ttl:0", "ttl:-5", "ttl:1h"] {
+            assert!(OutputPolicy::parse(bad).is_err(), "{} should be rejected", bad);
+        }
+
+        assert_eq!(sweep_interval(Duration::from_secs(10)), Duration::from_secs(5));
+        assert_eq!(sweep_interval(Duration::from_secs(1)), Duration::from_secs(1));
+        assert_eq!(sweep_interval(Duration::from_secs(86400)), MAX_SWEEP_INTERVAL);
+    }
+}
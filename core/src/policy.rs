@@ -0,0 +1,178 @@
+//! Effective security posture of a cocoon
+//!
+//! Aggregates every setting that limits what a paired client can do into one
+//! report. It is built from an environment map so the same logic serves the
+//! `get_security_policy` request inside the cocoon and `adi cocoon policy` on
+//! the host, which reads the container's environment.
+
+use lib_env_parse::env_vars;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+env_vars! {
+    CocoonServices => "COCOON_SERVICES",
+    CocoonProtocols => "COCOON_PROTOCOLS",
+    CocoonSetupToken => "COCOON_SETUP_TOKEN",
+    CocoonRequirePersistence => "COCOON_REQUIRE_PERSISTENCE",
+    CocoonMaxSessionSecs => "COCOON_MAX_SESSION_SECS",
+    CocoonMaxResultBytes => "COCOON_MAX_RESULT_BYTES",
+    CocoonExecWrapper => "COCOON_EXEC_WRAPPER",
+    CocoonExecRate => "COCOON_EXEC_RATE",
+    CocoonCmdAllowlist => "COCOON_CMD_ALLOWLIST",
+    CocoonCmdDenylist => "COCOON_CMD_DENYLIST",
+}
+
+/// Command request types handled by the cocoon
+const COMMAND_TYPES: &[&str] = &[
+    "execute",
+    "cancel_exec",
+    "pipeline",
+    "clear_output",
+    "attach_pty",
+    "pty_input",
+    "pty_resize",
+    "pty_close",
+    "pty_subscribe",
+    "proxy_http",
+    "query_local",
+    "silk_create_session",
+    "silk_execute",
+    "silk_input",
+    "silk_resize",
+    "silk_close_session",
+    "silk_list_sessions",
+    "list_sessions",
+    "kill_session",
+    "reap_zombies",
+    "health_check",
+    "get_security_policy",
+    "list_webrtc_sessions",
+    "close_webrtc_session",
+    "get_webrtc_stats",
+    "proxy_stats",
+    "update_services",
+    "build_info",
+    "get_metrics",
+    "subscribe_events",
+    "unsubscribe_events",
+    "subscribe",
+    "unsubscribe",
+    "broadcast",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SecurityPolicy {
+    /// Command request types this cocoon accepts
+    pub command_types: Vec<String>,
+    /// Local services reachable through `proxy_http`
+    pub service_count: usize,
+    /// `name:port` of each proxied service, only in the full report
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub services: Option<Vec<String>>,
+    pub protocols: Vec<String>,
+    /// Whether a setup token for auto-claiming is configured (never its value)
+    pub setup_token: bool,
+    pub require_persistence: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_session_secs: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_result_bytes: Option<usize>,
+    /// Template every command is wrapped in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exec_wrapper: Option<String>,
+    /// `execute`/`pipeline` limit as `RATE:BURST`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exec_rate: Option<String>,
+    /// Only programs matching these patterns run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd_allowlist: Option<String>,
+    /// Programs refused unless there is an allowlist
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd_denylist: Option<String>,
+}
+
+impl SecurityPolicy {
+    /// Policy in effect for `env`. Details that map out the host (service
+    /// names and ports) are only listed when `full` is set.
+    pub fn from_env(env: &HashMap<String, String>, full: bool) -> Self {
+        let get = |var: EnvVar| env.get(var.as_str()).map(|v| v.trim()).filter(|v| !v.is_empty());
+        let positive = |var: EnvVar| get(var).and_then(|v| v.parse::<u64>().ok()).filter(|n| *n > 0);
+
+        let services: Vec<String> = get(EnvVar::CocoonServices)
+            .map(|s| {
+                s.split(',')
+                    .map(str::trim)
+                    .filter(|def| matches!(def.rsplit_once(':'), Some((_, port)) if port.parse::<u16>().is_ok()))
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let protocols = get(EnvVar::CocoonProtocols)
+            .map(|s| s.split(',').map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect())
+            .unwrap_or_else(|| vec!["silk".to_string()]);
+
+        Self {
+            command_types: COMMAND_TYPES.iter().map(|t| t.to_string()).collect(),
+            service_count: services.len(),
+            services: full.then_some(services),
+            protocols,
+            setup_token: get(EnvVar::CocoonSetupToken).is_some(),
+            require_persistence: get(EnvVar::CocoonRequirePersistence)
+                .is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes")),
+            max_session_secs: positive(EnvVar::CocoonMaxSessionSecs),
+            max_result_bytes: positive(EnvVar::CocoonMaxResultBytes).map(|n| n as usize),
+            exec_wrapper: get(EnvVar::CocoonExecWrapper).map(str::to_string),
+            exec_rate: get(EnvVar::CocoonExecRate)
+                .and_then(|v| crate::rate_limit::TokenBucket::parse(v).ok())
+                .map(|bucket| bucket.describe()),
+            cmd_allowlist: get(EnvVar::CocoonCmdAllowlist).map(str::to_string),
+            cmd_denylist: get(EnvVar::CocoonCmdDenylist).map(str::to_string),
+        }
+    }
+
+    /// Policy of the running process
+    pub fn current(full: bool) -> Self {
+        Self::from_env(&std::env::vars().collect(), full)
+    }
+
+    /// Report `services` instead of `COCOON_SERVICES`, for a registry that
+    /// was replaced after startup
+    pub fn with_services(self, services: Vec<String>, full: bool) -> Self {
+        Self {
+            service_count: services.len(),
+            services: full.then_some(services),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_summarizes_services_unless_full() {
+        let env: HashMap<String, String> = [
+            ("COCOON_SERVICES", "api:8080,postgres:5432,broken"),
+            ("COCOON_SETUP_TOKEN", "tok_123"),
+            ("COCOON_MAX_SESSION_SECS", "0"),
+            ("COCOON_MAX_RESULT_BYTES", "65536"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let summary = SecurityPolicy::from_env(&env, false);
+        assert_eq!(summary.service_count, 2);
+        assert!(summary.services.is_none());
+        assert!(summary.setup_token);
+        assert_eq!(summary.protocols, vec!["silk"]);
+        assert_eq!(summary.max_session_secs, None);
+        assert_eq!(summary.max_result_bytes, Some(65536));
+        assert!(!serde_json::to_string(&summary).unwrap().contains("tok_123"));
+
+        let full = SecurityPolicy::from_env(&env, true);
+        assert_eq!(full.services.unwrap(), vec!["api:8080", "postgres:5432"]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Signaling protocol version handshake
+//!
+//! Besides its crate version the cocoon registers with `PROTOCOL_VERSION`, as
+//! `device_config.protocol_version`, so the server can gate features on it. A
+//! server that cannot talk to this cocoon answers
+//! `{"type": "protocol_mismatch", "min_supported": 2, "server_version": 3}`:
+//! the oldest cocoon protocol it accepts and the one it speaks itself.
+//! `SignalingMessage` has no such variant, so it is picked out of the raw text.
+//! When the cocoon is the one out of date and `COCOON_AUTO_UPDATE` is set, a
+//! machine install updates itself and restarts.
+
+use lib_env_parse::{env_opt, env_vars};
+use serde::Deserialize;
+use std::ops::RangeInclusive;
+
+env_vars! {
+    CocoonAutoUpdate => "COCOON_AUTO_UPDATE",
+}
+
+/// Protocol this cocoon speaks
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Server protocols this cocoon can talk to
+pub const SUPPORTED_SERVER_PROTOCOLS: RangeInclusive<u32> = 1..=1;
+
+const MESSAGE_TYPE: &str = "protocol_mismatch";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ProtocolMismatch {
+    /// Oldest cocoon protocol the server accepts
+    pub min_supported: u32,
+    /// Protocol the server speaks
+    pub server_version: u32,
+}
+
+impl ProtocolMismatch {
+    /// `text` as a `protocol_mismatch` message, or `None` for anything else
+    pub fn parse(text: &str) -> Option<Self> {
+        if !text.contains(MESSAGE_TYPE) {
+            return None;
+        }
+        let value: serde_json::Value = serde_json::from_str(text).ok()?;
+        if value.get("type").and_then(|t| t.as_str()) != Some(MESSAGE_TYPE) {
+            return None;
+        }
+        match serde_json::from_value(value) {
+            Ok(mismatch) => Some(mismatch),
+            Err(e) => {
+                tracing::warn!("⚠️ Invalid protocol_mismatch message: {}", e);
+                None
+            }
+        }
+    }
+
+    /// The server needs a newer cocoon than this one
+    pub fn cocoon_outdated(&self) -> bool {
+        self.min_supported > PROTOCOL_VERSION || self.server_version > *SUPPORTED_SERVER_PROTOCOLS.end()
+    }
+}
+
+impl std::fmt::Display for ProtocolMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Signaling server speaks protocol {} and accepts cocoon protocol {} or newer; this cocoon (v{}) speaks protocol {} and supports servers on {}..={}",
+            self.server_version,
+            self.min_supported,
+            env!("CARGO_PKG_VERSION"),
+            PROTOCOL_VERSION,
+            SUPPORTED_SERVER_PROTOCOLS.start(),
+            SUPPORTED_SERVER_PROTOCOLS.end()
+        )
+    }
+}
+
+fn auto_update_enabled() -> bool {
+    env_opt(EnvVar::CocoonAutoUpdate.as_str())
+        .is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+}
+
+/// Log what to upgrade and, with `COCOON_AUTO_UPDATE`, update this cocoon when
+/// it is the side that is too old
+pub async fn handle(mismatch: &ProtocolMismatch) {
+    tracing::error!("❌ {}", mismatch);
+    if !mismatch.cocoon_outdated() {
+        tracing::error!("   Upgrade the signaling server; this cocoon cannot speak its protocol");
+        return;
+    }
+    if !auto_update_enabled() {
+        tracing::error!("   Update the cocoon (`adi cocoon update <name>`), or set COCOON_AUTO_UPDATE=1 to do it automatically");
+        return;
+    }
+    if std::path::Path::new("/.dockerenv").exists() {
+        tracing::error!("   COCOON_AUTO_UPDATE does not apply inside a container; run `adi cocoon update <name>` on the host");
+        return;
+    }
+
+    tracing::info!("⬆️ COCOON_AUTO_UPDATE is set, updating the cocoon...");
+    match tokio::task::spawn_blocking(crate::self_update::machine::update_and_restart).await {
+        Ok(Ok(result)) => tracing::info!("⬆️ {}", result),
+        Ok(Err(e)) => tracing::error!("❌ Self-update failed: {}", e),
+        Err(e) => tracing::error!("❌ Self-update task failed: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_mismatch_parse() {
+        let mismatch =
+            ProtocolMismatch::parse(r#"{"type":"protocol_mismatch","min_supported":2,"server_version":3}"#).unwrap();
+        assert_eq!(mismatch, ProtocolMismatch { min_supported: 2, server_version: 3 });
+        assert!(mismatch.cocoon_outdated());
+
+        // A server older than this cocoon supports is the server's problem
+        let old_server = ProtocolMismatch { min_supported: 0, server_version: 0 };
+        assert!(!old_server.cocoon_outdated());
+
+        assert!(ProtocolMismatch::parse(r#"{"type":"system_error","message":"protocol_mismatch"}"#).is_none());
+        assert!(ProtocolMismatch::parse(r#"{"type":"protocol_mismatch"}"#).is_none());
+        assert!(ProtocolMismatch::parse("protocol_mismatch").is_none());
+    }
+}
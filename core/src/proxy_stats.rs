@@ -0,0 +1,182 @@
+//! Per-service proxy traffic counters
+//!
+//! Every `proxy_http` request to a registered service updates that service's
+//! counters: request count, body bytes each way, failures and a latency
+//! histogram. All counters are atomics; the set of services sits behind a
+//! read lock that is only written when `update_services` replaces the
+//! registry. `proxy_stats` reports them and can reset them to start a fresh
+//! measurement window.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets in milliseconds; a final bucket catches the rest
+const LATENCY_BOUNDS_MS: &[u64] = &[1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 30_000];
+
+#[derive(Default)]
+struct ServiceCounters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    request_bytes: AtomicU64,
+    response_bytes: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BOUNDS_MS.len() + 1],
+}
+
+/// Counters of one service since start or the last reset
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceProxyStats {
+    pub service: String,
+    pub port: u16,
+    pub requests: u64,
+    /// Requests that failed to reach the service or got a 5xx back
+    pub errors: u64,
+    /// Request body bytes sent to the service
+    pub request_bytes: u64,
+    /// Response body bytes received from the service
+    pub response_bytes: u64,
+    /// Upper bound of the bucket holding the median latency; absent without requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p50_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p99_ms: Option<u64>,
+}
+
+pub struct ProxyStats {
+    services: RwLock<HashMap<String, (u16, Arc<ServiceCounters>)>>,
+}
+
+impl ProxyStats {
+    pub fn new(services: &HashMap<String, u16>) -> Self {
+        let stats = Self { services: RwLock::default() };
+        stats.set_services(services);
+        stats
+    }
+
+    /// Track exactly `services`. A service keeps its counters while its port
+    /// stays the same; new or moved ones start from zero.
+    pub fn set_services(&self, services: &HashMap<String, u16>) {
+        let mut current = self.services.write().unwrap_or_else(|e| e.into_inner());
+        *current = services
+            .iter()
+            .map(|(name, port)| {
+                let counters = match current.get(name) {
+                    Some((old_port, counters)) if old_port == port => counters.clone(),
+                    _ => Arc::default(),
+                };
+                (name.clone(), (*port, counters))
+            })
+            .collect();
+    }
+
+    pub fn record(
+        &self,
+        service: &str,
+        request_bytes: usize,
+        response_bytes: usize,
+        latency: Duration,
+        failed: bool,
+    ) {
+        let services = self.services.read().unwrap_or_else(|e| e.into_inner());
+        let Some((_, counters)) = services.get(service) else {
+            return;
+        };
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            counters.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        counters.request_bytes.fetch_add(request_bytes as u64, Ordering::Relaxed);
+        counters.response_bytes.fetch_add(response_bytes as u64, Ordering::Relaxed);
+
+        let millis = latency.as_millis() as u64;
+        let bucket = LATENCY_BOUNDS_MS
+            .iter()
+            .position(|bound| millis <= *bound)
+            .unwrap_or(LATENCY_BOUNDS_MS.len());
+        counters.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counters of every service, sorted by name; `reset` zeroes them as they are read
+    pub fn snapshot(&self, reset: bool) -> Vec<ServiceProxyStats> {
+        let read = |counter: &AtomicU64| {
+            if reset {
+                counter.swap(0, Ordering::Relaxed)
+            } else {
+                counter.load(Ordering::Relaxed)
+            }
+        };
+
+        let mut stats: Vec<ServiceProxyStats> = self
+            .services
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(name, (port, counters))| {
+                let buckets: Vec<u64> = counters.latency_buckets.iter().map(read).collect();
+                ServiceProxyStats {
+                    service: name.clone(),
+                    port: *port,
+                    requests: read(&counters.requests),
+                    errors: read(&counters.errors),
+                    request_bytes: read(&counters.request_bytes),
+                    response_bytes: read(&counters.response_bytes),
+                    p50_ms: percentile(&buckets, 0.50),
+                    p99_ms: percentile(&buckets, 0.99),
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| a.service.cmp(&b.service));
+        stats
+    }
+}
+
+/// Upper bound of the bucket holding quantile `q`; the overflow bucket reports
+/// the largest bound
+fn percentile(buckets: &[u64], q: f64) -> Option<u64> {
+    let total: u64 = buckets.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = ((total as f64) * q).ceil().max(1.0) as u64;
+    let mut seen = 0;
+    for (i, count) in buckets.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return Some(LATENCY_BOUNDS_MS[i.min(LATENCY_BOUNDS_MS.len() - 1)]);
+        }
+    }
+    LATENCY_BOUNDS_MS.last().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_proxy_stats_record_and_reset() {
+        let services: HashMap<String, u16> = [("api".to_string(), 8080)].into_iter().collect();
+        let stats = ProxyStats::new(&services);
+
+        for _ in 0..98 {
+            stats.record("api", 10, 100, Duration::from_millis(3), false);
+        }
+        stats.record("api", 0, 0, Duration::from_millis(150), true);
+        stats.record("api", 0, 0, Duration::from_secs(60), true);
+        stats.record("unknown", 1, 1, Duration::ZERO, false);
+
+        let api = &stats.snapshot(true)[0];
+        assert_eq!(api.service, "api");
+        assert_eq!(api.requests, 100);
+        assert_eq!(api.errors, 2);
+        assert_eq!(api.request_bytes, 980);
+        assert_eq!(api.response_bytes, 9800);
+        assert_eq!(api.p50_ms, Some(5));
+        assert_eq!(api.p99_ms, Some(200));
+
+        let after_reset = &stats.snapshot(false)[0];
+        assert_eq!(after_reset.requests, 0);
+        assert_eq!(after_reset.p50_ms, None);
+    }
+}
@@ -0,0 +1,119 @@
+//! Framing raw PTY reads for JSON
+//!
+//! A read can end in the middle of a multibyte character, so the incomplete
+//! tail is held back and prepended to the next read. Output that is valid UTF-8
+//! goes out as plain `data`; anything that is not (raw bytes, other encodings)
+//! is also sent base64-encoded in `data_base64` with `binary: true`, keeping a
+//! lossy `data` for clients that only read the string.
+
+use base64::Engine;
+
+/// Longest incomplete UTF-8 sequence worth holding for the next read
+const MAX_PARTIAL: usize = 3;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub data: String,
+    pub binary: bool,
+    pub data_base64: Option<String>,
+}
+
+impl Frame {
+    fn text(data: String) -> Self {
+        Self { data, binary: false, data_base64: None }
+    }
+
+    fn binary(bytes: &[u8]) -> Self {
+        Self {
+            data: String::from_utf8_lossy(bytes).into_owned(),
+            binary: true,
+            data_base64: Some(base64::engine::general_purpose::STANDARD.encode(bytes)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Utf8Framer {
+    pending: Vec<u8>,
+}
+
+impl Utf8Framer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Frame for `bytes` after what was held back from the previous read, or
+    /// `None` when everything read so far is part of an unfinished character
+    pub fn push(&mut self, bytes: &[u8]) -> Option<Frame> {
+        let mut chunk = std::mem::take(&mut self.pending);
+        chunk.extend_from_slice(bytes);
+
+        match std::str::from_utf8(&chunk) {
+            Ok(_) => {}
+            // Only an unfinished character at the very end: hold it back
+            Err(e) if e.error_len().is_none() && chunk.len() - e.valid_up_to() <= MAX_PARTIAL => {
+                self.pending = chunk.split_off(e.valid_up_to());
+            }
+            Err(_) => return Some(Frame::binary(&chunk)),
+        }
+
+        if chunk.is_empty() {
+            return None;
+        }
+        Some(Frame::text(String::from_utf8(chunk).expect("validated above")))
+    }
+
+    /// Whatever is still held back once the PTY has closed
+    pub fn finish(&mut self) -> Option<Frame> {
+        let pending = std::mem::take(&mut self.pending);
+        (!pending.is_empty()).then(|| Frame::binary(&pending))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multibyte_character_split_across_reads() {
+        let text = "héllo → 世界 🎉";
+        let bytes = text.as_bytes();
+        let mut framer = Utf8Framer::new();
+        let mut out = String::new();
+
+        // Every split point, including ones inside each multibyte character
+        for split in 0..=bytes.len() {
+            out.clear();
+            for part in [&bytes[..split], &bytes[split..]] {
+                if let Some(frame) = framer.push(part) {
+                    assert!(!frame.binary, "split at {} produced a binary frame", split);
+                    out.push_str(&frame.data);
+                }
+            }
+            assert_eq!(out, text, "split at {}", split);
+            assert!(framer.finish().is_none());
+        }
+
+        // One byte at a time through the 4-byte emoji
+        let emoji = "🎉".as_bytes();
+        for byte in &emoji[..3] {
+            assert!(framer.push(std::slice::from_ref(byte)).is_none());
+        }
+        assert_eq!(framer.push(&emoji[3..]), Some(Frame::text("🎉".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_bytes_are_sent_as_base64() {
+        let mut framer = Utf8Framer::new();
+        let frame = framer.push(&[b'o', b'k', 0xff, 0xfe]).unwrap();
+        assert!(frame.binary);
+        assert_eq!(frame.data_base64.as_deref(), Some("b2v//g=="));
+        assert!(frame.data.starts_with("ok"));
+
+        // A truncated character at EOF is flushed rather than lost
+        assert!(framer.push(&"é".as_bytes()[..1]).is_none());
+        let flushed = framer.finish().unwrap();
+        assert!(flushed.binary);
+        assert_eq!(flushed.data_base64.as_deref(), Some("ww=="));
+    }
+}
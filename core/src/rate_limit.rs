@@ -0,0 +1,145 @@
+//! Process-wide limit on how fast commands may start shells
+//!
+//! `COCOON_EXEC_RATE` is `RATE` or `RATE:BURST`: `execute` and `pipeline`
+//! requests per second, and how many may arrive at once after a quiet period
+//! (default: the rate rounded up). Each request takes one token from a bucket
+//! that refills at `RATE` per second, and one that finds it empty is answered
+//! with `rate_limited` instead of spawning anything. Unset means no limit.
+
+use lib_env_parse::{env_opt, env_vars};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+env_vars! {
+    CocoonExecRate => "COCOON_EXEC_RATE",
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Tokens added per second
+    rate: f64,
+    burst: f64,
+    /// Tokens left and when they were counted
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Full bucket of `burst` tokens refilling at `rate` per second
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst as f64,
+            state: Mutex::new((burst as f64, Instant::now())),
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "Invalid COCOON_EXEC_RATE '{}': expected RATE or RATE:BURST, e.g. 5 or 5:20",
+                value
+            )
+        };
+        let (rate, burst) = match value.trim().split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (value.trim(), None),
+        };
+        let rate = rate
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|r| r.is_finite() && *r > 0.0)
+            .ok_or_else(invalid)?;
+        let burst = match burst {
+            Some(burst) => burst.trim().parse::<u32>().ok().filter(|b| *b > 0).ok_or_else(invalid)?,
+            None => rate.ceil().min(u32::MAX as f64) as u32,
+        };
+        Ok(Self::new(rate, burst))
+    }
+
+    /// Take a token, or say how long until the next one
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (tokens, counted_at) = *state;
+        let elapsed = now.saturating_duration_since(counted_at).as_secs_f64();
+        let tokens = (tokens + elapsed * self.rate).min(self.burst);
+        if tokens >= 1.0 {
+            *state = (tokens - 1.0, now);
+            Ok(())
+        } else {
+            *state = (tokens, now);
+            Err(Duration::from_secs_f64((1.0 - tokens) / self.rate))
+        }
+    }
+
+    /// `COCOON_EXEC_RATE` form of this bucket
+    pub fn describe(&self) -> String {
+        format!("{}:{}", self.rate, self.burst)
+    }
+}
+
+static EXEC_LIMITER: OnceLock<Option<TokenBucket>> = OnceLock::new();
+
+/// Read `COCOON_EXEC_RATE` once at startup; an invalid value is an error
+pub fn init_from_env() -> Result<(), String> {
+    let limiter = env_opt(EnvVar::CocoonExecRate.as_str())
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| TokenBucket::parse(&v))
+        .transpose()?;
+    if let Some(limiter) = &limiter {
+        tracing::info!("🚦 Exec rate limit: {} per second, burst {}", limiter.rate, limiter.burst);
+    }
+    let _ = EXEC_LIMITER.set(limiter);
+    Ok(())
+}
+
+/// Take a token for a command about to spawn; always succeeds without a limit
+pub fn acquire_exec() -> Result<(), Duration> {
+    match EXEC_LIMITER.get() {
+        Some(Some(limiter)) => limiter.try_acquire(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_rejects_burst_then_recovers() {
+        let bucket = TokenBucket::parse("2:3").unwrap();
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(bucket.try_acquire_at(start).is_ok());
+        }
+        let wait = bucket.try_acquire_at(start).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+        assert!(bucket.try_acquire_at(start + Duration::from_millis(100)).is_err());
+
+        // Half a second later one token is back, but not two
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_acquire_at(later).is_ok());
+        assert!(bucket.try_acquire_at(later).is_err());
+
+        // A long pause refills only up to the burst size
+        let idle = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_acquire_at(idle).is_ok());
+        }
+        assert!(bucket.try_acquire_at(idle).is_err());
+    }
+
+    #[test]
+    fn test_token_bucket_parse() {
+        assert_eq!(TokenBucket::parse("0.5").unwrap().describe(), "0.5:1");
+        assert_eq!(TokenBucket::parse(" 10 : 20 ").unwrap().describe(), "10:20");
+        for bad in ["", "0", "-1", "fast", "5:0", "5:x", "NaN"] {
+            assert!(TokenBucket::parse(bad).is_err(), "{:?} should be rejected", bad);
+        }
+    }
+}
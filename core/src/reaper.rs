@@ -0,0 +1,215 @@
+//! Zombie child reaping
+//!
+//! Children spawned for PTYs and Silk commands are normally waited on by whoever
+//! owns them. If an owner loses track of a child (a dropped handle, a panicked
+//! task), the exited child stays defunct until the cocoon exits. Over a long
+//! uptime these can exhaust the process table, so they are reaped here.
+//!
+//! Only zombies that persist across a short grace period and are not tracked in
+//! any session map are reaped, so a child whose owner is about to wait on it is
+//! never stolen from under it.
+//!
+//! The absolute session lifetime cap (`COCOON_MAX_SESSION_SECS`) and the idle
+//! timeout (`COCOON_SESSION_IDLE_SECS`) are configured here too; the sessions
+//! themselves are closed by their owners.
+
+use lib_env_parse::{env_opt, env_vars};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+env_vars! {
+    CocoonMaxSessionSecs => "COCOON_MAX_SESSION_SECS",
+    CocoonSessionIdleSecs => "COCOON_SESSION_IDLE_SECS",
+}
+
+/// How often the background reaper runs
+pub const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Exit reason reported for sessions closed by the lifetime cap
+pub const MAX_LIFETIME_REASON: &str = "max_lifetime";
+
+/// Exit reason reported for sessions closed by the idle timeout
+pub const IDLE_REASON: &str = "idle";
+
+/// Idle timeout when `COCOON_SESSION_IDLE_SECS` is unset
+const DEFAULT_SESSION_IDLE_SECS: u64 = 1800;
+
+/// Upper bound on how late a session past its lifetime cap gets closed
+const LIFETIME_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Absolute session lifetime from `COCOON_MAX_SESSION_SECS`; unset or 0 means unlimited
+pub fn max_session_lifetime() -> Option<Duration> {
+    env_opt(EnvVar::CocoonMaxSessionSecs.as_str())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// How often to check for sessions past `max_lifetime`
+pub fn lifetime_check_interval(max_lifetime: Duration) -> Duration {
+    max_lifetime.min(LIFETIME_CHECK_INTERVAL)
+}
+
+/// Idle timeout from `COCOON_SESSION_IDLE_SECS`, 30 minutes when unset; 0 disables it
+pub fn session_idle_timeout() -> Option<Duration> {
+    let secs = match env_opt(EnvVar::CocoonSessionIdleSecs.as_str()) {
+        None => DEFAULT_SESSION_IDLE_SECS,
+        Some(v) => match v.trim().parse::<u64>() {
+            Ok(secs) => secs,
+            Err(_) => {
+                tracing::warn!(
+                    "⚠️ Invalid COCOON_SESSION_IDLE_SECS '{}', using {}s",
+                    v,
+                    DEFAULT_SESSION_IDLE_SECS
+                );
+                DEFAULT_SESSION_IDLE_SECS
+            }
+        },
+    };
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// When a session last saw input or output. Clones share the timestamp, so the
+/// PTY reader thread can update it without the session lock.
+#[derive(Debug, Clone)]
+pub struct Activity(Arc<AtomicU64>);
+
+/// Reference point for `Activity` timestamps
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+fn millis_since_epoch() -> u64 {
+    epoch().elapsed().as_millis() as u64
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicU64::new(millis_since_epoch())))
+    }
+
+    pub fn touch(&self) {
+        self.0.store(millis_since_epoch(), Ordering::Relaxed);
+    }
+
+    /// Time since the last `touch`, or since creation
+    pub fn idle_for(&self) -> Duration {
+        Duration::from_millis(millis_since_epoch().saturating_sub(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How long a zombie must stay defunct before it counts as orphaned
+const GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// PIDs of this process's children that are currently zombies
+#[cfg(target_os = "linux")]
+fn zombie_children() -> HashSet<i32> {
+    let me = std::process::id() as i32;
+    let mut zombies = HashSet::new();
+
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return zombies,
+    };
+
+    for entry in entries.flatten() {
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let stat = match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+
+        // The command name may contain spaces or parens, so parse after the last ')'
+        let mut fields = match stat.rsplit_once(')') {
+            Some((_, rest)) => rest.split_whitespace(),
+            None => continue,
+        };
+        let state = fields.next();
+        let ppid = fields.next().and_then(|p| p.parse::<i32>().ok());
+
+        if state == Some("Z") && ppid == Some(me) {
+            zombies.insert(pid);
+        }
+    }
+
+    zombies
+}
+
+#[cfg(not(target_os = "linux"))]
+fn zombie_children() -> HashSet<i32> {
+    HashSet::new()
+}
+
+#[cfg(unix)]
+fn reap(pid: i32) -> bool {
+    let mut status = 0;
+    // SAFETY: `pid` is one of our own zombie children and WNOHANG never blocks
+    unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) == pid }
+}
+
+#[cfg(not(unix))]
+fn reap(_pid: i32) -> bool {
+    false
+}
+
+/// Reap zombie children that are not in `tracked`, returning how many were reaped.
+/// `tracked` is only taken after the grace period, so a child whose owner is
+/// about to wait for it is still tracked when its zombie is found.
+pub async fn reap_zombies(tracked: impl std::future::Future<Output = HashSet<u32>>) -> usize {
+    let candidates = zombie_children();
+    if candidates.is_empty() {
+        return 0;
+    }
+
+    tokio::time::sleep(GRACE_PERIOD).await;
+    let tracked = tracked.await;
+    let still_defunct = zombie_children();
+
+    let mut reaped = 0;
+    for pid in candidates.intersection(&still_defunct) {
+        if tracked.contains(&(*pid as u32)) {
+            continue;
+        }
+        if reap(*pid) {
+            tracing::info!("🧟 Reaped orphaned child process {}", pid);
+            reaped += 1;
+        }
+    }
+
+    reaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_reap_untracked_zombie() {
+        let child = std::process::Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        // Lose the handle without waiting, leaving the child defunct
+        std::mem::forget(child);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let mut tracked = HashSet::new();
+        tracked.insert(pid);
+        reap_zombies(async { tracked }).await;
+        assert!(zombie_children().contains(&(pid as i32)));
+
+        assert!(reap_zombies(async { HashSet::new() }).await >= 1);
+        assert!(!zombie_children().contains(&(pid as i32)));
+    }
+}
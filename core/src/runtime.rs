@@ -1,5 +1,7 @@
+use crate::container_engine::ContainerEngine;
 use crate::self_update;
 use lib_console_output::{out_info, KeyValue, Renderable};
+use std::collections::HashMap;
 use std::fmt;
 
 use lib_daemon_client::DaemonClient;
@@ -27,6 +29,22 @@ impl RuntimeType {
             _ => None,
         }
     }
+
+    /// Container engine behind the Docker runtime
+    pub fn engine(self) -> Option<ContainerEngine> {
+        match self {
+            RuntimeType::Docker => Some(crate::container_engine::current()),
+            RuntimeType::Machine => None,
+        }
+    }
+
+    /// Runtime name for display, with the engine when Podman stands in for Docker
+    pub fn label(self) -> String {
+        match self.engine() {
+            Some(engine @ ContainerEngine::Podman) => format!("{} ({})", self, engine),
+            _ => self.to_string(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -68,6 +86,364 @@ impl CocoonInfo {
     }
 }
 
+/// Docker labels that carry resource limits so they survive container recreation
+const LABEL_MEMORY: &str = "cocoon.limits.memory";
+const LABEL_CPUS: &str = "cocoon.limits.cpus";
+const LABEL_PIDS: &str = "cocoon.limits.pids";
+
+/// Optional resource limits for a Docker cocoon; `None` means unlimited
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResourceLimits {
+    pub memory: Option<String>,
+    pub cpus: Option<String>,
+    pub pids_limit: Option<String>,
+}
+
+impl ResourceLimits {
+    /// Validate raw flag values, normalizing them to what `docker run` accepts
+    pub fn parse(
+        memory: Option<&str>,
+        cpus: Option<&str>,
+        pids_limit: Option<&str>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            memory: memory.map(validate_memory).transpose()?,
+            cpus: cpus.map(validate_cpus).transpose()?,
+            pids_limit: pids_limit.map(validate_pids_limit).transpose()?,
+        })
+    }
+
+    /// Rebuild limits from container labels, ignoring labels that no longer validate
+    pub fn from_labels(labels: &[(String, String)]) -> Self {
+        let get = |key: &str| {
+            labels
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        Self {
+            memory: get(LABEL_MEMORY).and_then(|v| validate_memory(v).ok()),
+            cpus: get(LABEL_CPUS).and_then(|v| validate_cpus(v).ok()),
+            pids_limit: get(LABEL_PIDS).and_then(|v| validate_pids_limit(v).ok()),
+        }
+    }
+
+    /// `docker run` arguments: the limit flags plus the labels recording them
+    pub fn docker_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let entries = [
+            ("--memory", LABEL_MEMORY, &self.memory),
+            ("--cpus", LABEL_CPUS, &self.cpus),
+            ("--pids-limit", LABEL_PIDS, &self.pids_limit),
+        ];
+        for (flag, label, value) in entries {
+            if let Some(value) = value {
+                args.push(flag.to_string());
+                args.push(value.clone());
+                args.push("--label".to_string());
+                args.push(format!("{}={}", label, value));
+            }
+        }
+        args
+    }
+}
+
+/// Docker's minimum memory limit
+const MIN_MEMORY_BYTES: u64 = 6 * 1024 * 1024;
+
+fn validate_memory(value: &str) -> Result<String, String> {
+    let value = value.trim().to_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => {
+            return Err(format!(
+                "Invalid --memory '{}': expected a size like 512m or 2g",
+                value
+            ))
+        }
+    };
+    let bytes = number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid --memory '{}': expected a size like 512m or 2g", value))?;
+    if bytes < MIN_MEMORY_BYTES {
+        return Err(format!("Invalid --memory '{}': minimum is 6m", value));
+    }
+    Ok(format!("{}{}", number, unit.trim_end_matches('b')))
+}
+
+fn validate_cpus(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    match value.parse::<f64>() {
+        Ok(cpus) if cpus.is_finite() && cpus > 0.0 => Ok(value.to_string()),
+        _ => Err(format!(
+            "Invalid --cpus '{}': expected a positive number like 1.5",
+            value
+        )),
+    }
+}
+
+fn validate_pids_limit(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    match value.parse::<u32>() {
+        Ok(pids) if pids > 0 => Ok(pids.to_string()),
+        _ => Err(format!(
+            "Invalid --pids-limit '{}': expected a positive integer",
+            value
+        )),
+    }
+}
+
+/// Docker label recording published ports; mounts can be read back from the
+/// container, but ports are rebuilt from this when it is recreated
+const LABEL_PORTS: &str = "cocoon.ports";
+
+/// Where the cocoon keeps its identity and data inside the container
+const DATA_DIR: &str = "/cocoon";
+
+/// Extra `--volume` and `--port` mappings for a Docker cocoon
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContainerMappings {
+    /// `HOST:CONTAINER[:ro|rw]`, with host paths made absolute
+    pub volumes: Vec<String>,
+    /// `HOST:CONTAINER[/tcp|/udp]`
+    pub ports: Vec<String>,
+}
+
+impl ContainerMappings {
+    /// Validate raw `--volume` and `--port` values
+    pub fn parse(volumes: &[String], ports: &[String]) -> Result<Self, String> {
+        let volumes = volumes
+            .iter()
+            .map(|v| validate_volume(v))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ports = ports
+            .iter()
+            .map(|p| validate_port(p))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut targets = std::collections::HashSet::new();
+        for volume in &volumes {
+            let target = volume.split(':').nth(1).unwrap_or_default();
+            if !targets.insert(target) {
+                return Err(format!("Invalid --volume: '{}' is mounted more than once", target));
+            }
+        }
+        let mut published = std::collections::HashSet::new();
+        for port in &ports {
+            let (host, rest) = port.split_once(':').unwrap_or_default();
+            let protocol = rest.split_once('/').map_or("tcp", |(_, p)| p);
+            if !published.insert((host, protocol)) {
+                return Err(format!("Invalid --port: host port {}/{} is published more than once", host, protocol));
+            }
+        }
+
+        Ok(Self { volumes, ports })
+    }
+
+    /// Rebuild the port mappings from container labels, ignoring entries that no longer validate
+    pub fn from_labels(labels: &[(String, String)]) -> Self {
+        let ports = labels
+            .iter()
+            .find(|(k, _)| k == LABEL_PORTS)
+            .map(|(_, v)| v.split(',').filter_map(|p| validate_port(p).ok()).collect())
+            .unwrap_or_default();
+        Self { volumes: Vec::new(), ports }
+    }
+
+    /// `docker run` volume arguments: the `name:/cocoon` data volume unless a
+    /// mapping replaces `/cocoon`, then the mappings
+    pub fn volume_args(&self, name: &str) -> Vec<String> {
+        let replaces_data_dir = self
+            .volumes
+            .iter()
+            .any(|v| v.split(':').nth(1) == Some(DATA_DIR));
+        let default_volume = (!replaces_data_dir).then(|| format!("{}:{}", name, DATA_DIR));
+        default_volume
+            .iter()
+            .chain(&self.volumes)
+            .flat_map(|v| ["-v".to_string(), v.clone()])
+            .collect()
+    }
+
+    /// `docker run` port arguments plus the label recording them
+    pub fn port_args(&self) -> Vec<String> {
+        if self.ports.is_empty() {
+            return Vec::new();
+        }
+        let mut args: Vec<String> = self
+            .ports
+            .iter()
+            .flat_map(|p| ["-p".to_string(), p.clone()])
+            .collect();
+        args.push("--label".to_string());
+        args.push(format!("{}={}", LABEL_PORTS, self.ports.join(",")));
+        args
+    }
+}
+
+fn validate_volume(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let invalid = |reason: &str| {
+        format!(
+            "Invalid --volume '{}': {} (expected HOST:CONTAINER[:ro|rw])",
+            value, reason
+        )
+    };
+    if value.chars().any(char::is_whitespace) {
+        return Err(invalid("whitespace is not supported"));
+    }
+
+    let parts: Vec<&str> = value.split(':').collect();
+    let (host, container, mode) = match parts.as_slice() {
+        [host, container] => (*host, *container, None),
+        [host, container, mode] => (*host, *container, Some(*mode)),
+        _ => return Err(invalid("wrong number of fields")),
+    };
+
+    let host = if host.starts_with('/') {
+        host.to_string()
+    } else if host.starts_with('.') {
+        // Docker only binds absolute paths; relative ones are taken from here
+        let cwd = std::env::current_dir().map_err(|e| invalid(&format!("cannot resolve relative path: {}", e)))?;
+        cwd.join(host).display().to_string()
+    } else if host.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        // Named volume
+        host.to_string()
+    } else {
+        return Err(invalid("host side must be an absolute path, ./relative path or volume name"));
+    };
+
+    if !container.starts_with('/') {
+        return Err(invalid("container side must be an absolute path"));
+    }
+    let container = container.trim_end_matches('/');
+    if container.is_empty() {
+        return Err(invalid("cannot mount over /"));
+    }
+
+    match mode {
+        None => Ok(format!("{}:{}", host, container)),
+        Some(mode @ ("ro" | "rw")) => Ok(format!("{}:{}:{}", host, container, mode)),
+        Some(_) => Err(invalid("mode must be ro or rw")),
+    }
+}
+
+fn validate_port(value: &str) -> Result<String, String> {
+    let value = value.trim();
+    let invalid = || {
+        format!(
+            "Invalid --port '{}': expected HOST:CONTAINER[/tcp|/udp] with ports 1-65535",
+            value
+        )
+    };
+
+    let (ports, protocol) = match value.split_once('/') {
+        Some((ports, protocol @ ("tcp" | "udp"))) => (ports, Some(protocol)),
+        Some(_) => return Err(invalid()),
+        None => (value, None),
+    };
+    let (host, container) = ports.split_once(':').ok_or_else(invalid)?;
+    let port = |s: &str| s.parse::<u16>().ok().filter(|p| *p > 0).ok_or_else(invalid);
+    let (host, container) = (port(host)?, port(container)?);
+
+    Ok(match protocol {
+        Some(protocol) => format!("{}:{}/{}", host, container, protocol),
+        None => format!("{}:{}", host, container),
+    })
+}
+
+/// Name generated for Docker cocoons when no `--name-prefix` is given
+pub const DEFAULT_CONTAINER_NAME: &str = "cocoon-worker";
+
+/// Docker lists cocoons by this name prefix
+const CONTAINER_NAME_PREFIX: &str = "cocoon-";
+
+/// Check a `--name-prefix` so the generated containers still show up as cocoons
+pub fn validate_name_prefix(prefix: &str) -> Result<String, String> {
+    let prefix = prefix.trim().trim_end_matches('-');
+    let valid_chars = prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !prefix.starts_with(CONTAINER_NAME_PREFIX) || prefix.len() == CONTAINER_NAME_PREFIX.len() || !valid_chars {
+        return Err(format!(
+            "Invalid --name-prefix '{}': expected '{}<name>' using letters, digits, '-', '_' or '.'",
+            prefix, CONTAINER_NAME_PREFIX
+        ));
+    }
+    Ok(prefix.to_string())
+}
+
+/// First free name in a series, given the names of existing containers.
+///
+/// Without a prefix this is `cocoon-worker`, then `cocoon-worker-2`, ... A prefix
+/// is always numbered from 1 (`cocoon-ci-1`, `cocoon-ci-2`, ...), so scripts can
+/// predict every name in the series.
+pub fn next_container_name(prefix: Option<&str>, existing: &[String]) -> String {
+    let taken = |name: &str| existing.iter().any(|n| n == name);
+    let (base, first) = match prefix {
+        None if !taken(DEFAULT_CONTAINER_NAME) => return DEFAULT_CONTAINER_NAME.to_string(),
+        None => (DEFAULT_CONTAINER_NAME, 2),
+        Some(prefix) => (prefix, 1),
+    };
+    (first..)
+        .map(|num| format!("{}-{}", base, num))
+        .find(|candidate| !taken(candidate))
+        .expect("an unbounded series always has a free name")
+}
+
+/// Names of all Docker containers, running or not
+pub fn docker_container_names() -> Vec<String> {
+    crate::container_engine::command()
+        .args(["ps", "-a", "--format", "{{.Names}}"])
+        .output()
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Whether a failed `docker run` lost a race for its container name
+pub fn is_name_conflict(error: &str) -> bool {
+    error.contains("is already in use")
+}
+
+/// Make `name` free for a new container. A stopped container holding it is only
+/// removed with `replace` (its `<name>` volume, and so its identity, is kept);
+/// a running one is never touched.
+pub fn claim_container_name(name: &str, replace: bool) -> Result<(), String> {
+    let Ok(existing) = DockerRuntime::new().status(name) else {
+        return Ok(());
+    };
+    match existing.status {
+        CocoonStatus::Running | CocoonStatus::Restarting => Err(format!(
+            "Container '{}' is already running. Stop and remove it first, or pick another --name.",
+            name
+        )),
+        _ if !replace => Err(format!(
+            "Container '{}' already exists ({}). Pass --replace to recreate it, or pick another --name.",
+            name, existing.status
+        )),
+        _ => {
+            out_info!("Replacing existing container '{}'", name);
+            DockerRuntime::new().remove(name, false).map(|_| ())
+        }
+    }
+}
+
 pub trait Runtime {
     fn list(&self) -> Result<Vec<CocoonInfo>, String>;
     fn status(&self, name: &str) -> Result<CocoonInfo, String>;
@@ -80,13 +456,34 @@ pub trait Runtime {
     fn runtime_type(&self) -> RuntimeType;
     fn update(&self, name: &str) -> Result<String, String>;
     fn check_update(&self, name: &str) -> Result<String, String>;
+    /// Go back to the binary the last update replaced
+    fn rollback(&self, name: &str) -> Result<String, String>;
+    /// Replace the cocoon's secret and device ID, invalidating every existing claim
+    fn rekey(&self, name: &str) -> Result<String, String>;
+    /// Environment the cocoon process runs with
+    fn env(&self, name: &str) -> Result<HashMap<String, String>, String>;
+    /// Latest metrics snapshot the cocoon saved
+    fn metrics(&self, name: &str) -> Result<crate::metrics::MetricsSnapshot, String>;
 }
 
-pub struct DockerRuntime;
+fn parse_metrics(json: &[u8]) -> Result<crate::metrics::MetricsSnapshot, String> {
+    serde_json::from_slice(json).map_err(|e| format!("Invalid metrics snapshot: {}", e))
+}
+
+/// Container cocoons, run by Docker or Podman (see [`crate::container_engine`])
+pub struct DockerRuntime {
+    engine: ContainerEngine,
+}
 
 impl DockerRuntime {
     pub fn new() -> Self {
-        DockerRuntime
+        DockerRuntime {
+            engine: crate::container_engine::current(),
+        }
+    }
+
+    pub fn engine(&self) -> ContainerEngine {
+        self.engine
     }
 
     fn parse_status(status_str: &str) -> CocoonStatus {
@@ -106,7 +503,7 @@ impl DockerRuntime {
 
 impl Runtime for DockerRuntime {
     fn list(&self) -> Result<Vec<CocoonInfo>, String> {
-        let output = std::process::Command::new("docker")
+        let output = self.engine.command()
             .args([
                 "ps",
                 "-a",
@@ -116,11 +513,11 @@ impl Runtime for DockerRuntime {
                 "{{.Names}}\t{{.Status}}\t{{.Image}}\t{{.CreatedAt}}",
             ])
             .output()
-            .map_err(|e| format!("Failed to run docker: {}", e))?;
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
 
         if !output.status.success() {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(format!("Docker error: {}", stderr));
+            return Err(format!("{} error: {}", self.engine, stderr));
         }
 
         let stdout = String::from_utf8_lossy(&output.stdout);
@@ -154,7 +551,7 @@ impl Runtime for DockerRuntime {
     }
 
     fn status(&self, name: &str) -> Result<CocoonInfo, String> {
-        let output = std::process::Command::new("docker")
+        let output = self.engine.command()
             .args([
                 "inspect",
                 "--format",
@@ -162,7 +559,7 @@ impl Runtime for DockerRuntime {
                 name,
             ])
             .output()
-            .map_err(|e| format!("Failed to run docker: {}", e))?;
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
 
         if !output.status.success() {
             return Err(format!("Container '{}' not found", name));
@@ -185,10 +582,10 @@ impl Runtime for DockerRuntime {
     }
 
     fn start(&self, name: &str) -> Result<String, String> {
-        let output = std::process::Command::new("docker")
+        let output = self.engine.command()
             .args(["start", name])
             .output()
-            .map_err(|e| format!("Failed to run docker: {}", e))?;
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
 
         if output.status.success() {
             Ok(format!("Container '{}' started", name))
@@ -199,10 +596,10 @@ impl Runtime for DockerRuntime {
     }
 
     fn stop(&self, name: &str) -> Result<String, String> {
-        let output = std::process::Command::new("docker")
+        let output = self.engine.command()
             .args(["stop", name])
             .output()
-            .map_err(|e| format!("Failed to run docker: {}", e))?;
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
 
         if output.status.success() {
             Ok(format!("Container '{}' stopped", name))
@@ -213,10 +610,10 @@ impl Runtime for DockerRuntime {
     }
 
     fn restart(&self, name: &str) -> Result<String, String> {
-        let output = std::process::Command::new("docker")
+        let output = self.engine.command()
             .args(["restart", name])
             .output()
-            .map_err(|e| format!("Failed to run docker: {}", e))?;
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
 
         if output.status.success() {
             Ok(format!("Container '{}' restarted", name))
@@ -228,7 +625,7 @@ impl Runtime for DockerRuntime {
 
     fn logs(&self, name: &str, follow: bool, tail: Option<u32>) -> Result<(), String> {
         let tail_str = tail.unwrap_or(50).to_string();
-        let mut cmd = std::process::Command::new("docker");
+        let mut cmd = self.engine.command();
         cmd.args(["logs", "--tail", &tail_str]);
 
         if follow {
@@ -239,7 +636,7 @@ impl Runtime for DockerRuntime {
         cmd.arg(name);
         let status = cmd
             .status()
-            .map_err(|e| format!("Failed to run docker: {}", e))?;
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
 
         if status.success() {
             Ok(())
@@ -249,7 +646,7 @@ impl Runtime for DockerRuntime {
     }
 
     fn remove(&self, name: &str, force: bool) -> Result<String, String> {
-        let mut cmd = std::process::Command::new("docker");
+        let mut cmd = self.engine.command();
         cmd.arg("rm");
 
         if force {
@@ -260,7 +657,7 @@ impl Runtime for DockerRuntime {
 
         let output = cmd
             .output()
-            .map_err(|e| format!("Failed to run docker: {}", e))?;
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
 
         if output.status.success() {
             Ok(format!("Container '{}' removed", name))
@@ -278,7 +675,7 @@ impl Runtime for DockerRuntime {
     }
 
     fn is_available(&self) -> bool {
-        std::process::Command::new("docker")
+        self.engine.command()
             .arg("version")
             .output()
             .map(|o| o.status.success())
@@ -300,6 +697,8 @@ impl Runtime for DockerRuntime {
             return Ok("Already running the latest image.".to_string());
         }
 
+        self_update::docker::verify_image("latest")?;
+
         let result = self_update::docker::recreate_container(name, "latest")?;
 
         Ok(format!(
@@ -317,7 +716,7 @@ impl Runtime for DockerRuntime {
 
         let mut kv = KeyValue::new()
             .entry("Cocoon", name)
-            .entry("Runtime", "Docker")
+            .entry("Runtime", RuntimeType::Docker.label())
             .entry("Status", info.status.to_string());
         if let Some(ref image) = info.image {
             kv = kv.entry("Image", image);
@@ -334,6 +733,64 @@ impl Runtime for DockerRuntime {
 
         Ok(hint)
     }
+
+    fn rollback(&self, _name: &str) -> Result<String, String> {
+        Err("Rolling back a Docker cocoon is not supported; a failed update already restores the old container".to_string())
+    }
+
+    fn rekey(&self, name: &str) -> Result<String, String> {
+        out_info!("Rekeying Docker cocoon '{}'...", name);
+
+        let _ = self.status(name)?;
+        let secret = crate::core::generate_strong_secret();
+        let result = self_update::docker::rekey_container(name, &secret)?;
+
+        Ok(format!(
+            "Rekey complete!\n  {}\n\n\
+             The cocoon registers with a new device ID; every previous claim on it is void.\n\
+             To claim ownership, add the cocoon in the web UI with this secret:\n\n  {}\n\n\
+             Anyone with this secret can become an owner. Share it only with trusted co-owners.",
+            result, secret
+        ))
+    }
+
+    fn env(&self, name: &str) -> Result<HashMap<String, String>, String> {
+        let output = self.engine.command()
+            .args([
+                "inspect",
+                "--format",
+                "{{range .Config.Env}}{{println .}}{{end}}",
+                name,
+            ])
+            .output()
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
+
+        if !output.status.success() {
+            return Err(format!("Container '{}' not found", name));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect())
+    }
+
+    fn metrics(&self, name: &str) -> Result<crate::metrics::MetricsSnapshot, String> {
+        let output = self.engine.command()
+            .args(["exec", name, "cat", crate::metrics::CONTAINER_SNAPSHOT_PATH])
+            .output()
+            .map_err(|e| format!("Failed to run {}: {}", self.engine, e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "No metrics snapshot in '{}' yet; it is written every {}s while the cocoon runs with a writable /cocoon",
+                name,
+                crate::metrics::SNAPSHOT_INTERVAL.as_secs()
+            ));
+        }
+        parse_metrics(&output.stdout)
+    }
 }
 
 const SERVICE_NAME: &str = "adi.cocoon";
@@ -525,6 +982,101 @@ impl Runtime for MachineRuntime {
         let check_result = self_update::check_for_updates()?;
         Ok(self_update::format_check_result(&check_result))
     }
+
+    fn rollback(&self, _name: &str) -> Result<String, String> {
+        out_info!("Rolling back Machine cocoon...");
+
+        let client = DaemonClient::new();
+        let services = get_runtime()
+            .block_on(client.list_services())
+            .unwrap_or_default();
+
+        if find_cocoon_service(&services).is_none() {
+            return Err(
+                "Cocoon service not registered. Start with: adi cocoon create --runtime machine"
+                    .to_string(),
+            );
+        }
+
+        self_update::machine::rollback()
+    }
+
+    fn rekey(&self, _name: &str) -> Result<String, String> {
+        Err("Rekeying a Machine cocoon is not supported; stop the service, delete its secret and device ID files, and start it again".to_string())
+    }
+
+    fn env(&self, _name: &str) -> Result<HashMap<String, String>, String> {
+        Err("Reading the environment of a Machine cocoon is not supported; query it with get_security_policy instead".to_string())
+    }
+
+    fn metrics(&self, _name: &str) -> Result<crate::metrics::MetricsSnapshot, String> {
+        // Same host, so the snapshot is read directly
+        let path = crate::metrics::machine_snapshot_path()?;
+        let json = std::fs::read(&path).map_err(|e| format!("No metrics snapshot at {}: {}", path.display(), e))?;
+        parse_metrics(&json)
+    }
+}
+
+/// Why [`RuntimeManager::find_cocoon`] could not settle on one cocoon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FindCocoonError {
+    /// No runtime in `searched` has a cocoon by that name
+    NotFound { name: String, searched: Vec<RuntimeType> },
+    /// Several runtimes have a cocoon by that name
+    Ambiguous { name: String, runtimes: Vec<RuntimeType> },
+}
+
+fn join_runtimes(runtimes: &[RuntimeType]) -> String {
+    runtimes.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+impl fmt::Display for FindCocoonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FindCocoonError::NotFound { name, searched } if searched.is_empty() => write!(
+                f,
+                "Cocoon '{}' not found: no runtime available to search (is Docker running?)",
+                name
+            ),
+            FindCocoonError::NotFound { name, searched } => write!(
+                f,
+                "Cocoon '{}' not found (searched: {}). Use 'adi cocoon list' to see available cocoons.",
+                name,
+                join_runtimes(searched)
+            ),
+            FindCocoonError::Ambiguous { name, runtimes } => write!(
+                f,
+                "Cocoon '{}' exists in several runtimes ({}). Pass --runtime {} to choose one.",
+                name,
+                join_runtimes(runtimes),
+                runtimes.iter().map(|r| r.to_string()).collect::<Vec<_>>().join("|")
+            ),
+        }
+    }
+}
+
+impl From<FindCocoonError> for String {
+    fn from(e: FindCocoonError) -> Self {
+        e.to_string()
+    }
+}
+
+fn pick_cocoon(
+    name: &str,
+    searched: Vec<RuntimeType>,
+    mut found: Vec<(CocoonInfo, RuntimeType)>,
+) -> Result<(CocoonInfo, RuntimeType), FindCocoonError> {
+    match found.len() {
+        0 => Err(FindCocoonError::NotFound {
+            name: name.to_string(),
+            searched,
+        }),
+        1 => Ok(found.remove(0)),
+        _ => Err(FindCocoonError::Ambiguous {
+            name: name.to_string(),
+            runtimes: found.into_iter().map(|(_, rt)| rt).collect(),
+        }),
+    }
 }
 
 pub struct RuntimeManager {
@@ -565,21 +1117,37 @@ impl RuntimeManager {
         }
     }
 
-    pub fn find_cocoon(&self, name: &str) -> Option<(CocoonInfo, RuntimeType)> {
-        if self.docker.is_available() {
+    /// Find the cocoon called `name`, optionally only in `runtime`.
+    ///
+    /// A name that exists in more than one runtime is reported as ambiguous
+    /// rather than resolved to whichever runtime happens to be checked first.
+    pub fn find_cocoon(
+        &self,
+        name: &str,
+        runtime: Option<RuntimeType>,
+    ) -> Result<(CocoonInfo, RuntimeType), FindCocoonError> {
+        let wanted = |rt: RuntimeType| runtime.is_none_or(|r| r == rt);
+        let mut searched = Vec::new();
+        let mut found = Vec::new();
+
+        if wanted(RuntimeType::Docker) && self.docker.is_available() {
+            searched.push(RuntimeType::Docker);
             if let Ok(info) = self.docker.status(name) {
-                return Some((info, RuntimeType::Docker));
+                found.push((info, RuntimeType::Docker));
             }
         }
 
-        // Check Machine (only has one cocoon named "cocoon")
-        if self.machine.is_available() && name == "cocoon" {
-            if let Ok(info) = self.machine.status(name) {
-                return Some((info, RuntimeType::Machine));
+        // Machine only has one cocoon, named "cocoon"
+        if wanted(RuntimeType::Machine) && self.machine.is_available() {
+            searched.push(RuntimeType::Machine);
+            if name == "cocoon" {
+                if let Ok(info) = self.machine.status(name) {
+                    found.push((info, RuntimeType::Machine));
+                }
             }
         }
 
-        None
+        pick_cocoon(name, searched, found)
     }
 
     pub fn available_runtimes(&self) -> Vec<RuntimeType> {
@@ -593,3 +1161,141 @@ impl RuntimeManager {
         runtimes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(name: &str, runtime: RuntimeType) -> (CocoonInfo, RuntimeType) {
+        let info = CocoonInfo {
+            name: name.to_string(),
+            runtime,
+            status: CocoonStatus::Running,
+            created: None,
+            image: None,
+        };
+        (info, runtime)
+    }
+
+    #[test]
+    fn test_pick_cocoon_ambiguous_across_runtimes() {
+        let both = vec![RuntimeType::Docker, RuntimeType::Machine];
+        let found = vec![info("cocoon", RuntimeType::Docker), info("cocoon", RuntimeType::Machine)];
+
+        let err = pick_cocoon("cocoon", both.clone(), found).unwrap_err();
+        assert_eq!(
+            err,
+            FindCocoonError::Ambiguous {
+                name: "cocoon".to_string(),
+                runtimes: both.clone(),
+            }
+        );
+        assert!(err.to_string().contains("--runtime docker|machine"));
+
+        // Narrowed to one runtime, the same name resolves
+        let (_, runtime) =
+            pick_cocoon("cocoon", vec![RuntimeType::Machine], vec![info("cocoon", RuntimeType::Machine)]).unwrap();
+        assert_eq!(runtime, RuntimeType::Machine);
+    }
+
+    #[test]
+    fn test_pick_cocoon_not_found_lists_searched_runtimes() {
+        let err = pick_cocoon("worker", vec![RuntimeType::Docker, RuntimeType::Machine], vec![]).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("searched: docker, machine"));
+        assert!(message.contains("adi cocoon list"));
+
+        let none = pick_cocoon("worker", vec![], vec![]).unwrap_err();
+        assert!(none.to_string().contains("no runtime available"));
+    }
+
+    #[test]
+    fn test_resource_limits_parse() {
+        let limits = ResourceLimits::parse(Some("512M"), Some("1.5"), Some("256")).unwrap();
+        assert_eq!(limits.memory.as_deref(), Some("512m"));
+        assert_eq!(limits.cpus.as_deref(), Some("1.5"));
+        assert_eq!(limits.pids_limit.as_deref(), Some("256"));
+
+        assert_eq!(ResourceLimits::parse(None, None, None).unwrap(), ResourceLimits::default());
+        assert!(ResourceLimits::default().docker_args().is_empty());
+
+        assert!(ResourceLimits::parse(Some("1k"), None, None).is_err());
+        assert!(ResourceLimits::parse(Some("lots"), None, None).is_err());
+        assert!(ResourceLimits::parse(None, Some("0"), None).is_err());
+        assert!(ResourceLimits::parse(None, Some("NaN"), None).is_err());
+        assert!(ResourceLimits::parse(None, None, Some("-1")).is_err());
+    }
+
+    #[test]
+    fn test_container_mappings_parse() {
+        let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let mappings = ContainerMappings::parse(
+            &strings(&["/srv/src:/workspace/:ro", "cache:/root/.cache"]),
+            &strings(&["8080:80", " 5353:53/udp "]),
+        )
+        .unwrap();
+        assert_eq!(mappings.volumes, vec!["/srv/src:/workspace:ro", "cache:/root/.cache"]);
+        assert_eq!(mappings.ports, vec!["8080:80", "5353:53/udp"]);
+        assert_eq!(
+            mappings.volume_args("cocoon-dev"),
+            strings(&["-v", "cocoon-dev:/cocoon", "-v", "/srv/src:/workspace:ro", "-v", "cache:/root/.cache"])
+        );
+        assert_eq!(
+            mappings.port_args(),
+            strings(&["-p", "8080:80", "-p", "5353:53/udp", "--label", "cocoon.ports=8080:80,5353:53/udp"])
+        );
+
+        let labels = vec![("cocoon.ports".to_string(), "8080:80,5353:53/udp".to_string())];
+        assert_eq!(ContainerMappings::from_labels(&labels).ports, mappings.ports);
+
+        let data = ContainerMappings::parse(&strings(&["/srv/cocoon:/cocoon"]), &[]).unwrap();
+        assert_eq!(data.volume_args("cocoon-dev"), strings(&["-v", "/srv/cocoon:/cocoon"]));
+        assert!(ContainerMappings::default().port_args().is_empty());
+
+        for bad in ["/srv", "/srv:workspace", "/srv:/", "/srv:/w:rx", "my vol:/w", "$HOME:/w", "a:/w:ro:x"] {
+            assert!(ContainerMappings::parse(&strings(&[bad]), &[]).is_err(), "{:?} should be rejected", bad);
+        }
+        for bad in ["8080", "0:80", "8080:65536", "8080:80/sctp", "a:80"] {
+            assert!(ContainerMappings::parse(&[], &strings(&[bad])).is_err(), "{:?} should be rejected", bad);
+        }
+        assert!(ContainerMappings::parse(&strings(&["/a:/w", "/b:/w/"]), &[]).is_err());
+        assert!(ContainerMappings::parse(&[], &strings(&["8080:80", "8080:81"])).is_err());
+        assert!(ContainerMappings::parse(&[], &strings(&["53:53/tcp", "53:53/udp"])).is_ok());
+    }
+
+    #[test]
+    fn test_next_container_name() {
+        let existing = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(next_container_name(None, &[]), "cocoon-worker");
+        assert_eq!(next_container_name(None, &existing(&["cocoon-worker"])), "cocoon-worker-2");
+        assert_eq!(
+            next_container_name(None, &existing(&["cocoon-worker", "cocoon-worker-2", "cocoon-worker-4"])),
+            "cocoon-worker-3"
+        );
+
+        assert_eq!(next_container_name(Some("cocoon-ci"), &existing(&["cocoon-worker"])), "cocoon-ci-1");
+        assert_eq!(
+            next_container_name(Some("cocoon-ci"), &existing(&["cocoon-ci-1", "cocoon-ci-2"])),
+            "cocoon-ci-3"
+        );
+
+        assert_eq!(validate_name_prefix("cocoon-ci-").unwrap(), "cocoon-ci");
+        assert!(validate_name_prefix("ci").is_err());
+        assert!(validate_name_prefix("cocoon-").is_err());
+        assert!(validate_name_prefix("cocoon-ci/x").is_err());
+    }
+
+    #[test]
+    fn test_resource_limits_label_roundtrip() {
+        let limits = ResourceLimits::parse(Some("2g"), Some("2"), Some("100")).unwrap();
+        let args = limits.docker_args();
+        let labels: Vec<(String, String)> = args
+            .iter()
+            .filter_map(|arg| arg.split_once('='))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(ResourceLimits::from_labels(&labels), limits);
+    }
+}
@@ -1,13 +1,23 @@
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
 use lib_console_output::{out_info, out_success, KeyValue, Renderable};
 use semver::Version;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use lib_env_parse::{env_opt, env_vars};
 
 env_vars! {
     Home => "HOME",
+    CocoonUpdatePubkey => "COCOON_UPDATE_PUBKEY",
 }
 
+/// Release signing key (base64 ed25519) baked in by release builds, which set
+/// `COCOON_UPDATE_PUBKEY` at compile time. The runtime variable overrides it.
+const PINNED_UPDATE_PUBKEY: Option<&str> = option_env!("COCOON_UPDATE_PUBKEY");
+
 const REPO_OWNER: &str = "adi-family";
 const REPO_NAME: &str = "cocoon";
 const DOCKER_IMAGE: &str = "docker-registry.the-ihor.com/cocoon";
@@ -42,7 +52,7 @@ pub fn get_target_triple() -> String {
     format!("{}-{}", arch, os)
 }
 
-pub fn fetch_latest_version() -> Result<(String, Option<String>), String> {
+fn fetch_latest_release() -> Result<self_update::update::Release, String> {
     use self_update::backends::github::ReleaseList;
 
     let releases = ReleaseList::configure()
@@ -53,14 +63,16 @@ pub fn fetch_latest_version() -> Result<(String, Option<String>), String> {
         .fetch()
         .map_err(|e| format!("Failed to fetch releases: {}", e))?;
 
-    let latest = releases
-        .first()
-        .ok_or_else(|| "No releases found".to_string())?;
+    releases
+        .into_iter()
+        .next()
+        .ok_or_else(|| "No releases found".to_string())
+}
 
+pub fn fetch_latest_version() -> Result<(String, Option<String>), String> {
+    let latest = fetch_latest_release()?;
     let version = latest.version.trim_start_matches('v').to_string();
-    let notes = latest.body.clone();
-
-    Ok((version, notes))
+    Ok((version, latest.body))
 }
 
 pub fn check_for_updates() -> Result<UpdateCheckResult, String> {
@@ -86,46 +98,399 @@ pub fn check_for_updates() -> Result<UpdateCheckResult, String> {
     })
 }
 
-pub fn download_latest_binary(install_dir: &PathBuf) -> Result<String, String> {
-    use self_update::backends::github::Update;
-    use self_update::cargo_crate_version;
+/// Release assets that may carry the SHA-256 of a binary archive, most specific first
+fn checksum_asset_names(asset_name: &str) -> [String; 3] {
+    [
+        format!("{}.sha256", asset_name),
+        "SHA256SUMS".to_string(),
+        "sha256sums.txt".to_string(),
+    ]
+}
+
+/// Expected hash for `asset_name` from a `sha256sum`-style listing, or from a
+/// per-asset file holding just the hash
+fn parse_checksum(listing: &str, asset_name: &str) -> Option<String> {
+    let is_sha256 = |s: &str| s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit());
+
+    for line in listing.lines() {
+        let mut parts = line.split_whitespace();
+        let Some(hash) = parts.next().filter(|h| is_sha256(h)) else {
+            continue;
+        };
+        // `sha256sum -b` marks binary mode with a leading '*'
+        match parts.next().map(|name| name.trim_start_matches('*')) {
+            None => return Some(hash.to_ascii_lowercase()),
+            Some(name) if name == asset_name || name.ends_with(&format!("/{}", asset_name)) => {
+                return Some(hash.to_ascii_lowercase())
+            }
+            Some(_) => {}
+        }
+    }
+    None
+}
+
+/// Key release assets must be signed with; without one nothing is installed
+fn update_public_key() -> Result<VerifyingKey, String> {
+    let (source, value) = match env_opt(EnvVar::CocoonUpdatePubkey.as_str()).filter(|v| !v.trim().is_empty()) {
+        Some(value) => ("COCOON_UPDATE_PUBKEY", value),
+        None => match PINNED_UPDATE_PUBKEY {
+            Some(value) => ("pinned update key", value.to_string()),
+            None => {
+                return Err(
+                    "This build has no pinned update key and COCOON_UPDATE_PUBKEY is unset; refusing to install an unverified binary"
+                        .to_string(),
+                )
+            }
+        },
+    };
+    parse_public_key(&value).map_err(|e| format!("Invalid {}: {}", source, e))
+}
+
+fn parse_public_key(value: &str) -> Result<VerifyingKey, String> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(value.trim())
+        .map_err(|e| format!("not base64: {}", e))?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| format!("expected a 32-byte ed25519 key, got {} bytes", b.len()))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())
+}
+
+/// A detached signature as published with a release: 64 raw bytes or base64
+fn parse_signature(data: &[u8]) -> Result<Signature, String> {
+    let bytes = if data.len() == Signature::BYTE_SIZE {
+        data.to_vec()
+    } else {
+        let text = std::str::from_utf8(data).map_err(|_| "signature is neither raw nor base64".to_string())?;
+        base64::engine::general_purpose::STANDARD
+            .decode(text.trim())
+            .map_err(|e| format!("signature is not base64: {}", e))?
+    };
+    let bytes: [u8; Signature::BYTE_SIZE] = bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| format!("expected a 64-byte ed25519 signature, got {} bytes", b.len()))?;
+    Ok(Signature::from_bytes(&bytes))
+}
+
+fn verify_signature(key: &VerifyingKey, data: &[u8], signature: &Signature) -> Result<(), String> {
+    key.verify_strict(data, signature)
+        .map_err(|_| "signature does not match the update key".to_string())
+}
+
+fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = std::fs::File::open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn github_client() -> Result<reqwest::Client, String> {
+    reqwest::Client::builder()
+        .user_agent(concat!("cocoon/", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(|e| format!("Failed to create HTTP client: {}", e))
+}
+
+fn asset_request(client: &reqwest::Client, url: &str) -> reqwest::RequestBuilder {
+    // GitHub API asset URLs serve the file itself only with this Accept header
+    client.get(url).header(reqwest::header::ACCEPT, "application/octet-stream")
+}
+
+/// Download `url` to `dest`. If a previous attempt left a partial file there,
+/// only the missing bytes are requested with a `Range` header.
+async fn download_resumable(client: &reqwest::Client, url: &str, dest: &Path) -> Result<(), String> {
+    let offset = std::fs::metadata(dest).map(|m| m.len()).unwrap_or(0);
+
+    let mut request = asset_request(client, url);
+    if offset > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+    }
+    let mut response = request.send().await.map_err(|e| format!("Download failed: {}", e))?;
+
+    let status = response.status();
+    let append = match status {
+        reqwest::StatusCode::PARTIAL_CONTENT => {
+            out_info!("  Resuming download at {} bytes...", offset);
+            true
+        }
+        // Everything was already downloaded; the checksum decides if it is intact
+        reqwest::StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(()),
+        s if s.is_success() => false,
+        s => return Err(format!("Download failed: HTTP {}", s)),
+    };
+
+    let mut options = std::fs::OpenOptions::new();
+    if append {
+        options.append(true);
+    } else {
+        options.create(true).write(true).truncate(true);
+    }
+    let mut file = options
+        .open(dest)
+        .map_err(|e| format!("Failed to open {}: {}", dest.display(), e))?;
+
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| format!("Download interrupted (rerun to resume): {}", e))?
+    {
+        file.write_all(&chunk)
+            .map_err(|e| format!("Failed to write {}: {}", dest.display(), e))?;
+    }
+    file.sync_all()
+        .map_err(|e| format!("Failed to write {}: {}", dest.display(), e))
+}
+
+/// Versions the last update or rollback left installed, kept next to the
+/// binary as `cocoon.versions.json` so a rollback knows what it returns to. The
+/// install directory is the one place both the CLI and the service agree on,
+/// even when the service is a system unit with no `HOME`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstalledVersions {
+    pub current: String,
+    pub previous: Option<String>,
+    /// The current binary has registered with the signaling server since it was installed
+    pub confirmed: bool,
+}
+
+fn versions_path(install_dir: &Path) -> PathBuf {
+    install_dir.join("cocoon.versions.json")
+}
+
+pub fn read_installed_versions(install_dir: &Path) -> Option<InstalledVersions> {
+    let json = std::fs::read(versions_path(install_dir)).ok()?;
+    serde_json::from_slice(&json).ok()
+}
+
+fn write_installed_versions(install_dir: &Path, versions: &InstalledVersions) -> Result<(), String> {
+    let path = versions_path(install_dir);
+    let json = serde_json::to_vec_pretty(versions).map_err(|e| e.to_string())?;
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, json)
+        .and_then(|_| std::fs::rename(&tmp, &path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Mark the running version as healthy once it has registered, which is what
+/// the update health check waits for. Does nothing unless an update or
+/// rollback installed this version and is still waiting.
+pub fn confirm_running_version() {
+    let Ok(install_dir) = machine::get_install_dir() else {
+        return;
+    };
+    let Some(mut versions) = read_installed_versions(&install_dir) else {
+        return;
+    };
+    if versions.confirmed || versions.current != env!("CARGO_PKG_VERSION") {
+        return;
+    }
+    versions.confirmed = true;
+    if let Err(e) = write_installed_versions(&install_dir, &versions) {
+        tracing::warn!("⚠️ Could not confirm version {} after update: {}", versions.current, e);
+    }
+}
+
+/// Where the binary an update replaced is kept
+fn backup_path(installed: &Path) -> PathBuf {
+    let mut name = installed.file_name().unwrap_or_default().to_os_string();
+    name.push(".bak");
+    installed.with_file_name(name)
+}
+
+/// Exchange `installed` and its backup. `installed` is replaced with an atomic
+/// rename, so a binary is in place at every point.
+fn swap_with_backup(installed: &Path) -> Result<(), String> {
+    let backup = backup_path(installed);
+    if !backup.exists() {
+        return Err(format!("No previous binary at {}; nothing to roll back to", backup.display()));
+    }
+    let mut swap_name = installed.file_name().unwrap_or_default().to_os_string();
+    swap_name.push(".swap");
+    let swap = installed.with_file_name(swap_name);
+
+    std::fs::copy(installed, &swap).map_err(|e| format!("Failed to copy {}: {}", installed.display(), e))?;
+    if let Err(e) = std::fs::rename(&backup, installed) {
+        let _ = std::fs::remove_file(&swap);
+        return Err(format!("Failed to restore {}: {}", backup.display(), e));
+    }
+    std::fs::rename(&swap, &backup).map_err(|e| format!("Failed to keep {} as {}: {}", installed.display(), backup.display(), e))
+}
+
+/// Unpack the cocoon binary from a downloaded asset next to the installed one
+fn stage_binary(download: &Path, asset_name: &str, install_dir: &Path, bin_name: &str) -> Result<PathBuf, String> {
+    let staged = install_dir.join(format!(".{}.new", bin_name));
+
+    if asset_name.ends_with(".tar.gz") || asset_name.ends_with(".tgz") {
+        let extract_dir = install_dir.join(".cocoon-update");
+        let _ = std::fs::remove_dir_all(&extract_dir);
+        std::fs::create_dir_all(&extract_dir)
+            .map_err(|e| format!("Failed to create {}: {}", extract_dir.display(), e))?;
+        self_update::Extract::from_source(download)
+            .archive(self_update::ArchiveKind::Tar(Some(self_update::Compression::Gz)))
+            .extract_file(&extract_dir, bin_name)
+            .map_err(|e| format!("Failed to extract {}: {}", asset_name, e))?;
+        std::fs::rename(extract_dir.join(bin_name), &staged)
+            .map_err(|e| format!("Failed to stage new binary: {}", e))?;
+        let _ = std::fs::remove_dir_all(&extract_dir);
+    } else {
+        std::fs::copy(download, &staged).map_err(|e| format!("Failed to stage new binary: {}", e))?;
+    }
 
-    let current_version = cargo_crate_version!();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))
+            .map_err(|e| format!("Failed to make new binary executable: {}", e))?;
+    }
+    Ok(staged)
+}
+
+/// Download, verify and install the latest release into `install_dir`.
+///
+/// The asset is downloaded to a `.part` file that survives failures, so a rerun
+/// resumes it. It must match the SHA-256 published with the release, then the
+/// ed25519 signature in `<asset>.sig` made with the update key. If either check
+/// fails the download is discarded and the installed binary is left alone.
+/// Only a verified binary is swapped in, with an atomic rename. The binary it
+/// replaces is kept as `<binary>.bak` for a rollback.
+pub fn download_latest_binary(install_dir: &Path) -> Result<String, String> {
+    let current_version = env!("CARGO_PKG_VERSION");
     let target = get_target_triple();
+    let bin_name = format!("cocoon{}", std::env::consts::EXE_SUFFIX);
 
     out_info!("  Current version: {}", current_version);
     out_info!("  Target: {}", target);
     out_info!("  Checking for updates...");
 
-    let status = Update::configure()
-        .repo_owner(REPO_OWNER)
-        .repo_name(REPO_NAME)
-        .bin_name("cocoon")
-        .current_version(current_version)
-        .target(&target)
-        .bin_install_path(install_dir)
-        .no_confirm(true)
-        .show_download_progress(true)
-        .show_output(true)
-        .build()
-        .map_err(|e| format!("Failed to configure updater: {}", e))?
-        .update()
-        .map_err(|e| format!("Update failed: {}", e))?;
+    let release = fetch_latest_release()?;
+    let latest_version = release.version.trim_start_matches('v').to_string();
+    let current = Version::parse(current_version)
+        .map_err(|e| format!("Failed to parse current version '{}': {}", current_version, e))?;
+    let latest = Version::parse(&latest_version)
+        .map_err(|e| format!("Failed to parse latest version '{}': {}", latest_version, e))?;
+    if latest <= current {
+        return Ok(format!("Already up to date (version {})", current_version));
+    }
+
+    let asset = release
+        .asset_for(&target, None)
+        .ok_or_else(|| format!("Release {} has no asset for {}", latest_version, target))?;
+    let checksum_asset = checksum_asset_names(&asset.name)
+        .iter()
+        .find_map(|name| release.assets.iter().find(|a| &a.name == name))
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "Release {} publishes no SHA-256 checksum for {}; refusing to install an unverified binary",
+                latest_version, asset.name
+            )
+        })?;
+
+    let signature_name = format!("{}.sig", asset.name);
+    let signature_asset = release
+        .assets
+        .iter()
+        .find(|a| a.name == signature_name)
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "Release {} publishes no signature for {}; refusing to install an unverified binary",
+                latest_version, asset.name
+            )
+        })?;
+    let public_key = update_public_key()?;
+
+    let download = install_dir.join(format!(".{}.part", asset.name));
+    crate::get_runtime().block_on(async {
+        let client = github_client()?;
+
+        let listing = asset_request(&client, &checksum_asset.download_url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Failed to fetch {}: {}", checksum_asset.name, e))?
+            .text()
+            .await
+            .map_err(|e| format!("Failed to fetch {}: {}", checksum_asset.name, e))?;
+        let expected = parse_checksum(&listing, &asset.name)
+            .ok_or_else(|| format!("{} has no SHA-256 entry for {}", checksum_asset.name, asset.name))?;
+
+        out_info!("  Downloading {}...", asset.name);
+        download_resumable(&client, &asset.download_url, &download).await?;
+
+        let actual = sha256_file(&download)?;
+        if actual != expected {
+            let _ = std::fs::remove_file(&download);
+            return Err(format!(
+                "Checksum mismatch for {}: expected {}, got {}. The download was discarded and the current binary kept.",
+                asset.name, expected, actual
+            ));
+        }
+        out_info!("  Verified SHA-256 {}", actual);
+
+        let signature = asset_request(&client, &signature_asset.download_url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| format!("Failed to fetch {}: {}", signature_asset.name, e))?
+            .bytes()
+            .await
+            .map_err(|e| format!("Failed to fetch {}: {}", signature_asset.name, e))?;
+        let verified = parse_signature(&signature).and_then(|signature| {
+            let data = std::fs::read(&download).map_err(|e| format!("Failed to read {}: {}", download.display(), e))?;
+            verify_signature(&public_key, &data, &signature)
+        });
+        if let Err(e) = verified {
+            let _ = std::fs::remove_file(&download);
+            return Err(format!(
+                "Signature check failed for {}: {}. The download was discarded and the current binary kept.",
+                asset.name, e
+            ));
+        }
+        out_info!("  Verified ed25519 signature");
+        Ok::<(), String>(())
+    })?;
+
+    let staged = stage_binary(&download, &asset.name, install_dir, &bin_name)?;
+    let installed = install_dir.join(&bin_name);
+    if installed.exists() {
+        let backup = backup_path(&installed);
+        if let Err(e) = std::fs::copy(&installed, &backup) {
+            let _ = std::fs::remove_file(&staged);
+            return Err(format!("Failed to back up {} to {}: {}", installed.display(), backup.display(), e));
+        }
+    }
+    if let Err(e) = std::fs::rename(&staged, &installed) {
+        let _ = std::fs::remove_file(&staged);
+        return Err(format!("Failed to replace {}: {}", installed.display(), e));
+    }
+    let _ = std::fs::remove_file(&download);
 
-    match status {
-        self_update::Status::UpToDate(v) => Ok(format!("Already up to date (version {})", v)),
-        self_update::Status::Updated(v) => Ok(format!("Updated to version {}", v)),
+    let versions = InstalledVersions {
+        current: latest_version.clone(),
+        previous: Some(current_version.to_string()),
+        confirmed: false,
+    };
+    if let Err(e) = write_installed_versions(install_dir, &versions) {
+        out_info!("  Warning: {}", e);
     }
+
+    Ok(format!("Updated to version {}", latest_version))
 }
 
 pub mod docker {
-    use lib_console_output::out_info;
+    use lib_console_output::{out_info, out_warn};
+    use std::time::{Duration, Instant};
     use super::DOCKER_IMAGE;
+    use crate::container_engine;
+    use crate::runtime::{ContainerMappings, ResourceLimits};
 
     pub fn pull_latest_image(tag: &str) -> Result<bool, String> {
         let image = format!("{}:{}", DOCKER_IMAGE, tag);
 
-        let before_digest = std::process::Command::new("docker")
+        let before_digest = container_engine::command()
             .args(["images", "--digests", "--format", "{{.Digest}}", &image])
             .output()
             .ok()
@@ -139,7 +504,7 @@ pub mod docker {
 
         out_info!("  Pulling {}...", image);
 
-        let output = std::process::Command::new("docker")
+        let output = container_engine::command()
             .args(["pull", &image])
             .status()
             .map_err(|e| format!("Failed to pull image: {}", e))?;
@@ -148,7 +513,7 @@ pub mod docker {
             return Err("Failed to pull image".to_string());
         }
 
-        let after_digest = std::process::Command::new("docker")
+        let after_digest = container_engine::command()
             .args(["images", "--digests", "--format", "{{.Digest}}", &image])
             .output()
             .ok()
@@ -170,7 +535,7 @@ pub mod docker {
     }
 
     pub fn get_container_env(container_name: &str) -> Result<Vec<(String, String)>, String> {
-        let output = std::process::Command::new("docker")
+        let output = container_engine::command()
             .args([
                 "inspect",
                 "--format",
@@ -202,11 +567,11 @@ pub mod docker {
     }
 
     pub fn get_container_volumes(container_name: &str) -> Result<Vec<String>, String> {
-        let output = std::process::Command::new("docker")
+        let output = container_engine::command()
             .args([
                 "inspect",
                 "--format",
-                "{{range .Mounts}}{{.Source}}:{{.Destination}} {{end}}",
+                "{{range .Mounts}}{{.Source}}:{{.Destination}}{{if not .RW}}:ro{{end}} {{end}}",
                 container_name,
             ])
             .output()
@@ -226,25 +591,126 @@ pub mod docker {
         Ok(volumes)
     }
 
-    pub fn recreate_container(container_name: &str, tag: &str) -> Result<String, String> {
+    pub fn get_container_labels(container_name: &str) -> Result<Vec<(String, String)>, String> {
+        let output = container_engine::command()
+            .args([
+                "inspect",
+                "--format",
+                "{{range $k, $v := .Config.Labels}}{{println $k \"=\" $v}}{{end}}",
+                container_name,
+            ])
+            .output()
+            .map_err(|e| format!("Failed to inspect container: {}", e))?;
+
+        if !output.status.success() {
+            return Err(format!("Container '{}' not found", container_name));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let labels: Vec<(String, String)> = stdout
+            .lines()
+            .filter_map(|line| line.split_once(" = "))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        Ok(labels)
+    }
+
+    /// How long a recreated container gets to come up before the update is rolled back
+    const HEALTH_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// How long a container without a HEALTHCHECK must stay running to count as healthy
+    const STABLE_RUNNING: Duration = Duration::from_secs(5);
+
+    fn docker(args: &[&str]) -> Result<std::process::Output, String> {
+        container_engine::command()
+            .args(args)
+            .output()
+            .map_err(|e| format!("Failed to run {}: {}", container_engine::current(), e))
+    }
+
+    fn backup_name(container_name: &str) -> String {
+        format!("{}-update-backup", container_name)
+    }
+
+    /// Make sure a pulled image is present locally and can be inspected
+    pub fn verify_image(tag: &str) -> Result<(), String> {
         let image = format!("{}:{}", DOCKER_IMAGE, tag);
+        let output = docker(&["image", "inspect", "--format", "{{.Id}}", &image])?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Image {} is not usable: {}",
+                image,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
 
-        out_info!("  Saving container configuration...");
-        let env_vars = get_container_env(container_name)?;
-        let volumes = get_container_volumes(container_name)?;
+    /// Wait until a container is healthy.
+    ///
+    /// Containers with a HEALTHCHECK must report `healthy`; others must stay
+    /// `running` (not restarting) for [`STABLE_RUNNING`].
+    pub fn wait_healthy(container_name: &str) -> Result<(), String> {
+        let deadline = Instant::now() + HEALTH_TIMEOUT;
+        let mut running_since: Option<Instant> = None;
 
-        out_info!("  Stopping old container...");
-        let _ = std::process::Command::new("docker")
-            .args(["stop", container_name])
-            .status();
+        loop {
+            let output = docker(&[
+                "inspect",
+                "--format",
+                "{{.State.Status}}\t{{.State.Restarting}}\t{{if .State.Health}}{{.State.Health.Status}}{{end}}",
+                container_name,
+            ])?;
+            if !output.status.success() {
+                return Err(format!("Container '{}' disappeared", container_name));
+            }
 
-        out_info!("  Removing old container...");
-        let _ = std::process::Command::new("docker")
-            .args(["rm", container_name])
-            .status();
+            let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            let parts: Vec<&str> = stdout.split('\t').collect();
+            let status = parts.first().copied().unwrap_or("");
+            let restarting = parts.get(1).copied() == Some("true");
+            let health = parts.get(2).copied().unwrap_or("");
 
-        out_info!("  Creating new container...");
-        let mut cmd = std::process::Command::new("docker");
+            match (status, health) {
+                (_, "healthy") => return Ok(()),
+                (_, "unhealthy") => {
+                    return Err(format!("Container '{}' reported unhealthy", container_name))
+                }
+                ("exited", _) | ("dead", _) => {
+                    return Err(format!("Container '{}' exited after start", container_name))
+                }
+                ("running", "") if !restarting => {
+                    let since = *running_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= STABLE_RUNNING {
+                        return Ok(());
+                    }
+                }
+                _ => running_since = None,
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "Container '{}' did not become healthy within {}s (status: {})",
+                    container_name,
+                    HEALTH_TIMEOUT.as_secs(),
+                    status
+                ));
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    fn run_container(
+        container_name: &str,
+        image: &str,
+        env_vars: &[(String, String)],
+        volumes: &[String],
+        ports: &ContainerMappings,
+        limits: &ResourceLimits,
+    ) -> Result<String, String> {
+        let mut cmd = container_engine::command();
         cmd.args([
             "run",
             "-d",
@@ -254,7 +720,7 @@ pub mod docker {
             container_name,
         ]);
 
-        for (key, value) in &env_vars {
+        for (key, value) in env_vars {
             // Skip internal Docker env vars
             if key == "PATH" || key == "HOME" || key.starts_with("HOSTNAME") {
                 continue;
@@ -262,45 +728,201 @@ pub mod docker {
             cmd.args(["-e", &format!("{}={}", key, value)]);
         }
 
-        for vol in &volumes {
+        for vol in volumes {
             cmd.args(["-v", vol]);
         }
 
-        // Add host mapping for .local domains
-        for (key, value) in &env_vars {
-            if key == "SIGNALING_SERVER_URL" {
-                if let Ok(url) = url::Url::parse(value) {
-                    if let Some(host) = url.host_str() {
-                        if host.ends_with(".local") {
-                            cmd.args(["--add-host", &format!("{}:host-gateway", host)]);
-                        }
-                    }
-                }
-            }
-        }
+        cmd.args(ports.port_args());
+        cmd.args(limits.docker_args());
 
-        cmd.arg(&image);
+        // `--add-host` flags don't survive as env, so rebuild them from the
+        // aliases and signaling URL the old container ran with
+        let env_value = |name: &str| env_vars.iter().find(|(key, _)| key == name).map(|(_, v)| v.as_str());
+        let host_aliases = match env_value(crate::host_aliases::ENV_VAR) {
+            Some(list) => crate::host_aliases::HostAliases::parse(list)?,
+            None => crate::host_aliases::HostAliases::default(),
+        };
+        cmd.args(host_aliases.docker_args(env_value("SIGNALING_SERVER_URL"), container_engine::current()));
+
+        cmd.arg(image);
 
         let output = cmd
             .output()
             .map_err(|e| format!("Failed to create container: {}", e))?;
 
         if output.status.success() {
-            let container_id = String::from_utf8_lossy(&output.stdout).trim().to_string();
-            Ok(format!(
-                "Container recreated: {}",
-                &container_id[..12.min(container_id.len())]
-            ))
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
         } else {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(format!("Failed to create container: {}", stderr))
+            Err(format!("Failed to create container: {}", stderr.trim()))
+        }
+    }
+
+    /// Put the backup container back under its original name and start it
+    fn restore_backup(container_name: &str, backup: &str) -> Result<(), String> {
+        let _ = docker(&["rm", "-f", container_name]);
+
+        let output = docker(&["rename", backup, container_name])?;
+        if !output.status.success() {
+            return Err(format!(
+                "failed to rename backup '{}': {}",
+                backup,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        let output = docker(&["start", container_name])?;
+        if !output.status.success() {
+            return Err(format!(
+                "failed to start restored container: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
         }
+        Ok(())
+    }
+
+    /// Recreate a container on the given image tag without risking the cocoon.
+    ///
+    /// The old container is stopped and renamed to a backup rather than removed.
+    /// The backup is deleted only once the new container is healthy; any failure
+    /// before that removes the new container and restores the backup.
+    pub fn recreate_container(container_name: &str, tag: &str) -> Result<String, String> {
+        let image = format!("{}:{}", DOCKER_IMAGE, tag);
+        out_info!("  Saving container configuration...");
+        let env_vars = get_container_env(container_name)?;
+        recreate(container_name, &image, &env_vars, "Update")
+    }
+
+    /// Recreate a container from `image` with `env_vars`, keeping its volumes and
+    /// limits. `action` names the operation in errors ("Update", "Rekey").
+    fn recreate(
+        container_name: &str,
+        image: &str,
+        env_vars: &[(String, String)],
+        action: &str,
+    ) -> Result<String, String> {
+        let backup = backup_name(container_name);
+        let volumes = get_container_volumes(container_name)?;
+        let labels = get_container_labels(container_name)?;
+        let ports = ContainerMappings::from_labels(&labels);
+        let limits = ResourceLimits::from_labels(&labels);
+
+        // A leftover backup from an interrupted update would block the rename
+        let _ = docker(&["rm", "-f", &backup]);
+
+        out_info!("  Stopping old container...");
+        let _ = docker(&["stop", container_name]);
+
+        out_info!("  Keeping old container as '{}'...", backup);
+        let output = docker(&["rename", container_name, &backup])?;
+        if !output.status.success() {
+            let _ = docker(&["start", container_name]);
+            return Err(format!(
+                "{} aborted before any change: could not back up container: {}",
+                action,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        out_info!("  Creating new container...");
+        let result = run_container(container_name, image, env_vars, &volumes, &ports, &limits).and_then(|id| {
+            out_info!("  Waiting for new container to become healthy...");
+            wait_healthy(container_name).map(|_| id)
+        });
+
+        match result {
+            Ok(container_id) => {
+                out_info!("  Removing backup container...");
+                let _ = docker(&["rm", "-f", &backup]);
+                Ok(format!(
+                    "Container recreated: {}",
+                    &container_id[..12.min(container_id.len())]
+                ))
+            }
+            Err(update_err) => {
+                out_warn!("  {} failed, rolling back: {}", action, update_err);
+                match restore_backup(container_name, &backup) {
+                    Ok(()) => Err(format!(
+                        "{} failed: {}\nRolled back: the previous container was restored and restarted.",
+                        action, update_err
+                    )),
+                    Err(rollback_err) => Err(format!(
+                        "{} failed: {}\nRollback failed: {}. The previous container is kept as '{}'.",
+                        action, update_err, rollback_err, backup
+                    )),
+                }
+            }
+        }
+    }
+
+    /// Environment a rekeyed container keeps: the identity comes from the volume
+    /// instead, and the old setup token would claim it for the previous owner
+    fn rekeyed_env(env_vars: Vec<(String, String)>) -> Vec<(String, String)> {
+        env_vars
+            .into_iter()
+            .filter(|(key, _)| key != "COCOON_SECRET" && key != "COCOON_SETUP_TOKEN")
+            .collect()
+    }
+
+    /// Give a container a new identity: write `secret` to its volume, discard
+    /// the old device ID and any stale secret, and recreate it without the old
+    /// `COCOON_SECRET`/`COCOON_SETUP_TOKEN`.
+    ///
+    /// The volume is rewritten by a throwaway container on the same image while
+    /// the cocoon is stopped, so the secret never appears in `docker inspect`.
+    /// If the recreate is rolled back, the previous container comes back on the
+    /// rewritten volume; one that set `COCOON_SECRET` then keeps its old identity.
+    pub fn rekey_container(container_name: &str, secret: &str) -> Result<String, String> {
+        let output = docker(&["inspect", "--format", "{{.Config.Image}}", container_name])?;
+        if !output.status.success() {
+            return Err(format!("Container '{}' not found", container_name));
+        }
+        let image = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        let env_vars = get_container_env(container_name)?;
+        let strict = env_vars.iter().any(|(key, value)| {
+            key == "COCOON_STRICT_SECRET"
+                && matches!(value.to_lowercase().as_str(), "1" | "true" | "yes")
+        });
+        if strict {
+            return Err(format!(
+                "Container '{}' sets COCOON_STRICT_SECRET, which refuses a changed secret. Remove it before rekeying.",
+                container_name
+            ));
+        }
+
+        out_info!("  Stopping container...");
+        let _ = docker(&["stop", container_name])?;
+
+        out_info!("  Writing new secret and discarding the old device ID...");
+        let script = format!(
+            "umask 077 && printf '%s' \"$COCOON_REKEY_SECRET\" > {secret} && rm -f {device_id} {secret}.stale",
+            secret = crate::core::SECRET_PATH,
+            device_id = crate::core::DEVICE_ID_PATH,
+        );
+        // `-e NAME` without a value forwards it from our environment, keeping the
+        // secret off the docker command line
+        let output = container_engine::command()
+            .args(["run", "--rm", "--volumes-from", container_name, "-e", "COCOON_REKEY_SECRET"])
+            .args(["--entrypoint", "/bin/sh", &image, "-c", &script])
+            .env("COCOON_REKEY_SECRET", secret)
+            .output()
+            .map_err(|e| format!("Failed to run {}: {}", container_engine::current(), e))?;
+        if !output.status.success() {
+            let _ = docker(&["start", container_name]);
+            return Err(format!(
+                "Rekey aborted before any change: could not rewrite the identity volume: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        recreate(container_name, &image, &rekeyed_env(env_vars), "Rekey")
     }
 
     pub fn check_for_updates(tag: &str) -> Result<(bool, String), String> {
         let image = format!("{}:{}", DOCKER_IMAGE, tag);
 
-        let local_output = std::process::Command::new("docker")
+        let local_output = container_engine::command()
             .args(["images", "--digests", "--format", "{{.Digest}}", &image])
             .output()
             .map_err(|e| format!("Failed to check local image: {}", e))?;
@@ -331,7 +953,12 @@ pub mod docker {
 
 pub mod machine {
     use super::*;
+    use lib_console_output::out_warn;
     use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    /// How long a restarted cocoon has to register before an update is rolled back
+    const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(60);
 
     pub fn get_install_dir() -> Result<PathBuf, String> {
         // Try to get from current exe location
@@ -368,24 +995,100 @@ pub mod machine {
         }
 
         out_info!("Restarting service...");
+        let (restarted, note) = restart_service()?;
+        if !restarted {
+            return Ok(format!("{}\n{}", update_result, note));
+        }
 
-        let os = detect_os();
-        match os {
+        out_info!(
+            "Waiting up to {}s for the new version to register...",
+            HEALTH_CHECK_TIMEOUT.as_secs()
+        );
+        if wait_for_registration(HEALTH_CHECK_TIMEOUT) {
+            return Ok(format!("{}\n{}\nThe new version registered with the signaling server.", update_result, note));
+        }
+
+        out_warn!(
+            "The new version did not register within {}s, rolling back...",
+            HEALTH_CHECK_TIMEOUT.as_secs()
+        );
+        let rolled_back = rollback()?;
+        Err(format!(
+            "{}, but it did not register within {}s. {}",
+            update_result,
+            HEALTH_CHECK_TIMEOUT.as_secs(),
+            rolled_back
+        ))
+    }
+
+    /// Poll the version record until the restarted cocoon confirms it registered
+    fn wait_for_registration(timeout: Duration) -> bool {
+        let Ok(install_dir) = get_install_dir() else {
+            return false;
+        };
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if read_installed_versions(&install_dir).is_some_and(|v| v.confirmed) {
+                return true;
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        false
+    }
+
+    /// Put back the binary the last update replaced and restart the service.
+    /// The replaced binary becomes the backup, so a second rollback undoes it.
+    pub fn rollback() -> Result<String, String> {
+        let bin_name = format!("cocoon{}", std::env::consts::EXE_SUFFIX);
+        let install_dir = get_install_dir()?;
+        let installed = install_dir.join(&bin_name);
+        swap_with_backup(&installed)?;
+
+        let versions = read_installed_versions(&install_dir);
+        let restored = versions
+            .as_ref()
+            .and_then(|v| v.previous.clone())
+            .unwrap_or_else(|| "unknown".to_string());
+        let record = InstalledVersions {
+            current: restored.clone(),
+            previous: versions.map(|v| v.current),
+            confirmed: false,
+        };
+        if let Err(e) = write_installed_versions(&install_dir, &record) {
+            out_warn!("{}", e);
+        }
+
+        out_info!("Restarting service...");
+        let (_, note) = restart_service()?;
+        Ok(format!("Rolled back to version {}.\n{}", restored, note))
+    }
+
+    /// Restart the installed service; `true` when it was restarted. On Linux
+    /// that is the system unit `scripts/install.sh` writes, or else a user unit.
+    fn restart_service() -> Result<(bool, String), String> {
+        match detect_os() {
             "linux" => {
+                let system_unit = Path::new(crate::service::SYSTEM_UNIT).exists();
+                let scope: &[&str] = if system_unit { &[] } else { &["--user"] };
                 let output = std::process::Command::new("systemctl")
-                    .args(["--user", "restart", "cocoon"])
+                    .args(scope)
+                    .args(["restart", "cocoon"])
                     .status()
                     .map_err(|e| format!("Failed to restart service: {}", e))?;
 
                 if output.success() {
-                    Ok(format!(
-                        "{}\nService restarted successfully.",
-                        update_result
+                    Ok((true, "Service restarted successfully.".to_string()))
+                } else if system_unit {
+                    Ok((
+                        false,
+                        "Warning: Service restart failed; restarting the system unit needs root. Check status with: sudo systemctl status cocoon"
+                            .to_string(),
                     ))
                 } else {
-                    Ok(format!(
-                        "{}\nWarning: Service restart may have failed. Check status with: systemctl --user status cocoon",
-                        update_result
+                    Ok((
+                        false,
+                        "Warning: Service restart may have failed. Check status with: systemctl --user status cocoon"
+                            .to_string(),
                     ))
                 }
             }
@@ -404,21 +1107,12 @@ pub mod machine {
                         .args(["load", &plist])
                         .status();
 
-                    Ok(format!(
-                        "{}\nService restarted successfully.",
-                        update_result
-                    ))
+                    Ok((true, "Service restarted successfully.".to_string()))
                 } else {
-                    Ok(format!(
-                        "{}\nNote: No service installed. Start manually if needed.",
-                        update_result
-                    ))
+                    Ok((false, "Note: No service installed. Start manually if needed.".to_string()))
                 }
             }
-            _ => Ok(format!(
-                "{}\nNote: Cannot restart service on this OS.",
-                update_result
-            )),
+            _ => Ok((false, "Note: Cannot restart service on this OS.".to_string())),
         }
     }
 
@@ -470,4 +1164,84 @@ mod tests {
         assert!(!target.is_empty());
         assert!(target.contains('-'));
     }
+
+    #[test]
+    fn test_parse_checksum_listing_formats() {
+        let hash = "a".repeat(64);
+        let other = "b".repeat(64);
+        let asset = "cocoon-x86_64-unknown-linux-musl.tar.gz";
+
+        let listing = format!("{}  cocoon-aarch64-apple-darwin.tar.gz\n{} *dist/{}\n", other, hash, asset);
+        assert_eq!(parse_checksum(&listing, asset), Some(hash.clone()));
+        assert_eq!(parse_checksum(&format!("{}\n", hash.to_uppercase()), asset), Some(hash));
+        assert_eq!(parse_checksum("not-a-hash  cocoon.tar.gz", asset), None);
+    }
+
+    #[test]
+    fn test_verify_release_signature() {
+        use ed25519_dalek::{Signer, SigningKey};
+        let engine = base64::engine::general_purpose::STANDARD;
+
+        let signing = SigningKey::from_bytes(&[7u8; 32]);
+        let key = parse_public_key(&engine.encode(signing.verifying_key().to_bytes())).unwrap();
+        let binary = b"cocoon release archive";
+        let signature = signing.sign(binary);
+
+        let raw = parse_signature(&signature.to_bytes()).unwrap();
+        let text = parse_signature(format!("{}\n", engine.encode(signature.to_bytes())).as_bytes()).unwrap();
+        assert_eq!(raw, text);
+        assert!(verify_signature(&key, binary, &raw).is_ok());
+
+        // Tampered binary, or signed with another key
+        assert!(verify_signature(&key, b"cocoon release archivE", &raw).is_err());
+        let other = SigningKey::from_bytes(&[8u8; 32]).sign(binary);
+        assert!(verify_signature(&key, binary, &other).is_err());
+
+        assert!(parse_public_key("c2hvcnQ=").is_err());
+        assert!(parse_signature(b"not a signature").is_err());
+    }
+
+    #[test]
+    fn test_swap_with_backup() {
+        let dir = tempfile::tempdir().unwrap();
+        let installed = dir.path().join("cocoon");
+        assert_eq!(backup_path(&installed), dir.path().join("cocoon.bak"));
+        std::fs::write(&installed, b"new").unwrap();
+        assert!(swap_with_backup(&installed).is_err());
+
+        std::fs::write(backup_path(&installed), b"old").unwrap();
+        swap_with_backup(&installed).unwrap();
+        assert_eq!(std::fs::read(&installed).unwrap(), b"old");
+        assert_eq!(std::fs::read(backup_path(&installed)).unwrap(), b"new");
+        assert!(!dir.path().join("cocoon.swap").exists());
+
+        // Rolling back twice returns to the update
+        swap_with_backup(&installed).unwrap();
+        assert_eq!(std::fs::read(&installed).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_installed_versions_live_next_to_the_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_installed_versions(dir.path()), None);
+        let versions = InstalledVersions {
+            current: "0.3.0".to_string(),
+            previous: Some("0.2.9".to_string()),
+            confirmed: false,
+        };
+        write_installed_versions(dir.path(), &versions).unwrap();
+        assert!(dir.path().join("cocoon.versions.json").exists());
+        assert_eq!(read_installed_versions(dir.path()), Some(versions));
+    }
+
+    #[test]
+    fn test_sha256_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("binary");
+        std::fs::write(&path, b"abc").unwrap();
+        assert_eq!(
+            sha256_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
 }
@@ -0,0 +1,137 @@
+//! Removing the native service that `scripts/install.sh` sets up
+//!
+//! The installer writes a systemd unit on Linux and a launchd agent on macOS
+//! and keeps the secret in `~/.config/cocoon/secret`. Uninstalling undoes each
+//! step that is still in place, so running it twice, or after a partial
+//! manual cleanup, is not an error.
+
+use lib_env_parse::{env_opt, env_vars};
+use std::path::{Path, PathBuf};
+
+env_vars! {
+    Home => "HOME",
+}
+
+const SERVICE_NAME: &str = "cocoon";
+pub(crate) const SYSTEM_UNIT: &str = "/etc/systemd/system/cocoon.service";
+const LAUNCHD_LABEL: &str = "com.adi.cocoon";
+
+fn home() -> Result<PathBuf, String> {
+    env_opt(EnvVar::Home.as_str())
+        .map(PathBuf::from)
+        .ok_or_else(|| "HOME not set".to_string())
+}
+
+/// Where the installer saves the secret
+pub fn secret_path() -> Result<PathBuf, String> {
+    Ok(home()?.join(".config/cocoon/secret"))
+}
+
+/// Remove `path`; `false` if it was already gone
+fn remove_if_exists(path: &Path) -> Result<bool, String> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(format!(
+            "Permission denied removing {} (run with sudo)",
+            path.display()
+        )),
+        Err(e) => Err(format!("Failed to remove {}: {}", path.display(), e)),
+    }
+}
+
+/// Run a service manager command whose failure only means there was nothing to do
+fn run_quiet(program: &str, args: &[&str]) {
+    let _ = std::process::Command::new(program)
+        .args(args)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+}
+
+/// Stop, disable and remove the cocoon service, and the saved secret when
+/// `purge` is set. Returns a summary of what was removed.
+pub fn service_uninstall(purge: bool) -> Result<String, String> {
+    let mut removed = match detect_os() {
+        "linux" => uninstall_systemd()?,
+        "macos" => uninstall_launchd()?,
+        _ => return Err("Service uninstall is only supported on Linux and macOS".to_string()),
+    };
+
+    if purge {
+        let secret = secret_path()?;
+        if remove_if_exists(&secret)? {
+            removed.push(secret.display().to_string());
+        }
+    }
+
+    if removed.is_empty() {
+        Ok("No cocoon service installed, nothing to remove".to_string())
+    } else {
+        Ok(format!("Removed: {}", removed.join(", ")))
+    }
+}
+
+/// Both the system unit written by the installer and a per-user unit
+fn uninstall_systemd() -> Result<Vec<String>, String> {
+    let mut removed = Vec::new();
+
+    let system_unit = PathBuf::from(SYSTEM_UNIT);
+    if system_unit.exists() {
+        run_quiet("systemctl", &["stop", SERVICE_NAME]);
+        run_quiet("systemctl", &["disable", SERVICE_NAME]);
+        remove_if_exists(&system_unit)?;
+        run_quiet("systemctl", &["daemon-reload"]);
+        removed.push(SYSTEM_UNIT.to_string());
+    }
+
+    let user_unit = home()?.join(".config/systemd/user/cocoon.service");
+    if user_unit.exists() {
+        run_quiet("systemctl", &["--user", "stop", SERVICE_NAME]);
+        run_quiet("systemctl", &["--user", "disable", SERVICE_NAME]);
+        remove_if_exists(&user_unit)?;
+        run_quiet("systemctl", &["--user", "daemon-reload"]);
+        removed.push(user_unit.display().to_string());
+    }
+
+    Ok(removed)
+}
+
+fn uninstall_launchd() -> Result<Vec<String>, String> {
+    let plist = home()?.join(format!("Library/LaunchAgents/{}.plist", LAUNCHD_LABEL));
+    if !plist.exists() {
+        return Ok(Vec::new());
+    }
+
+    // `unload -w` stops the agent and keeps it from loading at the next login
+    run_quiet("launchctl", &["unload", "-w", &plist.display().to_string()]);
+    remove_if_exists(&plist)?;
+    Ok(vec![plist.display().to_string()])
+}
+
+fn detect_os() -> &'static str {
+    #[cfg(target_os = "linux")]
+    return "linux";
+
+    #[cfg(target_os = "macos")]
+    return "macos";
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    return "unknown";
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_if_exists_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        std::fs::write(&path, "s3cret").unwrap();
+
+        assert!(remove_if_exists(&path).unwrap());
+        assert!(!path.exists());
+        assert!(!remove_if_exists(&path).unwrap());
+    }
+}
@@ -8,7 +8,10 @@ pub async fn run_setup(port: u16, cli_url: Option<String>) -> Result<String, Str
     let (connect_tx, mut connect_rx) = tokio::sync::mpsc::channel::<ConnectRequest>(1);
 
     // If --url provided, store it so /connect can use it as override.
-    let signaling_override = cli_url.map(|u| Arc::new(u));
+    let signaling_override = cli_url
+        .filter(|u| !u.is_empty())
+        .map(|u| crate::signaling_url::normalize_signaling_url(&u).map(|url| Arc::new(url.to_string())))
+        .transpose()?;
 
     let state = Arc::new(SetupServerState {
         connected: RwLock::new(false),
@@ -51,6 +54,14 @@ pub async fn run_setup(port: u16, cli_url: Option<String>) -> Result<String, Str
             .as_deref()
             .filter(|s| !s.is_empty())
             .unwrap_or(&req.signaling_url);
+        let signaling_url = match crate::signaling_url::normalize_signaling_url(signaling_url) {
+            Ok(url) => url.to_string(),
+            Err(e) => {
+                server.abort();
+                return Err(e);
+            }
+        };
+        let signaling_url = signaling_url.as_str();
 
         out_info!("Browser connected! Setting up cocoon...");
         out_info!("  Signaling: {}", signaling_url);
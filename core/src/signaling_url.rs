@@ -0,0 +1,110 @@
+//! Checking signaling URLs before they are used
+//!
+//! The URL comes from `SIGNALING_SERVER_URL`, `--url` or the setup page. Mistakes
+//! such as `http://` for `ws://` are caught here with a hint, instead of
+//! failing later inside the WebSocket handshake. `ws://` and `wss://` URLs with
+//! no path get the server's default `/ws`. `unix://` URLs name a socket and are
+//! left as they are.
+
+use url::Url;
+
+/// Path the signaling server serves WebSockets on
+const DEFAULT_PATH: &str = "/ws";
+
+/// `input` as a signaling URL, or an error saying what to fix
+pub fn normalize_signaling_url(input: &str) -> Result<Url, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("Signaling URL is empty".to_string());
+    }
+
+    let mut url = Url::parse(input).map_err(|e| match e {
+        url::ParseError::RelativeUrlWithoutBase => format!(
+            "Invalid signaling URL '{}': missing scheme, did you mean wss://{}?",
+            input, input
+        ),
+        e => format!("Invalid signaling URL '{}': {}", input, e),
+    })?;
+
+    match url.scheme() {
+        "ws" | "wss" => {
+            if matches!(url.host_str(), None | Some("")) {
+                return Err(format!("Missing host in signaling URL '{}'", input));
+            }
+            if url.path().is_empty() || url.path() == "/" {
+                url.set_path(DEFAULT_PATH);
+            }
+            Ok(url)
+        }
+        scheme @ ("http" | "https") => {
+            let suggested = if scheme == "https" { "wss" } else { "ws" };
+            let mut hint = url.clone();
+            // Only fails for schemes that cannot be switched, which ws(s) can
+            let _ = hint.set_scheme(suggested);
+            Err(format!(
+                "Signaling URL '{}' uses {}://, but the signaling server speaks WebSocket: did you mean {}?",
+                input,
+                scheme,
+                normalize_signaling_url(hint.as_str()).map(|u| u.to_string()).unwrap_or_else(|_| hint.to_string())
+            ))
+        }
+        #[cfg(unix)]
+        "unix" => {
+            if url.path().is_empty() || url.path() == "/" {
+                return Err(format!("Missing socket path in signaling URL '{}'", input));
+            }
+            Ok(url)
+        }
+        #[cfg(not(unix))]
+        "unix" => Err("unix:// signaling URLs are only supported on Unix platforms".to_string()),
+        other => Err(format!(
+            "Unsupported signaling URL scheme '{}' in '{}' (expected ws://, wss:// or unix://)",
+            other, input
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(input: &str) -> String {
+        normalize_signaling_url(input).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_accepted_signaling_urls() {
+        assert_eq!(normalized("ws://localhost:8080/ws"), "ws://localhost:8080/ws");
+        assert_eq!(normalized(" wss://Signal.Example.com "), "wss://signal.example.com/ws");
+        assert_eq!(normalized("ws://adi.local:8080"), "ws://adi.local:8080/ws");
+        assert_eq!(normalized("wss://example.com/custom/path?x=1"), "wss://example.com/custom/path?x=1");
+        assert_eq!(normalized("ws://example.com/?kind=cocoon"), "ws://example.com/ws?kind=cocoon");
+        #[cfg(unix)]
+        assert_eq!(normalized("unix:///run/adi/signaling.sock"), "unix:///run/adi/signaling.sock");
+    }
+
+    #[test]
+    fn test_local_host_gateway_from_normalized_url() {
+        let url = normalized("ws://Adi.Local:8080");
+        assert_eq!(
+            crate::host_aliases::HostAliases::default()
+                .docker_args(Some(&url), crate::container_engine::ContainerEngine::Docker),
+            vec!["--add-host", "adi.local:host-gateway"]
+        );
+    }
+
+    #[test]
+    fn test_rejected_signaling_urls() {
+        let err = normalize_signaling_url("https://signal.example.com").unwrap_err();
+        assert!(err.contains("did you mean wss://signal.example.com/ws?"), "{}", err);
+        let err = normalize_signaling_url("http://localhost:8080/ws").unwrap_err();
+        assert!(err.contains("did you mean ws://localhost:8080/ws?"), "{}", err);
+
+        let err = normalize_signaling_url("signal.example.com/ws").unwrap_err();
+        assert!(err.contains("missing scheme"), "{}", err);
+
+        for bad in ["", "ftp://example.com/ws", "ws://", "unix://", "unix:///"] {
+            assert!(normalize_signaling_url(bad).is_err(), "{:?} should be rejected", bad);
+        }
+    }
+}
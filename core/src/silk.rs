@@ -1,6 +1,7 @@
 use crate::protocol::types::SilkHtmlSpan;
 use std::collections::HashMap;
 use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::OnceLock;
 use uuid::Uuid;
 
 use lib_env_parse::{env_vars, env_opt};
@@ -8,9 +9,18 @@ use lib_env_parse::{env_vars, env_opt};
 env_vars! {
     Shell => "SHELL",
     Home => "HOME",
+    Path => "PATH",
+    CocoonSilkInteractiveCmds => "COCOON_SILK_INTERACTIVE_CMDS",
 }
 
-/// Known interactive commands that always need a PTY
+/// Exit status POSIX shells use when the command could not be found
+pub const EXIT_COMMAND_NOT_FOUND: i32 = 127;
+
+/// Error code for a `command_id` that is still running in its session
+pub const DUPLICATE_COMMAND_ID: &str = "duplicate_command_id";
+
+/// Known interactive commands that always need a PTY, unless
+/// `COCOON_SILK_INTERACTIVE_CMDS` drops them
 const INTERACTIVE_COMMANDS: &[&str] = &[
     "vim",
     "nvim",
@@ -40,13 +50,54 @@ const INTERACTIVE_COMMANDS: &[&str] = &[
     "redis-cli",
 ];
 
+/// `INTERACTIVE_COMMANDS` with the comma-separated `overrides` applied: a name
+/// is added, and a name prefixed with `-` is removed
+fn merge_interactive_commands(overrides: Option<&str>) -> Vec<String> {
+    let mut commands: Vec<String> = INTERACTIVE_COMMANDS.iter().map(|c| c.to_string()).collect();
+    for entry in overrides.unwrap_or_default().split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry.strip_prefix('-') {
+            Some(removed) => commands.retain(|c| c != removed.trim()),
+            None if !commands.iter().any(|c| c == entry) => commands.push(entry.to_string()),
+            None => {}
+        }
+    }
+    commands
+}
+
+/// Interactive commands in effect for this process
+fn interactive_commands() -> &'static [String] {
+    static COMMANDS: OnceLock<Vec<String>> = OnceLock::new();
+    COMMANDS.get_or_init(|| merge_interactive_commands(env_opt(EnvVar::CocoonSilkInteractiveCmds.as_str()).as_deref()))
+}
+
+/// Whether `command` runs one of `interactive`. An entry matches the program,
+/// bare or as a path, and any words after it in the entry (`rails c`) must follow.
+fn is_interactive_with(command: &str, interactive: &[String]) -> bool {
+    let runs = |entry: &String| {
+        let mut words = command.split_whitespace();
+        let mut entry_words = entry.split_whitespace();
+        let (Some(program), Some(name)) = (words.next(), entry_words.next()) else {
+            return false;
+        };
+        (program == name || program.ends_with(&format!("/{}", name))) && entry_words.all(|w| words.next() == Some(w))
+    };
+    interactive.iter().any(runs) || command.contains(" -i") || command.contains(" --interactive")
+}
+
 pub struct SilkSession {
     pub id: Uuid,
     pub shell: String,
     pub cwd: String,
     pub env: HashMap<String, String>,
+    /// Start commands from a minimal environment instead of inheriting the cocoon's
+    pub clean_env: bool,
     /// Running commands that may need input
     pub running_commands: HashMap<String, RunningCommand>,
+    pub created_at: std::time::Instant,
+    /// Last input or output, shared with the session's interactive PTYs
+    pub activity: crate::reaper::Activity,
+    /// Loaded from a previous run's persisted sessions rather than created by a client
+    pub restored: bool,
 }
 
 pub struct RunningCommand {
@@ -55,17 +106,34 @@ pub struct RunningCommand {
     pub interactive: bool,
     /// For non-interactive: child process
     pub child: Option<Child>,
+    /// For non-interactive: PID of the child the caller owns, so the zombie
+    /// reaper leaves it alone and closing the session can still kill it
+    pub pid: Option<u32>,
     /// For interactive: PTY session ID (reuses cocoon PTY infrastructure)
     pub pty_session_id: Option<Uuid>,
     /// Stdin handle for non-interactive commands (for writing input responses)
     pub stdin: Option<ChildStdin>,
 }
 
+impl RunningCommand {
+    /// Send `signal` to the process group of a non-interactive command; whether
+    /// it was delivered. Interactive commands are signaled through their PTY.
+    #[cfg(unix)]
+    pub fn signal(&self, signal: i32) -> bool {
+        let Some(pid) = self.child.as_ref().map(|child| child.id()).or(self.pid) else {
+            return false;
+        };
+        // SAFETY: plain kill(2) on the process group this session started
+        unsafe { libc::kill(-(pid as i32), signal) == 0 }
+    }
+}
+
 impl SilkSession {
     pub fn new(
         cwd: Option<String>,
         env: HashMap<String, String>,
         shell: Option<String>,
+        clean_env: bool,
     ) -> Result<Self, String> {
         let shell = shell
             .or_else(|| env_opt(EnvVar::Shell.as_str()))
@@ -87,32 +155,61 @@ impl SilkSession {
             shell,
             cwd,
             env,
+            clean_env,
             running_commands: HashMap::new(),
+            created_at: std::time::Instant::now(),
+            activity: crate::reaper::Activity::new(),
+            restored: false,
         })
     }
 
-    pub fn is_interactive_command(command: &str) -> bool {
-        let cmd_name = command.split_whitespace().next().unwrap_or("");
-
-        for interactive in INTERACTIVE_COMMANDS {
-            if cmd_name == *interactive || cmd_name.ends_with(&format!("/{}", interactive)) {
-                return true;
-            }
+    /// Rebuild a session persisted by a previous run, keeping its id
+    pub(crate) fn restore(record: crate::silk_store::SessionRecord) -> Result<Self, String> {
+        if !std::path::Path::new(&record.shell).exists() {
+            return Err(format!("Shell not found: {}", record.shell));
         }
+        Ok(Self {
+            id: record.id,
+            shell: record.shell,
+            cwd: record.cwd,
+            env: record.env,
+            clean_env: record.clean_env,
+            running_commands: HashMap::new(),
+            created_at: std::time::Instant::now(),
+            activity: crate::reaper::Activity::new(),
+            restored: true,
+        })
+    }
 
-        if command.contains(" -i") || command.contains(" --interactive") {
-            return true;
-        }
+    /// Environment commands in this session run with
+    pub fn child_env(&self) -> HashMap<String, String> {
+        crate::child_env::child_env(self.clean_env, &self.env)
+    }
 
-        false
+    pub fn is_interactive_command(command: &str) -> bool {
+        is_interactive_with(command, interactive_commands())
     }
 
+    /// Whether `command_id` is still tracked as running. Interactive commands
+    /// stay tracked until the session closes.
+    pub fn is_running(&self, command_id: &str) -> bool {
+        self.running_commands.contains_key(command_id)
+    }
+
+    /// Start `command`, or only track it when it needs a PTY. `force_interactive`
+    /// decides that instead of `is_interactive_command` when set.
     pub fn execute(
         &mut self,
         command: &str,
         command_id: String,
+        force_interactive: Option<bool>,
     ) -> Result<(bool, Option<Child>), String> {
-        let interactive = Self::is_interactive_command(command);
+        // Reusing a live id would overwrite the tracking of the running command
+        if self.is_running(&command_id) {
+            return Err(format!("Command {} is already running in this session", command_id));
+        }
+
+        let interactive = force_interactive.unwrap_or_else(|| Self::is_interactive_command(command));
 
         if interactive {
             // Mark as needing PTY, actual PTY creation happens in core.rs
@@ -123,6 +220,7 @@ impl SilkSession {
                     command: command.to_string(),
                     interactive: true,
                     child: None,
+                    pid: None,
                     pty_session_id: None,
                     stdin: None,
                 },
@@ -133,12 +231,11 @@ impl SilkSession {
         // Non-interactive: execute with piped I/O
         // We wrap in shell to properly handle pipes, redirects, etc.
         let mut cmd = Command::new(&self.shell);
-        cmd.arg("-c").arg(command);
+        cmd.arg("-c").arg(&*crate::exec_wrapper::wrap(&self.shell, command));
         cmd.current_dir(&self.cwd);
 
-        for (key, value) in &self.env {
-            cmd.env(key, value);
-        }
+        cmd.env_clear();
+        cmd.envs(self.child_env());
 
         // Set common terminal env vars for proper output
         cmd.env("TERM", "xterm-256color");
@@ -148,6 +245,9 @@ impl SilkSession {
         cmd.stdin(Stdio::piped());
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
+        // A group of its own, so closing the session reaches what the shell started
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
 
         let child = cmd
             .spawn()
@@ -160,6 +260,7 @@ impl SilkSession {
                 command: command.to_string(),
                 interactive: false,
                 child: None, // We return the child, caller manages it
+                pid: Some(child.id()),
                 pty_session_id: None,
                 stdin: None,
             },
@@ -197,11 +298,59 @@ impl SilkSession {
         }
     }
 
+    /// PIDs of the running non-interactive commands
+    pub fn command_pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.running_commands
+            .values()
+            .filter_map(|c| c.child.as_ref().map(|child| child.id()).or(c.pid))
+    }
+
     pub fn complete_command(&mut self, command_id: String) {
         self.running_commands.remove(&command_id);
     }
 }
 
+/// Returns the name of the missing binary when a `sh -c` run failed because
+/// the command could not be found.
+///
+/// Understands the stderr formats of dash, bash, busybox ash and zsh; falls back
+/// to the first word of the command when exit code 127 came without a parsable message.
+pub fn detect_missing_command(command: &str, exit_code: i32, stderr: &str) -> Option<String> {
+    if exit_code != EXIT_COMMAND_NOT_FOUND {
+        return None;
+    }
+
+    for line in stderr.lines().map(str::trim_end) {
+        if let Some((_, name)) = line.split_once("command not found: ") {
+            let name = name.trim();
+            if !name.is_empty() {
+                return Some(name.to_string());
+            }
+        }
+
+        let rest = line
+            .strip_suffix(": command not found")
+            .or_else(|| line.strip_suffix(": not found"));
+        if let Some(rest) = rest {
+            let name = rest.rsplit(": ").next().unwrap_or(rest).trim();
+            if !name.is_empty() {
+                return Some(name.to_string());
+            }
+        }
+    }
+
+    command.split_whitespace().next().map(|s| s.to_string())
+}
+
+/// Human-readable hint for a missing binary, including the effective PATH
+pub fn missing_command_hint(binary: &str) -> String {
+    format!(
+        "Command '{}' not found. Check that it is installed and on PATH (PATH={})",
+        binary,
+        env_opt(EnvVar::Path.as_str()).unwrap_or_default()
+    )
+}
+
 pub struct AnsiToHtml;
 
 impl AnsiToHtml {
@@ -380,6 +529,61 @@ mod tests {
         assert!(!SilkSession::is_interactive_command("echo hello"));
     }
 
+    #[test]
+    fn test_interactive_commands_overrides() {
+        let defaults = merge_interactive_commands(None);
+        assert_eq!(defaults.len(), INTERACTIVE_COMMANDS.len());
+        assert!(is_interactive_with("rails c", &defaults));
+        assert!(!is_interactive_with("rails server", &defaults));
+        assert!(!is_interactive_with("k9s", &defaults));
+
+        let merged = merge_interactive_commands(Some(" k9s, -python ,vim,, /opt/bin/repl"));
+        assert!(is_interactive_with("k9s --context prod", &merged));
+        assert!(is_interactive_with("/usr/local/bin/k9s", &merged));
+        assert!(!is_interactive_with("python script.py", &merged));
+        assert!(is_interactive_with("python3", &merged));
+        assert_eq!(merged.iter().filter(|c| *c == "vim").count(), 1);
+        assert!(is_interactive_with("/opt/bin/repl", &merged));
+    }
+
+    #[test]
+    fn test_force_interactive_overrides_the_guess() {
+        let mut session = SilkSession::new(None, HashMap::new(), Some("/bin/sh".to_string()), false).unwrap();
+
+        let (interactive, child) = session.execute("echo hello", "forced-pty".to_string(), Some(true)).unwrap();
+        assert!(interactive);
+        assert!(child.is_none());
+
+        let (interactive, child) = session.execute("vim --version", "forced-pipe".to_string(), Some(false)).unwrap();
+        assert!(!interactive);
+        let _ = child.expect("piped commands are spawned").wait();
+
+        let (interactive, _) = session.execute("vim", "guessed".to_string(), None).unwrap();
+        assert!(interactive);
+    }
+
+    #[test]
+    fn test_detect_missing_command() {
+        assert_eq!(
+            detect_missing_command("foo --bar", 127, "/bin/sh: 1: foo: not found\n"),
+            Some("foo".to_string())
+        );
+        assert_eq!(
+            detect_missing_command("foo", 127, "bash: line 1: foo: command not found\n"),
+            Some("foo".to_string())
+        );
+        assert_eq!(
+            detect_missing_command("foo", 127, "zsh: command not found: foo\n"),
+            Some("foo".to_string())
+        );
+        assert_eq!(
+            detect_missing_command("ls && bar", 127, "sh: bar: not found"),
+            Some("bar".to_string())
+        );
+        assert_eq!(detect_missing_command("baz -x", 127, ""), Some("baz".to_string()));
+        assert_eq!(detect_missing_command("false", 1, "foo: not found"), None);
+    }
+
     #[test]
     fn test_ansi_to_html_plain_text() {
         let spans = AnsiToHtml::convert("hello world");
@@ -0,0 +1,168 @@
+//! Silk session context that survives a cocoon restart
+//!
+//! With `COCOON_PERSIST_SILK_SESSIONS=1` the id, working directory, env and
+//! shell of every open Silk session are written to `/cocoon/.silk_sessions.json`
+//! whenever a session is created, changes directory or closes, and read back at
+//! startup. Only that context is restored: commands that were running when the
+//! cocoon went down are gone. The file holds session env, so it is written with
+//! mode 0600.
+
+use crate::silk::SilkSession;
+use lib_env_parse::{env_opt, env_vars};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+env_vars! {
+    CocoonPersistSilkSessions => "COCOON_PERSIST_SILK_SESSIONS",
+}
+
+const SESSIONS_PATH: &str = "/cocoon/.silk_sessions.json";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub id: Uuid,
+    pub cwd: String,
+    pub env: HashMap<String, String>,
+    pub shell: String,
+    #[serde(default)]
+    pub clean_env: bool,
+}
+
+impl SessionRecord {
+    pub fn of(session: &SilkSession) -> Self {
+        Self {
+            id: session.id,
+            cwd: session.cwd.clone(),
+            env: session.env.clone(),
+            shell: session.shell.clone(),
+            clean_env: session.clean_env,
+        }
+    }
+}
+
+/// Where sessions are persisted, or `None` when persistence is off
+pub fn path() -> Option<PathBuf> {
+    env_opt(EnvVar::CocoonPersistSilkSessions.as_str())
+        .filter(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes"))
+        .map(|_| PathBuf::from(SESSIONS_PATH))
+}
+
+/// Sessions as they stood when `snapshot` was called, numbered in that order
+pub struct Snapshot {
+    path: PathBuf,
+    records: Vec<SessionRecord>,
+    generation: u64,
+}
+
+/// Generation of the latest snapshot taken
+static TAKEN: AtomicU64 = AtomicU64::new(0);
+/// Generation of the latest snapshot written; held while writing, so writes
+/// don't interleave
+static WRITTEN: Mutex<u64> = Mutex::new(0);
+
+/// Copy the sessions out for `persist`, or `None` when persistence is off.
+/// Call it with the session lock held, so snapshots are numbered in the order
+/// the changes happened, and release the lock before persisting.
+pub fn snapshot(sessions: &HashMap<Uuid, SilkSession>) -> Option<Snapshot> {
+    let path = path()?;
+    let mut records: Vec<SessionRecord> = sessions.values().map(SessionRecord::of).collect();
+    records.sort_by_key(|r| r.id);
+    let generation = TAKEN.fetch_add(1, Ordering::SeqCst) + 1;
+    Some(Snapshot { path, records, generation })
+}
+
+/// Write `snapshot` out on the blocking pool. A snapshot older than one already
+/// written is dropped, so racing writes can't bring back a closed session.
+/// Failures are logged; a session that cannot be persisted still works.
+pub async fn persist(snapshot: Option<Snapshot>) {
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+    let written = tokio::task::spawn_blocking(move || {
+        let mut written = WRITTEN.lock().unwrap_or_else(|e| e.into_inner());
+        if snapshot.generation < *written {
+            return Ok(());
+        }
+        save(&snapshot.path, &snapshot.records)?;
+        *written = snapshot.generation;
+        Ok(())
+    })
+    .await
+    .unwrap_or_else(|e| Err(format!("Persist task failed: {}", e)));
+    if let Err(e) = written {
+        tracing::warn!("⚠️ Failed to persist Silk sessions: {}", e);
+    }
+}
+
+fn save(path: &Path, records: &[SessionRecord]) -> Result<(), String> {
+    let json = serde_json::to_vec_pretty(records).map_err(|e| format!("Failed to serialize sessions: {}", e))?;
+    let tmp = path.with_extension("json.tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let write = || -> std::io::Result<()> {
+        use std::io::Write;
+        let mut file = options.open(&tmp)?;
+        file.write_all(&json)?;
+        file.sync_all()
+    };
+    write().map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+    std::fs::rename(&tmp, path).map_err(|e| format!("Failed to replace {}: {}", path.display(), e))
+}
+
+/// Sessions saved by a previous run; empty when there are none or the file is unreadable
+pub fn load(path: &Path) -> Vec<SessionRecord> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            tracing::warn!("⚠️ Failed to read {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    serde_json::from_slice(&data).unwrap_or_else(|e| {
+        tracing::warn!("⚠️ Ignoring malformed {}: {}", path.display(), e);
+        Vec::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_round_trip_and_bad_files_load_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.json");
+        assert!(load(&path).is_empty());
+
+        let record = SessionRecord {
+            id: Uuid::new_v4(),
+            cwd: "/tmp".to_string(),
+            env: HashMap::from([("SILK_MODE".to_string(), "true".to_string())]),
+            shell: "/bin/sh".to_string(),
+            clean_env: true,
+        };
+        save(&path, std::slice::from_ref(&record)).unwrap();
+        assert_eq!(load(&path), vec![record]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        std::fs::write(&path, "{not json").unwrap();
+        assert!(load(&path).is_empty());
+    }
+}
@@ -0,0 +1,133 @@
+//! TLS settings for the signaling connection
+//!
+//! By default `wss://` verifies the server against the bundled web PKI roots.
+//! `COCOON_CA_CERT` names a PEM file whose certificates are trusted in addition,
+//! for signaling servers behind an internal CA. `COCOON_TLS_INSECURE=1` turns
+//! verification off entirely and is meant for development only. Neither has any
+//! effect on `ws://` or `unix://` URLs.
+
+use lib_env_parse::{env_opt, env_vars};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::sync::Arc;
+use tokio_tungstenite::Connector;
+
+env_vars! {
+    CocoonCaCert => "COCOON_CA_CERT",
+    CocoonTlsInsecure => "COCOON_TLS_INSECURE",
+}
+
+/// Connector configured from `COCOON_CA_CERT` / `COCOON_TLS_INSECURE`, or
+/// `None` for the default verification when neither is set
+pub fn connector_from_env() -> Result<Option<Connector>, String> {
+    let insecure = env_opt(EnvVar::CocoonTlsInsecure.as_str())
+        .is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes"));
+    connector(env_opt(EnvVar::CocoonCaCert.as_str()).as_deref(), insecure)
+}
+
+fn connector(ca_cert: Option<&str>, insecure: bool) -> Result<Option<Connector>, String> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    if insecure {
+        tracing::warn!("⚠️ ==================================================================");
+        tracing::warn!("⚠️ COCOON_TLS_INSECURE is set: signaling server certificates are NOT verified");
+        tracing::warn!("⚠️ Anyone on the network path can impersonate the server. Development only!");
+        tracing::warn!("⚠️ ==================================================================");
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| format!("Failed to build TLS config: {}", e))?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth();
+        return Ok(Some(Connector::Rustls(Arc::new(config))));
+    }
+
+    let Some(path) = ca_cert else {
+        return Ok(None);
+    };
+
+    let pem = std::fs::read(path).map_err(|e| format!("Failed to read COCOON_CA_CERT {}: {}", path, e))?;
+    let certs: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid PEM in COCOON_CA_CERT {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in COCOON_CA_CERT {}", path));
+    }
+
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    for cert in certs {
+        roots
+            .add(cert)
+            .map_err(|e| format!("Invalid certificate in COCOON_CA_CERT {}: {}", path, e))?;
+    }
+    tracing::info!("🔐 Trusting extra CA certificate(s) from {}", path);
+
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| format!("Failed to build TLS config: {}", e))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Some(Connector::Rustls(Arc::new(config))))
+}
+
+/// Accepts any server certificate; handshake signatures are still checked
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connector_configuration() {
+        assert!(connector(None, false).unwrap().is_none());
+        assert!(matches!(connector(None, true), Ok(Some(Connector::Rustls(_)))));
+
+        let missing = connector(Some("/nonexistent/ca.pem"), false).unwrap_err();
+        assert!(missing.contains("Failed to read"), "{}", missing);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, "not a certificate\n").unwrap();
+        let empty = connector(Some(path.to_str().unwrap()), false).unwrap_err();
+        assert!(empty.contains("No certificates"), "{}", empty);
+    }
+}
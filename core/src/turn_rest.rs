@@ -0,0 +1,174 @@
+//! Short-lived TURN credentials from a REST endpoint
+//!
+//! With `WEBRTC_TURN_REST_URL` set, each new WebRTC session asks that URL for
+//! TURN credentials instead of using `WEBRTC_TURN_USERNAME` and
+//! `WEBRTC_TURN_CREDENTIAL`. This is the usual pattern for coturn's
+//! `use-auth-secret`: a service signs time-limited credentials and answers a
+//! GET with `{"username": "...", "password": "...", "ttl": 86400}`, optionally
+//! with the relay `uris`. Credentials are reused until a fifth of their
+//! lifetime is left. When the endpoint fails, still-valid cached credentials
+//! are kept, and otherwise the static variables are used.
+
+use lib_env_parse::{env_opt, env_vars};
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+env_vars! {
+    WebrtcTurnRestUrl => "WEBRTC_TURN_REST_URL",
+}
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+struct RestResponse {
+    username: String,
+    password: String,
+    /// Seconds the credentials stay valid
+    ttl: u64,
+    #[serde(default)]
+    uris: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub credential: String,
+    /// Relay URLs suggested by the endpoint, used when `WEBRTC_ICE_SERVERS`
+    /// names no TURN server
+    pub uris: Vec<String>,
+    pub ttl: Duration,
+    expires_at: Instant,
+}
+
+impl TurnCredentials {
+    fn parse(body: &str, now: Instant) -> Result<Self, String> {
+        let response: RestResponse =
+            serde_json::from_str(body).map_err(|e| format!("Invalid TURN REST response: {}", e))?;
+        if response.username.is_empty() || response.password.is_empty() {
+            return Err("TURN REST response has an empty username or password".to_string());
+        }
+        if response.ttl == 0 {
+            return Err("TURN REST response has a ttl of 0".to_string());
+        }
+        let ttl = Duration::from_secs(response.ttl);
+        let expires_at = now
+            .checked_add(ttl)
+            .ok_or_else(|| format!("TURN REST response has an out of range ttl {}", response.ttl))?;
+        Ok(Self {
+            username: response.username,
+            credential: response.password,
+            uris: response.uris,
+            ttl,
+            expires_at,
+        })
+    }
+
+    /// Still valid at `now`
+    fn is_valid(&self, now: Instant) -> bool {
+        now < self.expires_at
+    }
+
+    /// More than a fifth of the lifetime left at `now`, so not worth refreshing
+    fn is_fresh(&self, now: Instant) -> bool {
+        now + self.ttl / 5 < self.expires_at
+    }
+}
+
+static CACHE: OnceLock<Mutex<Option<TurnCredentials>>> = OnceLock::new();
+
+async fn fetch(url: &str) -> Result<TurnCredentials, String> {
+    let client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Failed to build HTTP client: {}", e))?;
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("TURN REST request failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("TURN REST endpoint answered {}", response.status()));
+    }
+    let body = response
+        .text()
+        .await
+        .map_err(|e| format!("Failed to read TURN REST response: {}", e))?;
+    TurnCredentials::parse(&body, Instant::now())
+}
+
+/// Credentials from `WEBRTC_TURN_REST_URL`, or `None` when it is unset or no
+/// valid credentials could be had
+pub async fn credentials() -> Option<TurnCredentials> {
+    let url = env_opt(EnvVar::WebrtcTurnRestUrl.as_str()).filter(|u| !u.trim().is_empty())?;
+
+    // Held across the fetch, so sessions created together share one request
+    let mut cache = CACHE.get_or_init(Default::default).lock().await;
+    let now = Instant::now();
+    if let Some(cached) = cache.as_ref().filter(|c| c.is_fresh(now)) {
+        return Some(cached.clone());
+    }
+
+    match fetch(url.trim()).await {
+        Ok(fresh) => {
+            tracing::info!(
+                "🔑 Fetched TURN credentials from WEBRTC_TURN_REST_URL (ttl {}s, refreshed after {}s)",
+                fresh.ttl.as_secs(),
+                (fresh.ttl - fresh.ttl / 5).as_secs()
+            );
+            *cache = Some(fresh.clone());
+            Some(fresh)
+        }
+        Err(e) => match cache.as_ref().filter(|c| c.is_valid(now)) {
+            Some(cached) => {
+                tracing::warn!(
+                    "⚠️ {}; reusing TURN credentials valid for another {}s",
+                    e,
+                    cached.expires_at.saturating_duration_since(now).as_secs()
+                );
+                Some(cached.clone())
+            }
+            None => {
+                tracing::warn!("⚠️ {}; falling back to WEBRTC_TURN_USERNAME/WEBRTC_TURN_CREDENTIAL", e);
+                None
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turn_rest_credentials() {
+        let now = Instant::now();
+        let creds = TurnCredentials::parse(
+            r#"{"username":"1700000000:cocoon","password":"c2lnbmVk","ttl":600,"uris":["turn:relay.example.com:3478"]}"#,
+            now,
+        )
+        .unwrap();
+        assert_eq!(creds.username, "1700000000:cocoon");
+        assert_eq!(creds.credential, "c2lnbmVk");
+        assert_eq!(creds.uris, vec!["turn:relay.example.com:3478"]);
+
+        // Refreshed once less than a fifth of the ttl is left, valid until it runs out
+        assert!(creds.is_fresh(now + Duration::from_secs(479)));
+        assert!(!creds.is_fresh(now + Duration::from_secs(481)));
+        assert!(creds.is_valid(now + Duration::from_secs(599)));
+        assert!(!creds.is_valid(now + Duration::from_secs(600)));
+
+        let no_uris = TurnCredentials::parse(r#"{"username":"u","password":"p","ttl":60}"#, now).unwrap();
+        assert!(no_uris.uris.is_empty());
+
+        for bad in [
+            r#"{"username":"u","password":"p"}"#,
+            r#"{"username":"u","password":"p","ttl":0}"#,
+            r#"{"username":"","password":"p","ttl":60}"#,
+            "not json",
+        ] {
+            assert!(TurnCredentials::parse(bad, now).is_err(), "{} should be rejected", bad);
+        }
+    }
+}
@@ -14,26 +14,38 @@
 //!
 //! - `WEBRTC_TURN_CREDENTIAL`: Credential/password for TURN server authentication
 //!
+//! - `WEBRTC_TURN_REST_URL`: Endpoint handing out short-lived TURN credentials,
+//!   used instead of the two above when set (see `turn_rest`)
+//!
+//! - `WEBRTC_MAX_MESSAGE_BYTES`: Largest data channel message handled (default
+//!   16 MiB); bigger ones are answered with a `message_too_large` error
+//!
 //! If no ICE servers are configured, defaults to Google's public STUN server.
 
 use crate::adi_frame;
 use crate::adi_router::{AdiCallerContext, AdiDiscovery, AdiRouter, AdiRouterBinaryResult};
-use crate::filesystem::{FileSystemRequest, handle_request as handle_fs_request};
+use crate::command_filter::COMMAND_FORBIDDEN;
+use crate::core::PtySlots;
+use crate::filesystem::{FileSystemRequest, FileTransfers, handle_request as handle_fs_request};
 use crate::protocol::messages::CocoonMessage;
 use crate::protocol::types::SilkStream;
-use crate::silk::{AnsiToHtml, SilkSession};
+use crate::silk::{
+    detect_missing_command, missing_command_hint, AnsiToHtml, SilkSession, DUPLICATE_COMMAND_ID,
+};
 use lib_signaling_protocol::SignalingMessage;
 use portable_pty::PtySize;
 use std::collections::HashMap;
 use std::io::Read;
 use std::sync::Arc;
 use tokio::sync::{Mutex, mpsc};
+use tracing::Instrument;
 use uuid::Uuid;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::data_channel_state::RTCDataChannelState;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice_transport::ice_credential_type::RTCIceCredentialType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
@@ -42,6 +54,7 @@ use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::stats::StatsReportType;
 
 use lib_env_parse::{env_vars, env_opt};
 
@@ -49,17 +62,66 @@ env_vars! {
     WebrtcIceServers => "WEBRTC_ICE_SERVERS",
     WebrtcTurnUsername => "WEBRTC_TURN_USERNAME",
     WebrtcTurnCredential => "WEBRTC_TURN_CREDENTIAL",
+    WebrtcMaxMessageBytes => "WEBRTC_MAX_MESSAGE_BYTES",
 }
 
-fn build_ice_servers() -> Vec<RTCIceServer> {
-    let ice_servers_env = env_opt(EnvVar::WebrtcIceServers.as_str());
-    let turn_username = env_opt(EnvVar::WebrtcTurnUsername.as_str());
-    let turn_credential = env_opt(EnvVar::WebrtcTurnCredential.as_str());
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+fn max_message_bytes() -> usize {
+    env_opt(EnvVar::WebrtcMaxMessageBytes.as_str())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES)
+}
+
+/// Error to send back on `channel` for a message of `len` bytes, or `None` if
+/// it is within `limit`. The file channel gets the `fs_error` its clients parse.
+fn oversize_reply(channel: &str, len: usize, limit: usize) -> Option<String> {
+    if len <= limit {
+        return None;
+    }
+    let message = format!(
+        "Message of {} bytes exceeds the {} byte limit (WEBRTC_MAX_MESSAGE_BYTES)",
+        len, limit
+    );
+    let reply = if channel == "file" {
+        serde_json::json!({
+            "type": "fs_error",
+            "request_id": "",
+            "code": "message_too_large",
+            "message": message,
+        })
+    } else {
+        serde_json::json!({
+            "type": "error",
+            "code": "message_too_large",
+            "message": message,
+        })
+    };
+    Some(reply.to_string())
+}
 
-    let urls: Vec<String> = ice_servers_env
+async fn build_ice_servers() -> Vec<RTCIceServer> {
+    let ice_servers_env = env_opt(EnvVar::WebrtcIceServers.as_str());
+    let rest_credentials = crate::turn_rest::credentials().await;
+    let (turn_username, turn_credential) = match &rest_credentials {
+        Some(creds) => (Some(creds.username.clone()), Some(creds.credential.clone())),
+        None => (
+            env_opt(EnvVar::WebrtcTurnUsername.as_str()),
+            env_opt(EnvVar::WebrtcTurnCredential.as_str()),
+        ),
+    };
+
+    let mut urls: Vec<String> = ice_servers_env
         .as_ref()
         .map(|s| s.split(',').map(|u| u.trim().to_string()).filter(|u| !u.is_empty()).collect())
         .unwrap_or_default();
+    let is_turn = |u: &String| u.starts_with("turn:") || u.starts_with("turns:");
+    if let Some(creds) = &rest_credentials {
+        if !urls.iter().any(is_turn) {
+            urls.extend(creds.uris.iter().filter(|&u| is_turn(u)).cloned());
+        }
+    }
 
     if urls.is_empty() {
         tracing::info!("No WEBRTC_ICE_SERVERS configured, using default Google STUN server");
@@ -70,7 +132,7 @@ fn build_ice_servers() -> Vec<RTCIceServer> {
     }
 
     let stun_urls: Vec<String> = urls.iter().filter(|u| u.starts_with("stun:")).cloned().collect();
-    let turn_urls: Vec<String> = urls.iter().filter(|u| u.starts_with("turn:") || u.starts_with("turns:")).cloned().collect();
+    let turn_urls: Vec<String> = urls.iter().filter(|&u| is_turn(u)).cloned().collect();
 
     let mut ice_servers = Vec::new();
 
@@ -88,7 +150,11 @@ fn build_ice_servers() -> Vec<RTCIceServer> {
             "Configured {} TURN server(s): {:?} (credentials: {})",
             turn_urls.len(),
             turn_urls,
-            if has_credentials { "provided" } else { "none" }
+            match (&rest_credentials, has_credentials) {
+                (Some(_), _) => "from WEBRTC_TURN_REST_URL",
+                (None, true) => "provided",
+                (None, false) => "none",
+            }
         );
 
         ice_servers.push(RTCIceServer {
@@ -110,26 +176,291 @@ fn build_ice_servers() -> Vec<RTCIceServer> {
     ice_servers
 }
 
+/// Outcome of the TURN credential check in an [`IceReport`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnStatus {
+    NotConfigured,
+    MissingCredentials,
+    /// A relay candidate was allocated, so the credentials were accepted
+    Valid,
+    /// TURN is configured but no relay candidate was gathered
+    NoRelay,
+}
+
+/// Candidates gathered against the configured ICE servers
+#[derive(Debug, Clone, Default)]
+pub struct IceReport {
+    pub stun_urls: Vec<String>,
+    pub turn_urls: Vec<String>,
+    pub turn_credentials: bool,
+    pub host: usize,
+    pub srflx: usize,
+    pub relay: usize,
+    /// Gathering finished before the timeout
+    pub complete: bool,
+}
+
+impl IceReport {
+    pub fn turn_status(&self) -> TurnStatus {
+        if self.turn_urls.is_empty() {
+            TurnStatus::NotConfigured
+        } else if self.relay > 0 {
+            TurnStatus::Valid
+        } else if !self.turn_credentials {
+            TurnStatus::MissingCredentials
+        } else {
+            TurnStatus::NoRelay
+        }
+    }
+
+    /// Count candidate types from the `a=candidate` lines of an SDP
+    fn count_candidates(&mut self, sdp: &str) {
+        for line in sdp.lines().filter(|l| l.starts_with("a=candidate:")) {
+            let typ = line
+                .split_whitespace()
+                .skip_while(|field| *field != "typ")
+                .nth(1);
+            match typ {
+                Some("host") => self.host += 1,
+                Some("srflx") | Some("prflx") => self.srflx += 1,
+                Some("relay") => self.relay += 1,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Gather ICE candidates with the servers from `WEBRTC_ICE_SERVERS` and report
+/// which candidate types could be obtained.
+pub async fn test_ice_connectivity(timeout: std::time::Duration) -> Result<IceReport, String> {
+    let ice_servers = build_ice_servers().await;
+
+    let mut report = IceReport::default();
+    for server in &ice_servers {
+        for url in &server.urls {
+            if url.starts_with("turn:") || url.starts_with("turns:") {
+                report.turn_urls.push(url.clone());
+                report.turn_credentials = !server.username.is_empty() && !server.credential.is_empty();
+            } else {
+                report.stun_urls.push(url.clone());
+            }
+        }
+    }
+
+    let mut media_engine = MediaEngine::default();
+    let registry = register_default_interceptors(Registry::new(), &mut media_engine)
+        .map_err(|e| format!("Failed to register interceptors: {}", e))?;
+    let api = APIBuilder::new()
+        .with_media_engine(media_engine)
+        .with_interceptor_registry(registry)
+        .build();
+
+    let pc = api
+        .new_peer_connection(RTCConfiguration {
+            ice_servers,
+            ..Default::default()
+        })
+        .await
+        .map_err(|e| format!("Failed to create peer connection: {}", e))?;
+
+    // A data channel gives the offer a media section to gather candidates for
+    pc.create_data_channel("ice-test", None)
+        .await
+        .map_err(|e| format!("Failed to create data channel: {}", e))?;
+    let offer = pc
+        .create_offer(None)
+        .await
+        .map_err(|e| format!("Failed to create offer: {}", e))?;
+
+    let mut gathering_complete = pc.gathering_complete_promise().await;
+    pc.set_local_description(offer)
+        .await
+        .map_err(|e| format!("Failed to set local description: {}", e))?;
+    report.complete = tokio::time::timeout(timeout, gathering_complete.recv())
+        .await
+        .is_ok();
+
+    if let Some(desc) = pc.local_description().await {
+        report.count_candidates(&desc.sdp);
+    }
+    let _ = pc.close().await;
+
+    Ok(report)
+}
+
 struct SilkPtySession {
     id: Uuid,
     pair: portable_pty::PtyPair,
-    #[allow(dead_code)]
     child: Box<dyn portable_pty::Child + Send>,
     writer: Box<dyn std::io::Write + Send>,
 }
 
+/// Channels carrying filesystem or control data, which must stay reliable and ordered
+const RELIABLE_CHANNELS: &[&str] = &["file", "silk", "adi", "terminal"];
+
+/// Whether `dc` was negotiated to drop or reorder messages (e.g. `ordered: false,
+/// maxRetransmits: 0`), which suits real-time frames where stale data is worthless
+pub fn is_lossy(dc: &RTCDataChannel) -> bool {
+    !dc.ordered() || dc.max_retransmits().is_some() || dc.max_packet_lifetime().is_some()
+}
+
 struct SilkDcState {
+    /// Fallback link for output when the data channel is gone
+    signaling_tx: mpsc::UnboundedSender<SignalingMessage>,
+    /// Command handler owning the sessions opened over the "terminal" channel
+    terminal_tx: Option<mpsc::UnboundedSender<TerminalEvent>>,
+    pty_slots: Arc<PtySlots>,
     silk_sessions: Mutex<HashMap<String, SilkSession>>,
     pty_sessions: Mutex<HashMap<String, SilkPtySession>>,
+    /// Lossy channel preferred for high-frequency PTY frames, if the client opened one
+    realtime_channel: Mutex<Option<Arc<RTCDataChannel>>>,
 }
 
 impl SilkDcState {
-    fn new() -> Arc<Self> {
+    fn new(
+        signaling_tx: mpsc::UnboundedSender<SignalingMessage>,
+        terminal_tx: Option<mpsc::UnboundedSender<TerminalEvent>>,
+        pty_slots: Arc<PtySlots>,
+    ) -> Arc<Self> {
         Arc::new(Self {
+            signaling_tx,
+            terminal_tx,
+            pty_slots,
             silk_sessions: Mutex::new(HashMap::new()),
             pty_sessions: Mutex::new(HashMap::new()),
+            realtime_channel: Mutex::new(None),
         })
     }
+
+    /// The session's lossy channel while it is open
+    async fn realtime_channel(&self) -> Option<Arc<RTCDataChannel>> {
+        let dc = self.realtime_channel.lock().await.clone()?;
+        (dc.ready_state() == RTCDataChannelState::Open).then_some(dc)
+    }
+
+    /// Silk session ids and PTY ids attached through this WebRTC session's data channels
+    async fn terminal_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.silk_sessions.lock().await.keys().cloned().collect();
+        ids.extend(self.pty_sessions.lock().await.values().map(|p| p.id.to_string()));
+        ids
+    }
+
+    /// PIDs of the PTY children and Silk commands owned by this WebRTC session
+    async fn child_pids(&self) -> Vec<u32> {
+        let mut pids: Vec<u32> = self
+            .silk_sessions
+            .lock()
+            .await
+            .values()
+            .flat_map(SilkSession::command_pids)
+            .collect();
+        pids.extend(
+            self.pty_sessions
+                .lock()
+                .await
+                .values()
+                .filter_map(|p| p.child.process_id()),
+        );
+        pids
+    }
+
+    /// Kill every PTY and drop every Silk session attached to a WebRTC session that
+    /// has ended, reporting the exits over signaling since the data channel is gone.
+    /// Sessions opened over its "terminal" channel are the command handler's to end.
+    async fn reap(
+        &self,
+        webrtc_session_id: &str,
+        tx: &mpsc::UnboundedSender<SignalingMessage>,
+        reason: Option<&str>,
+    ) {
+        let ptys: Vec<(String, SilkPtySession)> = self.pty_sessions.lock().await.drain().collect();
+        let mut silk_sessions = self.silk_sessions.lock().await;
+        let reaped = ptys.len() + silk_sessions.len();
+
+        for (command_id, mut pty) in ptys {
+            let _ = pty.child.kill();
+            let exit_code = pty.child.wait().map(|s| s.exit_code() as i32).unwrap_or(-1);
+            tracing::debug!("🧹 Killed PTY {} for command {} (exit {})", pty.id, command_id, exit_code);
+
+            let owner = silk_sessions
+                .iter()
+                .find(|(_, s)| s.running_commands.contains_key(&command_id));
+            if let Some((silk_id, silk)) = owner {
+                send_via_signaling(tx, &CocoonMessage::SilkCommandCompleted {
+                    session_id: silk_id.clone(),
+                    command_id,
+                    exit_code,
+                    cwd: silk.cwd.clone(),
+                });
+            }
+        }
+
+        for (silk_id, _) in silk_sessions.drain() {
+            send_via_signaling(tx, &CocoonMessage::SilkSessionClosed {
+                session_id: silk_id,
+                reason: reason.map(str::to_string),
+            });
+        }
+
+        if reaped > 0 {
+            tracing::info!("🧹 Reaped {} terminal session(s) for WebRTC session {}", reaped, webrtc_session_id);
+        }
+
+        if let Some(terminal_tx) = &self.terminal_tx {
+            let _ = terminal_tx.send(TerminalEvent::SessionEnded {
+                session_id: webrtc_session_id.to_string(),
+                reason: reason.map(str::to_string),
+            });
+        }
+    }
+}
+
+fn send_via_signaling(tx: &mpsc::UnboundedSender<SignalingMessage>, msg: &CocoonMessage) {
+    let _ = tx.send(SignalingMessage::SyncData {
+        payload: serde_json::to_value(msg).expect("CocoonMessage serialization cannot fail"),
+    });
+}
+
+/// Link a message was ultimately delivered on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    DataChannel,
+    Signaling,
+}
+
+/// Send `msg` on `dc` while it is open, and as `sync_data` over signaling when
+/// there is no channel or the send fails, so output is not lost when a data
+/// channel flaps. The client accepts the same payload on either link.
+pub async fn send_with_fallback<T: serde::Serialize>(
+    dc: Option<&RTCDataChannel>,
+    tx: &mpsc::UnboundedSender<SignalingMessage>,
+    msg: &T,
+) -> Transport {
+    let payload = match serde_json::to_value(msg) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::error!("❌ Failed to serialize data channel message: {}", e);
+            return Transport::Signaling;
+        }
+    };
+
+    if let Some(dc) = dc.filter(|dc| dc.ready_state() == RTCDataChannelState::Open) {
+        let json = payload.to_string();
+        let len = json.len();
+        match dc.send(&json.into_bytes().into()).await {
+            Ok(_) => {
+                tracing::debug!("📤 Sent {} bytes on data channel '{}'", len, dc.label());
+                return Transport::DataChannel;
+            }
+            Err(e) => {
+                tracing::warn!("⚠️ Data channel '{}' send failed, falling back to signaling: {}", dc.label(), e);
+            }
+        }
+    }
+
+    tracing::debug!("📤 Sent message over signaling");
+    let _ = tx.send(SignalingMessage::SyncData { payload });
+    Transport::Signaling
 }
 
 pub struct WebRtcSession {
@@ -138,6 +469,81 @@ pub struct WebRtcSession {
     pub data_channels: HashMap<String, Arc<RTCDataChannel>>,
     pub state: String,
     pub user_id: Option<String>,
+    /// Silk/PTY sessions attached over this session's data channels; reaped when it ends
+    silk_state: Arc<SilkDcState>,
+    /// Chunked uploads on the "file" channel; abandoned when the session ends
+    file_transfers: Arc<FileTransfers>,
+    created_at: std::time::Instant,
+}
+
+/// Operator-facing summary of a WebRTC session
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct WebRtcSessionInfo {
+    pub session_id: String,
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    pub channels: usize,
+    pub age_secs: u64,
+    /// Silk and PTY sessions that end when this session is closed
+    pub terminal_sessions: Vec<String>,
+}
+
+/// Connection quality of a WebRTC session, taken from its selected ICE candidate pair
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct WebRtcSessionStats {
+    pub session_id: String,
+    pub state: String,
+    /// `host`, `srflx`, `prflx` or `relay`; unset until candidates are paired
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_candidate_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_candidate_type: Option<String>,
+    /// Traffic goes through a TURN server
+    pub relayed: bool,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub round_trip_time_ms: Option<f64>,
+    /// Share of connectivity checks on the pair that went unanswered, 0 to 1.
+    /// Data channels retransmit over SCTP, so this is the loss the link shows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packet_loss: Option<f64>,
+}
+
+/// What `summarize_pairs` needs from an ICE candidate pair report
+#[derive(Debug, Clone, Default)]
+struct PairSample {
+    local_candidate_id: String,
+    remote_candidate_id: String,
+    nominated: bool,
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Seconds, 0 until measured
+    current_round_trip_time: f64,
+    requests_sent: u64,
+    responses_received: u64,
+}
+
+/// Fill `stats` from the selected pair: the nominated one, or the busiest pair
+/// while none is nominated. `candidate_types` maps candidate ids to their type.
+fn summarize_pairs(stats: &mut WebRtcSessionStats, pairs: &[PairSample], candidate_types: &HashMap<String, String>) {
+    let Some(pair) = pairs
+        .iter()
+        .max_by_key(|p| (p.nominated, p.bytes_sent + p.bytes_received))
+    else {
+        return;
+    };
+    stats.local_candidate_type = candidate_types.get(&pair.local_candidate_id).cloned();
+    stats.remote_candidate_type = candidate_types.get(&pair.remote_candidate_id).cloned();
+    stats.relayed = [&stats.local_candidate_type, &stats.remote_candidate_type]
+        .iter()
+        .any(|t| t.as_deref() == Some("relay"));
+    stats.bytes_sent = pair.bytes_sent;
+    stats.bytes_received = pair.bytes_received;
+    stats.round_trip_time_ms = (pair.current_round_trip_time > 0.0).then(|| pair.current_round_trip_time * 1000.0);
+    stats.packet_loss = (pair.requests_sent > 0)
+        .then(|| 1.0 - pair.responses_received.min(pair.requests_sent) as f64 / pair.requests_sent as f64);
 }
 
 pub struct WebRtcManager {
@@ -145,6 +551,27 @@ pub struct WebRtcManager {
     signaling_tx: mpsc::UnboundedSender<SignalingMessage>,
     close_timeout: std::time::Duration,
     adi_router: Option<Arc<Mutex<AdiRouter>>>,
+    terminal_tx: Option<mpsc::UnboundedSender<TerminalEvent>>,
+    /// Cap on open PTYs, shared with signaling PTYs; unlimited unless set
+    pty_slots: Arc<PtySlots>,
+    /// Span the manager was built in (the `cocoon` span); peer connection
+    /// callbacks run on webrtc-rs tasks, so they log under it explicitly
+    span: tracing::Span,
+}
+
+/// What the command handler hears about "terminal" data channels
+pub enum TerminalEvent {
+    Request(TerminalRequest),
+    /// The WebRTC session is gone; sessions its commands opened go with it
+    SessionEnded { session_id: String, reason: Option<String> },
+}
+
+/// Text received on a session's "terminal" data channel, to be handled as a
+/// command and answered on `reply`
+pub struct TerminalRequest {
+    pub session_id: String,
+    pub payload: String,
+    pub reply: Arc<RTCDataChannel>,
 }
 
 impl WebRtcManager {
@@ -154,6 +581,9 @@ impl WebRtcManager {
             signaling_tx,
             close_timeout: std::time::Duration::from_secs(5),
             adi_router: None,
+            terminal_tx: None,
+            pty_slots: Arc::new(PtySlots::new(0)),
+            span: tracing::Span::current(),
         }
     }
 
@@ -166,9 +596,25 @@ impl WebRtcManager {
             signaling_tx,
             close_timeout: std::time::Duration::from_secs(5),
             adi_router: Some(adi_router),
+            terminal_tx: None,
+            pty_slots: Arc::new(PtySlots::new(0)),
+            span: tracing::Span::current(),
         }
     }
 
+    /// Pass what arrives on "terminal" data channels to `tx` instead of
+    /// relaying it through signaling
+    pub fn with_terminal_requests(mut self, tx: mpsc::UnboundedSender<TerminalEvent>) -> Self {
+        self.terminal_tx = Some(tx);
+        self
+    }
+
+    /// Count interactive Silk PTYs against `slots` (`COCOON_MAX_PTY_SESSIONS`)
+    pub(crate) fn with_pty_slots(mut self, slots: Arc<PtySlots>) -> Self {
+        self.pty_slots = slots;
+        self
+    }
+
     #[cfg(test)]
     pub fn with_close_timeout(
         signaling_tx: mpsc::UnboundedSender<SignalingMessage>,
@@ -179,6 +625,9 @@ impl WebRtcManager {
             signaling_tx,
             close_timeout,
             adi_router: None,
+            terminal_tx: None,
+            pty_slots: Arc::new(PtySlots::new(0)),
+            span: tracing::Span::current(),
         }
     }
 
@@ -186,7 +635,7 @@ impl WebRtcManager {
         tracing::info!("🔧 [create_session] START session_id={}", session_id);
         tracing::info!("🔧 [create_session] current session count: {}", self.sessions.lock().await.len());
 
-        let ice_servers = build_ice_servers();
+        let ice_servers = build_ice_servers().await;
         tracing::info!("🔧 [create_session] ICE servers configured: {}", ice_servers.len());
         let config = RTCConfiguration {
             ice_servers,
@@ -220,6 +669,7 @@ impl WebRtcManager {
 
         let session_id_clone = session_id.clone();
         let signaling_tx_clone = self.signaling_tx.clone();
+        let cocoon_span = self.span.clone();
         peer_connection.on_ice_candidate(Box::new(move |candidate| {
             let session_id = session_id_clone.clone();
             let tx = signaling_tx_clone.clone();
@@ -266,10 +716,11 @@ impl WebRtcManager {
                 } else {
                     tracing::debug!("🧊 ICE gathering complete for session {}", session_id);
                 }
-            })
+            }.instrument(cocoon_span.clone()))
         }));
 
         let session_id_clone = session_id.clone();
+        let cocoon_span = self.span.clone();
         peer_connection.on_ice_gathering_state_change(Box::new(move |state| {
             let session_id = session_id_clone.clone();
             Box::pin(async move {
@@ -278,10 +729,11 @@ impl WebRtcManager {
                     session_id,
                     state
                 );
-            })
+            }.instrument(cocoon_span.clone()))
         }));
 
         let session_id_clone = session_id.clone();
+        let cocoon_span = self.span.clone();
         peer_connection.on_ice_connection_state_change(Box::new(move |state| {
             let session_id = session_id_clone.clone();
             Box::pin(async move {
@@ -290,12 +742,13 @@ impl WebRtcManager {
                     session_id,
                     state
                 );
-            })
+            }.instrument(cocoon_span.clone()))
         }));
 
         let session_id_clone = session_id.clone();
         let signaling_tx_clone = self.signaling_tx.clone();
         let sessions_clone = self.sessions.clone();
+        let cocoon_span = self.span.clone();
         peer_connection.on_peer_connection_state_change(Box::new(move |state| {
             let session_id = session_id_clone.clone();
             let tx = signaling_tx_clone.clone();
@@ -347,32 +800,44 @@ impl WebRtcManager {
                             }).unwrap(),
                         });
 
-                        sessions.lock().await.remove(&session_id);
+                        let removed = sessions.lock().await.remove(&session_id);
+                        if let Some(session) = removed {
+                            session.silk_state.reap(&session_id, &tx, None).await;
+                            session.file_transfers.abandon_all().await;
+                        }
                     }
                     _ => {
                         tracing::info!("🔌 [PC-STATE] session={} → unhandled state {:?}", session_id, state);
                     }
                 }
-            })
+            }.instrument(cocoon_span.clone()))
         }));
 
         // Per-session silk state (outlives individual data channel handler calls)
-        let silk_state = SilkDcState::new();
+        let silk_state = SilkDcState::new(self.signaling_tx.clone(), self.terminal_tx.clone(), self.pty_slots.clone());
+        // Chunked uploads on the "file" channel, abandoned when the session goes away
+        let file_transfers = Arc::new(FileTransfers::default());
+        let max_message_bytes = max_message_bytes();
 
         let session_id_clone = session_id.clone();
         let signaling_tx_clone = self.signaling_tx.clone();
         let sessions_clone = self.sessions.clone();
         let adi_router_clone = self.adi_router.clone();
+        let terminal_tx_clone = self.terminal_tx.clone();
         let user_id_clone = user_id.clone();
         let silk_state_clone = silk_state.clone();
+        let file_transfers_clone = file_transfers.clone();
+        let cocoon_span = self.span.clone();
         peer_connection.on_data_channel(Box::new(move |dc| {
             let session_id = session_id_clone.clone();
             let tx = signaling_tx_clone.clone();
             let sessions = sessions_clone.clone();
             let dc_label = dc.label().to_string();
             let adi_router = adi_router_clone.clone();
+            let terminal_tx = terminal_tx_clone.clone();
             let user_id = user_id_clone.clone();
             let silk_state = silk_state_clone.clone();
+            let file_transfers = file_transfers_clone.clone();
 
             Box::pin(async move {
                 tracing::warn!(
@@ -383,32 +848,106 @@ impl WebRtcManager {
                     dc.ready_state(),
                 );
 
+                if is_lossy(&dc) {
+                    if RELIABLE_CHANNELS.contains(&dc_label.as_str()) {
+                        tracing::warn!(
+                            "⚠️ Rejecting '{}' channel for session {}: it was negotiated unordered/unreliable but carries data that must arrive intact",
+                            dc_label,
+                            session_id
+                        );
+                        let _ = dc.close().await;
+                        return;
+                    }
+                    tracing::info!("⚡ Real-time channel '{}' opened for session {}", dc_label, session_id);
+                    *silk_state.realtime_channel.lock().await = Some(dc.clone());
+                }
+
                 if let Some(session) = sessions.lock().await.get_mut(&session_id) {
                     session.data_channels.insert(dc_label.clone(), dc.clone());
                 }
 
+                // Tell the client when the channel is usable, so its first message is not lost
+                let session_id_for_open = session_id.clone();
+                let label_for_open = dc_label.clone();
+                let tx_for_open = tx.clone();
+                dc.on_open(Box::new(move || {
+                    tracing::info!("📡 Data channel '{}' open for session {}", label_for_open, session_id_for_open);
+                    send_via_signaling(&tx_for_open, &CocoonMessage::WebrtcChannelOpen {
+                        session_id: session_id_for_open.clone(),
+                        label: label_for_open.clone(),
+                    });
+                    Box::pin(async {})
+                }));
+
+                let session_id_for_close = session_id.clone();
+                let label_for_close = dc_label.clone();
+                let tx_for_close = tx.clone();
+                let sessions_for_close = sessions.clone();
+                let dc_for_close = Arc::downgrade(&dc);
+                dc.on_close(Box::new(move || {
+                    let session_id = session_id_for_close.clone();
+                    let label = label_for_close.clone();
+                    let tx = tx_for_close.clone();
+                    let sessions = sessions_for_close.clone();
+                    let dc = dc_for_close.clone();
+                    Box::pin(async move {
+                        tracing::info!("📡 Data channel '{}' closed for session {}", label, session_id);
+                        // Only forget the channel if a reopened one has not replaced it
+                        if let Some(session) = sessions.lock().await.get_mut(&session_id) {
+                            let current = session.data_channels.get(&label);
+                            if current.is_some_and(|c| dc.upgrade().is_some_and(|dc| Arc::ptr_eq(c, &dc))) {
+                                session.data_channels.remove(&label);
+                            }
+                        }
+                        send_via_signaling(&tx, &CocoonMessage::WebrtcChannelClose { session_id, label });
+                    })
+                }));
+
                 let dc_label_clone = dc_label.clone();
                 let session_id_clone = session_id.clone();
                 let tx_clone = tx.clone();
                 let dc_clone = dc.clone();
                 let adi_router_for_msg = adi_router.clone();
+                let terminal_tx_for_msg = terminal_tx.clone();
                 let user_id_for_msg = user_id.clone();
                 let silk_state_for_msg = silk_state.clone();
+                let file_transfers_for_msg = file_transfers.clone();
+                let cocoon_span = tracing::Span::current();
                 dc.on_message(Box::new(move |msg: DataChannelMessage| {
                     let session_id = session_id_clone.clone();
                     let channel = dc_label_clone.clone();
                     let tx = tx_clone.clone();
                     let dc_for_response = dc_clone.clone();
                     let adi_router = adi_router_for_msg.clone();
+                    let terminal_tx = terminal_tx_for_msg.clone();
                     let user_id = user_id_for_msg.clone();
                     let silk_state = silk_state_for_msg.clone();
+                    let file_transfers = file_transfers_for_msg.clone();
 
+                    let span = tracing::info_span!(
+                        parent: &cocoon_span,
+                        "data_channel",
+                        session_id = %session_id,
+                        channel = %channel,
+                    );
                     Box::pin(async move {
                         tracing::warn!(
                             "📨 [DC-MSG] on_message FIRED! session={} channel={} len={} is_string={}",
                             session_id, channel, msg.data.len(), msg.is_string
                         );
 
+                        // Rejected before anything parses or copies it
+                        if let Some(reply) = oversize_reply(&channel, msg.data.len(), max_message_bytes) {
+                            tracing::warn!(
+                                "⚠️ Rejecting {} byte message on '{}' (limit {} bytes)",
+                                msg.data.len(), channel, max_message_bytes
+                            );
+                            if let Err(e) = dc_for_response.send(&reply.into_bytes().into()).await {
+                                tracing::warn!("⚠️ Failed to send message_too_large error: {}", e);
+                            }
+                            return;
+                        }
+
                         if channel == "adi" && !msg.is_string {
                             if let Some(router) = &adi_router {
                                 tracing::debug!("📦 ADI binary request received: {} bytes", msg.data.len());
@@ -452,7 +991,7 @@ impl WebRtcManager {
                                                     break;
                                                 }
                                             }
-                                        });
+                                        }.in_current_span());
                                     }
                                 }
                             } else {
@@ -461,6 +1000,20 @@ impl WebRtcManager {
                             return;
                         }
 
+                        if channel == "terminal" {
+                            if let Some(terminal_tx) = &terminal_tx {
+                                let request = TerminalRequest {
+                                    session_id,
+                                    payload: String::from_utf8_lossy(&msg.data).to_string(),
+                                    reply: dc_for_response,
+                                };
+                                if terminal_tx.send(TerminalEvent::Request(request)).is_err() {
+                                    tracing::warn!("⚠️ Terminal request dropped: command handler is gone");
+                                }
+                                return;
+                            }
+                        }
+
                         let (data, binary) = if msg.is_string {
                             (String::from_utf8_lossy(&msg.data).to_string(), false)
                         } else {
@@ -474,7 +1027,7 @@ impl WebRtcManager {
                                     let dc = dc_for_response.clone();
                                     tokio::spawn(async move {
                                         handle_silk_dc_msg(cocoon_msg, silk_state, dc).await;
-                                    });
+                                    }.in_current_span());
                                 }
                                 Err(e) => {
                                     tracing::warn!("⚠️ Invalid silk message: {}", e);
@@ -487,20 +1040,8 @@ impl WebRtcManager {
                             tracing::debug!("📁 File system request received: {} bytes", data.len());
                             match serde_json::from_str::<FileSystemRequest>(&data) {
                                 Ok(request) => {
-                                    let response = handle_fs_request(request).await;
-                                    match serde_json::to_string(&response) {
-                                        Ok(response_json) => {
-                                            let response_len = response_json.len();
-                                            if let Err(e) = dc_for_response.send(&response_json.into_bytes().into()).await {
-                                                tracing::error!("❌ Failed to send filesystem response: {}", e);
-                                            } else {
-                                                tracing::debug!("📤 Filesystem response sent: {} bytes", response_len);
-                                            }
-                                        }
-                                        Err(e) => {
-                                            tracing::error!("❌ Failed to serialize filesystem response: {}", e);
-                                        }
-                                    }
+                                    let response = handle_fs_request(request, &file_transfers).await;
+                                    send_with_fallback(Some(&dc_for_response), &tx, &response).await;
                                 }
                                 Err(e) => {
                                     tracing::warn!("⚠️ Invalid filesystem request: {}", e);
@@ -599,7 +1140,7 @@ impl WebRtcManager {
                                             if let Ok(json) = serde_json::to_string(&response) {
                                                 let _ = dc.send(&json.into_bytes().into()).await;
                                             }
-                                        });
+                                        }.in_current_span());
                                         return;
                                     }
                                 }
@@ -631,22 +1172,24 @@ impl WebRtcManager {
                                 binary,
                             }).unwrap(),
                         });
-                    })
+                    }.instrument(span))
                 }));
-            })
+            }.instrument(cocoon_span.clone()))
         }));
 
-        // Store the session (silk_state is held alive by the on_data_channel closure)
-        drop(silk_state);
         let session = WebRtcSession {
             session_id: session_id.clone(),
             peer_connection,
             data_channels: HashMap::new(),
             state: "pending".to_string(),
             user_id,
+            silk_state,
+            file_transfers,
+            created_at: std::time::Instant::now(),
         };
 
         self.sessions.lock().await.insert(session_id.clone(), session);
+        crate::metrics::increment(crate::metrics::Counter::WebrtcSessionsOpened);
         tracing::info!("🔧 [create_session] END session_id={} — stored and ready for offer", session_id);
 
         Ok(())
@@ -789,12 +1332,111 @@ impl WebRtcManager {
         Ok(())
     }
 
+    /// Send `msg` on the `label` channel of every session that has it open.
+    /// Returns how many sessions it reached; no signaling fallback.
+    pub async fn broadcast<T: serde::Serialize>(&self, label: &str, msg: &T) -> usize {
+        let channels: Vec<Arc<RTCDataChannel>> = self
+            .sessions
+            .lock()
+            .await
+            .values()
+            .filter_map(|s| s.data_channels.get(label).cloned())
+            .filter(|dc| dc.ready_state() == RTCDataChannelState::Open && !is_lossy(dc))
+            .collect();
+
+        let json = match serde_json::to_string(msg) {
+            Ok(json) => json,
+            Err(e) => {
+                tracing::error!("❌ Failed to serialize broadcast message: {}", e);
+                return 0;
+            }
+        };
+        let mut sent = 0;
+        for dc in channels {
+            match dc.send(&json.clone().into_bytes().into()).await {
+                Ok(_) => sent += 1,
+                Err(e) => tracing::debug!("📡 Broadcast on {} channel failed: {}", label, e),
+            }
+        }
+        sent
+    }
+
+    /// Look up a session's data channel by label, returning it only while it is open.
+    ///
+    /// Lossy channels are skipped: callers send ordered output and control frames.
+    pub async fn open_data_channel(&self, session_id: &str, label: &str) -> Option<Arc<RTCDataChannel>> {
+        let sessions = self.sessions.lock().await;
+        let dc = sessions.get(session_id)?.data_channels.get(label)?.clone();
+        (dc.ready_state() == RTCDataChannelState::Open && !is_lossy(&dc)).then_some(dc)
+    }
+
     /// Close a session
     ///
     /// Uses a timeout for the peer connection close to prevent hanging
     /// when the connection was never fully established.
     pub async fn close_session(&self, session_id: &str) -> Result<(), String> {
-        if let Some(session) = self.sessions.lock().await.remove(session_id) {
+        self.close_session_with_reason(session_id, None).await
+    }
+
+    /// Close a session on an operator's request, announcing it to the client with `reason`.
+    /// Returns the terminal sessions that ended with it, or `None` if there was no such session.
+    pub async fn terminate_session(&self, session_id: &str, reason: &str) -> Option<Vec<String>> {
+        if !self.session_exists(session_id).await {
+            return None;
+        }
+        let terminals = self.terminal_sessions(session_id).await;
+
+        tracing::info!("🛑 Closing WebRTC session {} ({})", session_id, reason);
+        send_via_signaling(&self.signaling_tx, &CocoonMessage::WebrtcSessionEnded {
+            session_id: session_id.to_string(),
+            reason: Some(reason.to_string()),
+        });
+        let _ = self.close_session_with_reason(session_id, Some(reason)).await;
+        Some(terminals)
+    }
+
+    /// Close sessions older than `max_lifetime`, announcing each with `reason`.
+    /// Returns the ids of the closed sessions.
+    pub async fn expire_sessions(&self, max_lifetime: std::time::Duration, reason: &str) -> Vec<String> {
+        let expired: Vec<String> = self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .filter(|(_, s)| s.created_at.elapsed() >= max_lifetime)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        for session_id in &expired {
+            tracing::info!("⏰ WebRTC session {} reached its maximum lifetime", session_id);
+            send_via_signaling(&self.signaling_tx, &CocoonMessage::WebrtcSessionEnded {
+                session_id: session_id.clone(),
+                reason: Some(reason.to_string()),
+            });
+            let _ = self.close_session_with_reason(session_id, Some(reason)).await;
+        }
+        expired
+    }
+
+    /// End every session, announcing `reason` to its client. Returns the ended session IDs.
+    pub async fn close_all_sessions(&self, reason: &str) -> Vec<String> {
+        let session_ids = self.list_sessions().await;
+        for session_id in &session_ids {
+            send_via_signaling(&self.signaling_tx, &CocoonMessage::WebrtcSessionEnded {
+                session_id: session_id.clone(),
+                reason: Some(reason.to_string()),
+            });
+            let _ = self.close_session_with_reason(session_id, Some(reason)).await;
+        }
+        session_ids
+    }
+
+    async fn close_session_with_reason(&self, session_id: &str, reason: Option<&str>) -> Result<(), String> {
+        let removed = self.sessions.lock().await.remove(session_id);
+        if let Some(session) = removed {
+            session.silk_state.reap(session_id, &self.signaling_tx, reason).await;
+            session.file_transfers.abandon_all().await;
+
             // Use a timeout for close() as it can hang if the connection
             // was never fully established (common in tests or rapid page refreshes)
             let close_result = tokio::time::timeout(
@@ -833,6 +1475,75 @@ impl WebRtcManager {
             .collect()
     }
 
+    /// Summaries of every session, including the terminal sessions closing it would end
+    pub async fn describe_sessions(&self) -> Vec<WebRtcSessionInfo> {
+        let snapshots: Vec<(WebRtcSessionInfo, Arc<SilkDcState>)> = self
+            .sessions
+            .lock()
+            .await
+            .values()
+            .map(|s| {
+                let info = WebRtcSessionInfo {
+                    session_id: s.session_id.clone(),
+                    state: s.state.clone(),
+                    user_id: s.user_id.clone(),
+                    channels: s.data_channels.len(),
+                    age_secs: s.created_at.elapsed().as_secs(),
+                    terminal_sessions: Vec::new(),
+                };
+                (info, s.silk_state.clone())
+            })
+            .collect();
+
+        let mut infos = Vec::with_capacity(snapshots.len());
+        for (mut info, silk_state) in snapshots {
+            info.terminal_sessions = silk_state.terminal_ids().await;
+            infos.push(info);
+        }
+        infos.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        infos
+    }
+
+    /// Connection statistics of a session, or `None` if there is no such session
+    pub async fn get_session_stats(&self, session_id: &str) -> Option<WebRtcSessionStats> {
+        // Stats are gathered without holding the sessions lock
+        let (pc, state) = {
+            let sessions = self.sessions.lock().await;
+            let session = sessions.get(session_id)?;
+            (session.peer_connection.clone(), session.state.clone())
+        };
+
+        let report = pc.get_stats().await;
+        let mut pairs = Vec::new();
+        let mut candidate_types = HashMap::new();
+        for stat in report.reports.values() {
+            match stat {
+                StatsReportType::CandidatePair(pair) => pairs.push(PairSample {
+                    local_candidate_id: pair.local_candidate_id.clone(),
+                    remote_candidate_id: pair.remote_candidate_id.clone(),
+                    nominated: pair.nominated,
+                    bytes_sent: pair.bytes_sent,
+                    bytes_received: pair.bytes_received,
+                    current_round_trip_time: pair.current_round_trip_time,
+                    requests_sent: pair.requests_sent,
+                    responses_received: pair.responses_received,
+                }),
+                StatsReportType::LocalCandidate(candidate) | StatsReportType::RemoteCandidate(candidate) => {
+                    candidate_types.insert(candidate.id.clone(), candidate.candidate_type.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        let mut stats = WebRtcSessionStats {
+            session_id: session_id.to_string(),
+            state,
+            ..Default::default()
+        };
+        summarize_pairs(&mut stats, &pairs, &candidate_types);
+        Some(stats)
+    }
+
     pub async fn session_count(&self) -> usize {
         self.sessions.lock().await.len()
     }
@@ -841,6 +1552,31 @@ impl WebRtcManager {
         self.sessions.lock().await.contains_key(session_id)
     }
 
+    /// Ids of the Silk and PTY sessions attached to a WebRTC session
+    pub async fn terminal_sessions(&self, session_id: &str) -> Vec<String> {
+        let silk_state = match self.sessions.lock().await.get(session_id) {
+            Some(session) => session.silk_state.clone(),
+            None => return Vec::new(),
+        };
+        silk_state.terminal_ids().await
+    }
+
+    /// PIDs of every child process owned by WebRTC terminal sessions
+    pub async fn child_pids(&self) -> Vec<u32> {
+        let states: Vec<Arc<SilkDcState>> = self
+            .sessions
+            .lock()
+            .await
+            .values()
+            .map(|s| s.silk_state.clone())
+            .collect();
+        let mut pids = Vec::new();
+        for state in states {
+            pids.extend(state.child_pids().await);
+        }
+        pids
+    }
+
     pub async fn get_session_state(&self, session_id: &str) -> Option<String> {
         self.sessions
             .lock()
@@ -850,23 +1586,8 @@ impl WebRtcManager {
     }
 }
 
-async fn dc_send(dc: &RTCDataChannel, msg: &CocoonMessage) {
-    match serde_json::to_string(msg) {
-        Ok(json) => {
-            tracing::warn!("📤 [dc_send] sending {} bytes, dc_id={}, readyState={:?}, preview={}", json.len(), dc.id(), dc.ready_state(), &json[..json.len().min(200)]);
-            match dc.send(&json.into_bytes().into()).await {
-                Ok(n) => {
-                    tracing::warn!("📤 [dc_send] OK — sent {} bytes", n);
-                }
-                Err(e) => {
-                    tracing::error!("📤 [dc_send] FAILED: {}", e);
-                }
-            }
-        }
-        Err(e) => {
-            tracing::error!("📤 [dc_send] serialization FAILED: {}", e);
-        }
-    }
+async fn dc_send(dc: &RTCDataChannel, tx: &mpsc::UnboundedSender<SignalingMessage>, msg: &CocoonMessage) {
+    send_with_fallback(Some(dc), tx, msg).await;
 }
 
 async fn handle_silk_dc_msg(
@@ -875,11 +1596,11 @@ async fn handle_silk_dc_msg(
     dc: Arc<RTCDataChannel>,
 ) {
     match msg {
-        CocoonMessage::SilkCreateSession { cwd, env, shell } => {
+        CocoonMessage::SilkCreateSession { cwd, env, shell, clean_env } => {
             tracing::warn!("🧵 [SILK] Creating session cwd={:?} shell={:?}", cwd, shell);
             let env = env.unwrap_or_default();
             tracing::warn!("🧵 [SILK] Calling SilkSession::new...");
-            match SilkSession::new(cwd, env, shell) {
+            match SilkSession::new(cwd, env, shell, clean_env.unwrap_or(false)) {
                 Ok(session) => {
                     tracing::warn!("🧵 [SILK] Session OK id={} cwd={} shell={}", session.id, session.cwd, session.shell);
                     let response = CocoonMessage::SilkCreateSessionResponse {
@@ -890,12 +1611,12 @@ async fn handle_silk_dc_msg(
                     tracing::warn!("🧵 [SILK] Acquiring silk_sessions lock...");
                     state.silk_sessions.lock().await.insert(session.id.to_string(), session);
                     tracing::warn!("🧵 [SILK] Session stored, calling dc_send...");
-                    dc_send(&dc, &response).await;
+                    dc_send(&dc, &state.signaling_tx, &response).await;
                     tracing::warn!("🧵 [SILK] dc_send COMPLETE — response sent!");
                 }
                 Err(e) => {
                     tracing::error!("🧵 [SILK] SilkSession::new FAILED: {}", e);
-                    dc_send(&dc, &CocoonMessage::SilkError {
+                    dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
                         session_id: None,
                         command_id: None,
                         code: "session_create_failed".to_string(),
@@ -906,12 +1627,12 @@ async fn handle_silk_dc_msg(
             }
         }
 
-        CocoonMessage::SilkExecute { session_id, command, command_id, cols, rows, .. } => {
-            tracing::info!("🧵 [DC] Silk execute: {} (session {})", command, session_id);
+        CocoonMessage::SilkExecute { session_id, command, command_id, cols, rows, force_interactive, .. } => {
+            tracing::info!("🧵 [DC] Silk execute: {} (session {})", crate::log_redact::redact(&command), session_id);
             let mut sessions = state.silk_sessions.lock().await;
             let Some(session) = sessions.get_mut(&session_id) else {
                 drop(sessions);
-                dc_send(&dc, &CocoonMessage::SilkError {
+                dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
                     session_id: Some(session_id),
                     command_id: Some(command_id),
                     code: "session_not_found".to_string(),
@@ -920,9 +1641,35 @@ async fn handle_silk_dc_msg(
                 return;
             };
 
-            match session.execute(&command, command_id.clone()) {
+            if session.is_running(&command_id) {
+                drop(sessions);
+                dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
+                    session_id: Some(session_id),
+                    message: format!("Command {} is still running; reuse its id once it completes", command_id),
+                    command_id: Some(command_id),
+                    code: DUPLICATE_COMMAND_ID.to_string(),
+                }).await;
+                return;
+            }
+
+            if let Err(reason) = crate::command_filter::check(&command) {
+                drop(sessions);
+                tracing::warn!("🔒 Refusing Silk command: {}", reason);
+                dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
+                    session_id: Some(session_id),
+                    command_id: Some(command_id),
+                    code: COMMAND_FORBIDDEN.to_string(),
+                    message: format!("Command not allowed: {}", reason),
+                }).await;
+                return;
+            }
+
+            crate::history::record(&command, &HashMap::new());
+
+            match session.execute(&command, command_id.clone(), force_interactive) {
                 Ok((interactive, child_opt)) => {
                     if interactive {
+                        let child_env = session.child_env();
                         drop(sessions);
                         let dc_for_pty = dc.clone();
                         let state_for_pty = state.clone();
@@ -930,12 +1677,33 @@ async fn handle_silk_dc_msg(
                         let term_rows = rows.map(|r| r as u16).unwrap_or(24);
                         let pty_id = Uuid::new_v4();
 
+                        let Some(slot) = state.pty_slots.acquire() else {
+                            tracing::warn!("⚠️ Refusing Silk PTY: {} sessions already open", state.pty_slots.max);
+                            if let Some(s) = state.silk_sessions.lock().await.get_mut(&session_id) {
+                                s.complete_command(command_id.clone());
+                            }
+                            dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
+                                session_id: Some(session_id),
+                                command_id: Some(command_id),
+                                code: "pty_limit_reached".to_string(),
+                                message: format!(
+                                    "PTY limit reached ({} open sessions, COCOON_MAX_PTY_SESSIONS)",
+                                    state.pty_slots.max
+                                ),
+                            }).await;
+                            return;
+                        };
+
                         let pty_system = portable_pty::native_pty_system();
                         match pty_system.openpty(PtySize { rows: term_rows, cols: term_cols, pixel_width: 0, pixel_height: 0 }) {
                             Ok(pair) => {
                                 let mut cmd = portable_pty::CommandBuilder::new("/bin/sh");
                                 cmd.arg("-c");
-                                cmd.arg(&command);
+                                cmd.arg(&*crate::exec_wrapper::wrap("/bin/sh", &command));
+                                cmd.env_clear();
+                                for (key, value) in child_env {
+                                    cmd.env(key, value);
+                                }
                                 cmd.env("TERM", "xterm-256color");
 
                                 match pair.slave.spawn_command(cmd) {
@@ -948,7 +1716,12 @@ async fn handle_silk_dc_msg(
                                         let session_id_for_pty = session_id.clone();
                                         let command_id_for_pty = command_id.clone();
                                         let pty_id_str = pty_id.to_string();
+                                        let state_for_reader = state_for_pty.clone();
+                                        let span = tracing::Span::current();
                                         tokio::task::spawn_blocking(move || {
+                                            let _span = span.enter();
+                                            // Held until the PTY's output ends
+                                            let _slot = slot;
                                             let mut buf = [0u8; 4096];
                                             loop {
                                                 match reader.read(&mut buf) {
@@ -962,9 +1735,12 @@ async fn handle_silk_dc_msg(
                                                             data,
                                                         };
                                                         let dc_clone = dc_for_pty.clone();
+                                                        let state = state_for_reader.clone();
                                                         tokio::spawn(async move {
-                                                            dc_send(&dc_clone, &response).await;
-                                                        });
+                                                            // Frames superseded by later output may be dropped
+                                                            let dc = state.realtime_channel().await.unwrap_or(dc_clone);
+                                                            dc_send(&dc, &state.signaling_tx, &response).await;
+                                                        }.in_current_span());
                                                     }
                                                     Err(_) => break,
                                                 }
@@ -980,7 +1756,7 @@ async fn handle_silk_dc_msg(
                                         };
                                         state_for_pty.pty_sessions.lock().await.insert(command_id.clone(), pty_session);
 
-                                        dc_send(&dc, &CocoonMessage::SilkInteractiveRequired {
+                                        dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkInteractiveRequired {
                                             session_id,
                                             command_id,
                                             reason: format!("Command '{}' requires interactive mode", command.split_whitespace().next().unwrap_or(&command)),
@@ -988,7 +1764,7 @@ async fn handle_silk_dc_msg(
                                         }).await;
                                     }
                                     Err(e) => {
-                                        dc_send(&dc, &CocoonMessage::SilkError {
+                                        dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
                                             session_id: Some(session_id),
                                             command_id: Some(command_id),
                                             code: "pty_spawn_failed".to_string(),
@@ -998,7 +1774,7 @@ async fn handle_silk_dc_msg(
                                 }
                             }
                             Err(e) => {
-                                dc_send(&dc, &CocoonMessage::SilkError {
+                                dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
                                     session_id: Some(session_id),
                                     command_id: Some(command_id),
                                     code: "pty_create_failed".to_string(),
@@ -1012,7 +1788,7 @@ async fn handle_silk_dc_msg(
                         let state_for_out = state.clone();
                         let command_id_clone = command_id.clone();
 
-                        dc_send(&dc, &CocoonMessage::SilkCommandStarted {
+                        dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkCommandStarted {
                             session_id: session_id.clone(),
                             command_id: command_id.clone(),
                             interactive: false,
@@ -1030,7 +1806,7 @@ async fn handle_silk_dc_msg(
                                     Ok(n) => {
                                         let data = String::from_utf8_lossy(&buf[..n]).to_string();
                                         let html = AnsiToHtml::convert(&data);
-                                        dc_send(&dc_for_out, &CocoonMessage::SilkOutput {
+                                        dc_send(&dc_for_out, &state_for_out.signaling_tx, &CocoonMessage::SilkOutput {
                                             session_id: session_id.clone(),
                                             command_id: command_id.clone(),
                                             stream: SilkStream::Stdout,
@@ -1044,20 +1820,29 @@ async fn handle_silk_dc_msg(
 
                             let mut stderr_buf = Vec::new();
                             let _ = stderr.read_to_end(&mut stderr_buf);
-                            if !stderr_buf.is_empty() {
-                                let data = String::from_utf8_lossy(&stderr_buf).to_string();
-                                let html = AnsiToHtml::convert(&data);
-                                dc_send(&dc_for_out, &CocoonMessage::SilkOutput {
+                            let stderr_text = String::from_utf8_lossy(&stderr_buf).to_string();
+                            if !stderr_text.is_empty() {
+                                let html = AnsiToHtml::convert(&stderr_text);
+                                dc_send(&dc_for_out, &state_for_out.signaling_tx, &CocoonMessage::SilkOutput {
                                     session_id: session_id.clone(),
                                     command_id: command_id.clone(),
                                     stream: SilkStream::Stderr,
-                                    data,
+                                    data: stderr_text.clone(),
                                     html: Some(html),
                                 }).await;
                             }
 
                             let exit_code = child.wait().map(|s| s.code().unwrap_or(-1)).unwrap_or(-1);
 
+                            if let Some(binary) = detect_missing_command(&command, exit_code, &stderr_text) {
+                                dc_send(&dc_for_out, &state_for_out.signaling_tx, &CocoonMessage::SilkError {
+                                    session_id: Some(session_id.clone()),
+                                    command_id: Some(command_id.clone()),
+                                    code: "command_not_found".to_string(),
+                                    message: missing_command_hint(&binary),
+                                }).await;
+                            }
+
                             let mut sessions = state_for_out.silk_sessions.lock().await;
                             let cwd = if let Some(s) = sessions.get_mut(&session_id) {
                                 s.update_cwd_if_cd(&command);
@@ -1068,15 +1853,15 @@ async fn handle_silk_dc_msg(
                             };
                             drop(sessions);
 
-                            dc_send(&dc_for_out, &CocoonMessage::SilkCommandCompleted {
+                            dc_send(&dc_for_out, &state_for_out.signaling_tx, &CocoonMessage::SilkCommandCompleted {
                                 session_id,
                                 command_id,
                                 exit_code,
                                 cwd,
                             }).await;
-                        });
+                        }.in_current_span());
                     } else {
-                        dc_send(&dc, &CocoonMessage::SilkError {
+                        dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
                             session_id: Some(session_id),
                             command_id: Some(command_id),
                             code: "execute_failed".to_string(),
@@ -1085,7 +1870,7 @@ async fn handle_silk_dc_msg(
                     }
                 }
                 Err(e) => {
-                    dc_send(&dc, &CocoonMessage::SilkError {
+                    dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
                         session_id: Some(session_id),
                         command_id: Some(command_id),
                         code: "execute_failed".to_string(),
@@ -1104,7 +1889,7 @@ async fn handle_silk_dc_msg(
                     let _ = std::io::Write::flush(&mut pty.writer);
                 }
             } else {
-                dc_send(&dc, &CocoonMessage::SilkError {
+                dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkError {
                     session_id: Some(session_id),
                     command_id: Some(command_id),
                     code: "command_not_found".to_string(),
@@ -1128,7 +1913,7 @@ async fn handle_silk_dc_msg(
         CocoonMessage::SilkCloseSession { session_id } => {
             tracing::info!("🧵 [DC] Closing silk session {}", session_id);
             state.silk_sessions.lock().await.remove(&session_id);
-            dc_send(&dc, &CocoonMessage::SilkSessionClosed { session_id }).await;
+            dc_send(&dc, &state.signaling_tx, &CocoonMessage::SilkSessionClosed { session_id, reason: None }).await;
         }
 
         _ => {
@@ -1142,6 +1927,119 @@ mod tests {
     use super::*;
     use tokio::sync::mpsc;
 
+    #[test]
+    fn test_ice_report_counts_and_turn_status() {
+        let sdp = "v=0\r\n\
+a=candidate:1 1 udp 2130706431 192.168.1.5 50000 typ host\r\n\
+a=candidate:2 1 udp 1694498815 203.0.113.7 50001 typ srflx raddr 192.168.1.5 rport 50000\r\n\
+a=candidate:3 1 udp 16777215 198.51.100.9 3478 typ relay raddr 203.0.113.7 rport 50001\r\n";
+
+        let mut report = IceReport {
+            turn_urls: vec!["turn:turn.example.com:3478".to_string()],
+            turn_credentials: true,
+            ..Default::default()
+        };
+        assert_eq!(report.turn_status(), TurnStatus::NoRelay);
+
+        report.count_candidates(sdp);
+        assert_eq!((report.host, report.srflx, report.relay), (1, 1, 1));
+        assert_eq!(report.turn_status(), TurnStatus::Valid);
+
+        assert_eq!(IceReport::default().turn_status(), TurnStatus::NotConfigured);
+    }
+
+    #[test]
+    fn test_oversize_message_is_rejected() {
+        assert_eq!(oversize_reply("file", 1024, 1024), None);
+
+        let payload = format!(r#"{{"type":"write_file","path":"/tmp/x","content":"{}"}}"#, "a".repeat(2048));
+        let reply: serde_json::Value =
+            serde_json::from_str(&oversize_reply("file", payload.len(), 1024).unwrap()).unwrap();
+        assert_eq!(reply["type"], "fs_error");
+        assert_eq!(reply["code"], "message_too_large");
+        assert!(reply["message"].as_str().unwrap().contains(&payload.len().to_string()));
+
+        let reply: serde_json::Value =
+            serde_json::from_str(&oversize_reply("silk", payload.len(), 1024).unwrap()).unwrap();
+        assert_eq!(reply["type"], "error");
+        assert_eq!(reply["code"], "message_too_large");
+    }
+
+    #[test]
+    fn test_summarize_pairs_picks_nominated_pair() {
+        let candidate_types: HashMap<String, String> =
+            [("l-host", "host"), ("l-relay", "relay"), ("r-srflx", "srflx")]
+                .map(|(id, typ)| (id.to_string(), typ.to_string()))
+                .into_iter()
+                .collect();
+        let pairs = [
+            PairSample {
+                local_candidate_id: "l-host".to_string(),
+                remote_candidate_id: "r-srflx".to_string(),
+                bytes_sent: 9000,
+                requests_sent: 4,
+                ..Default::default()
+            },
+            PairSample {
+                local_candidate_id: "l-relay".to_string(),
+                remote_candidate_id: "r-srflx".to_string(),
+                nominated: true,
+                bytes_sent: 1200,
+                bytes_received: 3400,
+                current_round_trip_time: 0.042,
+                requests_sent: 20,
+                responses_received: 19,
+            },
+        ];
+
+        let mut stats = WebRtcSessionStats::default();
+        summarize_pairs(&mut stats, &pairs, &candidate_types);
+        assert_eq!(stats.local_candidate_type.as_deref(), Some("relay"));
+        assert_eq!(stats.remote_candidate_type.as_deref(), Some("srflx"));
+        assert!(stats.relayed);
+        assert_eq!((stats.bytes_sent, stats.bytes_received), (1200, 3400));
+        assert!((stats.round_trip_time_ms.unwrap() - 42.0).abs() < 1e-9);
+        assert!((stats.packet_loss.unwrap() - 0.05).abs() < 1e-9);
+
+        // Before nomination the busiest pair stands in, with nothing measured yet
+        let mut stats = WebRtcSessionStats::default();
+        summarize_pairs(&mut stats, &pairs[..1], &candidate_types);
+        assert_eq!(stats.local_candidate_type.as_deref(), Some("host"));
+        assert!(!stats.relayed);
+        assert_eq!(stats.round_trip_time_ms, None);
+        assert_eq!(stats.packet_loss, Some(1.0));
+
+        let mut stats = WebRtcSessionStats::default();
+        summarize_pairs(&mut stats, &[], &candidate_types);
+        assert_eq!(stats, WebRtcSessionStats::default());
+    }
+
+    #[tokio::test]
+    async fn test_is_lossy_follows_negotiated_reliability() {
+        use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+
+        let api = APIBuilder::new().build();
+        let pc = api.new_peer_connection(RTCConfiguration::default()).await.unwrap();
+
+        let reliable = pc.create_data_channel("terminal", None).await.unwrap();
+        assert!(!is_lossy(&reliable));
+
+        let realtime = pc
+            .create_data_channel(
+                "terminal-rt",
+                Some(RTCDataChannelInit {
+                    ordered: Some(false),
+                    max_retransmits: Some(0),
+                    ..Default::default()
+                }),
+            )
+            .await
+            .unwrap();
+        assert!(is_lossy(&realtime));
+
+        let _ = pc.close().await;
+    }
+
     fn create_test_manager() -> (WebRtcManager, mpsc::UnboundedReceiver<SignalingMessage>) {
         let (tx, rx) = mpsc::unbounded_channel();
         let manager =
@@ -1236,6 +2134,38 @@ mod tests {
         assert_eq!(manager.session_count().await, 0);
     }
 
+    #[tokio::test]
+    async fn test_describe_and_terminate_session() {
+        let (manager, mut rx) = create_test_manager();
+        manager
+            .create_session("operator-session".to_string(), Some("user-1".to_string()))
+            .await
+            .expect("Failed to create session");
+
+        let infos = manager.describe_sessions().await;
+        assert_eq!(infos.len(), 1);
+        assert_eq!(infos[0].session_id, "operator-session");
+        assert_eq!(infos[0].user_id.as_deref(), Some("user-1"));
+        assert!(infos[0].terminal_sessions.is_empty());
+
+        assert_eq!(
+            manager.terminate_session("operator-session", "closed_by_operator").await,
+            Some(vec![])
+        );
+        assert!(!manager.session_exists("operator-session").await);
+        assert_eq!(manager.terminate_session("operator-session", "closed_by_operator").await, None);
+
+        let mut announced = false;
+        while let Ok(msg) = rx.try_recv() {
+            let SignalingMessage::SyncData { payload } = msg else { continue };
+            if payload["type"] == "webrtc_session_ended" {
+                assert_eq!(payload["reason"], "closed_by_operator");
+                announced = true;
+            }
+        }
+        assert!(announced);
+    }
+
     #[tokio::test]
     async fn test_recreate_session_after_close() {
         let (manager, _rx) = create_test_manager();
@@ -1475,6 +2405,74 @@ mod tests {
         assert!(result.unwrap_err().contains("not found"));
     }
 
+    #[tokio::test]
+    async fn test_send_with_fallback_without_channel() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+
+        let msg = CocoonMessage::SilkSessionClosed {
+            session_id: "silk-1".to_string(),
+            reason: None,
+        };
+        let transport = send_with_fallback(None, &tx, &msg).await;
+        assert_eq!(transport, Transport::Signaling);
+
+        match rx.try_recv() {
+            Ok(SignalingMessage::SyncData { payload }) => {
+                assert_eq!(payload["type"], "silk_session_closed");
+                assert_eq!(payload["session_id"], "silk-1");
+            }
+            other => panic!("Expected SyncData, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_data_channel_missing() {
+        let (manager, _rx) = create_test_manager();
+
+        manager
+            .create_session("dc-lookup".to_string(), None)
+            .await
+            .expect("Failed to create session");
+
+        assert!(manager.open_data_channel("dc-lookup", "terminal").await.is_none());
+        assert!(manager.open_data_channel("nonexistent", "terminal").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_close_session_reaps_silk_sessions() {
+        let (manager, mut rx) = create_test_manager();
+
+        manager
+            .create_session("reap-test".to_string(), None)
+            .await
+            .expect("Failed to create session");
+
+        let silk = SilkSession::new(Some("/".to_string()), HashMap::new(), Some("/bin/sh".to_string()), false)
+            .expect("Failed to create silk session");
+        let silk_id = silk.id.to_string();
+        {
+            let sessions = manager.sessions.lock().await;
+            let state = sessions.get("reap-test").unwrap().silk_state.clone();
+            state.silk_sessions.lock().await.insert(silk_id.clone(), silk);
+        }
+
+        assert_eq!(manager.terminal_sessions("reap-test").await, vec![silk_id.clone()]);
+
+        manager.close_session("reap-test").await.expect("Failed to close session");
+        assert!(manager.terminal_sessions("reap-test").await.is_empty());
+
+        let mut closed = false;
+        while let Ok(msg) = rx.try_recv() {
+            if let SignalingMessage::SyncData { payload } = msg {
+                if payload.get("type").and_then(|v| v.as_str()) == Some("silk_session_closed") {
+                    assert_eq!(payload.get("session_id").and_then(|v| v.as_str()), Some(silk_id.as_str()));
+                    closed = true;
+                }
+            }
+        }
+        assert!(closed, "expected silk_session_closed for reaped session");
+    }
+
     #[tokio::test]
     async fn test_stress_many_sessions() {
         let (manager, _rx) = create_test_manager();
@@ -1,5 +1,13 @@
-use cocoon_core::{CocoonInfo, CocoonStatus, RuntimeManager, RuntimeType};
-use lib_console_output::{out_error, out_info, out_success, theme, KeyValue, Renderable};
+use cocoon_core::policy::SecurityPolicy;
+use cocoon_core::webrtc::TurnStatus;
+use cocoon_core::{
+    claim_container_name, docker_container_names, is_name_conflict, next_container_name,
+    validate_name_prefix, CocoonInfo, CocoonStatus, ContainerMappings, FindCocoonError,
+    ResourceLimits, RuntimeManager, RuntimeType,
+};
+use lib_console_output::{
+    out_error, out_info, out_success, out_warn, theme, Columns, Confirm, KeyValue, Renderable,
+};
 use lib_env_parse::{env_opt, env_vars};
 use once_cell::sync::OnceCell;
 
@@ -45,23 +53,20 @@ async fn start_cocoon_daemon(
         return Ok(());
     }
 
-    let config = if extra_env.is_empty() {
-        None
-    } else {
-        let exe = std::env::current_exe()
-            .map_err(|e| format!("Failed to get exe path: {}", e))?;
-        let mut cfg = lib_daemon_client::ServiceConfig::new(exe.display().to_string())
-            .args(["daemon", "run-service", "adi.cocoon"])
-            .env("RUST_LOG", "info,webrtc_ice=warn,webrtc_sctp=warn,webrtc_dtls=warn,webrtc_mdns=warn");
-        for &(key, value) in extra_env {
-            cfg = cfg.env(key, value);
-        }
-        Some(cfg)
-    };
+    // Always a config of our own: the daemon's default would leave out service mode
+    let exe = std::env::current_exe()
+        .map_err(|e| format!("Failed to get exe path: {}", e))?;
+    let mut config = lib_daemon_client::ServiceConfig::new(exe.display().to_string())
+        .args(["daemon", "run-service", "adi.cocoon"])
+        .env("RUST_LOG", "info,webrtc_ice=warn,webrtc_sctp=warn,webrtc_dtls=warn,webrtc_mdns=warn")
+        .env("COCOON_SERVICE_MODE", "true");
+    for &(key, value) in extra_env {
+        config = config.env(key, value);
+    }
 
     out_info!("Starting cocoon service...");
     client
-        .start_service("adi.cocoon", config)
+        .start_service("adi.cocoon", Some(config))
         .await
         .map_err(|e| format!("Failed to start cocoon service: {}", e))?;
 
@@ -98,6 +103,24 @@ use lib_plugin_prelude::*;
 pub struct NameArg {
     #[arg(position = 0)]
     pub name: Option<String>,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
+}
+
+#[derive(CliArgs)]
+pub struct StatusArgs {
+    #[arg(position = 0)]
+    pub names: Vec<String>,
+
+    #[arg(long)]
+    pub all: bool,
+
+    #[arg(long)]
+    pub json: bool,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
 }
 
 #[derive(CliArgs)]
@@ -110,6 +133,9 @@ pub struct LogsArgs {
 
     #[arg(long)]
     pub tail: Option<u32>,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
 }
 
 #[derive(CliArgs)]
@@ -119,6 +145,9 @@ pub struct RmArgs {
 
     #[arg(long)]
     pub force: bool,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
 }
 
 #[derive(CliArgs)]
@@ -140,6 +169,36 @@ pub struct CreateArgs {
 
     #[arg(long)]
     pub start: bool,
+
+    #[arg(long)]
+    pub memory: Option<String>,
+
+    #[arg(long)]
+    pub cpus: Option<String>,
+
+    #[arg(long = "pids-limit")]
+    pub pids_limit: Option<String>,
+
+    #[arg(long = "name-prefix")]
+    pub name_prefix: Option<String>,
+
+    #[arg(long)]
+    pub replace: bool,
+
+    #[arg(long)]
+    pub volume: Vec<String>,
+
+    #[arg(long)]
+    pub port: Vec<String>,
+}
+
+#[derive(CliArgs)]
+pub struct RunArgs {
+    #[arg(long)]
+    pub job: Option<String>,
+
+    #[arg(long)]
+    pub upload: bool,
 }
 
 #[derive(CliArgs)]
@@ -150,10 +209,19 @@ pub struct SetupArgs {
     pub url: Option<String>,
 }
 
+#[derive(CliArgs)]
+pub struct UninstallArgs {
+    #[arg(long)]
+    pub purge: bool,
+}
+
 #[derive(CliArgs)]
 pub struct CheckUpdateArgs {
     #[arg(position = 0)]
     pub name: Option<String>,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
 }
 
 #[derive(CliArgs)]
@@ -163,45 +231,141 @@ pub struct UpdateArgs {
 
     #[arg(long)]
     pub all: bool,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
 }
 
-fn generate_container_name() -> String {
-    let output = std::process::Command::new("docker")
-        .args(["ps", "-a", "--format", "{{.Names}}"])
-        .output();
+#[derive(CliArgs)]
+pub struct RollbackArgs {
+    #[arg(position = 0)]
+    pub name: Option<String>,
 
-    if let Ok(output) = output {
-        let names = String::from_utf8_lossy(&output.stdout);
-        let existing: Vec<&str> = names.lines().filter(|n| n.starts_with("cocoon-")).collect();
+    #[arg(long)]
+    pub runtime: Option<String>,
+}
 
-        if existing.is_empty() {
-            return "cocoon-worker".to_string();
-        }
+#[derive(CliArgs)]
+pub struct RekeyArgs {
+    #[arg(position = 0)]
+    pub name: Option<String>,
 
-        if !existing.contains(&"cocoon-worker") {
-            return "cocoon-worker".to_string();
-        }
+    #[arg(long)]
+    pub yes: bool,
 
-        let mut num = 2;
-        loop {
-            let candidate = format!("cocoon-worker-{}", num);
-            if !existing.contains(&candidate.as_str()) {
-                return candidate;
-            }
-            num += 1;
-        }
+    #[arg(long)]
+    pub runtime: Option<String>,
+}
+
+#[derive(CliArgs)]
+pub struct PolicyArgs {
+    #[arg(position = 0)]
+    pub name: Option<String>,
+
+    #[arg(long)]
+    pub full: bool,
+
+    #[arg(long)]
+    pub json: bool,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
+}
+
+#[derive(CliArgs)]
+pub struct MetricsArgs {
+    #[arg(position = 0)]
+    pub name: Option<String>,
+
+    #[arg(long)]
+    pub json: bool,
+
+    #[arg(long)]
+    pub runtime: Option<String>,
+}
+
+#[derive(CliArgs)]
+pub struct VersionArgs {
+    #[arg(long)]
+    pub verbose: bool,
+}
+
+#[derive(CliArgs)]
+pub struct WebrtcArgs {
+    #[arg(position = 0)]
+    pub action: Option<String>,
+
+    #[arg(long)]
+    pub timeout: Option<u64>,
+}
+
+fn styled_status(info: &CocoonInfo) -> String {
+    let status_str = format!("{} {}", info.status_icon(), info.status);
+    match &info.status {
+        CocoonStatus::Running => theme::success(&status_str).to_string(),
+        CocoonStatus::Stopped => theme::muted(&status_str).to_string(),
+        CocoonStatus::Restarting => theme::warning(&status_str).to_string(),
+        CocoonStatus::Unknown(_) => theme::error(&status_str).to_string(),
     }
+}
+
+/// Error `lookup_status` gives for a name no runtime knows
+const NOT_FOUND: &str = "not found";
+
+/// Resolve a cocoon by name and fetch its status, keeping lookup errors per name
+fn lookup_status(
+    manager: &RuntimeManager,
+    name: &str,
+    runtime: Option<RuntimeType>,
+) -> std::result::Result<CocoonInfo, String> {
+    match manager.find_cocoon(name, runtime) {
+        Ok((_, runtime_type)) => manager.get_runtime(runtime_type).status(name),
+        Err(FindCocoonError::NotFound { .. }) => Err(NOT_FOUND.to_string()),
+        Err(e) => Err(e.into()),
+    }
+}
 
-    "cocoon-worker".to_string()
+/// Parse `--runtime` for commands that act on an existing cocoon
+fn runtime_filter(runtime: Option<&str>) -> std::result::Result<Option<RuntimeType>, String> {
+    runtime
+        .map(|r| {
+            RuntimeType::from_str(r)
+                .ok_or_else(|| format!("Invalid runtime '{}'. Use 'docker' or 'machine'.", r))
+        })
+        .transpose()
+}
+
+fn status_json(name: &str, result: &std::result::Result<CocoonInfo, String>) -> serde_json::Value {
+    match result {
+        Ok(info) => serde_json::json!({
+            "name": info.name,
+            "found": true,
+            "runtime": info.runtime.to_string(),
+            "status": info.status.to_string(),
+            "image": info.image,
+            "created": info.created,
+        }),
+        Err(e) => serde_json::json!({
+            "name": name,
+            "found": false,
+            "error": e,
+        }),
+    }
 }
 
+/// Attempts at a generated name before giving up on concurrent creates
+const NAME_CONFLICT_RETRIES: usize = 5;
+
 fn create_docker_cocoon(
     name: &str,
     signaling_url: &str,
     setup_token: Option<&str>,
     cocoon_secret: Option<&str>,
+    mappings: &ContainerMappings,
+    limits: &ResourceLimits,
 ) -> std::result::Result<String, String> {
-    let mut docker_cmd = std::process::Command::new("docker");
+    let engine = cocoon_core::container_engine::current();
+    let mut docker_cmd = engine.command();
     docker_cmd
         .arg("run")
         .arg("-d")
@@ -210,21 +374,23 @@ fn create_docker_cocoon(
         .arg("--name")
         .arg(name);
 
-    if let Ok(url) = url::Url::parse(signaling_url) {
-        if let Some(host) = url.host_str() {
-            if host.ends_with(".local") {
-                docker_cmd
-                    .arg("--add-host")
-                    .arg(format!("{}:host-gateway", host));
-            }
-        }
-    }
+    let host_aliases = cocoon_core::host_aliases::from_env()?;
+    docker_cmd.args(host_aliases.docker_args(Some(signaling_url), engine));
 
     docker_cmd
         .arg("-e")
         .arg(format!("SIGNALING_SERVER_URL={}", signaling_url))
-        .arg("-v")
-        .arg(format!("{}:/cocoon", name));
+        .args(mappings.volume_args(name))
+        .args(mappings.port_args());
+
+    // Kept in the container so updates can recreate it with the same mappings
+    if !host_aliases.is_empty() {
+        docker_cmd.arg("-e").arg(format!(
+            "{}={}",
+            cocoon_core::host_aliases::ENV_VAR,
+            host_aliases.env_value()
+        ));
+    }
 
     if let Some(secret) = cocoon_secret {
         docker_cmd
@@ -238,6 +404,8 @@ fn create_docker_cocoon(
             .arg(format!("COCOON_SETUP_TOKEN={}", token));
     }
 
+    docker_cmd.args(limits.docker_args());
+
     docker_cmd.arg("docker-registry.the-ihor.com/cocoon:latest");
 
     out_info!("Creating Docker cocoon '{}'...", name);
@@ -254,11 +422,11 @@ fn create_docker_cocoon(
         }
         Ok(output) => {
             let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(format!("Docker failed: {}", stderr))
+            Err(format!("{} failed: {}", engine, stderr))
         }
         Err(e) => Err(format!(
-            "Failed to start Docker: {}. Make sure Docker is installed and running.",
-            e
+            "Failed to run {}: {}. Make sure it is installed and running.",
+            engine, e
         )),
     }
 }
@@ -272,7 +440,7 @@ USAGE:
 COMMANDS:
     (no args)           Interactive mode - select actions from menu
     list, ls            List all cocoons (Docker and Machine)
-    status <name>       Show cocoon status
+    status <name>...    Show cocoon status (--all for every cocoon, --json for JSON)
     start <name>        Start a stopped cocoon
     stop <name>         Stop a running cocoon
     restart <name>      Restart a cocoon
@@ -280,26 +448,70 @@ COMMANDS:
     rm <name> [--force] Remove a cocoon
     create              Create a new cocoon (interactive)
     run                 Run cocoon natively in foreground
+    run --job SCRIPT    Register, run SCRIPT once, deregister and exit with its code
     setup [--port PORT] Start pairing server for browser setup (default: 14730)
+    uninstall [--purge] Remove the native systemd/launchd service
     check-update [name] Check for available updates
     update [name]       Update cocoon to latest version
-    version             Show current version
+    rollback <name>     Go back to the binary the last update replaced (machine only)
+    rekey <name>        New secret and device ID; invalidates existing claims
+    policy <name>       Show a cocoon's effective security policy
+    metrics <name>      Show a cocoon's latest metrics snapshot (--json for JSON)
+    webrtc test         Gather ICE candidates and check STUN/TURN setup
+    version [--verbose] Show version (--verbose: commit, build time, target, path, features)
     help                Show this help message
 
+TARGET OPTIONS (commands taking <name>):
+    --runtime TYPE      Only look in this runtime (docker or machine); needed
+                        when the same name exists in both
+
 CREATE OPTIONS:
     --runtime TYPE      Runtime: docker or machine
-    --name NAME         Container name (docker only)
+    --name NAME         Container name (docker only); refuses an existing container
+    --name-prefix P     Generate P-1, P-2, ... instead of cocoon-worker[-N] (docker
+                        only); P must start with cocoon-
+    --replace           Recreate a stopped container that already has --name
     --url URL           Signaling server URL
     --token TOKEN       Setup token for auto-claim
     --secret SECRET     Pre-generated secret
     --start             Start service after create (machine only)
+    --memory SIZE       Memory limit, e.g. 512m or 2g (docker only)
+    --cpus N            CPU limit, e.g. 1.5 (docker only)
+    --pids-limit N      Max processes in the container (docker only)
+    --volume H:C[:ro]   Mount host path or named volume H at C (docker only,
+                        repeatable); mounting at /cocoon replaces the data volume
+    --port H:C[/udp]    Publish container port C on host port H (docker only,
+                        repeatable)
+
+STATUS OPTIONS:
+    --all               Show status for all cocoons
+    --json              Print status as JSON
+
+RUN OPTIONS:
+    --job SCRIPT        One-shot job mode (or set COCOON_JOB_SCRIPT)
+    --upload            Include output files in the job report (or COCOON_JOB_UPLOAD=true)
+
+POLICY OPTIONS:
+    --full              List proxied services instead of just counting them
+    --json              Print the policy as JSON
+
+WEBRTC TEST OPTIONS:
+    --timeout SECS      How long to wait for candidate gathering (default: 10)
 
 UPDATE OPTIONS:
     --all, -a           Update all cocoons
 
+REKEY OPTIONS:
+    --yes               Skip the confirmation prompt
+
+UNINSTALL OPTIONS:
+    --purge             Also delete the saved secret (~/.config/cocoon/secret)
+
 RUNTIMES:
-    docker      Docker containers (prefix: cocoon-*)
-                Update: Pulls latest image and recreates container
+    docker      Docker or Podman containers (prefix: cocoon-*)
+                Update: Pulls latest image and recreates container; the old
+                container is kept as a backup and restored if the new one
+                fails to become healthy
     machine     Native systemd/launchd service
                 Update: Downloads latest binary and restarts service
 
@@ -318,6 +530,15 @@ EXAMPLES:
     # Create a Docker cocoon
     adi cocoon create --runtime docker --name my-worker --url wss://example.com/ws
 
+    # Scripted fleet: cocoon-ci-1, cocoon-ci-2, ...
+    adi cocoon create --runtime docker --name-prefix cocoon-ci --url wss://example.com/ws
+
+    # Create a Docker cocoon with resource limits
+    adi cocoon create --runtime docker --memory 2g --cpus 1.5 --pids-limit 512
+
+    # Mount a project and expose a dev server running inside the cocoon
+    adi cocoon create --runtime docker --volume ./src:/workspace --port 3000:3000
+
     # Create a Machine (native service) cocoon
     adi cocoon create --runtime machine --url wss://example.com/ws --start
 
@@ -333,10 +554,29 @@ EXAMPLES:
     # Update all cocoons
     adi cocoon update --all
 
+    # Ephemeral CI worker: run one job and exit with its status
+    adi cocoon run --job ./ci.sh --upload
+
+    # Recover from a suspected secret leak (re-claim afterwards)
+    adi cocoon rekey cocoon-worker
+
+    # Audit what a cocoon allows
+    adi cocoon policy cocoon-worker
+
+    # Commands run, open sessions and proxy traffic
+    adi cocoon metrics cocoon-worker
+
+    # Remove the native service and its secret
+    sudo adi cocoon uninstall --purge
+
+    # Troubleshoot WebRTC (uses WEBRTC_ICE_SERVERS / WEBRTC_TURN_* from this shell)
+    adi cocoon webrtc test
+
 ENVIRONMENT VARIABLES:
     SIGNALING_SERVER_URL    WebSocket URL (default: ws://localhost:8080/ws)
     COCOON_SECRET           Pre-generated secret for persistent device ID
     COCOON_SETUP_TOKEN      Setup token for auto-claim
+    COCOON_CONTAINER_ENGINE docker or podman (default: first found on PATH)
 "#
 }
 
@@ -390,8 +630,14 @@ impl CliCommands for CocoonPlugin {
             Self::__sdk_cmd_meta_create(),
             Self::__sdk_cmd_meta_run_native(),
             Self::__sdk_cmd_meta_setup_pairing(),
+            Self::__sdk_cmd_meta_uninstall(),
             Self::__sdk_cmd_meta_check_update(),
             Self::__sdk_cmd_meta_update(),
+            Self::__sdk_cmd_meta_rollback(),
+            Self::__sdk_cmd_meta_rekey(),
+            Self::__sdk_cmd_meta_policy(),
+            Self::__sdk_cmd_meta_metrics(),
+            Self::__sdk_cmd_meta_webrtc(),
             Self::__sdk_cmd_meta_version(),
         ]
     }
@@ -408,10 +654,16 @@ impl CliCommands for CocoonPlugin {
             Some("create") | Some("new") => self.__sdk_cmd_handler_create(ctx).await,
             Some("run") => self.__sdk_cmd_handler_run_native(ctx).await,
             Some("setup") => self.__sdk_cmd_handler_setup_pairing(ctx).await,
+            Some("uninstall") => self.__sdk_cmd_handler_uninstall(ctx).await,
             Some("check-update") | Some("check") => self.__sdk_cmd_handler_check_update(ctx).await,
             Some("update") | Some("upgrade") | Some("self-update") => {
                 self.__sdk_cmd_handler_update(ctx).await
             }
+            Some("rollback") => self.__sdk_cmd_handler_rollback(ctx).await,
+            Some("rekey") => self.__sdk_cmd_handler_rekey(ctx).await,
+            Some("policy") => self.__sdk_cmd_handler_policy(ctx).await,
+            Some("metrics") => self.__sdk_cmd_handler_metrics(ctx).await,
+            Some("webrtc") => self.__sdk_cmd_handler_webrtc(ctx).await,
             Some("version") | Some("-v") | Some("-V") | Some("--version") => {
                 self.__sdk_cmd_handler_version(ctx).await
             }
@@ -442,59 +694,104 @@ impl CocoonPlugin {
     }
 
     #[command(name = "status", description = "Show cocoon status")]
-    async fn status(&self, args: NameArg) -> CmdResult {
+    async fn status(&self, args: StatusArgs) -> CmdResult {
         let manager = RuntimeManager::new();
-        if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
-                    let runtime = manager.get_runtime(runtime_type);
-                    match runtime.status(&name) {
-                        Ok(info) => {
-                            let status_str = format!("{} {}", info.status_icon(), info.status);
-                            let styled_status = match &info.status {
-                                CocoonStatus::Running => theme::success(&status_str).to_string(),
-                                CocoonStatus::Stopped => theme::muted(&status_str).to_string(),
-                                CocoonStatus::Restarting => theme::warning(&status_str).to_string(),
-                                CocoonStatus::Unknown(_) => theme::error(&status_str).to_string(),
-                            };
-                            let mut kv = KeyValue::new()
-                                .entry("Cocoon", &info.name)
-                                .entry("Runtime", info.runtime.to_string())
-                                .entry("Status", styled_status);
-                            if let Some(image) = &info.image {
-                                kv = kv.entry("Image", image);
-                            }
-                            if let Some(created) = &info.created {
-                                kv = kv.entry("Created", created);
-                            }
-                            kv.print();
-                            Ok(format!("Status: {}", info.status))
-                        }
-                        Err(e) => Err(e),
+
+        let names: Vec<String> = if args.all {
+            manager.list_all()?.into_iter().map(|info| info.name).collect()
+        } else {
+            args.names
+        };
+
+        if names.is_empty() {
+            if args.all {
+                out_info!("No cocoons found. Create one with: adi cocoon create");
+                return Ok("No cocoons found".to_string());
+            }
+            cocoon_core::run_interactive(&manager).map_err(|e| e)?;
+            return Ok("Done".to_string());
+        }
+
+        let runtime = runtime_filter(args.runtime.as_deref())?;
+        let results: Vec<(String, std::result::Result<CocoonInfo, String>)> = names
+            .into_iter()
+            .map(|name| {
+                let result = lookup_status(&manager, &name, runtime);
+                (name, result)
+            })
+            .collect();
+
+        if args.json {
+            let entries: Vec<serde_json::Value> = results
+                .iter()
+                .map(|(name, result)| status_json(name, result))
+                .collect();
+            return serde_json::to_string_pretty(&entries).map_err(|e| e.to_string());
+        }
+
+        if let [(name, result)] = results.as_slice() {
+            if !args.all {
+                let info = result.as_ref().map_err(|e| {
+                    if e == NOT_FOUND {
+                        format!("Cocoon '{}' not found", name)
+                    } else {
+                        e.clone()
                     }
+                })?;
+                let mut kv = KeyValue::new()
+                    .entry("Cocoon", &info.name)
+                    .entry("Runtime", info.runtime.label())
+                    .entry("Status", styled_status(info));
+                if let Some(image) = &info.image {
+                    kv = kv.entry("Image", image);
+                }
+                if let Some(created) = &info.created {
+                    kv = kv.entry("Created", created);
                 }
-                None => Err(format!("Cocoon '{}' not found", name)),
+                kv.print();
+                return Ok(format!("Status: {}", info.status));
             }
-        } else {
-            cocoon_core::run_interactive(&manager).map_err(|e| e)?;
-            Ok("Done".to_string())
         }
+
+        let missing = results.iter().filter(|(_, r)| matches!(r, Err(e) if e == NOT_FOUND)).count();
+        let failed = results.iter().filter(|(_, r)| r.is_err()).count() - missing;
+        let cols = results.iter().fold(
+            Columns::new().header(["NAME", "RUNTIME", "STATUS", "IMAGE"]),
+            |cols, (name, result)| match result {
+                Ok(info) => cols.row([
+                    info.name.clone(),
+                    info.runtime.label(),
+                    styled_status(info),
+                    info.image.clone().unwrap_or_else(|| "-".to_string()),
+                ]),
+                Err(e) => cols.row([
+                    name.clone(),
+                    "-".to_string(),
+                    theme::error(e).to_string(),
+                    "-".to_string(),
+                ]),
+            },
+        );
+        cols.print();
+
+        let mut summary = format!("{} cocoon(s) checked, {} not found", results.len(), missing);
+        if failed > 0 {
+            summary.push_str(&format!(", {} failed", failed));
+        }
+        Ok(summary)
     }
 
     #[command(name = "start", description = "Start a stopped cocoon")]
     async fn start_cocoon(&self, args: NameArg) -> CmdResult {
         let manager = RuntimeManager::new();
         if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
+            match manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?) {
+                Ok((_, runtime_type)) => {
                     let runtime = manager.get_runtime(runtime_type);
                     out_info!("Starting '{}'...", name);
                     runtime.start(&name)
                 }
-                None => Err(format!(
-                    "Cocoon '{}' not found. Use 'adi cocoon list' to see available cocoons.",
-                    name
-                )),
+                Err(e) => Err(e.into()),
             }
         } else {
             cocoon_core::run_interactive(&manager).map_err(|e| e)?;
@@ -506,13 +803,13 @@ impl CocoonPlugin {
     async fn stop(&self, args: NameArg) -> CmdResult {
         let manager = RuntimeManager::new();
         if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
+            match manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?) {
+                Ok((_, runtime_type)) => {
                     let runtime = manager.get_runtime(runtime_type);
                     out_info!("Stopping '{}'...", name);
                     runtime.stop(&name)
                 }
-                None => Err(format!("Cocoon '{}' not found", name)),
+                Err(e) => Err(e.into()),
             }
         } else {
             cocoon_core::run_interactive(&manager).map_err(|e| e)?;
@@ -524,13 +821,13 @@ impl CocoonPlugin {
     async fn restart(&self, args: NameArg) -> CmdResult {
         let manager = RuntimeManager::new();
         if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
+            match manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?) {
+                Ok((_, runtime_type)) => {
                     let runtime = manager.get_runtime(runtime_type);
                     out_info!("Restarting '{}'...", name);
                     runtime.restart(&name)
                 }
-                None => Err(format!("Cocoon '{}' not found", name)),
+                Err(e) => Err(e.into()),
             }
         } else {
             cocoon_core::run_interactive(&manager).map_err(|e| e)?;
@@ -542,13 +839,13 @@ impl CocoonPlugin {
     async fn logs(&self, args: LogsArgs) -> CmdResult {
         let manager = RuntimeManager::new();
         if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
+            match manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?) {
+                Ok((_, runtime_type)) => {
                     let runtime = manager.get_runtime(runtime_type);
                     runtime.logs(&name, args.follow, args.tail).map_err(|e| e)?;
                     Ok("Logs displayed".to_string())
                 }
-                None => Err(format!("Cocoon '{}' not found", name)),
+                Err(e) => Err(e.into()),
             }
         } else {
             cocoon_core::run_interactive(&manager).map_err(|e| e)?;
@@ -560,13 +857,13 @@ impl CocoonPlugin {
     async fn rm(&self, args: RmArgs) -> CmdResult {
         let manager = RuntimeManager::new();
         if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
+            match manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?) {
+                Ok((_, runtime_type)) => {
                     let runtime = manager.get_runtime(runtime_type);
                     out_info!("Removing '{}'...", name);
                     runtime.remove(&name, args.force)
                 }
-                None => Err(format!("Cocoon '{}' not found", name)),
+                Err(e) => Err(e.into()),
             }
         } else {
             cocoon_core::run_interactive(&manager).map_err(|e| e)?;
@@ -586,22 +883,68 @@ impl CocoonPlugin {
             })?;
             match runtime_type {
                 RuntimeType::Docker => {
-                    let name = args.name.unwrap_or_else(generate_container_name);
+                    let limits = ResourceLimits::parse(
+                        args.memory.as_deref(),
+                        args.cpus.as_deref(),
+                        args.pids_limit.as_deref(),
+                    )?;
+                    let mappings = ContainerMappings::parse(&args.volume, &args.port)?;
+                    let name_prefix = match args.name_prefix.as_deref() {
+                        Some(_) if args.name.is_some() => {
+                            return Err("Use either --name or --name-prefix, not both".to_string())
+                        }
+                        Some(prefix) => Some(validate_name_prefix(prefix)?),
+                        None => None,
+                    };
+                    if let Some(name) = &args.name {
+                        claim_container_name(name, args.replace)?;
+                    }
                     let signaling_url = args
                         .url
                         .or_else(|| env_opt(EnvVar::SignalingServerUrl.as_str()))
                         .unwrap_or_else(|| "ws://localhost:8080/ws".to_string());
+                    let signaling_url =
+                        cocoon_core::signaling_url::normalize_signaling_url(&signaling_url)?.to_string();
                     let setup_token = args
                         .token
                         .or_else(|| env_opt(EnvVar::CocoonSetupToken.as_str()));
                     let cocoon_secret = args
                         .secret
                         .or_else(|| env_opt(EnvVar::CocoonSecret.as_str()));
+
+                    let Some(name) = args.name else {
+                        // Another create may grab the same generated name between listing and
+                        // `docker run`; Docker refuses the duplicate, so move on to the next one
+                        let mut taken = docker_container_names();
+                        for _ in 0..NAME_CONFLICT_RETRIES {
+                            let name = next_container_name(name_prefix.as_deref(), &taken);
+                            match create_docker_cocoon(
+                                &name,
+                                &signaling_url,
+                                setup_token.as_deref(),
+                                cocoon_secret.as_deref(),
+                                &mappings,
+                                &limits,
+                            ) {
+                                Err(e) if is_name_conflict(&e) => {
+                                    out_warn!("Name '{}' was taken concurrently, trying the next one", name);
+                                    taken.push(name);
+                                }
+                                result => return result,
+                            }
+                        }
+                        return Err(format!(
+                            "Could not find a free container name after {} attempts",
+                            NAME_CONFLICT_RETRIES
+                        ));
+                    };
                     create_docker_cocoon(
                         &name,
                         &signaling_url,
                         setup_token.as_deref(),
                         cocoon_secret.as_deref(),
+                        &mappings,
+                        &limits,
                     )
                 }
                 RuntimeType::Machine => {
@@ -617,7 +960,25 @@ impl CocoonPlugin {
     }
 
     #[command(name = "run", description = "Run cocoon natively in foreground")]
-    async fn run_native(&self) -> CmdResult {
+    async fn run_native(&self, args: RunArgs) -> CmdResult {
+        let job = match args.job {
+            Some(script) => Some(cocoon_core::Job {
+                script,
+                upload_outputs: args.upload,
+            }),
+            None => cocoon_core::Job::from_env(),
+        };
+
+        if let Some(job) = job {
+            let exit_code = run_with_runtime(async move {
+                cocoon_core::run_job(job).await.map_err(|e| e.to_string())
+            })?;
+            if exit_code != 0 {
+                return Err(format!("Job failed with exit code {}", exit_code));
+            }
+            return Ok("Job finished".to_string());
+        }
+
         run_with_runtime(async {
             if let Err(e) = cocoon_core::run().await {
                 out_error!("Cocoon error: {}", e);
@@ -635,12 +996,19 @@ impl CocoonPlugin {
         })
     }
 
+    #[command(name = "uninstall", description = "Remove the native cocoon service")]
+    async fn uninstall(&self, args: UninstallArgs) -> CmdResult {
+        let msg = cocoon_core::service_uninstall(args.purge)?;
+        out_success!("{}", msg);
+        Ok(msg)
+    }
+
     #[command(name = "check-update", description = "Check for available updates")]
     async fn check_update(&self, args: CheckUpdateArgs) -> CmdResult {
         let manager = RuntimeManager::new();
         if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
+            match manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?) {
+                Ok((_, runtime_type)) => {
                     let runtime = manager.get_runtime(runtime_type);
                     match runtime.check_update(&name) {
                         Ok(msg) => {
@@ -650,10 +1018,7 @@ impl CocoonPlugin {
                         Err(e) => Err(e),
                     }
                 }
-                None => Err(format!(
-                    "Cocoon '{}' not found. Use 'adi cocoon list' to see available cocoons.",
-                    name
-                )),
+                Err(e) => Err(e.into()),
             }
         } else {
             match manager.list_all() {
@@ -688,8 +1053,8 @@ impl CocoonPlugin {
     async fn update(&self, args: UpdateArgs) -> CmdResult {
         let manager = RuntimeManager::new();
         if let Some(name) = args.name {
-            match manager.find_cocoon(&name) {
-                Some((_, runtime_type)) => {
+            match manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?) {
+                Ok((_, runtime_type)) => {
                     let runtime = manager.get_runtime(runtime_type);
                     match runtime.update(&name) {
                         Ok(msg) => {
@@ -699,10 +1064,7 @@ impl CocoonPlugin {
                         Err(e) => Err(e),
                     }
                 }
-                None => Err(format!(
-                    "Cocoon '{}' not found. Use 'adi cocoon list' to see available cocoons.",
-                    name
-                )),
+                Err(e) => Err(e.into()),
             }
         } else if args.all {
             match manager.list_all() {
@@ -740,24 +1102,286 @@ impl CocoonPlugin {
         }
     }
 
+    #[command(name = "rollback", description = "Go back to the binary the last update replaced")]
+    async fn rollback(&self, args: RollbackArgs) -> CmdResult {
+        let manager = RuntimeManager::new();
+        let name = args
+            .name
+            .ok_or_else(|| "Usage: adi cocoon rollback <name>".to_string())?;
+        let (_, runtime_type) = manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?)?;
+        let msg = manager.get_runtime(runtime_type).rollback(&name)?;
+        out_success!("{}", msg);
+        Ok(msg)
+    }
+
+    #[command(name = "rekey", description = "Give a cocoon a new secret and device ID")]
+    async fn rekey(&self, args: RekeyArgs) -> CmdResult {
+        let manager = RuntimeManager::new();
+        let name = args
+            .name
+            .ok_or_else(|| "Usage: adi cocoon rekey <name> [--yes]".to_string())?;
+        let (_, runtime_type) = manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?)?;
+
+        out_warn!(
+            "Rekeying '{}' replaces its secret and device ID. Every existing claim on it stops working and it must be claimed again.",
+            name
+        );
+        let confirmed = args.yes
+            || Confirm::new(format!("Rekey cocoon '{}'?", name))
+                .default(false)
+                .run()
+                .unwrap_or(false);
+        if !confirmed {
+            out_warn!("Cancelled");
+            return Ok("Cancelled".to_string());
+        }
+
+        let msg = manager.get_runtime(runtime_type).rekey(&name)?;
+        out_success!("{}", msg);
+        Ok(msg)
+    }
+
+    #[command(name = "policy", description = "Show a cocoon's effective security policy")]
+    async fn policy(&self, args: PolicyArgs) -> CmdResult {
+        let manager = RuntimeManager::new();
+        let name = args
+            .name
+            .ok_or_else(|| "Usage: adi cocoon policy <name> [--full] [--json]".to_string())?;
+        let (_, runtime_type) = manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?)?;
+        let env = manager.get_runtime(runtime_type).env(&name)?;
+        let policy = SecurityPolicy::from_env(&env, args.full);
+
+        if args.json {
+            return serde_json::to_string_pretty(&policy).map_err(|e| e.to_string());
+        }
+
+        let limit_or_none = |limit: Option<String>| {
+            limit.unwrap_or_else(|| theme::muted("unlimited").to_string())
+        };
+        let mut kv = KeyValue::new()
+            .entry("Cocoon", &name)
+            .entry("Commands", policy.command_types.join(", "))
+            .entry("Protocols", policy.protocols.join(", "))
+            .entry("Services", policy.service_count.to_string());
+        if let Some(services) = &policy.services {
+            kv = kv.entry("Service list", services.join(", "));
+        }
+        kv.entry(
+            "Setup token",
+            if policy.setup_token { "configured" } else { "none" },
+        )
+        .entry(
+            "Persistence",
+            if policy.require_persistence { "required" } else { "optional" },
+        )
+        .entry(
+            "Session lifetime",
+            limit_or_none(policy.max_session_secs.map(|s| format!("{}s", s))),
+        )
+        .entry(
+            "Result size",
+            limit_or_none(policy.max_result_bytes.map(|b| format!("{} bytes", b))),
+        )
+        .entry(
+            "Exec wrapper",
+            policy.exec_wrapper.clone().unwrap_or_else(|| "none".to_string()),
+        )
+        .entry(
+            "Exec rate",
+            limit_or_none(policy.exec_rate.as_ref().map(|r| format!("{} per second (rate:burst)", r))),
+        )
+        .entry(
+            "Allowed commands",
+            policy.cmd_allowlist.clone().unwrap_or_else(|| "any".to_string()),
+        )
+        .entry(
+            "Denied commands",
+            policy.cmd_denylist.clone().unwrap_or_else(|| "none".to_string()),
+        )
+        .print();
+
+        Ok(format!(
+            "{} command types, {} services",
+            policy.command_types.len(),
+            policy.service_count
+        ))
+    }
+
+    #[command(name = "metrics", description = "Show a cocoon's latest metrics snapshot")]
+    async fn metrics(&self, args: MetricsArgs) -> CmdResult {
+        let manager = RuntimeManager::new();
+        let name = args
+            .name
+            .ok_or_else(|| "Usage: adi cocoon metrics <name> [--json]".to_string())?;
+        let (_, runtime_type) = manager.find_cocoon(&name, runtime_filter(args.runtime.as_deref())?)?;
+        let metrics = manager.get_runtime(runtime_type).metrics(&name)?;
+
+        if args.json {
+            return serde_json::to_string_pretty(&metrics).map_err(|e| e.to_string());
+        }
+
+        let counters = &metrics.counters;
+        let gauges = &metrics.gauges;
+        let mut kv = KeyValue::new()
+            .entry("Cocoon", &name)
+            .entry("Taken at", &metrics.timestamp)
+            .entry("Uptime", format!("{}s", metrics.uptime_secs))
+            .entry("Version", &metrics.version)
+            .entry(
+                "Commands",
+                format!("{} run, {} failed", counters.commands_executed, counters.commands_failed),
+            )
+            .entry("PTYs opened", counters.pty_sessions_opened.to_string())
+            .entry("WebRTC sessions opened", counters.webrtc_sessions_opened.to_string())
+            .entry("Silk commands", counters.silk_commands.to_string())
+            .entry(
+                "Open sessions",
+                format!(
+                    "{} PTY, {} Silk, {} WebRTC",
+                    gauges.pty_sessions, gauges.silk_sessions, gauges.webrtc_sessions
+                ),
+            )
+            .entry(
+                "Signaling bytes",
+                format!(
+                    "{} sent, {} received",
+                    counters.signaling_bytes_sent, counters.signaling_bytes_received
+                ),
+            );
+        for service in &metrics.proxy {
+            kv = kv.entry(
+                "Proxy",
+                format!(
+                    "{}: {} requests, {} errors, {} bytes out, {} bytes in",
+                    service.service,
+                    service.requests,
+                    service.errors,
+                    service.request_bytes,
+                    service.response_bytes
+                ),
+            );
+        }
+        kv.print();
+
+        Ok(format!("Metrics as of {}", metrics.timestamp))
+    }
+
+    #[command(name = "webrtc", description = "WebRTC diagnostics (webrtc test)")]
+    async fn webrtc(&self, args: WebrtcArgs) -> CmdResult {
+        match args.action.as_deref() {
+            Some("test") => {}
+            _ => return Err("Usage: adi cocoon webrtc test [--timeout SECS]".to_string()),
+        }
+        let timeout = std::time::Duration::from_secs(args.timeout.unwrap_or(10));
+
+        out_info!("Gathering ICE candidates (timeout {}s)...", timeout.as_secs());
+        let report = run_with_runtime(async move {
+            cocoon_core::webrtc::test_ice_connectivity(timeout).await
+        })?;
+
+        let list_or_none = |urls: &[String]| {
+            if urls.is_empty() {
+                theme::muted("none").to_string()
+            } else {
+                urls.join(", ")
+            }
+        };
+        let count = |n: usize| {
+            if n > 0 {
+                theme::success(&n.to_string()).to_string()
+            } else {
+                theme::error("0").to_string()
+            }
+        };
+
+        KeyValue::new()
+            .entry("STUN", list_or_none(&report.stun_urls))
+            .entry("TURN", list_or_none(&report.turn_urls))
+            .entry("Host", count(report.host))
+            .entry("Srflx", count(report.srflx))
+            .entry("Relay", count(report.relay))
+            .entry(
+                "Gathering",
+                if report.complete {
+                    theme::success("complete").to_string()
+                } else {
+                    theme::warning("timed out").to_string()
+                },
+            )
+            .print();
+
+        let turn = match report.turn_status() {
+            TurnStatus::NotConfigured => {
+                out_info!("TURN credentials: not checked (no TURN server configured)");
+                "not configured"
+            }
+            TurnStatus::MissingCredentials => {
+                out_error!(
+                    "TURN credentials: missing - set WEBRTC_TURN_USERNAME and WEBRTC_TURN_CREDENTIAL"
+                );
+                "missing credentials"
+            }
+            TurnStatus::Valid => {
+                out_success!("TURN credentials: valid (relay candidate allocated)");
+                "valid"
+            }
+            TurnStatus::NoRelay => {
+                out_error!(
+                    "TURN credentials: no relay candidate gathered - credentials rejected or TURN server unreachable"
+                );
+                "no relay"
+            }
+        };
+
+        if report.host == 0 {
+            out_warn!("No host candidates: no usable network interface");
+        }
+        if report.srflx == 0 {
+            out_warn!("No server-reflexive candidates: STUN unreachable or blocked");
+        }
+
+        Ok(format!(
+            "host={} srflx={} relay={} turn={}",
+            report.host, report.srflx, report.relay, turn
+        ))
+    }
+
     #[command(name = "version", description = "Show current version")]
-    async fn version(&self) -> CmdResult {
+    async fn version(&self, args: VersionArgs) -> CmdResult {
         let version = env!("CARGO_PKG_VERSION");
-        out_info!("cocoon {}", version);
-        Ok(format!("cocoon {}", version))
+        if !args.verbose {
+            out_info!("cocoon {}", version);
+            return Ok(format!("cocoon {}", version));
+        }
+
+        let info = cocoon_core::build_info::BuildInfo::current();
+        let features = if info.features.is_empty() {
+            theme::muted("none").to_string()
+        } else {
+            info.features.join(", ")
+        };
+        KeyValue::new()
+            .entry("Version", version)
+            .entry("Commit", &info.git_commit)
+            .entry("Built", info.built_at())
+            .entry("Target", &info.target)
+            .entry(
+                "Executable",
+                info.executable.clone().unwrap_or_else(|| theme::muted("unknown").to_string()),
+            )
+            .entry("Features", features)
+            .print();
+        Ok(format!("cocoon {} ({})", version, info.git_commit))
     }
 }
 
 #[daemon_service]
 impl CocoonPlugin {
     async fn start(&self, _ctx: DaemonContext) -> Result<()> {
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| "cocoon=info".into()),
-            )
-            .with_ansi(false)
-            .try_init();
+        cocoon_core::log_format::init(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "cocoon=info".into()),
+            false,
+        );
 
         let (tx, rx) = tokio::sync::oneshot::channel::<std::result::Result<(), String>>();
 
@@ -789,9 +1413,11 @@ impl CocoonPlugin {
     }
 }
 
-fn run_with_runtime<F: std::future::Future<Output = CmdResult> + Send + 'static>(
-    fut: F,
-) -> CmdResult {
+fn run_with_runtime<T, F>(fut: F) -> std::result::Result<T, String>
+where
+    T: Send + 'static,
+    F: std::future::Future<Output = std::result::Result<T, String>> + Send + 'static,
+{
     std::thread::spawn(move || {
         tokio::runtime::Runtime::new()
             .map_err(|e| format!("Failed to create runtime: {e}"))?
@@ -178,6 +178,17 @@ generate_secret() {
     fi
 }
 
+# Check the signaling URL before installing anything; the cocoon does the
+# full validation (and adds a default /ws path) when it starts
+check_signaling_url() {
+    case "$1" in
+        ws://?*|wss://?*|unix:///?*) ;;
+        http://*) error "SIGNALING_SERVER_URL '$1' uses http://, did you mean ws://${1#http://}?" ;;
+        https://*) error "SIGNALING_SERVER_URL '$1' uses https://, did you mean wss://${1#https://}?" ;;
+        *) error "SIGNALING_SERVER_URL '$1' must start with ws://, wss:// or unix://" ;;
+    esac
+}
+
 # Setup systemd service (Linux)
 setup_systemd() {
     local install_dir="$1"
@@ -208,6 +219,7 @@ Restart=always
 RestartSec=5
 Environment=SIGNALING_SERVER_URL=$signaling_url
 Environment=COCOON_SECRET=$secret
+Environment=COCOON_SERVICE_MODE=true
 ${setup_token:+Environment=COCOON_SETUP_TOKEN=$setup_token}
 
 [Install]
@@ -252,6 +264,8 @@ setup_launchd() {
         <string>$signaling_url</string>
         <key>COCOON_SECRET</key>
         <string>$secret</string>
+        <key>COCOON_SERVICE_MODE</key>
+        <string>true</string>
 EOF
 
     if [ -n "$setup_token" ]; then
@@ -350,6 +364,9 @@ main() {
 
     info "Detected platform: $target"
 
+    local signaling_url="${SIGNALING_SERVER_URL:-$DEFAULT_SIGNALING_URL}"
+    check_signaling_url "$signaling_url"
+
     # Determine version
     local version="${COCOON_VERSION:-}"
     if [ -z "$version" ]; then
@@ -419,8 +436,6 @@ main() {
         secret=$(generate_secret)
     fi
 
-    local signaling_url="${SIGNALING_SERVER_URL:-$DEFAULT_SIGNALING_URL}"
-
     # Create config directory
     local config_dir="$HOME/.config/cocoon"
     mkdir -p "$config_dir"
@@ -52,7 +52,7 @@ export class CocoonClient {
   }
 
   @trace('creating silk session')
-  async createSession(opts?: { cwd?: string; env?: Record<string, string>; shell?: string }): Promise<SilkSession> {
+  async createSession(opts?: { cwd?: string; env?: Record<string, string>; shell?: string; cleanEnv?: boolean }): Promise<SilkSession> {
     // Ensure WebRTC data channel is open before sending
     console.log(`[CocoonClient] createSession: connecting WebRTC for cocoon=${this.cocoonId}`);
     await this.webrtc.connect();
@@ -95,6 +95,7 @@ export class CocoonClient {
         cwd: opts?.cwd,
         env: opts?.env,
         shell: opts?.shell,
+        clean_env: opts?.cleanEnv,
       };
       console.log(`[CocoonClient] sending silk_create_session:`, msg);
       this.webrtc.send(msg);
@@ -332,6 +332,14 @@ export class CocoonWebRTC {
         this.answerReject = null;
         break;
       }
+      case 'webrtc_channel_open': {
+        console.log(`[CocoonWebRTC] cocoon channel open: ${msg['label']} session=${this.sessionId}`);
+        break;
+      }
+      case 'webrtc_channel_close': {
+        console.warn(`[CocoonWebRTC] cocoon channel closed: ${msg['label']} session=${this.sessionId}`);
+        break;
+      }
       case 'webrtc_error': {
         console.error(`[CocoonWebRTC] error from cocoon: ${msg['message']}`);
         this.answerReject?.(new Error(msg['message'] as string));
@@ -7,7 +7,7 @@ import type { AdiPluginInfo, QueryType, SilkHtmlSpan, SilkSignal, SilkStream } f
 
 export type SignalingMessage =
   // ── silk ──
-  | { type: 'silk_create_session'; cwd?: string; env?: Record<string, string>; shell?: string }
+  | { type: 'silk_create_session'; cwd?: string; env?: Record<string, string>; shell?: string; clean_env?: boolean }
   | { type: 'silk_create_session_response'; session_id: string; cwd: string; shell: string }
   | { type: 'silk_execute'; session_id: string; command: string; command_id: string; cols?: number; rows?: number; env?: Record<string, string> }
   | { type: 'silk_input'; session_id: string; command_id: string; data: string }
@@ -44,6 +44,8 @@ export type SignalingMessage =
   | { type: 'webrtc_answer'; session_id: string; sdp: string }
   | { type: 'webrtc_ice_candidate'; session_id: string; candidate: string; sdp_mid?: string; sdp_mline_index?: number }
   | { type: 'webrtc_session_ended'; session_id: string; reason?: string }
+  | { type: 'webrtc_channel_open'; session_id: string; label: string }
+  | { type: 'webrtc_channel_close'; session_id: string; label: string }
   | { type: 'webrtc_data'; session_id: string; channel: string; data: string; binary: boolean }
   | { type: 'webrtc_error'; session_id: string; code: string; message: string }
 